@@ -1,18 +1,49 @@
-use std::{convert::TryFrom, ffi::CString, path::Path, time::Duration};
+use std::{borrow::Cow, convert::TryFrom, ffi::CString, path::Path, sync::Arc, time::Duration};
 
-use log::{self, debug};
+use log::{self, debug, error};
+#[cfg(feature = "c-sdk")]
 use newrelic_sys as ffi;
 
+#[cfg(feature = "async")]
+use crate::futures::AppFuture;
 use crate::{
+    attributes::AttributeFilter,
+    backend::{self, Backend},
+    config::Config,
+    env,
     error::{Error, Result},
-    transaction::Transaction,
+    ignore::IgnoreRules,
+    metrics::{Counter, Gauge, MetricRegistry, MetricUnit, Summary},
+    naming::{NamingRules, TransactionNamer},
+    transaction::{Transaction, TransactionType},
 };
 
 /// The default timeout when connecting to the daemon upon app creation.
 pub const DEFAULT_APP_TIMEOUT: u16 = 10000;
 
+/// The name of the non-web transactions used to report custom metrics by
+/// `App::flush_metrics`.
+const METRICS_TRANSACTION_NAME: &str = "FlushMetrics";
+
+/// The size of the SDK's app name field, including the trailing NUL byte.
+const APP_NAME_SIZE: usize = 255;
+
+/// The size of the SDK's redirect collector field, including the trailing
+/// NUL byte.
+const REDIRECT_COLLECTOR_SIZE: usize = 100;
+
+/// The length of a New Relic license key.
+const LICENSE_KEY_SIZE: usize = 40;
+
+/// The maximum number of labels New Relic accepts for an app.
+const MAX_LABELS: usize = 64;
+
+/// The maximum length of label names and values, and of host display names.
+const MAX_LABEL_SIZE: usize = 255;
+
 /// Whether to consider transactions for trace generation based on the apdex configuration or a
 /// specific duration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TracingThreshold {
     /// Use 4*apdex(T) as the minimum time a transaction must take before  a trace may be generated
     ApdexFailing,
@@ -21,6 +52,7 @@ pub enum TracingThreshold {
 }
 
 /// Controls the format of the sql put into transaction traces for supported sql-like products.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RecordSQL {
     /// Transaction traces have no sql in them.
     Off,
@@ -32,6 +64,58 @@ pub enum RecordSQL {
     Obfuscated,
 }
 
+/// The backend through which an app records its transactions.
+///
+/// Each backend is only available if its crate feature, given by `feature`,
+/// is enabled; several may be enabled at once, and the backend is chosen
+/// per app using `AppBuilder::backend`. Building an app with a backend whose
+/// feature isn't enabled returns `Error::InvalidConfig`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BackendKind {
+    /// The New Relic C SDK, which talks to the daemon. Requires the `c-sdk`
+    /// feature.
+    CSdk,
+    /// A pure Rust implementation of the C SDK's daemon protocol. Requires
+    /// the `pure-rust` feature.
+    PureRust,
+    /// An exporter to an OpenTelemetry collector over OTLP/HTTP, which
+    /// doesn't need a daemon at all. Requires the `otlp` feature.
+    Otlp,
+}
+
+impl BackendKind {
+    /// The crate feature which makes this backend available.
+    pub fn feature(self) -> &'static str {
+        match self {
+            BackendKind::CSdk => "c-sdk",
+            BackendKind::PureRust => "pure-rust",
+            BackendKind::Otlp => "otlp",
+        }
+    }
+
+    /// Whether this backend is available in this build of the crate.
+    pub fn is_available(self) -> bool {
+        match self {
+            BackendKind::CSdk => cfg!(feature = "c-sdk"),
+            BackendKind::PureRust => cfg!(feature = "pure-rust"),
+            BackendKind::Otlp => cfg!(feature = "otlp"),
+        }
+    }
+}
+
+impl Default for BackendKind {
+    /// The first available backend out of the C SDK, the pure Rust daemon
+    /// client and the OTLP exporter, or the C SDK if none is available.
+    fn default() -> Self {
+        [BackendKind::CSdk, BackendKind::PureRust, BackendKind::Otlp]
+            .iter()
+            .copied()
+            .find(|kind| kind.is_available())
+            .unwrap_or(BackendKind::CSdk)
+    }
+}
+
 /// A builder to construct a New Relic application
 ///
 /// Example:
@@ -56,67 +140,398 @@ pub enum RecordSQL {
 /// # }
 /// ```
 pub struct AppBuilder {
-    config: AppConfig,
+    /// The config being built, or `None` if the builder is disabled.
+    config: Option<AppConfig>,
 }
 
 impl AppBuilder {
     /// Begin creating an App
     pub fn new(name: &str, license_key: &str) -> Result<Self> {
         Ok(Self {
-            config: AppConfig::new(name, license_key)?,
+            config: Some(AppConfig::new(name, license_key)?),
         })
     }
 
+    /// Begin creating a disabled App.
+    ///
+    /// A disabled app never calls into the New Relic SDK, so doesn't need a
+    /// daemon to be running. Every transaction it creates is a no-op, as are
+    /// that transaction's segments, custom events and distributed trace
+    /// payloads. This is useful for local development and CI, without
+    /// needing an `Option<App>` to be threaded through the application.
+    ///
+    /// The other methods of this builder can still be called, but their
+    /// settings are ignored.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use newrelic::AppBuilder;
+    ///
+    /// let app = AppBuilder::disabled()
+    ///     .span_events(true)
+    ///     .build()
+    ///     .expect("Unable to create app");
+    /// let transaction = app
+    ///     .web_transaction("Transaction name")
+    ///     .expect("Could not start transaction");
+    /// assert!(transaction.is_noop());
+    /// ```
+    pub fn disabled() -> Self {
+        Self { config: None }
+    }
+
+    /// The config being built, or `None` if the builder is disabled.
+    #[cfg(feature = "testing")]
+    pub(crate) fn config(&self) -> Option<&AppConfig> {
+        self.config.as_ref()
+    }
+
+    /// The config being built, or `None` if the builder is disabled.
+    fn config_mut(&mut self) -> Option<&mut AppConfig> {
+        self.config.as_mut()
+    }
+
+    /// Begin creating an App using the standard `NEW_RELIC_*` environment
+    /// variables.
+    ///
+    /// `NEW_RELIC_APP_NAME` and `NEW_RELIC_LICENSE_KEY` are required unless
+    /// `NEW_RELIC_ENABLED` is `false`; the remaining variables are optional
+    /// and map onto the other methods of this builder:
+    ///
+    /// | Variable | Method |
+    /// |----------|--------|
+    /// | `NEW_RELIC_ENABLED` | `disabled`, if `false` |
+    /// | `NEW_RELIC_BACKEND` | `backend` |
+    /// | `NEW_RELIC_HOST` | `redirect_collector` |
+    /// | `NEW_RELIC_LABELS` | `labels` |
+    /// | `NEW_RELIC_PROCESS_HOST_DISPLAY_NAME` | `host_display_name` |
+    /// | `NEW_RELIC_HIGH_SECURITY` | `high_security` |
+    /// | `NEW_RELIC_TRANSACTION_TRACER_ENABLED` | `transaction_tracing` |
+    /// | `NEW_RELIC_TRANSACTION_TRACER_TRANSACTION_THRESHOLD` | `transaction_threshold` |
+    /// | `NEW_RELIC_TRANSACTION_TRACER_STACK_TRACE_THRESHOLD` | `stack_trace_threshold` |
+    /// | `NEW_RELIC_TRANSACTION_TRACER_DATASTORE_REPORTING_ENABLED` | `datastore_reporting` |
+    /// | `NEW_RELIC_TRANSACTION_TRACER_DATASTORE_REPORTING_THRESHOLD` | `datastore_reporting_threshold` |
+    /// | `NEW_RELIC_TRANSACTION_TRACER_RECORD_SQL` | `record_sql` |
+    /// | `NEW_RELIC_DATASTORE_TRACER_DATABASE_NAME_REPORTING_ENABLED` | `database_name_reporting` |
+    /// | `NEW_RELIC_DATASTORE_TRACER_INSTANCE_REPORTING_ENABLED` | `datastore_instance_reporting` |
+    /// | `NEW_RELIC_SPAN_EVENTS_ENABLED` | `span_events` |
+    /// | `NEW_RELIC_DISTRIBUTED_TRACING_ENABLED` | `distributed_tracing` |
+    /// | `NEW_RELIC_ATTRIBUTES_INCLUDE` | `attribute_filter` |
+    /// | `NEW_RELIC_ATTRIBUTES_EXCLUDE` | `attribute_filter` |
+    ///
+    /// Booleans may be given as `true`/`false`, `1`/`0`, `yes`/`no` or
+    /// `on`/`off`. Durations are given in (possibly fractional) seconds, as
+    /// with the other New Relic agents; the transaction threshold may also be
+    /// `apdex_f` to use `TracingThreshold::ApdexFailing`. `RecordSQL` must be
+    /// one of `off`, `raw` or `obfuscated`, and the backend one of `c-sdk`,
+    /// `pure-rust` or `otlp`. Attribute rules are given as
+    /// comma-separated lists of patterns, and labels as semicolon-separated
+    /// `name:value` pairs, such as `env:prod;team:billing`.
+    ///
+    /// Returns `Error::EnvVarError`, naming the offending variable, if a
+    /// required variable is missing or any variable has an invalid value.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use newrelic::AppBuilder;
+    ///
+    /// # if false {
+    /// let app = AppBuilder::from_env()
+    ///     .expect("Invalid New Relic configuration")
+    ///     .build()
+    ///     .expect("Unable to create app");
+    /// # }
+    /// ```
+    pub fn from_env() -> Result<Self> {
+        Self::from_vars(&env::Vars::process())
+    }
+
+    /// Begin creating an App using the given environment variables.
+    pub(crate) fn from_vars<F>(vars: &env::Vars<F>) -> Result<Self>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let config = Config::from_vars(vars)?;
+        if config.is_enabled() {
+            vars.required(env::APP_NAME)?;
+            vars.required(env::LICENSE_KEY)?;
+        }
+        Self::from_config(&config)
+    }
+
+    /// Begin creating an App using the given `Config`.
+    ///
+    /// The config must contain an app name and license key; every other
+    /// setting is optional and maps onto the method of this builder with
+    /// the same name.
+    ///
+    /// Returns `Error::InvalidConfig` if the app name or license key is
+    /// missing.
+    ///
+    /// If the config disables the agent, the app name and license key
+    /// aren't required and a disabled builder is returned; see
+    /// `AppBuilder::disabled`.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use newrelic::{AppBuilder, Config};
+    ///
+    /// # if false {
+    /// let config = Config::from_env().expect("Invalid New Relic configuration");
+    /// let app = AppBuilder::from_config(&config)
+    ///     .expect("Invalid New Relic configuration")
+    ///     .build()
+    ///     .expect("Unable to create app");
+    /// # }
+    /// ```
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let mut builder = if config.is_enabled() {
+            let name = config.app_name.as_ref().ok_or_else(|| {
+                Error::InvalidConfig(format!("app_name is required (or set {})", env::APP_NAME))
+            })?;
+            let license_key = config.license_key.as_ref().ok_or_else(|| {
+                Error::InvalidConfig(format!(
+                    "license_key is required (or set {})",
+                    env::LICENSE_KEY
+                ))
+            })?;
+            Self::new(name, license_key)?
+        } else {
+            Self::disabled()
+        };
+
+        if let Some(kind) = config.backend {
+            builder.backend(kind);
+        }
+        if let Some(host) = &config.redirect_collector {
+            builder.redirect_collector(host)?;
+        }
+        if let Some(labels) = &config.labels {
+            let labels: Vec<(&str, &str)> = labels
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str()))
+                .collect();
+            builder.labels(&labels)?;
+        }
+        if let Some(name) = &config.process_host.display_name {
+            builder.host_display_name(name)?;
+        }
+        if let Some(enabled) = config.high_security {
+            builder.high_security(enabled);
+        }
+        let tracer = &config.transaction_tracer;
+        if let Some(enabled) = tracer.enabled {
+            builder.transaction_tracing(enabled);
+        }
+        if let Some(threshold) = tracer.transaction_threshold {
+            builder.transaction_threshold(threshold)?;
+        }
+        if let Some(duration) = tracer.stack_trace_threshold {
+            builder.stack_trace_threshold(duration)?;
+        }
+        let reporting = &tracer.datastore_reporting;
+        if let Some(enabled) = reporting.enabled {
+            builder.datastore_reporting(enabled);
+        }
+        if let Some(duration) = reporting.threshold {
+            builder.datastore_reporting_threshold(duration)?;
+        }
+        if let Some(record_sql) = reporting.record_sql {
+            builder.record_sql(record_sql);
+        }
+        if let Some(enabled) = config.datastore_tracer.database_name_reporting {
+            builder.database_name_reporting(enabled);
+        }
+        if let Some(enabled) = config.datastore_tracer.instance_reporting {
+            builder.datastore_instance_reporting(enabled);
+        }
+        if let Some(enabled) = config.span_events.enabled {
+            builder.span_events(enabled);
+        }
+        if let Some(enabled) = config.distributed_tracing.enabled {
+            #[cfg(feature = "distributed_tracing")]
+            builder.distributed_tracing(enabled);
+            #[cfg(not(feature = "distributed_tracing"))]
+            if enabled {
+                log::warn!(
+                    "Distributed tracing is configured but the distributed_tracing feature is not enabled"
+                );
+            }
+        }
+        let attributes = &config.attributes;
+        if attributes.include.is_some() || attributes.exclude.is_some() {
+            let mut filter = AttributeFilter::new();
+            for pattern in attributes.include.iter().flatten() {
+                filter = filter.include(pattern);
+            }
+            for pattern in attributes.exclude.iter().flatten() {
+                filter = filter.exclude(pattern);
+            }
+            builder.attribute_filter(filter);
+        }
+
+        Ok(builder)
+    }
+
+    /// Choose the backend the app records its transactions with.
+    ///
+    /// Defaults to `BackendKind::default()`, the C SDK if the `c-sdk`
+    /// feature is enabled. The backend's feature must be enabled; otherwise
+    /// `build` returns `Error::InvalidConfig`.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use newrelic::{AppBuilder, BackendKind};
+    ///
+    /// # if false {
+    /// let license_key = std::env::var("NEW_RELIC_LICENSE_KEY").unwrap();
+    /// let app = AppBuilder::new("my app", &license_key)
+    ///     .expect("Invalid license key or app name")
+    ///     .backend(BackendKind::Otlp)
+    ///     .build()
+    ///     .expect("Unable to create app");
+    /// # }
+    /// ```
+    pub fn backend(&mut self, kind: BackendKind) -> &mut Self {
+        if let Some(config) = self.config_mut() {
+            config.backend = kind;
+        }
+        self
+    }
+
+    /// Set the New Relic collector host the daemon should connect to for
+    /// this application.
+    ///
+    /// There is little reason to ever change this from the default; if it
+    /// isn't set, the daemon chooses the correct collector for the
+    /// account's region based on the license key.
+    ///
+    /// Returns an error if `host` contains a NUL byte or is longer than the
+    /// SDK allows (99 bytes).
+    pub fn redirect_collector(&mut self, host: &str) -> Result<&mut Self> {
+        check_length(host, REDIRECT_COLLECTOR_SIZE, "redirect_collector")?;
+        if let Some(config) = self.config_mut() {
+            config.redirect_collector = Some(host.to_string());
+        }
+        Ok(self)
+    }
+
+    /// Set labels, name and value pairs used to group and filter apps in
+    /// the New Relic UI, such as `("env", "prod")`.
+    ///
+    /// Replaces any labels set previously. Returns `Error::InvalidConfig` if
+    /// there are more than 64 labels, or if a name or value is empty,
+    /// longer than 255 bytes, or contains `:` or `;`.
+    ///
+    /// The C SDK doesn't support labels, so they are only reported with the
+    /// `pure-rust` backend, or as resource attributes with the `otlp`
+    /// backend; the C SDK backend logs a warning and ignores them.
+    pub fn labels(&mut self, labels: &[(&str, &str)]) -> Result<&mut Self> {
+        if labels.len() > MAX_LABELS {
+            return Err(Error::InvalidConfig(format!(
+                "at most {} labels are allowed",
+                MAX_LABELS
+            )));
+        }
+        for (name, value) in labels {
+            check_label(name, "name")?;
+            check_label(value, "value")?;
+        }
+        if let Some(config) = self.config_mut() {
+            config.labels = labels
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect();
+        }
+        Ok(self)
+    }
+
+    /// Set the name shown for this host in the New Relic UI, instead of its
+    /// hostname.
+    ///
+    /// Returns `Error::InvalidConfig` if `name` contains a NUL byte or is
+    /// longer than 255 bytes.
+    ///
+    /// The C SDK doesn't support display names, so they are only reported
+    /// with the `pure-rust` backend, or as the `host.display_name` resource
+    /// attribute with the `otlp` backend; the C SDK backend logs a warning
+    /// and ignores them.
+    pub fn host_display_name(&mut self, name: &str) -> Result<&mut Self> {
+        check_length(name, MAX_LABEL_SIZE + 1, "host_display_name")?;
+        if let Some(config) = self.config_mut() {
+            config.host_display_name = Some(name.to_string());
+        }
+        Ok(self)
+    }
+
+    /// Whether to enable high security mode.
+    ///
+    /// High security mode must match the setting of the New Relic account;
+    /// the daemon refuses to connect apps whose setting differs. With high
+    /// security mode enabled, SQL is never recorded raw: `RecordSQL::Raw`
+    /// is treated as `RecordSQL::Obfuscated`.
+    ///
+    /// The C SDK doesn't support high security mode, so it is only reported
+    /// to New Relic with the `pure-rust` backend. The C SDK backend still
+    /// obfuscates SQL, but logs a warning as the daemon isn't told.
+    pub fn high_security(&mut self, enabled: bool) -> &mut Self {
+        if let Some(config) = self.config_mut() {
+            config.high_security = enabled;
+        }
+        self
+    }
+
     /// Whether to enable transaction traces.
     ///
     /// If set to true for a transaction, the transaction tracer records the top-10 slowest queries
     /// along with a stack trace of where the call occurred.
     pub fn transaction_tracing(&mut self, enabled: bool) -> &mut Self {
-        let config = unsafe { self.config.inner.as_mut() }.unwrap();
-        config.transaction_tracer.enabled = enabled;
+        if let Some(config) = self.config_mut() {
+            config.transaction_tracing = enabled;
+        }
         self
     }
 
     /// Whether to consider transactions for trace generation based on the apdex configuration or a
     /// specific duration.
     pub fn transaction_threshold(&mut self, threshold: TracingThreshold) -> Result<&mut Self> {
-        let config = unsafe { self.config.inner.as_mut() }.unwrap();
-
-        match threshold {
-            TracingThreshold::ApdexFailing => {
-                config.transaction_tracer.threshold = ffi::_newrelic_transaction_tracer_threshold_t_NEWRELIC_THRESHOLD_IS_APDEX_FAILING;
-            }
-            TracingThreshold::OverDuration(duration) => {
-                config.transaction_tracer.threshold = ffi::_newrelic_transaction_tracer_threshold_t_NEWRELIC_THRESHOLD_IS_OVER_DURATION;
-                config.transaction_tracer.duration_us =
-                    TryFrom::try_from(duration.as_micros()).map_err(|_| Error::DurationOverFlow)?;
-            }
-        };
-
+        if let TracingThreshold::OverDuration(duration) = threshold {
+            micros(duration)?;
+        }
+        if let Some(config) = self.config_mut() {
+            config.transaction_threshold = threshold;
+        }
         Ok(self)
     }
 
     /// Sets the threshold above which the New Relic SDK will record a stack trace for a
     /// transaction trace.
     pub fn stack_trace_threshold(&mut self, duration: Duration) -> Result<&mut Self> {
-        let config = unsafe { self.config.inner.as_mut() }.unwrap();
-        config.transaction_tracer.stack_trace_threshold_us =
-            TryFrom::try_from(duration.as_micros()).map_err(|_| Error::DurationOverFlow)?;
+        micros(duration)?;
+        if let Some(config) = self.config_mut() {
+            config.stack_trace_threshold = duration;
+        }
         Ok(self)
     }
 
     /// Whether slow datastore queries are recorded.
     pub fn datastore_reporting(&mut self, enabled: bool) -> &mut Self {
-        let config = unsafe { self.config.inner.as_mut() }.unwrap();
-        config.transaction_tracer.datastore_reporting.enabled = enabled;
+        if let Some(config) = self.config_mut() {
+            config.datastore_reporting = enabled;
+        }
         self
     }
 
     /// Specify the threshold above which a datastore query is considered "slow".
     pub fn datastore_reporting_threshold(&mut self, duration: Duration) -> Result<&mut Self> {
-        let config = unsafe { self.config.inner.as_mut() }.unwrap();
-        config.transaction_tracer.datastore_reporting.threshold_us =
-            TryFrom::try_from(duration.as_micros()).map_err(|_| Error::DurationOverFlow)?;
+        micros(duration)?;
+        if let Some(config) = self.config_mut() {
+            config.datastore_reporting_threshold = duration;
+        }
         Ok(self)
     }
 
@@ -124,33 +539,33 @@ impl AppBuilder {
     ///
     /// Only relevant if datastore_reporting is enabled
     pub fn record_sql(&mut self, record_sql: RecordSQL) -> &mut Self {
-        let config = unsafe { self.config.inner.as_mut() }.unwrap();
-        config.transaction_tracer.datastore_reporting.record_sql = match record_sql {
-            RecordSQL::Off => ffi::_newrelic_tt_recordsql_t_NEWRELIC_SQL_OFF,
-            RecordSQL::Raw => ffi::_newrelic_tt_recordsql_t_NEWRELIC_SQL_RAW,
-            RecordSQL::Obfuscated => ffi::_newrelic_tt_recordsql_t_NEWRELIC_SQL_OBFUSCATED,
-        };
+        if let Some(config) = self.config_mut() {
+            config.record_sql = record_sql;
+        }
         self
     }
 
     /// Whether database names inside datastore segments are reported to New Relic.
     pub fn database_name_reporting(&mut self, enabled: bool) -> &mut Self {
-        let config = unsafe { self.config.inner.as_mut() }.unwrap();
-        config.datastore_tracer.database_name_reporting = enabled;
+        if let Some(config) = self.config_mut() {
+            config.database_name_reporting = enabled;
+        }
         self
     }
 
     /// Whether host and port inside datastore segments are reported to New Relic.
     pub fn datastore_instance_reporting(&mut self, enabled: bool) -> &mut Self {
-        let config = unsafe { self.config.inner.as_mut() }.unwrap();
-        config.datastore_tracer.instance_reporting = enabled;
+        if let Some(config) = self.config_mut() {
+            config.datastore_instance_reporting = enabled;
+        }
         self
     }
 
     /// Whether or not span events are generated.
     pub fn span_events(&mut self, enabled: bool) -> &mut Self {
-        let config = unsafe { self.config.inner.as_mut() }.unwrap();
-        config.span_events.enabled = enabled;
+        if let Some(config) = self.config_mut() {
+            config.span_events = enabled;
+        }
         self
     }
 
@@ -158,21 +573,216 @@ impl AppBuilder {
     #[cfg(feature = "distributed_tracing")]
     #[cfg_attr(docsrs, doc(cfg(feature = "distributed_tracing")))]
     pub fn distributed_tracing(&mut self, enabled: bool) -> &mut Self {
-        let config = unsafe { self.config.inner.as_mut() }.unwrap();
-        config.distributed_tracing.enabled = enabled;
+        if let Some(config) = self.config_mut() {
+            config.distributed_tracing = enabled;
+        }
+        self
+    }
+
+    /// Filter the attributes added to the app's transactions and custom
+    /// events.
+    ///
+    /// Attributes excluded by the filter are dropped silently, whichever
+    /// code adds them. This replaces any filter set previously.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use newrelic::{AppBuilder, AttributeFilter};
+    ///
+    /// # fn main() -> Result<(), newrelic::Error> {
+    /// # if false {
+    /// let license_key = std::env::var("NEW_RELIC_LICENSE_KEY").unwrap();
+    /// let app = AppBuilder::new("my app", &license_key)?
+    ///     .attribute_filter(
+    ///         AttributeFilter::new()
+    ///             .exclude("request.headers.authorization")
+    ///             .exclude("*.password"),
+    ///     )
+    ///     .build()?;
+    /// # }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn attribute_filter(&mut self, filter: AttributeFilter) -> &mut Self {
+        if let Some(config) = self.config_mut() {
+            config.attribute_filter = filter;
+        }
+        self
+    }
+
+    /// Rewrite the names of the app's transactions, to prevent metric
+    /// grouping issues.
+    ///
+    /// The rules apply to every transaction name before it is sent to New
+    /// Relic. This replaces any rules set previously.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use newrelic::{AppBuilder, NamingRules};
+    ///
+    /// # fn main() -> Result<(), newrelic::Error> {
+    /// # if false {
+    /// let license_key = std::env::var("NEW_RELIC_LICENSE_KEY").unwrap();
+    /// let app = AppBuilder::new("my app", &license_key)?
+    ///     .naming_rules(NamingRules::new().collapse_ids().max_unique_names(500))
+    ///     .build()?;
+    /// # }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn naming_rules(&mut self, rules: NamingRules) -> &mut Self {
+        if let Some(config) = self.config_mut() {
+            config.naming_rules = rules;
+        }
+        self
+    }
+
+    /// Ignore some of the app's transactions automatically, by name or by
+    /// sampling.
+    ///
+    /// This replaces any rules set previously.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use newrelic::{AppBuilder, IgnoreRules};
+    ///
+    /// # fn main() -> Result<(), newrelic::Error> {
+    /// # if false {
+    /// let license_key = std::env::var("NEW_RELIC_LICENSE_KEY").unwrap();
+    /// let app = AppBuilder::new("my app", &license_key)?
+    ///     .ignore_rules(
+    ///         IgnoreRules::new()
+    ///             .ignore("GET /health")
+    ///             .sample("GET /items/*", 0.1)?,
+    ///     )
+    ///     .build()?;
+    /// # }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn ignore_rules(&mut self, rules: IgnoreRules) -> &mut Self {
+        if let Some(config) = self.config_mut() {
+            config.ignore_rules = rules;
+        }
         self
     }
 
     /// Consume the builder, returning the `App`.
     pub fn build(&self) -> Result<App> {
-        App::with_timeout_ref(&self.config, DEFAULT_APP_TIMEOUT)
+        match &self.config {
+            Some(config) => App::with_timeout_ref(config, DEFAULT_APP_TIMEOUT),
+            None => Ok(App::disabled()),
+        }
+    }
+
+    /// Consume the builder, returning a future which resolves to the `App`
+    /// without blocking the current thread.
+    ///
+    /// Uses the default timeout, `DEFAULT_APP_TIMEOUT`, when establishing a
+    /// connection to the daemon. See `App::connect_async` for details.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// # use newrelic::Error;
+    /// # async fn run() -> Result<(), Error> {
+    /// use newrelic::AppBuilder;
+    ///
+    /// let license_key = std::env::var("NEW_RELIC_LICENSE_KEY").unwrap();
+    ///
+    /// let app = AppBuilder::new("my app", &license_key)?
+    ///     .span_events(true)
+    ///     .build_async()
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "async")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "async")))]
+    pub fn build_async(&self) -> AppFuture {
+        match &self.config {
+            Some(config) => App::connect_async(config.clone(), DEFAULT_APP_TIMEOUT),
+            None => AppFuture::ready(Ok(App::disabled())),
+        }
+    }
+}
+
+/// Check that a label's name or value is valid.
+fn check_label(value: &str, part: &str) -> Result<()> {
+    CString::new(value)?;
+    if value.is_empty() || value.len() > MAX_LABEL_SIZE || value.contains(&[':', ';'][..]) {
+        return Err(Error::InvalidConfig(format!(
+            "label {}s must be between 1 and {} bytes long and not contain ':' or ';', got '{}'",
+            part, MAX_LABEL_SIZE, value
+        )));
+    }
+    Ok(())
+}
+
+/// Check that a string fits into one of the fixed-size, NUL-terminated
+/// string fields of the SDK's app config.
+fn check_length(value: &str, size: usize, field: &str) -> Result<()> {
+    CString::new(value)?;
+    if value.len() >= size {
+        return Err(Error::InvalidConfig(format!(
+            "{} must be at most {} bytes long",
+            field,
+            size - 1
+        )));
     }
+    Ok(())
+}
+
+/// Convert a duration to the microseconds given to the SDK, checking that
+/// it fits.
+pub(crate) fn micros(duration: Duration) -> Result<u64> {
+    u64::try_from(duration.as_micros()).map_err(|_| Error::DurationOverFlow)
 }
 
 #[must_use = "must be used by an App"]
 /// Application config used by New Relic.
+#[derive(Clone)]
+#[cfg_attr(
+    not(any(feature = "c-sdk", feature = "pure-rust", feature = "otlp")),
+    allow(dead_code)
+)]
 pub struct AppConfig {
-    inner: *mut ffi::_newrelic_app_config_t,
+    pub(crate) backend: BackendKind,
+    pub(crate) app_name: String,
+    pub(crate) license_key: String,
+    pub(crate) redirect_collector: Option<String>,
+    pub(crate) labels: Vec<(String, String)>,
+    pub(crate) host_display_name: Option<String>,
+    pub(crate) high_security: bool,
+    pub(crate) transaction_tracing: bool,
+    pub(crate) transaction_threshold: TracingThreshold,
+    pub(crate) stack_trace_threshold: Duration,
+    pub(crate) datastore_reporting: bool,
+    pub(crate) datastore_reporting_threshold: Duration,
+    pub(crate) record_sql: RecordSQL,
+    pub(crate) database_name_reporting: bool,
+    pub(crate) datastore_instance_reporting: bool,
+    pub(crate) span_events: bool,
+    // Only read by the OTLP exporter when creating distributed trace
+    // payloads, which need the distributed_tracing feature.
+    #[cfg_attr(
+        all(
+            feature = "otlp",
+            not(any(
+                feature = "c-sdk",
+                feature = "pure-rust",
+                feature = "distributed_tracing"
+            ))
+        ),
+        allow(dead_code)
+    )]
+    pub(crate) distributed_tracing: bool,
+    pub(crate) attribute_filter: AttributeFilter,
+    pub(crate) naming_rules: NamingRules,
+    pub(crate) ignore_rules: IgnoreRules,
 }
 
 impl AppConfig {
@@ -180,30 +790,68 @@ impl AppConfig {
     /// and license key.
     ///
     /// This function may return `Err` if the name or license key contain
-    /// a NUL byte, or if the SDK deems the name or license key unsuitable.
+    /// a NUL byte, if the name is empty or too long (the SDK allows at most
+    /// 254 bytes), or if the license key isn't 40 bytes long.
+    ///
+    /// The other settings take the SDK's defaults, which can be changed
+    /// using `AppBuilder`.
     pub fn new(name: &str, license_key: &str) -> Result<Self> {
-        let name = CString::new(name)?;
-        let license_key = CString::new(license_key)?;
-        let inner = unsafe { ffi::newrelic_create_app_config(name.as_ptr(), license_key.as_ptr()) };
-        if inner.is_null() {
-            Err(Error::ConfigError)
-        } else {
-            Ok(AppConfig { inner })
+        if name.is_empty() || name.len() >= APP_NAME_SIZE {
+            return Err(Error::InvalidConfig(format!(
+                "app_name must be between 1 and {} bytes long",
+                APP_NAME_SIZE - 1
+            )));
+        }
+        CString::new(name)?;
+        CString::new(license_key)?;
+        if license_key.len() != LICENSE_KEY_SIZE {
+            error!("Invalid license key format");
+            return Err(Error::ConfigError);
         }
+        Ok(AppConfig {
+            backend: BackendKind::default(),
+            app_name: name.to_string(),
+            license_key: license_key.to_string(),
+            redirect_collector: None,
+            labels: Vec::new(),
+            host_display_name: None,
+            high_security: false,
+            transaction_tracing: true,
+            transaction_threshold: TracingThreshold::ApdexFailing,
+            stack_trace_threshold: Duration::from_millis(500),
+            datastore_reporting: true,
+            datastore_reporting_threshold: Duration::from_millis(500),
+            record_sql: RecordSQL::Obfuscated,
+            database_name_reporting: true,
+            datastore_instance_reporting: true,
+            span_events: true,
+            distributed_tracing: false,
+            attribute_filter: AttributeFilter::new(),
+            naming_rules: NamingRules::new(),
+            ignore_rules: IgnoreRules::new(),
+        })
     }
-}
 
-impl Drop for AppConfig {
-    fn drop(&mut self) {
-        unsafe {
-            ffi::newrelic_destroy_app_config(&mut self.inner);
+    /// The format of recorded SQL, which is never raw in high security
+    /// mode.
+    #[cfg_attr(not(any(feature = "c-sdk", feature = "pure-rust")), allow(dead_code))]
+    pub(crate) fn record_sql(&self) -> RecordSQL {
+        match self.record_sql {
+            RecordSQL::Raw if self.high_security => RecordSQL::Obfuscated,
+            record_sql => record_sql,
         }
     }
 }
 
 /// A New Relic application.
 pub struct App {
-    pub(crate) inner: *mut ffi::newrelic_app_t,
+    /// The backend transactions are recorded with, or `None` if the app is
+    /// disabled.
+    backend: Option<Arc<dyn Backend>>,
+    attribute_filter: Arc<AttributeFilter>,
+    namer: Arc<TransactionNamer>,
+    ignore_rules: Arc<IgnoreRules>,
+    metrics: Arc<MetricRegistry>,
 }
 
 impl App {
@@ -231,18 +879,121 @@ impl App {
         Self::with_timeout_ref(&config, timeout)
     }
 
-    fn with_timeout_ref(config: &AppConfig, timeout: u16) -> Result<Self> {
-        let inner = unsafe { ffi::newrelic_create_app(config.inner, timeout) };
-        if inner.is_null() {
-            Err(Error::ConfigError)
-        } else {
-            debug!("Created app");
-            Ok(App { inner })
+    /// Create a new application without blocking the current thread, using
+    /// the specified time in milliseconds as the maximum time to wait for a
+    /// connection to the daemon to be established.
+    ///
+    /// The New Relic SDK blocks the calling thread while it connects to the
+    /// daemon, which would stall an async runtime's worker thread for up to
+    /// `timeout` milliseconds. Instead, the connection is made on a
+    /// dedicated thread; the returned future resolves once it completes and
+    /// works with any async runtime.
+    ///
+    /// Dropping the future cancels the connection: any `App` created after
+    /// the future is dropped is destroyed immediately.
+    ///
+    /// The future resolves to the same errors as `App::with_timeout`, such
+    /// as `Error::ConnectTimeout` if the `pure-rust` backend's daemon didn't
+    /// connect the application within `timeout`.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// # use newrelic::Error;
+    /// # async fn run() -> Result<(), Error> {
+    /// use newrelic::{App, AppConfig};
+    ///
+    /// let license_key = std::env::var("NEW_RELIC_LICENSE_KEY").unwrap();
+    ///
+    /// let config = AppConfig::new("my app", &license_key)?;
+    /// let app = App::connect_async(config, 5000).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "async")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "async")))]
+    pub fn connect_async(config: AppConfig, timeout: u16) -> AppFuture {
+        AppFuture::spawn(config, timeout)
+    }
+
+    /// Create a disabled application.
+    ///
+    /// A disabled app never calls into the New Relic SDK; every transaction
+    /// it creates is a no-op. See `AppBuilder::disabled` for details.
+    pub fn disabled() -> Self {
+        debug!("Created disabled app");
+        App {
+            backend: None,
+            attribute_filter: Arc::default(),
+            namer: Arc::default(),
+            ignore_rules: Arc::default(),
+            metrics: Arc::default(),
+        }
+    }
+
+    /// Whether this app is disabled, in which case its transactions are
+    /// no-ops.
+    pub fn is_disabled(&self) -> bool {
+        self.backend.is_none()
+    }
+
+    /// Create an application which records transactions with `backend`,
+    /// using the attribute filter, naming rules and ignore rules of
+    /// `config`, or the defaults if it's `None`.
+    pub(crate) fn with_backend(backend: Arc<dyn Backend>, config: Option<&AppConfig>) -> Self {
+        App {
+            backend: Some(backend),
+            attribute_filter: Arc::new(
+                config.map_or_else(AttributeFilter::new, |c| c.attribute_filter.clone()),
+            ),
+            namer: Arc::new(TransactionNamer::new(
+                config.map_or_else(NamingRules::new, |c| c.naming_rules.clone()),
+            )),
+            ignore_rules: Arc::new(
+                config.map_or_else(IgnoreRules::new, |c| c.ignore_rules.clone()),
+            ),
+            metrics: Arc::default(),
         }
     }
 
+    /// The backend transactions are recorded with, or `None` if the app is
+    /// disabled.
+    pub(crate) fn backend(&self) -> Option<&Arc<dyn Backend>> {
+        self.backend.as_ref()
+    }
+
+    /// The filter applied to the attributes of this app's transactions and
+    /// custom events.
+    pub(crate) fn attribute_filter(&self) -> &Arc<AttributeFilter> {
+        &self.attribute_filter
+    }
+
+    /// The naming rules applied to this app's transactions.
+    pub(crate) fn namer(&self) -> &Arc<TransactionNamer> {
+        &self.namer
+    }
+
+    /// The rules deciding which of this app's transactions are ignored.
+    pub(crate) fn ignore_rules(&self) -> &Arc<IgnoreRules> {
+        &self.ignore_rules
+    }
+
+    /// The custom metrics created by this app.
+    pub(crate) fn metrics(&self) -> &Arc<MetricRegistry> {
+        &self.metrics
+    }
+
+    fn with_timeout_ref(config: &AppConfig, timeout: u16) -> Result<Self> {
+        let backend = backend::connect(config, timeout)?;
+        debug!("Created app");
+        Ok(App::with_backend(backend, Some(config)))
+    }
+
     /// Begin a new web transaction in New Relic with the given name.
     ///
+    /// The app's `NamingRules` are applied to the name, and the
+    /// transaction is ignored if the app's `IgnoreRules` match the result.
+    ///
     /// This function will return an `Err` if the name contains a NUL byte.
     pub fn web_transaction(&self, name: &str) -> Result<Transaction> {
         Transaction::web(self, name)
@@ -250,24 +1001,134 @@ impl App {
 
     /// Begin a new non-web transaction in New Relic with the given name.
     ///
+    /// The app's `NamingRules` are applied to the name, and the
+    /// transaction is ignored if the app's `IgnoreRules` match the result.
+    ///
     /// This function will return an `Err` if the name contains a NUL byte.
     pub fn non_web_transaction(&self, name: &str) -> Result<Transaction> {
         Transaction::non_web(self, name)
     }
+
+    /// Get or create a custom metric counting things, such as processed
+    /// messages or bytes sent.
+    ///
+    /// Metrics are aggregated in-process, and reported using the New Relic
+    /// SDK's custom metric path each time one of the app's transactions
+    /// ends; ignored transactions don't report metrics. They are also
+    /// reported by `App::flush_metrics`, and when the app is dropped. A
+    /// metric is only reported if a value has been recorded since it was
+    /// last reported. By convention, custom metric names begin with
+    /// `Custom/`.
+    ///
+    /// **Note:** the New Relic SDK can only record metrics in a
+    /// transaction, so `App::flush_metrics` reports any unreported values
+    /// in a real non-web transaction named `FlushMetrics`, including when
+    /// the app is dropped. These transactions appear in New Relic's
+    /// throughput charts and transaction lists like any other.
+    ///
+    /// Creating a metric with the name of an existing metric returns a
+    /// handle to the existing metric, or `Error::InvalidMetric` if the
+    /// existing metric is of a different kind or unit. Returns an error if
+    /// the name contains a NUL byte.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use newrelic::{AppBuilder, MetricUnit};
+    ///
+    /// let app = AppBuilder::disabled().build().expect("Unable to create app");
+    /// let processed = app
+    ///     .counter("Custom/Queue/Processed", MetricUnit::Count)
+    ///     .expect("Could not create metric");
+    /// let depth = app
+    ///     .gauge("Custom/Queue/Depth", MetricUnit::Count)
+    ///     .expect("Could not create metric");
+    /// let sizes = app
+    ///     .summary("Custom/Queue/MessageSize", MetricUnit::Bytes)
+    ///     .expect("Could not create metric");
+    ///
+    /// processed.increment();
+    /// depth.set(42.0);
+    /// sizes.record(1024.0);
+    /// ```
+    pub fn counter(&self, name: &str, unit: MetricUnit) -> Result<Counter> {
+        self.metrics.counter(name, unit)
+    }
+
+    /// Get or create a custom metric measuring a current value, such as a
+    /// queue's depth.
+    ///
+    /// See `App::counter` for how metrics are reported. Unreported values
+    /// are flushed in a non-web `FlushMetrics` transaction, which is
+    /// visible in New Relic, by `App::flush_metrics` and when the app is
+    /// dropped.
+    pub fn gauge(&self, name: &str, unit: MetricUnit) -> Result<Gauge> {
+        self.metrics.gauge(name, unit)
+    }
+
+    /// Get or create a custom metric summarising a distribution of values,
+    /// such as response sizes.
+    ///
+    /// See `App::counter` for how metrics are reported. Unreported values
+    /// are flushed in a non-web `FlushMetrics` transaction, which is
+    /// visible in New Relic, by `App::flush_metrics` and when the app is
+    /// dropped.
+    pub fn summary(&self, name: &str, unit: MetricUnit) -> Result<Summary> {
+        self.metrics.summary(name, unit)
+    }
+
+    /// Report the values recorded by the app's custom metrics since they
+    /// were last reported, without waiting for a transaction to end.
+    ///
+    /// The New Relic SDK can only record custom metrics in a transaction,
+    /// so this starts a non-web transaction named `FlushMetrics`, which
+    /// isn't affected by the app's `NamingRules` or `IgnoreRules`, records
+    /// the metrics in it and ends it. Like any other transaction, it counts
+    /// towards the app's throughput and appears in its transaction list.
+    /// Nothing is started if no values have been recorded, or if the app is
+    /// disabled.
+    ///
+    /// This is called when the app is dropped. Metrics aren't reported on a
+    /// timer; an app whose transactions are rare or ignored should call
+    /// this periodically, such as once a minute, to keep its metrics up to
+    /// date.
+    ///
+    /// Returns `Error::TransactionStartError` if the transaction couldn't be
+    /// started, in which case the values are lost.
+    pub fn flush_metrics(&self) -> Result<()> {
+        let backend = match self.backend() {
+            Some(backend) => backend,
+            None => return Ok(()),
+        };
+        let metrics = self.metrics.drain();
+        if metrics.is_empty() {
+            return Ok(());
+        }
+        let name = CString::new(METRICS_TRANSACTION_NAME)?;
+        let handle = match backend.start_transaction(&name, &TransactionType::NonWeb) {
+            Some(handle) => handle,
+            None => {
+                error!("Could not start transaction to flush metrics");
+                return Err(Error::TransactionStartError);
+            }
+        };
+        for metric in &metrics {
+            backend.record_custom_metric(handle, &metric.name, metric.value, metric.unit);
+        }
+        backend.end_transaction(handle);
+        debug!("Flushed {} metric values", metrics.len());
+        Ok(())
+    }
 }
 
 impl Drop for App {
     fn drop(&mut self) {
-        unsafe {
-            ffi::newrelic_destroy_app(&mut self.inner);
+        if let Err(e) = self.flush_metrics() {
+            error!("Could not flush metrics: {}", e);
         }
-        debug!("Destroyed app");
     }
 }
 
-unsafe impl Send for App {}
-unsafe impl Sync for App {}
-
 /// The log level of the New Relic SDK.
 enum LogLevel {
     /// The highest-priority log level; only errors are logged.
@@ -281,6 +1142,7 @@ enum LogLevel {
 }
 
 impl LogLevel {
+    #[cfg(feature = "c-sdk")]
     fn inner(&self) -> ffi::_newrelic_loglevel_t {
         match self {
             LogLevel::Error => ffi::_newrelic_loglevel_t_NEWRELIC_LOG_ERROR,
@@ -314,11 +1176,12 @@ pub enum LogOutput<'a> {
 }
 
 impl<'a> LogOutput<'a> {
-    fn to_str(&self) -> Option<&'a str> {
+    /// The path given to the SDK; "stderr" and "stdout" are special cased.
+    fn into_path(self) -> Cow<'a, Path> {
         match self {
-            LogOutput::StdErr => Some("stderr"),
-            LogOutput::StdOut => Some("stdout"),
-            LogOutput::File(path) => path.to_str(),
+            LogOutput::StdErr => Cow::Borrowed(Path::new("stderr")),
+            LogOutput::StdOut => Cow::Borrowed(Path::new("stdout")),
+            LogOutput::File(path) => Cow::Borrowed(path),
         }
     }
 }
@@ -345,24 +1208,124 @@ impl<'a> LogOutput<'a> {
 /// ```
 #[must_use]
 pub struct NewRelicConfig<'a> {
-    socket: Option<&'a str>,
+    socket: Option<Cow<'a, str>>,
     timeout: Option<Duration>,
+    #[cfg_attr(not(feature = "c-sdk"), allow(dead_code))]
     log_level: LogLevel,
-    log_output: Option<LogOutput<'a>>,
+    log_output: Option<Cow<'a, Path>>,
+    #[cfg(feature = "otlp")]
+    otlp_endpoint: Option<Cow<'a, str>>,
+    enabled: bool,
 }
 
 impl<'a> Default for NewRelicConfig<'a> {
     fn default() -> Self {
         NewRelicConfig {
+            enabled: true,
             socket: None,
             timeout: None,
             log_level: LogLevel::Info,
             log_output: None,
+            #[cfg(feature = "otlp")]
+            otlp_endpoint: None,
         }
     }
 }
 
+impl NewRelicConfig<'static> {
+    /// Create a config using the standard `NEW_RELIC_*` environment
+    /// variables.
+    ///
+    /// All variables are optional:
+    ///
+    /// - `NEW_RELIC_ENABLED`, if `false`, makes `init` do nothing; see
+    ///   `AppBuilder::disabled`.
+    /// - `NEW_RELIC_DAEMON_SOCKET` sets the daemon socket; see `socket`.
+    /// - `NEW_RELIC_DAEMON_TIMEOUT` sets the timeout, in (possibly
+    ///   fractional) seconds; see `timeout`.
+    /// - `NEW_RELIC_LOG` sets the log output: `stderr`, `stdout` or the path
+    ///   of a log file; see `logging`.
+    /// - `NEW_RELIC_LOG_LEVEL` sets the log level: one of `error`,
+    ///   `warning`, `info` or `debug`; see `logging`.
+    ///
+    /// If only one of `NEW_RELIC_LOG` and `NEW_RELIC_LOG_LEVEL` is set, the
+    /// other takes its default value as described in `logging`.
+    ///
+    /// Returns `Error::EnvVarError`, naming the offending variable, if any
+    /// variable has an invalid value.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use newrelic::NewRelicConfig;
+    ///
+    /// # if false {
+    /// NewRelicConfig::from_env()
+    ///     .expect("Invalid New Relic configuration")
+    ///     .init()
+    ///     .expect("Could not connect to daemon!");
+    /// # }
+    /// ```
+    pub fn from_env() -> Result<Self> {
+        let config = Config::from_env()?;
+        Ok(NewRelicConfig::from_config(&config).into_owned())
+    }
+}
+
 impl<'a> NewRelicConfig<'a> {
+    /// Create a config using the daemon and logging settings of the given
+    /// `Config`.
+    ///
+    /// If only one of the log level and log output is set, the other takes
+    /// its default value as described in `logging`. If the config disables
+    /// the agent, `init` does nothing.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use newrelic::{Config, NewRelicConfig};
+    ///
+    /// # if false {
+    /// let config = Config::from_env().expect("Invalid New Relic configuration");
+    /// NewRelicConfig::from_config(&config)
+    ///     .init()
+    ///     .expect("Could not connect to daemon!");
+    /// # }
+    /// ```
+    pub fn from_config(config: &'a Config) -> Self {
+        let (log_output, log_level) = match (&config.log.output, config.log.level) {
+            (None, None) => (None, LogLevel::Info),
+            (output, level) => (
+                Some(match output {
+                    Some(output) => Cow::Borrowed(output.as_path()),
+                    None => LogOutput::StdErr.into_path(),
+                }),
+                level.unwrap_or(log::Level::Info).into(),
+            ),
+        };
+        NewRelicConfig {
+            enabled: config.is_enabled(),
+            socket: config.daemon.socket.as_deref().map(Cow::Borrowed),
+            timeout: config.daemon.timeout,
+            log_level,
+            log_output,
+            #[cfg(feature = "otlp")]
+            otlp_endpoint: None,
+        }
+    }
+
+    fn into_owned(self) -> NewRelicConfig<'static> {
+        NewRelicConfig {
+            enabled: self.enabled,
+            socket: self.socket.map(|s| Cow::Owned(s.into_owned())),
+            timeout: self.timeout,
+            log_level: self.log_level,
+            log_output: self.log_output.map(|o| Cow::Owned(o.into_owned())),
+            #[cfg(feature = "otlp")]
+            otlp_endpoint: self.otlp_endpoint.map(|e| Cow::Owned(e.into_owned())),
+        }
+    }
+
     /// Set the socket address used to connect to the New Relic daemon.
     ///
     /// Generally, this function only needs to be called explicitly
@@ -383,7 +1346,7 @@ impl<'a> NewRelicConfig<'a> {
     ///     .init();
     /// ```
     pub fn socket(mut self, socket: &'a str) -> Self {
-        self.socket = Some(socket);
+        self.socket = Some(Cow::Borrowed(socket));
         self
     }
 
@@ -392,6 +1355,11 @@ impl<'a> NewRelicConfig<'a> {
     /// to have failed. If this is 0 or unset then the SDK's default value
     /// will be used.
     ///
+    /// For `BackendKind::PureRust` this is how long to wait when reading
+    /// from or writing to the daemon, defaulting to 500ms. For
+    /// `BackendKind::Otlp` it is how long to wait for the collector,
+    /// defaulting to 10s.
+    ///
     /// Example:
     ///
     /// ```rust
@@ -408,6 +1376,30 @@ impl<'a> NewRelicConfig<'a> {
         self
     }
 
+    /// Set the OTLP/HTTP endpoint of the OpenTelemetry collector which
+    /// apps using `BackendKind::Otlp` export to.
+    ///
+    /// Defaults to the `OTEL_EXPORTER_OTLP_ENDPOINT` environment variable,
+    /// or "http://localhost:4318" if that isn't set. The app's license key
+    /// is sent in the `api-key` header, so New Relic's own OTLP endpoint
+    /// can be used directly.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use newrelic::NewRelicConfig;
+    ///
+    /// NewRelicConfig::default()
+    ///     .otlp_endpoint("http://otel-collector:4318")
+    ///     .init();
+    /// ```
+    #[cfg(feature = "otlp")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "otlp")))]
+    pub fn otlp_endpoint(mut self, endpoint: &'a str) -> Self {
+        self.otlp_endpoint = Some(Cow::Borrowed(endpoint));
+        self
+    }
+
     /// Configure logging for the New Relic SDK.
     ///
     /// Defaults to `LogLevel::Info` and `LogOutput::StdErr`.
@@ -415,6 +1407,9 @@ impl<'a> NewRelicConfig<'a> {
     /// Note that this differs to the logs of the New Relic daemon,
     /// which are output by the daemon itself.
     ///
+    /// Only the C SDK logs this way; the pure Rust and OTLP backends log
+    /// through the `log` crate instead.
+    ///
     /// Examples:
     ///
     /// Logging to stderr:
@@ -441,7 +1436,7 @@ impl<'a> NewRelicConfig<'a> {
     /// # }
     /// ```
     pub fn logging(mut self, level: log::Level, output: LogOutput<'a>) -> Self {
-        self.log_output = Some(output);
+        self.log_output = Some(output.into_path());
         self.log_level = level.into();
         self
     }
@@ -449,7 +1444,8 @@ impl<'a> NewRelicConfig<'a> {
     /// Initialise the New Relic SDK.
     ///
     /// If non-default settings are to be used, this must be called
-    /// before the first `App` is created.
+    /// before the first `App` is created. Every backend enabled by the
+    /// crate's features is configured, whichever backend apps later use.
     ///
     /// Example:
     ///
@@ -464,6 +1460,22 @@ impl<'a> NewRelicConfig<'a> {
     /// # }
     /// ```
     pub fn init(self) -> Result<()> {
+        if !self.enabled {
+            debug!("New Relic is disabled, not initialising the SDK");
+            return Ok(());
+        }
+        #[cfg(feature = "pure-rust")]
+        backend::configure_daemon(self.socket.as_deref().map(str::to_string), self.timeout);
+        #[cfg(feature = "otlp")]
+        backend::configure_otlp(
+            self.otlp_endpoint.as_deref().map(str::to_string),
+            self.timeout,
+        );
+        self.init_c_sdk()
+    }
+
+    #[cfg(feature = "c-sdk")]
+    fn init_c_sdk(self) -> Result<()> {
         if let Some(log_output) = self.log_output {
             debug!("Configuring logging");
             let log_output = log_output.to_str().ok_or(Error::LogFileError)?;
@@ -477,7 +1489,7 @@ impl<'a> NewRelicConfig<'a> {
             debug!("Not configuring logging");
         }
         let socket = match self.socket {
-            Some(s) => Some(CString::new(s)?),
+            Some(s) => Some(CString::new(s.as_ref())?),
             None => None,
         };
         let timeout = self.timeout.map(|t| t.as_millis()).unwrap_or(0) as i32;
@@ -492,4 +1504,171 @@ impl<'a> NewRelicConfig<'a> {
             Err(Error::DaemonError)
         }
     }
+
+    #[cfg(not(feature = "c-sdk"))]
+    fn init_c_sdk(self) -> Result<()> {
+        if self.log_output.is_some() {
+            debug!("Logging through the log crate, ignoring the SDK log output");
+        }
+        let _ = (self.socket, self.timeout);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn builder() -> AppBuilder {
+        AppBuilder::new("test", &"0".repeat(LICENSE_KEY_SIZE)).unwrap()
+    }
+
+    fn config(builder: &AppBuilder) -> &AppConfig {
+        builder.config.as_ref().unwrap()
+    }
+
+    #[test]
+    fn labels() {
+        let mut builder = builder();
+        builder.labels(&[("env", "prod"), ("team", "web")]).unwrap();
+        assert_eq!(
+            config(&builder).labels,
+            vec![
+                ("env".to_string(), "prod".to_string()),
+                ("team".to_string(), "web".to_string())
+            ]
+        );
+
+        let names: Vec<String> = (0..=MAX_LABELS).map(|i| format!("label{}", i)).collect();
+        let labels: Vec<(&str, &str)> = names.iter().map(|name| (name.as_str(), "value")).collect();
+        assert!(builder.labels(&labels[..MAX_LABELS]).is_ok());
+        assert_eq!(config(&builder).labels.len(), MAX_LABELS);
+        assert!(matches!(
+            builder.labels(&labels),
+            Err(Error::InvalidConfig(_))
+        ));
+
+        let long = "a".repeat(MAX_LABEL_SIZE + 1);
+        let invalid = [
+            ("", "prod"),
+            ("env", ""),
+            ("env:name", "prod"),
+            ("env", "prod;staging"),
+            (long.as_str(), "prod"),
+            ("env", long.as_str()),
+        ];
+        for label in &invalid {
+            assert!(
+                matches!(builder.labels(&[*label]), Err(Error::InvalidConfig(_))),
+                "{:?}",
+                label
+            );
+        }
+        // Invalid labels don't replace the previous ones.
+        assert_eq!(config(&builder).labels.len(), MAX_LABELS);
+    }
+
+    #[test]
+    fn host_display_name() {
+        let mut builder = builder();
+        builder.host_display_name("web-1").unwrap();
+        assert_eq!(config(&builder).host_display_name.as_deref(), Some("web-1"));
+
+        let longest = "a".repeat(MAX_LABEL_SIZE);
+        assert!(builder.host_display_name(&longest).is_ok());
+        let too_long = "a".repeat(MAX_LABEL_SIZE + 1);
+        assert!(matches!(
+            builder.host_display_name(&too_long),
+            Err(Error::InvalidConfig(_))
+        ));
+        assert!(matches!(
+            builder.host_display_name("web\0"),
+            Err(Error::NulError(_))
+        ));
+        assert_eq!(config(&builder).host_display_name, Some(longest));
+    }
+
+    #[test]
+    fn high_security_obfuscates_sql() {
+        let mut builder = builder();
+        builder.record_sql(RecordSQL::Raw);
+        assert_eq!(config(&builder).record_sql(), RecordSQL::Raw);
+        builder.high_security(true);
+        assert!(config(&builder).high_security);
+        assert_eq!(config(&builder).record_sql(), RecordSQL::Obfuscated);
+        builder.record_sql(RecordSQL::Off);
+        assert_eq!(config(&builder).record_sql(), RecordSQL::Off);
+    }
+
+    #[test]
+    fn disabled_apps_are_inert() {
+        use std::time::SystemTime;
+
+        use crate::segment::{Datastore, DatastoreParamsBuilder, ExternalParamsBuilder};
+
+        let app = AppBuilder::disabled().build().unwrap();
+        assert!(app.is_disabled());
+
+        let transaction = app.web_transaction("index").unwrap();
+        assert!(transaction.is_noop());
+        transaction.add_attribute("user", "alice").unwrap();
+        transaction.name("renamed").unwrap();
+        transaction.notice_error(1, "message", "class").unwrap();
+        transaction
+            .set_timing(SystemTime::now(), Duration::from_millis(10))
+            .unwrap();
+        transaction
+            .record_custom_metric("Custom/duration", Duration::from_millis(1))
+            .unwrap();
+
+        let event = transaction.custom_event("Event").unwrap();
+        event.add_attribute("size", 3).unwrap();
+        event.record();
+
+        let datastore = DatastoreParamsBuilder::new(Datastore::Postgres)
+            .build()
+            .unwrap();
+        let external = ExternalParamsBuilder::new("https://example.com/")
+            .build()
+            .unwrap();
+        assert_eq!(transaction.custom_segment("custom", "test", |_| 1), 1);
+        assert_eq!(transaction.datastore_segment(&datastore, |_| 2), 2);
+        {
+            let segment = transaction.create_external_segment(&external);
+            segment
+                .set_timing(Duration::from_millis(1), Duration::from_millis(2))
+                .unwrap();
+            #[cfg(feature = "distributed_tracing")]
+            assert_eq!(segment.distributed_trace(), "");
+        }
+        let mut segment = transaction.create_custom_segment("custom", "test");
+        segment.end();
+        segment
+            .set_timing(Duration::from_millis(1), Duration::from_millis(2))
+            .unwrap();
+        transaction.ignore().unwrap();
+
+        app.counter("Custom/count", MetricUnit::Count)
+            .unwrap()
+            .increment();
+        app.flush_metrics().unwrap();
+    }
+
+    #[test]
+    fn unavailable_backends_are_invalid() {
+        let kinds = [BackendKind::CSdk, BackendKind::PureRust, BackendKind::Otlp];
+        for kind in kinds.iter().copied().filter(|kind| !kind.is_available()) {
+            match builder().backend(kind).build() {
+                Err(Error::InvalidConfig(message)) => assert!(message.contains(kind.feature())),
+                Err(e) => panic!("{:?} returned {}", kind, e),
+                Ok(_) => panic!("{:?} should be unavailable", kind),
+            }
+        }
+        if !BackendKind::default().is_available() {
+            assert!(matches!(
+                App::new("test", &"0".repeat(LICENSE_KEY_SIZE)),
+                Err(Error::InvalidConfig(_))
+            ));
+        }
+    }
 }
@@ -0,0 +1,499 @@
+#[cfg(feature = "serde")]
+use std::borrow::Cow;
+use std::{collections::HashSet, sync::Mutex};
+
+use log::{debug, warn};
+#[cfg(feature = "serde")]
+use serde::Serialize;
+#[cfg(feature = "serde")]
+use serde_json::{Map, Value};
+
+use crate::error::{Error, Result};
+#[cfg(feature = "serde")]
+use crate::transaction::Attribute;
+
+/// The maximum number of attributes New Relic keeps for a transaction.
+pub(crate) const MAX_TRANSACTION_ATTRIBUTES: usize = 64;
+
+/// The maximum number of attributes New Relic keeps for a custom event.
+pub(crate) const MAX_CUSTOM_EVENT_ATTRIBUTES: usize = 255;
+
+/// The maximum length, in bytes, of an attribute's name or string value.
+pub(crate) const MAX_ATTRIBUTE_BYTES: usize = 255;
+
+/// The names of the attributes added to a transaction or custom event,
+/// used to enforce New Relic's limits before the data is dropped
+/// silently.
+pub(crate) struct AttributeNames {
+    limit: usize,
+    names: Mutex<HashSet<String>>,
+}
+
+impl AttributeNames {
+    pub(crate) fn new(limit: usize) -> Self {
+        AttributeNames {
+            limit,
+            names: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Check that an attribute called `name` can be added, counting it
+    /// towards the limit.
+    ///
+    /// Replacing an attribute which was already added doesn't count again.
+    pub(crate) fn add(&self, name: &str) -> Result<()> {
+        if name.len() > MAX_ATTRIBUTE_BYTES {
+            return Err(Error::AttributeNameTooLong {
+                name: name.to_string(),
+                limit: MAX_ATTRIBUTE_BYTES,
+            });
+        }
+        let mut names = self.names.lock().unwrap();
+        if names.contains(name) {
+            return Ok(());
+        }
+        if names.len() >= self.limit {
+            return Err(Error::TooManyAttributes {
+                name: name.to_string(),
+                limit: self.limit,
+            });
+        }
+        names.insert(name.to_string());
+        Ok(())
+    }
+}
+
+/// Rules deciding which attributes are sent to New Relic, matching the
+/// `attributes.include` and `attributes.exclude` settings of the other New
+/// Relic agents.
+///
+/// Rules are glob patterns, in which `*` matches any sequence of
+/// characters, including none. Attributes are included unless an exclude
+/// rule matches their name; an include rule can then re-include more
+/// specific names. When several rules match, the most specific wins: the
+/// one with the most characters other than `*`. An exclude rule wins over
+/// an equally specific include rule.
+///
+/// The filter is configured using `AppBuilder::attribute_filter`, and
+/// applies to the attributes of every transaction and custom event created
+/// by the app. Filtered attributes are dropped silently, and don't count
+/// towards New Relic's attribute limits.
+///
+/// Unlike the other agents, there are no per-destination rules, such as
+/// `transaction_events.attributes.exclude`: the C SDK and daemon attach a
+/// transaction's attributes to its events, traces and errors alike, so
+/// one filter applies to every destination.
+///
+/// Example:
+///
+/// ```rust
+/// use newrelic::AttributeFilter;
+///
+/// let filter = AttributeFilter::new()
+///     .exclude("request.headers.*")
+///     .include("request.headers.user-agent")
+///     .exclude("*.password");
+///
+/// assert!(filter.is_included("request.method"));
+/// assert!(filter.is_included("request.headers.user-agent"));
+/// assert!(!filter.is_included("request.headers.authorization"));
+/// assert!(!filter.is_included("user.password"));
+/// ```
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct AttributeFilter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl AttributeFilter {
+    /// Create a filter which includes every attribute.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Include attributes whose names match `pattern`, overriding less
+    /// specific exclude rules.
+    pub fn include(mut self, pattern: impl Into<String>) -> Self {
+        self.include.push(pattern.into());
+        self
+    }
+
+    /// Exclude attributes whose names match `pattern`.
+    pub fn exclude(mut self, pattern: impl Into<String>) -> Self {
+        self.exclude.push(pattern.into());
+        self
+    }
+
+    /// Whether the attribute called `name` is sent to New Relic.
+    pub fn is_included(&self, name: &str) -> bool {
+        let include = most_specific(&self.include, name);
+        let exclude = most_specific(&self.exclude, name);
+        match (include, exclude) {
+            (_, None) => true,
+            (None, Some(_)) => false,
+            (Some(include), Some(exclude)) => include > exclude,
+        }
+    }
+
+    /// Whether the attribute called `name` is sent to New Relic, logging
+    /// when it isn't.
+    pub(crate) fn check(&self, name: &str) -> bool {
+        let included = self.is_included(name);
+        if !included {
+            debug!("Attribute {} excluded by the attribute filter", name);
+        }
+        included
+    }
+}
+
+/// The specificity of the most specific of `patterns` which matches
+/// `name`, if any do.
+fn most_specific(patterns: &[String], name: &str) -> Option<usize> {
+    patterns
+        .iter()
+        .filter(|pattern| matches(pattern, name))
+        .map(|pattern| pattern.chars().filter(|&c| c != '*').count())
+        .max()
+}
+
+/// Whether the glob `pattern` matches the whole of `name`.
+pub(crate) fn matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.as_bytes();
+    let name = name.as_bytes();
+    let (mut p, mut n) = (0, 0);
+    // The position of the last `*` seen, and of the name when it was seen,
+    // to backtrack to when the rest of the pattern fails to match.
+    let mut star = None;
+    while n < name.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, n));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == name[n] {
+            p += 1;
+            n += 1;
+        } else if let Some((star_p, star_n)) = star {
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Truncate the string value of the attribute `name` to New Relic's
+/// maximum length, without splitting a UTF-8 character.
+pub(crate) fn truncate<'a>(name: &str, value: &'a str) -> &'a str {
+    if value.len() <= MAX_ATTRIBUTE_BYTES {
+        return value;
+    }
+    let mut end = MAX_ATTRIBUTE_BYTES;
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    warn!(
+        "Truncated the value of attribute {} from {} to {} bytes, New Relic's limit is {} bytes",
+        name,
+        value.len(),
+        end,
+        MAX_ATTRIBUTE_BYTES
+    );
+    &value[..end]
+}
+
+/// Flatten a serializable value into named attributes.
+///
+/// The value must serialize to a map, such as a struct. Nested maps are
+/// flattened with their keys joined by dots, so `{"user": {"id": 1}}`
+/// becomes the attribute `user.id`. New Relic attributes can't hold lists,
+/// so sequences are added as their JSON representation, and null values
+/// are skipped.
+#[cfg(feature = "serde")]
+pub(crate) fn flatten<T>(value: &T) -> Result<Vec<(String, Attribute<'static>)>>
+where
+    T: Serialize + ?Sized,
+{
+    let value = serde_json::to_value(value).map_err(|e| Error::SerializeError(e.to_string()))?;
+    let map = match value {
+        Value::Object(map) => map,
+        other => {
+            return Err(Error::SerializeError(format!(
+                "expected a struct or map, found {}",
+                kind(&other)
+            )))
+        }
+    };
+    let mut attributes = Vec::new();
+    flatten_map(None, map, &mut attributes);
+    Ok(attributes)
+}
+
+#[cfg(feature = "serde")]
+fn flatten_map(
+    prefix: Option<&str>,
+    map: Map<String, Value>,
+    attributes: &mut Vec<(String, Attribute<'static>)>,
+) {
+    for (key, value) in map {
+        let name = match prefix {
+            Some(prefix) => format!("{}.{}", prefix, key),
+            None => key,
+        };
+        let attribute = match value {
+            Value::Null => continue,
+            Value::Bool(b) => Attribute::Bool(b),
+            Value::Number(n) => match (n.as_i64(), n.as_u64()) {
+                (Some(l), _) => Attribute::Long(l),
+                (None, Some(u)) => Attribute::UnsignedLong(u),
+                (None, None) => Attribute::Float(n.as_f64().unwrap_or_default()),
+            },
+            Value::String(s) => Attribute::Text(Cow::Owned(s)),
+            Value::Array(_) => Attribute::Text(Cow::Owned(value.to_string())),
+            Value::Object(map) => {
+                flatten_map(Some(&name), map, attributes);
+                continue;
+            }
+        };
+        attributes.push((name, attribute));
+    }
+}
+
+/// A description of the kind of a JSON value, for error messages.
+#[cfg(feature = "serde")]
+fn kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "a sequence",
+        Value::Object(_) => "a map",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_without_wildcards() {
+        assert!(matches("request.method", "request.method"));
+        assert!(!matches("request.method", "request.methods"));
+        assert!(!matches("request.method", "request"));
+        assert!(matches("", ""));
+        assert!(!matches("", "request"));
+    }
+
+    #[test]
+    fn matches_leading_wildcard() {
+        assert!(matches("*.password", "user.password"));
+        assert!(matches("*.password", "a.b.password"));
+        assert!(matches("*password", "password"));
+        assert!(!matches("*.password", "password"));
+        assert!(!matches("*.password", "user.password.hash"));
+    }
+
+    #[test]
+    fn matches_trailing_wildcard() {
+        assert!(matches("request.headers.*", "request.headers.host"));
+        assert!(matches("request.headers.*", "request.headers."));
+        assert!(!matches("request.headers.*", "request.headers"));
+        assert!(!matches("request.headers.*", "response.headers.host"));
+    }
+
+    #[test]
+    fn matches_inner_wildcards() {
+        assert!(matches("request.*.host", "request.headers.host"));
+        assert!(matches("request.*.host", "request..host"));
+        assert!(!matches("request.*.host", "request.headers.hostname"));
+        // Backtracking past a partial match of the rest of the pattern.
+        assert!(matches("a*ab", "aaab"));
+        assert!(matches("*a*b*", "xaxxbx"));
+        assert!(!matches("*a*b*", "xbxxax"));
+        assert!(matches("**", "anything"));
+        assert!(matches("*", ""));
+    }
+
+    #[test]
+    fn includes_everything_by_default() {
+        let filter = AttributeFilter::new();
+        assert!(filter.is_included("request.headers.authorization"));
+        assert!(filter.is_included(""));
+    }
+
+    #[test]
+    fn most_specific_rule_wins() {
+        let filter = AttributeFilter::new()
+            .exclude("request.*")
+            .include("request.headers.*")
+            .exclude("request.headers.cookie");
+        assert!(!filter.is_included("request.method"));
+        assert!(filter.is_included("request.headers.host"));
+        assert!(!filter.is_included("request.headers.cookie"));
+        assert!(filter.is_included("response.status"));
+        // Include rules alone don't exclude anything.
+        let filter = AttributeFilter::new().include("user.*");
+        assert!(filter.is_included("request.method"));
+    }
+
+    #[test]
+    fn exclude_wins_ties() {
+        let filter = AttributeFilter::new().include("user.*").exclude("*.email");
+        // Both rules have five characters other than `*`.
+        assert!(!filter.is_included("user.email"));
+        assert!(filter.is_included("user.name"));
+        let filter = AttributeFilter::new().include("user.id").exclude("user.id");
+        assert!(!filter.is_included("user.id"));
+    }
+
+    /// The flattened attributes of a value as they are sent to the
+    /// backend, sorted by name.
+    #[cfg(feature = "serde")]
+    fn flattened(value: &serde_json::Value) -> Vec<(String, String)> {
+        let mut attributes: Vec<(String, String)> = flatten(value)
+            .unwrap()
+            .into_iter()
+            .map(|(name, attribute)| {
+                let value = attribute
+                    .with_value(&name, |value| format!("{:?}", value))
+                    .unwrap()
+                    .unwrap();
+                (name, value)
+            })
+            .collect();
+        attributes.sort();
+        attributes
+    }
+
+    #[cfg(feature = "serde")]
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn flatten_nested_maps() {
+        let value = serde_json::json!({
+            "user": {
+                "id": 42,
+                "address": { "city": "Leeds" },
+            },
+            "request_id": "abc",
+        });
+        assert_eq!(
+            flattened(&value),
+            pairs(&[
+                ("request_id", r#"String("abc")"#),
+                ("user.address.city", r#"String("Leeds")"#),
+                ("user.id", "Long(42)"),
+            ])
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn flatten_values() {
+        let value = serde_json::json!({
+            "tags": ["a", 1],
+            "missing": null,
+            "empty": {},
+            "big": u64::MAX,
+            "max": i64::MAX,
+            "negative": -1,
+            "ratio": 0.5,
+            "admin": false,
+        });
+        assert_eq!(
+            flattened(&value),
+            pairs(&[
+                ("admin", "Bool(false)"),
+                ("big", "Float(1.8446744073709552e19)"),
+                ("max", "Long(9223372036854775807)"),
+                ("negative", "Long(-1)"),
+                ("ratio", "Float(0.5)"),
+                ("tags", r#"String("[\"a\",1]")"#),
+            ])
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn flatten_rejects_non_maps() {
+        for value in &[
+            serde_json::json!(1),
+            serde_json::json!("string"),
+            serde_json::json!([1, 2]),
+            serde_json::Value::Null,
+        ] {
+            match flatten(value) {
+                Err(Error::SerializeError(message)) => {
+                    assert!(message.contains(kind(value)), "{}", message)
+                }
+                _ => panic!("{} was flattened", value),
+            }
+        }
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn transactions_keep_at_most_64_attributes() {
+        use crate::testing::RecordingApp;
+
+        let app = RecordingApp::new();
+        {
+            let transaction = app.web_transaction("index").unwrap();
+            for i in 0..MAX_TRANSACTION_ATTRIBUTES {
+                transaction.add_attribute(&format!("a{}", i), 1).unwrap();
+            }
+            // Replacing an existing attribute doesn't count towards the limit.
+            transaction.add_attribute("a0", 2).unwrap();
+            match transaction.add_attribute("extra", 1) {
+                Err(Error::TooManyAttributes { name, limit }) => {
+                    assert_eq!(name, "extra");
+                    assert_eq!(limit, MAX_TRANSACTION_ATTRIBUTES);
+                }
+                result => panic!("Unexpected result {:?}", result),
+            }
+        }
+        let transactions = app.transactions();
+        let attributes = &transactions[0].attributes;
+        assert_eq!(attributes.len(), MAX_TRANSACTION_ATTRIBUTES);
+        assert!(!attributes.contains_key("extra"));
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn custom_events_each_keep_at_most_255_attributes() {
+        use crate::testing::RecordingApp;
+
+        let app = RecordingApp::new();
+        {
+            let transaction = app.web_transaction("index").unwrap();
+            for _ in 0..2 {
+                let event = transaction.custom_event("Event").unwrap();
+                for i in 0..MAX_CUSTOM_EVENT_ATTRIBUTES {
+                    event.add_attribute(&format!("a{}", i), 1).unwrap();
+                }
+                event.add_attribute("a0", 2).unwrap();
+                match event.add_attribute("extra", 1) {
+                    Err(Error::TooManyAttributes { name, limit }) => {
+                        assert_eq!(name, "extra");
+                        assert_eq!(limit, MAX_CUSTOM_EVENT_ATTRIBUTES);
+                    }
+                    result => panic!("Unexpected result {:?}", result.map(|_| ())),
+                }
+                event.record();
+            }
+        }
+        let transactions = app.transactions();
+        let events = &transactions[0].custom_events;
+        assert_eq!(events.len(), 2);
+        for event in events {
+            assert_eq!(event.attributes.len(), MAX_CUSTOM_EVENT_ATTRIBUTES);
+        }
+    }
+}
@@ -0,0 +1,1062 @@
+//! A backend which speaks the daemon's protocol directly, without the C SDK.
+//!
+//! Transactions are recorded in memory and sent to the daemon when they
+//! end, along with the metrics, events and errors the C SDK would create
+//! for them. Transaction traces, slow queries, span events and distributed
+//! trace payloads aren't supported.
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::CStr,
+    io,
+    os::unix::net::UnixStream,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, RwLock,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, error, warn};
+use serde_json::{json, Map, Value};
+
+use super::{
+    random_u64, uri_host, AttributeValue, Backend, CustomEventHandle, SegmentHandle,
+    TransactionHandle,
+};
+use crate::{
+    app::{AppConfig, RecordSQL, TracingThreshold},
+    error::{Error, Result},
+    metrics::MetricUnit,
+    protocol::{self, AppInfo, AppStatus, ErrorData, Metric, MetricData, TransactionData},
+    segment::{DatastoreParams, ExternalParams},
+    transaction::TransactionType,
+};
+
+/// The socket used by the daemon if one isn't configured.
+const DEFAULT_SOCKET: &str = "/tmp/.newrelic.sock";
+
+/// How long to wait when reading from or writing to the daemon if a
+/// timeout isn't configured.
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long to wait between queries while the daemon connects an app.
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The agent language and version reported to the daemon. The collector
+/// only accepts the languages of the official agents, so this backend
+/// identifies itself as the version of the C SDK whose protocol it speaks.
+const AGENT_LANGUAGE: &str = "c";
+const AGENT_VERSION: &str = "1.3.0";
+
+struct DaemonSettings {
+    socket: Option<String>,
+    timeout: Option<Duration>,
+}
+
+static SETTINGS: RwLock<DaemonSettings> = RwLock::new(DaemonSettings {
+    socket: None,
+    timeout: None,
+});
+
+/// Held by tests which configure the daemon settings and connect apps, so
+/// that they don't change each other's settings.
+#[cfg(all(test, any(feature = "testing", feature = "async")))]
+pub(crate) static TEST_SETTINGS_LOCK: Mutex<()> = Mutex::new(());
+
+/// Set the daemon socket and timeout used by apps created from now on.
+pub(crate) fn configure(socket: Option<String>, timeout: Option<Duration>) {
+    let mut settings = SETTINGS.write().unwrap();
+    settings.socket = socket;
+    settings.timeout = timeout.filter(|timeout| !timeout.is_zero());
+}
+
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| std::fs::read_to_string("/etc/hostname"))
+        .map(|name| name.trim().to_string())
+        .unwrap_or_default()
+}
+
+/// The app's settings, reported to New Relic using the keys of the
+/// configuration file.
+fn settings(config: &AppConfig) -> Value {
+    json!({
+        "transaction_tracer.enabled": config.transaction_tracing,
+        "transaction_tracer.transaction_threshold": match config.transaction_threshold {
+            TracingThreshold::ApdexFailing => json!("apdex_f"),
+            TracingThreshold::OverDuration(duration) => json!(duration.as_secs_f64()),
+        },
+        "transaction_tracer.stack_trace_threshold": config.stack_trace_threshold.as_secs_f64(),
+        "transaction_tracer.datastore_reporting.enabled": config.datastore_reporting,
+        "transaction_tracer.datastore_reporting.threshold":
+            config.datastore_reporting_threshold.as_secs_f64(),
+        "transaction_tracer.datastore_reporting.record_sql": match config.record_sql() {
+            RecordSQL::Off => "off",
+            RecordSQL::Raw => "raw",
+            RecordSQL::Obfuscated => "obfuscated",
+        },
+        "datastore_tracer.database_name_reporting": config.database_name_reporting,
+        "datastore_tracer.instance_reporting": config.datastore_instance_reporting,
+        "span_events.enabled": config.span_events,
+        "distributed_tracing.enabled": config.distributed_tracing,
+    })
+}
+
+/// The app's labels, in the format the daemon passes on to New Relic.
+fn labels(config: &AppConfig) -> Value {
+    config
+        .labels
+        .iter()
+        .map(|(name, value)| json!({ "label_type": name, "label_value": value }))
+        .collect()
+}
+
+fn app_info(config: &AppConfig) -> AppInfo {
+    AppInfo {
+        license: config.license_key.clone(),
+        app_name: config.app_name.clone(),
+        agent_language: AGENT_LANGUAGE.to_string(),
+        agent_version: AGENT_VERSION.to_string(),
+        high_security: config.high_security,
+        redirect_collector: config.redirect_collector.clone().unwrap_or_default(),
+        environment: "[]".to_string(),
+        settings: settings(config).to_string(),
+        labels: labels(config).to_string(),
+        display_host: config.host_display_name.clone().unwrap_or_default(),
+        supported_security_policies: "{}".to_string(),
+        host: hostname(),
+    }
+}
+
+fn seconds_since_epoch(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs_f64())
+        .unwrap_or_default()
+}
+
+fn to_string(value: &CStr) -> String {
+    value.to_string_lossy().into_owned()
+}
+
+fn attribute_value(value: AttributeValue<'_>) -> Value {
+    match value {
+        AttributeValue::Int(i) => json!(i),
+        AttributeValue::Long(l) => json!(l),
+        AttributeValue::Float(f) => json!(f),
+        AttributeValue::String(s) => json!(to_string(s)),
+        AttributeValue::Bool(b) => json!(b),
+    }
+}
+
+/// The connection to the daemon, shared by the app and its transactions.
+struct Connection {
+    stream: Option<UnixStream>,
+    agent_run_id: Option<String>,
+}
+
+struct NoticedError {
+    priority: i32,
+    message: String,
+    class: String,
+    backtrace: Vec<String>,
+    when: SystemTime,
+}
+
+enum SegmentKind {
+    Custom {
+        name: String,
+        category: String,
+    },
+    Datastore {
+        product: &'static str,
+        collection: Option<String>,
+        operation: Option<String>,
+    },
+    External {
+        host: String,
+    },
+}
+
+struct SegmentState {
+    kind: SegmentKind,
+    parent: Option<usize>,
+    started: Instant,
+    /// The start time, relative to the start of the transaction, set by
+    /// `set_segment_timing`, if any.
+    start: Option<Duration>,
+    duration: Option<Duration>,
+}
+
+struct TransactionState {
+    kind: TransactionType,
+    name: String,
+    renamed: bool,
+    start: SystemTime,
+    started: Instant,
+    /// The duration set by `set_transaction_timing`, if any.
+    duration: Option<Duration>,
+    attributes: Map<String, Value>,
+    error: Option<NoticedError>,
+    custom_metrics: Vec<(String, f64)>,
+    custom_events: Vec<String>,
+    segments: Vec<SegmentState>,
+    /// The segments which haven't ended, most recently started last.
+    active_segments: Vec<usize>,
+    ignored: bool,
+}
+
+struct CustomEventState {
+    event_type: String,
+    timestamp: SystemTime,
+    attributes: Map<String, Value>,
+}
+
+/// The metrics of a transaction, keyed by whether they're scoped and name.
+#[derive(Default)]
+struct Metrics(BTreeMap<(bool, String), MetricData>);
+
+impl Metrics {
+    fn add(
+        &mut self,
+        name: String,
+        scoped: bool,
+        forced: bool,
+        total: Duration,
+        exclusive: Duration,
+    ) {
+        let total = total.as_secs_f64();
+        let data = self.0.entry((scoped, name)).or_insert(MetricData {
+            min: total,
+            scoped,
+            forced,
+            ..MetricData::default()
+        });
+        data.count += 1.0;
+        data.total += total;
+        data.exclusive += exclusive.as_secs_f64();
+        data.min = data.min.min(total);
+        data.max = data.max.max(total);
+        data.sum_squares += total * total;
+    }
+
+    fn scoped(&mut self, name: String, total: Duration, exclusive: Duration) {
+        self.add(name, true, false, total, exclusive);
+    }
+
+    fn unscoped(&mut self, name: String, total: Duration, exclusive: Duration) {
+        self.add(name, false, false, total, exclusive);
+    }
+
+    fn forced(&mut self, name: String, total: Duration, exclusive: Duration) {
+        self.add(name, false, true, total, exclusive);
+    }
+
+    fn get(&self, name: &str) -> Option<&MetricData> {
+        self.0.get(&(false, name.to_string()))
+    }
+
+    fn into_vec(self) -> Vec<Metric> {
+        self.0
+            .into_iter()
+            .map(|((_, name), data)| Metric { name, data })
+            .collect()
+    }
+}
+
+/// The time within `interval` which isn't covered by any of `children`, as
+/// the C SDK calculates exclusive time.
+fn exclusive_time(
+    (start, end): (Duration, Duration),
+    mut children: Vec<(Duration, Duration)>,
+) -> Duration {
+    children.sort();
+    let mut covered = Duration::ZERO;
+    let mut cursor = start;
+    for (child_start, child_end) in children {
+        let child_start = child_start.max(cursor);
+        let child_end = child_end.min(end);
+        if child_end > child_start {
+            covered += child_end - child_start;
+            cursor = child_end;
+        }
+    }
+    (end - start).saturating_sub(covered)
+}
+
+impl TransactionState {
+    /// Create the data sent to the daemon for this transaction, as the C
+    /// SDK would.
+    fn into_data(self) -> TransactionData {
+        let ended = Instant::now();
+        let duration = self
+            .duration
+            .unwrap_or_else(|| ended.duration_since(self.started));
+        let (prefix, rollup, suffix) = match self.kind {
+            TransactionType::Web => ("WebTransaction", "WebTransaction", "allWeb"),
+            TransactionType::NonWeb => ("OtherTransaction", "OtherTransaction/all", "allOther"),
+        };
+        let path = if self.renamed { "Custom" } else { "Action" };
+        let name = format!("{}/{}/{}", prefix, path, self.name);
+
+        let mut metrics = Metrics::default();
+        // Each segment's start and end, relative to the start of the
+        // transaction.
+        let intervals: Vec<(Duration, Duration)> = self
+            .segments
+            .iter()
+            .map(|segment| {
+                let start = segment
+                    .start
+                    .unwrap_or_else(|| segment.started.saturating_duration_since(self.started));
+                let duration = segment
+                    .duration
+                    .unwrap_or_else(|| ended.saturating_duration_since(segment.started));
+                (start, start + duration)
+            })
+            .collect();
+        let mut children = vec![Vec::new(); self.segments.len()];
+        let mut root_children = Vec::new();
+        for (segment, interval) in self.segments.iter().zip(&intervals) {
+            match segment.parent {
+                Some(parent) => children[parent].push(*interval),
+                None => root_children.push(*interval),
+            }
+        }
+        for ((segment, &interval), children) in self.segments.iter().zip(&intervals).zip(children) {
+            let duration = interval.1 - interval.0;
+            let exclusive = exclusive_time(interval, children);
+            match &segment.kind {
+                SegmentKind::Custom { name, category } => {
+                    metrics.scoped(format!("{}/{}", category, name), duration, exclusive);
+                }
+                SegmentKind::Datastore {
+                    product,
+                    collection,
+                    operation,
+                } => {
+                    let operation = operation.as_deref().unwrap_or("other");
+                    for rollup in &["all".to_string(), suffix.to_string()] {
+                        metrics.unscoped(format!("Datastore/{}", rollup), duration, exclusive);
+                        metrics.unscoped(
+                            format!("Datastore/{}/{}", product, rollup),
+                            duration,
+                            exclusive,
+                        );
+                    }
+                    let operation_metric = format!("Datastore/operation/{}/{}", product, operation);
+                    let scoped = match collection {
+                        Some(collection) => {
+                            metrics.unscoped(operation_metric, duration, exclusive);
+                            format!(
+                                "Datastore/statement/{}/{}/{}",
+                                product, collection, operation
+                            )
+                        }
+                        None => operation_metric,
+                    };
+                    metrics.unscoped(scoped.clone(), duration, exclusive);
+                    metrics.scoped(scoped, duration, exclusive);
+                }
+                SegmentKind::External { host } => {
+                    metrics.unscoped("External/all".to_string(), duration, exclusive);
+                    metrics.unscoped(format!("External/{}", suffix), duration, exclusive);
+                    metrics.unscoped(format!("External/{}/all", host), duration, exclusive);
+                    metrics.scoped(format!("External/{}/all", host), duration, exclusive);
+                }
+            }
+        }
+
+        let root_exclusive = exclusive_time((Duration::ZERO, duration), root_children);
+        if self.kind == TransactionType::Web {
+            metrics.forced("HttpDispatcher".to_string(), duration, Duration::ZERO);
+        }
+        metrics.forced(name.clone(), duration, root_exclusive);
+        metrics.forced(rollup.to_string(), duration, root_exclusive);
+        metrics.forced(
+            format!("{}TotalTime/{}/{}", prefix, path, self.name),
+            duration,
+            duration,
+        );
+        metrics.forced(format!("{}TotalTime", prefix), duration, duration);
+        for (metric_name, milliseconds) in &self.custom_metrics {
+            let value = Duration::from_secs_f64(milliseconds.max(0.0) / 1000.0);
+            metrics.forced(metric_name.clone(), value, value);
+        }
+        if self.error.is_some() {
+            for error_metric in &["all".to_string(), suffix.to_string(), name.clone()] {
+                metrics.forced(
+                    format!("Errors/{}", error_metric),
+                    Duration::ZERO,
+                    Duration::ZERO,
+                );
+            }
+        }
+
+        let mut durations = Map::new();
+        if let Some(data) = metrics.get("Datastore/all") {
+            durations.insert("databaseDuration".to_string(), json!(data.total));
+            durations.insert("databaseCallCount".to_string(), json!(data.count as u64));
+        }
+        if let Some(data) = metrics.get("External/all") {
+            durations.insert("externalDuration".to_string(), json!(data.total));
+        }
+
+        let mut intrinsics = json!({
+            "type": "Transaction",
+            "name": name,
+            "timestamp": seconds_since_epoch(self.start),
+            "duration": duration.as_secs_f64(),
+            "totalTime": duration.as_secs_f64(),
+            "error": self.error.is_some(),
+        });
+        intrinsics
+            .as_object_mut()
+            .unwrap()
+            .extend(durations.clone());
+        let txn_event = json!([intrinsics, self.attributes, {}]).to_string();
+
+        let (errors, error_events) = match &self.error {
+            Some(error) => {
+                let when = error
+                    .when
+                    .duration_since(UNIX_EPOCH)
+                    .map(|duration| duration.as_millis() as u64)
+                    .unwrap_or_default();
+                let data = json!([
+                    when,
+                    name,
+                    error.message,
+                    error.class,
+                    {
+                        "stack_trace": error.backtrace,
+                        "agentAttributes": {},
+                        "userAttributes": self.attributes,
+                        "intrinsics": {"totalTime": duration.as_secs_f64()},
+                    },
+                ]);
+                let mut intrinsics = json!({
+                    "type": "TransactionError",
+                    "timestamp": seconds_since_epoch(error.when),
+                    "error.class": error.class,
+                    "error.message": error.message,
+                    "transactionName": name,
+                    "duration": duration.as_secs_f64(),
+                    "nr.transactionGuid": format!("{:016x}", random_u64()),
+                });
+                intrinsics.as_object_mut().unwrap().extend(durations);
+                let event = json!([intrinsics, self.attributes, {}]);
+                (
+                    vec![ErrorData {
+                        priority: error.priority,
+                        data: data.to_string(),
+                    }],
+                    vec![event.to_string()],
+                )
+            }
+            None => (vec![], vec![]),
+        };
+
+        TransactionData {
+            name,
+            uri: "<unknown>".to_string(),
+            pid: std::process::id() as i32,
+            txn_event: Some(txn_event),
+            metrics: metrics.into_vec(),
+            errors,
+            custom_events: self.custom_events,
+            error_events,
+            sampling_priority: (random_u64() >> 11) as f64 / (1u64 << 53) as f64,
+        }
+    }
+}
+
+/// A backend which sends data to the daemon without using the C SDK.
+pub(crate) struct DaemonBackend {
+    socket: String,
+    timeout: Duration,
+    app: AppInfo,
+    connection: Mutex<Connection>,
+    transactions: Mutex<HashMap<usize, TransactionState>>,
+    custom_events: Mutex<HashMap<usize, CustomEventState>>,
+    next_handle: AtomicUsize,
+}
+
+impl DaemonBackend {
+    /// Ask the daemon to connect an app, waiting up to `timeout`
+    /// milliseconds for it to do so.
+    ///
+    /// Returns `Error::ConnectTimeout` if the app wasn't connected in time,
+    /// `Error::InvalidConfig` if the daemon rejected its license key and
+    /// `Error::DaemonError` if the daemon disconnected it.
+    pub(crate) fn connect(config: &AppConfig, timeout: u16) -> Result<Self> {
+        let (socket, io_timeout) = {
+            let settings = SETTINGS.read().unwrap();
+            (
+                settings
+                    .socket
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_SOCKET.to_string()),
+                settings.timeout.unwrap_or(DEFAULT_TIMEOUT),
+            )
+        };
+        let backend = DaemonBackend {
+            socket,
+            timeout: io_timeout,
+            app: app_info(config),
+            connection: Mutex::new(Connection {
+                stream: None,
+                agent_run_id: None,
+            }),
+            transactions: Mutex::default(),
+            custom_events: Mutex::default(),
+            next_handle: AtomicUsize::new(0),
+        };
+        let deadline = Instant::now() + Duration::from_millis(timeout.into());
+        loop {
+            let status = backend.query_app(&mut backend.connection.lock().unwrap());
+            match status {
+                Ok(AppStatus::Connected) | Ok(AppStatus::StillValid) => {
+                    debug!("App connected by the daemon");
+                    return Ok(backend);
+                }
+                Ok(AppStatus::Unknown) => debug!("Waiting for the daemon to connect the app"),
+                Ok(AppStatus::Disconnected) => {
+                    error!("The daemon has disconnected the app");
+                    return Err(Error::DaemonError);
+                }
+                Ok(AppStatus::InvalidLicense) => {
+                    error!("The daemon rejected the app's license key");
+                    return Err(Error::InvalidConfig(
+                        "the daemon rejected the license key".to_string(),
+                    ));
+                }
+                Err(e) => debug!("Could not query the daemon at {}: {}", backend.socket, e),
+            }
+            if Instant::now() >= deadline {
+                error!("Timed out waiting for the daemon to connect the app");
+                return Err(Error::ConnectTimeout(Duration::from_millis(timeout.into())));
+            }
+            thread::sleep(RETRY_INTERVAL);
+        }
+    }
+
+    /// The connection's stream, connecting to the daemon if necessary.
+    fn stream<'a>(&self, connection: &'a mut Connection) -> io::Result<&'a mut UnixStream> {
+        let stream = match connection.stream.take() {
+            Some(stream) => stream,
+            None => {
+                let stream = protocol::connect(&self.socket)?;
+                stream.set_read_timeout(Some(self.timeout))?;
+                stream.set_write_timeout(Some(self.timeout))?;
+                stream
+            }
+        };
+        Ok(connection.stream.insert(stream))
+    }
+
+    /// Ask the daemon for the state of the app, recording the run ID it
+    /// gives a newly connected app.
+    ///
+    /// The connection is closed if anything goes wrong, or if the app isn't
+    /// connected, so that the next transaction queries the daemon again.
+    fn query_app(&self, connection: &mut Connection) -> io::Result<AppStatus> {
+        let result = self.try_query_app(connection);
+        match result {
+            Ok(AppStatus::Connected) | Ok(AppStatus::StillValid) => {}
+            _ => connection.stream = None,
+        }
+        result
+    }
+
+    fn try_query_app(&self, connection: &mut Connection) -> io::Result<AppStatus> {
+        let message = protocol::encode_app(connection.agent_run_id.as_deref(), &self.app);
+        let stream = self.stream(connection)?;
+        protocol::write_message(stream, &message)?;
+        let reply = protocol::decode_app_reply(&protocol::read_message(stream)?)?;
+        if reply.status == AppStatus::Connected {
+            let agent_run_id = reply
+                .connect_reply
+                .as_deref()
+                .and_then(|reply| serde_json::from_str::<Value>(reply).ok())
+                .and_then(|reply| reply.get("agent_run_id")?.as_str().map(str::to_string))
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "connect reply has no agent run ID",
+                    )
+                })?;
+            connection.agent_run_id = Some(agent_run_id);
+        }
+        Ok(reply.status)
+    }
+
+    fn send_transaction(&self, data: &TransactionData) {
+        let mut connection = self.connection.lock().unwrap();
+        if connection.stream.is_none() {
+            // The daemon may have restarted, in which case it needs to
+            // connect the app again before accepting data.
+            match self.query_app(&mut connection) {
+                Ok(AppStatus::Connected) | Ok(AppStatus::StillValid) => {}
+                Ok(status) => {
+                    warn!("Not sending transaction, the app is {:?}", status);
+                    return;
+                }
+                Err(e) => {
+                    error!("Could not connect to the daemon: {}", e);
+                    return;
+                }
+            }
+        }
+        let message = match &connection.agent_run_id {
+            Some(agent_run_id) => protocol::encode_transaction(agent_run_id, data),
+            None => return,
+        };
+        let result = self
+            .stream(&mut connection)
+            .and_then(|stream| protocol::write_message(stream, &message));
+        match result {
+            Ok(()) => debug!("Sent transaction to the daemon"),
+            Err(e) => {
+                connection.stream = None;
+                error!("Could not send transaction to the daemon: {}", e);
+            }
+        }
+    }
+
+    fn next_handle(&self) -> usize {
+        self.next_handle.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn with_transaction<T>(
+        &self,
+        transaction: TransactionHandle,
+        f: impl FnOnce(&mut TransactionState) -> T,
+    ) -> Option<T> {
+        self.transactions
+            .lock()
+            .unwrap()
+            .get_mut(&transaction.0)
+            .map(f)
+    }
+
+    fn start_segment(
+        &self,
+        transaction: TransactionHandle,
+        kind: SegmentKind,
+    ) -> Option<SegmentHandle> {
+        self.with_transaction(transaction, |state| {
+            let index = state.segments.len();
+            state.segments.push(SegmentState {
+                kind,
+                parent: state.active_segments.last().copied(),
+                started: Instant::now(),
+                start: None,
+                duration: None,
+            });
+            state.active_segments.push(index);
+            SegmentHandle(index)
+        })
+    }
+}
+
+impl Backend for DaemonBackend {
+    fn start_transaction(&self, name: &CStr, kind: &TransactionType) -> Option<TransactionHandle> {
+        let handle = self.next_handle();
+        self.transactions.lock().unwrap().insert(
+            handle,
+            TransactionState {
+                kind: *kind,
+                name: to_string(name),
+                renamed: false,
+                start: SystemTime::now(),
+                started: Instant::now(),
+                duration: None,
+                attributes: Map::new(),
+                error: None,
+                custom_metrics: Vec::new(),
+                custom_events: Vec::new(),
+                segments: Vec::new(),
+                active_segments: Vec::new(),
+                ignored: false,
+            },
+        );
+        Some(TransactionHandle(handle))
+    }
+
+    fn set_transaction_name(&self, transaction: TransactionHandle, name: &CStr) -> bool {
+        self.with_transaction(transaction, |state| {
+            state.name = to_string(name);
+            state.renamed = true;
+        })
+        .is_some()
+    }
+
+    fn add_attribute(
+        &self,
+        transaction: TransactionHandle,
+        name: &CStr,
+        value: AttributeValue<'_>,
+    ) -> bool {
+        self.with_transaction(transaction, |state| {
+            state
+                .attributes
+                .insert(to_string(name), attribute_value(value));
+        })
+        .is_some()
+    }
+
+    fn notice_error(
+        &self,
+        transaction: TransactionHandle,
+        priority: i32,
+        message: &CStr,
+        class: &CStr,
+        backtrace: &[String],
+    ) {
+        self.with_transaction(transaction, |state| {
+            // As with the C SDK, only the highest priority error is kept.
+            if state
+                .error
+                .as_ref()
+                .is_none_or(|error| priority >= error.priority)
+            {
+                state.error = Some(NoticedError {
+                    priority,
+                    message: to_string(message),
+                    class: to_string(class),
+                    backtrace: backtrace.to_vec(),
+                    when: SystemTime::now(),
+                });
+            }
+        });
+    }
+
+    fn set_transaction_timing(
+        &self,
+        transaction: TransactionHandle,
+        start_micros: u64,
+        duration_micros: u64,
+    ) -> bool {
+        self.with_transaction(transaction, |state| {
+            state.start = UNIX_EPOCH + Duration::from_micros(start_micros);
+            state.duration = Some(Duration::from_micros(duration_micros));
+        })
+        .is_some()
+    }
+
+    fn ignore_transaction(&self, transaction: TransactionHandle) -> bool {
+        self.with_transaction(transaction, |state| state.ignored = true)
+            .is_some()
+    }
+
+    fn record_custom_metric(
+        &self,
+        transaction: TransactionHandle,
+        name: &CStr,
+        value: f64,
+        unit: MetricUnit,
+    ) -> bool {
+        self.with_transaction(transaction, |state| {
+            let milliseconds = unit.milliseconds(value);
+            state.custom_metrics.push((to_string(name), milliseconds));
+        })
+        .is_some()
+    }
+
+    fn end_transaction(&self, transaction: TransactionHandle) {
+        let state = self.transactions.lock().unwrap().remove(&transaction.0);
+        match state {
+            Some(state) if state.ignored => debug!("Not sending ignored transaction"),
+            Some(state) => self.send_transaction(&state.into_data()),
+            None => {}
+        }
+    }
+
+    fn start_custom_segment(
+        &self,
+        transaction: TransactionHandle,
+        name: &CStr,
+        category: &CStr,
+    ) -> Option<SegmentHandle> {
+        self.start_segment(
+            transaction,
+            SegmentKind::Custom {
+                name: to_string(name),
+                category: to_string(category),
+            },
+        )
+    }
+
+    fn start_datastore_segment(
+        &self,
+        transaction: TransactionHandle,
+        params: &DatastoreParams,
+    ) -> Option<SegmentHandle> {
+        self.start_segment(
+            transaction,
+            SegmentKind::Datastore {
+                product: params.product.name(),
+                collection: params.collection.as_deref().map(to_string),
+                operation: params.operation.as_deref().map(to_string),
+            },
+        )
+    }
+
+    fn start_external_segment(
+        &self,
+        transaction: TransactionHandle,
+        params: &ExternalParams,
+    ) -> Option<SegmentHandle> {
+        self.start_segment(
+            transaction,
+            SegmentKind::External {
+                host: uri_host(&to_string(&params.uri))
+                    .unwrap_or("<unknown>")
+                    .to_string(),
+            },
+        )
+    }
+
+    fn set_segment_parent(
+        &self,
+        transaction: TransactionHandle,
+        segment: SegmentHandle,
+        parent: SegmentHandle,
+    ) -> bool {
+        self.with_transaction(transaction, |state| {
+            if segment.0 >= state.segments.len() || parent.0 >= state.segments.len() {
+                return false;
+            }
+            // Refuse to create a cycle.
+            let mut ancestor = Some(parent.0);
+            while let Some(index) = ancestor {
+                if index == segment.0 {
+                    return false;
+                }
+                ancestor = state.segments[index].parent;
+            }
+            state.segments[segment.0].parent = Some(parent.0);
+            true
+        })
+        .unwrap_or(false)
+    }
+
+    fn set_segment_timing(
+        &self,
+        transaction: TransactionHandle,
+        segment: SegmentHandle,
+        start_micros: u64,
+        duration_micros: u64,
+    ) -> bool {
+        // Transaction traces aren't sent, but the start time still affects
+        // the exclusive time of the segment's parent.
+        self.with_transaction(transaction, |state| {
+            state
+                .segments
+                .get_mut(segment.0)
+                .map(|segment_state| {
+                    segment_state.start = Some(Duration::from_micros(start_micros));
+                    segment_state.duration = Some(Duration::from_micros(duration_micros));
+                })
+                .is_some()
+        })
+        .unwrap_or(false)
+    }
+
+    fn end_segment(&self, transaction: TransactionHandle, segment: SegmentHandle) {
+        self.with_transaction(transaction, |state| {
+            if let Some(segment_state) = state.segments.get_mut(segment.0) {
+                // Keep any duration set by `set_segment_timing`.
+                if segment_state.duration.is_none() {
+                    segment_state.duration = Some(segment_state.started.elapsed());
+                }
+            }
+            state.active_segments.retain(|index| *index != segment.0);
+        });
+    }
+
+    #[cfg(feature = "distributed_tracing")]
+    fn distributed_trace_payload(
+        &self,
+        _transaction: TransactionHandle,
+        _segment: SegmentHandle,
+    ) -> Option<String> {
+        debug!("Distributed tracing is not supported by the pure-rust backend");
+        None
+    }
+
+    fn create_custom_event(&self, event_type: &CStr) -> Option<CustomEventHandle> {
+        let handle = self.next_handle();
+        self.custom_events.lock().unwrap().insert(
+            handle,
+            CustomEventState {
+                event_type: to_string(event_type),
+                timestamp: SystemTime::now(),
+                attributes: Map::new(),
+            },
+        );
+        Some(CustomEventHandle(handle))
+    }
+
+    fn add_custom_event_attribute(
+        &self,
+        event: CustomEventHandle,
+        name: &CStr,
+        value: AttributeValue<'_>,
+    ) -> bool {
+        match self.custom_events.lock().unwrap().get_mut(&event.0) {
+            Some(state) => {
+                state
+                    .attributes
+                    .insert(to_string(name), attribute_value(value));
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn record_custom_event(&self, transaction: TransactionHandle, event: CustomEventHandle) {
+        let state = match self.custom_events.lock().unwrap().remove(&event.0) {
+            Some(state) => state,
+            None => return,
+        };
+        let data = json!([
+            {
+                "type": state.event_type,
+                "timestamp": seconds_since_epoch(state.timestamp),
+            },
+            state.attributes,
+            {},
+        ]);
+        self.with_transaction(transaction, |transaction| {
+            transaction.custom_events.push(data.to_string());
+        });
+    }
+
+    fn discard_custom_event(&self, event: CustomEventHandle) {
+        self.custom_events.lock().unwrap().remove(&event.0);
+    }
+}
+
+impl Drop for DaemonBackend {
+    fn drop(&mut self) {
+        debug!("Destroyed app");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn exclusive_time_without_children() {
+        assert_eq!(exclusive_time((ms(10), ms(50)), vec![]), ms(40));
+    }
+
+    #[test]
+    fn exclusive_time_with_overlapping_children() {
+        let children = vec![(ms(40), ms(60)), (ms(10), ms(30)), (ms(20), ms(35))];
+        assert_eq!(exclusive_time((ms(0), ms(100)), children), ms(55));
+    }
+
+    #[test]
+    fn exclusive_time_clips_children_to_parent() {
+        let children = vec![(ms(0), ms(20)), (ms(90), ms(150))];
+        assert_eq!(exclusive_time((ms(10), ms(100)), children), ms(70));
+        assert_eq!(
+            exclusive_time((ms(10), ms(20)), vec![(ms(0), ms(30))]),
+            ms(0)
+        );
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn round_trip_through_fake_daemon() {
+        use std::ffi::CString;
+
+        use crate::testing::{FakeDaemon, Value};
+
+        let name = |value: &str| CString::new(value).unwrap();
+        let daemon = FakeDaemon::start(&format!("@newrelic-test-{}", std::process::id())).unwrap();
+        let _lock = TEST_SETTINGS_LOCK.lock().unwrap();
+        configure(Some(daemon.socket().to_string()), None);
+        let mut config =
+            AppConfig::new("test app", "0123456789012345678901234567890123456789").unwrap();
+        config.labels = vec![("env".to_string(), "test".to_string())];
+        config.host_display_name = Some("web-1".to_string());
+        config.high_security = true;
+        let backend = DaemonBackend::connect(&config, 5000).expect("App wasn't connected");
+
+        let transaction = backend
+            .start_transaction(&name("index"), &TransactionType::Web)
+            .unwrap();
+        backend.add_attribute(transaction, &name("user_id"), AttributeValue::Int(42));
+        let parent = backend
+            .start_custom_segment(transaction, &name("parent"), &name("Custom"))
+            .unwrap();
+        for (child, start) in [("first", 10_000), ("second", 30_000)].iter() {
+            let segment = backend
+                .start_custom_segment(transaction, &name(child), &name("Custom"))
+                .unwrap();
+            assert!(backend.set_segment_parent(transaction, segment, parent));
+            assert!(backend.set_segment_timing(transaction, segment, *start, 40_000));
+            backend.end_segment(transaction, segment);
+        }
+        assert!(backend.set_segment_timing(transaction, parent, 0, 100_000));
+        backend.end_segment(transaction, parent);
+        assert!(backend.set_transaction_timing(transaction, 1_000_000, 200_000));
+        backend.notice_error(
+            transaction,
+            5,
+            &name("message"),
+            &name("class"),
+            &["frame".to_string()],
+        );
+        backend.record_custom_metric(
+            transaction,
+            &name("Custom/processed[count]"),
+            3.0,
+            MetricUnit::Count,
+        );
+        let event = backend.create_custom_event(&name("Event")).unwrap();
+        backend.add_custom_event_attribute(event, &name("size"), AttributeValue::Long(7));
+        backend.record_custom_event(transaction, event);
+        backend.end_transaction(transaction);
+
+        let transactions = daemon.wait_for_transactions(1, Duration::from_secs(5));
+        let received = &transactions[0];
+        assert_eq!(received.name, "WebTransaction/Action/index");
+        assert_eq!(received.metric("WebTransaction").unwrap().total, 0.2);
+
+        // The children run from 10ms to 50ms and from 30ms to 70ms, so
+        // together cover 60ms of the parent's 100ms.
+        let parent = received.scoped_metric("Custom/parent").unwrap();
+        assert_eq!(parent.total, 0.1);
+        assert!((parent.exclusive - 0.04).abs() < 1e-9, "{:?}", parent);
+        let root = received.metric("WebTransaction/Action/index").unwrap();
+        assert!((root.exclusive - 0.1).abs() < 1e-9, "{:?}", root);
+
+        let metric = received.metric("Custom/processed[count]").unwrap();
+        assert_eq!(metric.total, 3.0);
+
+        let event = received.event.as_ref().unwrap();
+        assert_eq!(event.user_attributes["user_id"], Value::Long(42));
+        assert_eq!(event.intrinsics["timestamp"], Value::Float(1.0));
+        assert_eq!(received.errors[0].class, "class");
+        assert_eq!(received.errors[0].message, "message");
+        assert_eq!(received.errors[0].backtrace, vec!["frame".to_string()]);
+        assert_eq!(received.custom_events[0].event_type, "Event");
+        assert_eq!(received.custom_events[0].attributes["size"], Value::Long(7));
+
+        let app = &daemon.apps()[0];
+        assert_eq!(app.app_name, "test app");
+        assert_eq!(app.labels, vec![("env".to_string(), "test".to_string())]);
+        assert_eq!(app.display_host, "web-1");
+        assert!(app.high_security);
+    }
+}
@@ -0,0 +1,471 @@
+use std::{
+    ffi::{CStr, CString},
+    os::raw::c_char,
+};
+
+use log::{debug, warn};
+use newrelic_sys as ffi;
+
+use super::{AttributeValue, Backend, CustomEventHandle, SegmentHandle, TransactionHandle};
+use crate::{
+    app::{AppConfig, RecordSQL, TracingThreshold},
+    metrics::MetricUnit,
+    segment::{Datastore, DatastoreParams, ExternalParams},
+    transaction::TransactionType,
+};
+
+/// The C SDK has no boolean attributes, so booleans are sent as the
+/// strings `true` and `false`.
+fn bool_string(value: bool) -> &'static CStr {
+    let bytes: &'static [u8] = if value { b"true\0" } else { b"false\0" };
+    CStr::from_bytes_with_nul(bytes).unwrap()
+}
+
+fn transaction_ptr(handle: TransactionHandle) -> *mut ffi::newrelic_txn_t {
+    handle.0 as *mut ffi::newrelic_txn_t
+}
+
+fn segment_ptr(handle: SegmentHandle) -> *mut ffi::newrelic_segment_t {
+    handle.0 as *mut ffi::newrelic_segment_t
+}
+
+fn custom_event_ptr(handle: CustomEventHandle) -> *mut ffi::newrelic_custom_event_t {
+    handle.0 as *mut ffi::newrelic_custom_event_t
+}
+
+fn segment_handle(pointer: *mut ffi::newrelic_segment_t) -> Option<SegmentHandle> {
+    if pointer.is_null() {
+        None
+    } else {
+        Some(SegmentHandle(pointer as usize))
+    }
+}
+
+fn optional_ptr(value: &Option<CString>) -> *mut c_char {
+    value
+        .as_ref()
+        .map_or(std::ptr::null_mut(), |value| value.as_ptr() as *mut c_char)
+}
+
+/// Copy a string into one of the fixed-size, NUL-terminated string fields
+/// of the SDK's app config.
+///
+/// The string must already have been checked to fit.
+fn copy_to_array(dest: &mut [c_char], value: &str) {
+    for (dest, byte) in dest.iter_mut().zip(value.bytes().chain(Some(0))) {
+        *dest = byte as c_char;
+    }
+}
+
+fn product(datastore: &Datastore) -> *mut c_char {
+    let product = match datastore {
+        Datastore::Firebird => ffi::NEWRELIC_DATASTORE_FIREBIRD.as_ptr(),
+        Datastore::Informix => ffi::NEWRELIC_DATASTORE_INFORMIX.as_ptr(),
+        Datastore::MSSQL => ffi::NEWRELIC_DATASTORE_MSSQL.as_ptr(),
+        Datastore::MySQL => ffi::NEWRELIC_DATASTORE_MYSQL.as_ptr(),
+        Datastore::Oracle => ffi::NEWRELIC_DATASTORE_ORACLE.as_ptr(),
+        Datastore::Postgres => ffi::NEWRELIC_DATASTORE_POSTGRES.as_ptr(),
+        Datastore::SQLite => ffi::NEWRELIC_DATASTORE_SQLITE.as_ptr(),
+        Datastore::Sybase => ffi::NEWRELIC_DATASTORE_SYBASE.as_ptr(),
+        Datastore::Memcached => ffi::NEWRELIC_DATASTORE_MEMCACHE.as_ptr(),
+        Datastore::MongoDB => ffi::NEWRELIC_DATASTORE_MONGODB.as_ptr(),
+        Datastore::ODBC => ffi::NEWRELIC_DATASTORE_ODBC.as_ptr(),
+        Datastore::Redis => ffi::NEWRELIC_DATASTORE_REDIS.as_ptr(),
+        Datastore::Other => ffi::NEWRELIC_DATASTORE_OTHER.as_ptr(),
+    };
+    product as *mut c_char
+}
+
+fn datastore_params(params: &DatastoreParams) -> ffi::newrelic_datastore_segment_params_t {
+    ffi::newrelic_datastore_segment_params_t {
+        product: product(&params.product),
+        collection: optional_ptr(&params.collection),
+        operation: optional_ptr(&params.operation),
+        host: optional_ptr(&params.host),
+        port_path_or_id: optional_ptr(&params.port_path_or_id),
+        database_name: optional_ptr(&params.database_name),
+        query: optional_ptr(&params.query),
+    }
+}
+
+fn external_params(params: &ExternalParams) -> ffi::newrelic_external_segment_params_t {
+    ffi::newrelic_external_segment_params_t {
+        uri: params.uri.as_ptr() as *mut c_char,
+        procedure: optional_ptr(&params.procedure),
+        library: optional_ptr(&params.library),
+    }
+}
+
+/// Create the SDK's config for an app, returning a null pointer if the SDK
+/// rejects it.
+///
+/// The config has already been validated by `AppConfig` and `AppBuilder`.
+fn sdk_config(config: &AppConfig) -> *mut ffi::newrelic_app_config_t {
+    let (name, license_key) = match (
+        CString::new(config.app_name.as_str()),
+        CString::new(config.license_key.as_str()),
+    ) {
+        (Ok(name), Ok(license_key)) => (name, license_key),
+        _ => return std::ptr::null_mut(),
+    };
+    let pointer = unsafe { ffi::newrelic_create_app_config(name.as_ptr(), license_key.as_ptr()) };
+    let inner = match unsafe { pointer.as_mut() } {
+        Some(inner) => inner,
+        None => return pointer,
+    };
+    if let Some(host) = &config.redirect_collector {
+        copy_to_array(&mut inner.redirect_collector, host);
+    }
+    if !config.labels.is_empty() {
+        warn!("Labels aren't supported by the C SDK and won't be reported");
+    }
+    if config.host_display_name.is_some() {
+        warn!("Host display names aren't supported by the C SDK and won't be reported");
+    }
+    if config.high_security {
+        warn!("High security mode isn't supported by the C SDK and won't be reported");
+    }
+    let tracer = &mut inner.transaction_tracer;
+    tracer.enabled = config.transaction_tracing;
+    match config.transaction_threshold {
+        TracingThreshold::ApdexFailing => {
+            tracer.threshold =
+                ffi::_newrelic_transaction_tracer_threshold_t_NEWRELIC_THRESHOLD_IS_APDEX_FAILING;
+        }
+        TracingThreshold::OverDuration(duration) => {
+            tracer.threshold =
+                ffi::_newrelic_transaction_tracer_threshold_t_NEWRELIC_THRESHOLD_IS_OVER_DURATION;
+            tracer.duration_us = duration.as_micros() as u64;
+        }
+    }
+    tracer.stack_trace_threshold_us = config.stack_trace_threshold.as_micros() as u64;
+    tracer.datastore_reporting.enabled = config.datastore_reporting;
+    tracer.datastore_reporting.threshold_us =
+        config.datastore_reporting_threshold.as_micros() as u64;
+    tracer.datastore_reporting.record_sql = match config.record_sql() {
+        RecordSQL::Off => ffi::_newrelic_tt_recordsql_t_NEWRELIC_SQL_OFF,
+        RecordSQL::Raw => ffi::_newrelic_tt_recordsql_t_NEWRELIC_SQL_RAW,
+        RecordSQL::Obfuscated => ffi::_newrelic_tt_recordsql_t_NEWRELIC_SQL_OBFUSCATED,
+    };
+    inner.datastore_tracer.database_name_reporting = config.database_name_reporting;
+    inner.datastore_tracer.instance_reporting = config.datastore_instance_reporting;
+    inner.span_events.enabled = config.span_events;
+    inner.distributed_tracing.enabled = config.distributed_tracing;
+    pointer
+}
+
+/// A backend which records data using the New Relic C SDK.
+pub(crate) struct FfiBackend {
+    app: *mut ffi::newrelic_app_t,
+}
+
+impl FfiBackend {
+    /// Create an app in the SDK, waiting up to `timeout` milliseconds for
+    /// it to connect to the daemon.
+    ///
+    /// Returns `None` if the SDK couldn't create the app.
+    pub(crate) fn connect(config: &AppConfig, timeout: u16) -> Option<Self> {
+        let mut config = sdk_config(config);
+        if config.is_null() {
+            return None;
+        }
+        let app = unsafe { ffi::newrelic_create_app(config, timeout) };
+        unsafe { ffi::newrelic_destroy_app_config(&mut config) };
+        if app.is_null() {
+            None
+        } else {
+            Some(FfiBackend { app })
+        }
+    }
+}
+
+impl Backend for FfiBackend {
+    fn start_transaction(&self, name: &CStr, kind: &TransactionType) -> Option<TransactionHandle> {
+        let pointer = match kind {
+            TransactionType::Web => unsafe {
+                ffi::newrelic_start_web_transaction(self.app, name.as_ptr())
+            },
+            TransactionType::NonWeb => unsafe {
+                ffi::newrelic_start_non_web_transaction(self.app, name.as_ptr())
+            },
+        };
+        if pointer.is_null() {
+            None
+        } else {
+            Some(TransactionHandle(pointer as usize))
+        }
+    }
+
+    fn set_transaction_name(&self, transaction: TransactionHandle, name: &CStr) -> bool {
+        unsafe { ffi::newrelic_set_transaction_name(transaction_ptr(transaction), name.as_ptr()) }
+    }
+
+    fn add_attribute(
+        &self,
+        transaction: TransactionHandle,
+        name: &CStr,
+        value: AttributeValue<'_>,
+    ) -> bool {
+        let transaction = transaction_ptr(transaction);
+        match value {
+            AttributeValue::Int(i) => unsafe {
+                ffi::newrelic_add_attribute_int(transaction, name.as_ptr(), i)
+            },
+            AttributeValue::Long(l) => unsafe {
+                ffi::newrelic_add_attribute_long(transaction, name.as_ptr(), l)
+            },
+            AttributeValue::Float(f) => unsafe {
+                ffi::newrelic_add_attribute_double(transaction, name.as_ptr(), f)
+            },
+            AttributeValue::String(s) => unsafe {
+                ffi::newrelic_add_attribute_string(transaction, name.as_ptr(), s.as_ptr())
+            },
+            AttributeValue::Bool(b) => unsafe {
+                ffi::newrelic_add_attribute_string(
+                    transaction,
+                    name.as_ptr(),
+                    bool_string(b).as_ptr(),
+                )
+            },
+        }
+    }
+
+    fn notice_error(
+        &self,
+        transaction: TransactionHandle,
+        priority: i32,
+        message: &CStr,
+        class: &CStr,
+        _backtrace: &[String],
+    ) {
+        unsafe {
+            ffi::newrelic_notice_error(
+                transaction_ptr(transaction),
+                priority,
+                message.as_ptr(),
+                class.as_ptr(),
+            );
+        }
+    }
+
+    /// The SDK records its own stack trace, which doesn't show Rust frames.
+    fn records_backtraces(&self) -> bool {
+        false
+    }
+
+    fn set_transaction_timing(
+        &self,
+        transaction: TransactionHandle,
+        start_micros: u64,
+        duration_micros: u64,
+    ) -> bool {
+        unsafe {
+            ffi::newrelic_set_transaction_timing(
+                transaction_ptr(transaction),
+                start_micros,
+                duration_micros,
+            )
+        }
+    }
+
+    fn ignore_transaction(&self, transaction: TransactionHandle) -> bool {
+        unsafe { ffi::newrelic_ignore_transaction(transaction_ptr(transaction)) }
+    }
+
+    fn record_custom_metric(
+        &self,
+        transaction: TransactionHandle,
+        name: &CStr,
+        value: f64,
+        unit: MetricUnit,
+    ) -> bool {
+        unsafe {
+            ffi::newrelic_record_custom_metric(
+                transaction_ptr(transaction),
+                name.as_ptr(),
+                unit.milliseconds(value),
+            )
+        }
+    }
+
+    fn end_transaction(&self, transaction: TransactionHandle) {
+        let mut pointer = transaction_ptr(transaction);
+        unsafe {
+            ffi::newrelic_end_transaction(&mut pointer);
+        }
+    }
+
+    fn start_custom_segment(
+        &self,
+        transaction: TransactionHandle,
+        name: &CStr,
+        category: &CStr,
+    ) -> Option<SegmentHandle> {
+        segment_handle(unsafe {
+            ffi::newrelic_start_segment(
+                transaction_ptr(transaction),
+                name.as_ptr(),
+                category.as_ptr(),
+            )
+        })
+    }
+
+    fn start_datastore_segment(
+        &self,
+        transaction: TransactionHandle,
+        params: &DatastoreParams,
+    ) -> Option<SegmentHandle> {
+        segment_handle(unsafe {
+            ffi::newrelic_start_datastore_segment(
+                transaction_ptr(transaction),
+                &datastore_params(params),
+            )
+        })
+    }
+
+    fn start_external_segment(
+        &self,
+        transaction: TransactionHandle,
+        params: &ExternalParams,
+    ) -> Option<SegmentHandle> {
+        segment_handle(unsafe {
+            ffi::newrelic_start_external_segment(
+                transaction_ptr(transaction),
+                &external_params(params),
+            )
+        })
+    }
+
+    fn set_segment_parent(
+        &self,
+        _transaction: TransactionHandle,
+        segment: SegmentHandle,
+        parent: SegmentHandle,
+    ) -> bool {
+        unsafe { ffi::newrelic_set_segment_parent(segment_ptr(segment), segment_ptr(parent)) }
+    }
+
+    fn set_segment_timing(
+        &self,
+        _transaction: TransactionHandle,
+        segment: SegmentHandle,
+        start_micros: u64,
+        duration_micros: u64,
+    ) -> bool {
+        unsafe {
+            ffi::newrelic_set_segment_timing(segment_ptr(segment), start_micros, duration_micros)
+        }
+    }
+
+    fn end_segment(&self, transaction: TransactionHandle, segment: SegmentHandle) {
+        let mut pointer = segment_ptr(segment);
+        unsafe {
+            ffi::newrelic_end_segment(transaction_ptr(transaction), &mut pointer);
+        }
+    }
+
+    #[cfg(feature = "distributed_tracing")]
+    fn distributed_trace_payload(
+        &self,
+        transaction: TransactionHandle,
+        segment: SegmentHandle,
+    ) -> Option<String> {
+        let payload = unsafe {
+            ffi::newrelic_create_distributed_trace_payload_httpsafe(
+                transaction_ptr(transaction),
+                segment_ptr(segment),
+            )
+        };
+        if payload.is_null() {
+            None
+        } else {
+            Some(FreeableString::new(payload).convert())
+        }
+    }
+
+    fn create_custom_event(&self, event_type: &CStr) -> Option<CustomEventHandle> {
+        let pointer = unsafe { ffi::newrelic_create_custom_event(event_type.as_ptr()) };
+        if pointer.is_null() {
+            None
+        } else {
+            Some(CustomEventHandle(pointer as usize))
+        }
+    }
+
+    fn add_custom_event_attribute(
+        &self,
+        event: CustomEventHandle,
+        name: &CStr,
+        value: AttributeValue<'_>,
+    ) -> bool {
+        let event = custom_event_ptr(event);
+        match value {
+            AttributeValue::Int(i) => unsafe {
+                ffi::newrelic_custom_event_add_attribute_int(event, name.as_ptr(), i)
+            },
+            AttributeValue::Long(l) => unsafe {
+                ffi::newrelic_custom_event_add_attribute_long(event, name.as_ptr(), l)
+            },
+            AttributeValue::Float(f) => unsafe {
+                ffi::newrelic_custom_event_add_attribute_double(event, name.as_ptr(), f)
+            },
+            AttributeValue::String(s) => unsafe {
+                ffi::newrelic_custom_event_add_attribute_string(event, name.as_ptr(), s.as_ptr())
+            },
+            AttributeValue::Bool(b) => unsafe {
+                ffi::newrelic_custom_event_add_attribute_string(
+                    event,
+                    name.as_ptr(),
+                    bool_string(b).as_ptr(),
+                )
+            },
+        }
+    }
+
+    fn record_custom_event(&self, transaction: TransactionHandle, event: CustomEventHandle) {
+        let mut pointer = custom_event_ptr(event);
+        unsafe { ffi::newrelic_record_custom_event(transaction_ptr(transaction), &mut pointer) };
+    }
+
+    fn discard_custom_event(&self, event: CustomEventHandle) {
+        let mut pointer = custom_event_ptr(event);
+        unsafe { ffi::newrelic_discard_custom_event(&mut pointer) };
+    }
+}
+
+impl Drop for FfiBackend {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::newrelic_destroy_app(&mut self.app);
+        }
+        debug!("Destroyed app");
+    }
+}
+
+unsafe impl Send for FfiBackend {}
+unsafe impl Sync for FfiBackend {}
+
+#[cfg(feature = "distributed_tracing")]
+struct FreeableString(*mut std::os::raw::c_char);
+
+#[cfg(feature = "distributed_tracing")]
+impl FreeableString {
+    fn new(inner: *mut std::os::raw::c_char) -> Self {
+        Self(inner)
+    }
+
+    fn convert(&self) -> String {
+        let c_str = unsafe { CStr::from_ptr(self.0) };
+
+        c_str.to_str().unwrap().to_string()
+    }
+}
+
+#[cfg(feature = "distributed_tracing")]
+impl Drop for FreeableString {
+    fn drop(&mut self) {
+        unsafe {
+            libc::free(self.0 as *mut std::ffi::c_void);
+        }
+    }
+}
@@ -0,0 +1,246 @@
+//! The backends through which the public API records data.
+//!
+//! `App`, `Transaction`, segments and custom events don't talk to the New
+//! Relic SDK directly; instead they dispatch through a `Backend`, which
+//! hands out opaque handles for the transactions, segments and custom
+//! events it creates. `FfiBackend`, enabled with the `c-sdk` feature, uses
+//! the C SDK; `DaemonBackend`, enabled with the `pure-rust` feature, talks
+//! to the daemon directly, and `OtlpBackend`, enabled with the `otlp`
+//! feature, exports to an OpenTelemetry collector instead. Any of them may
+//! be enabled at once; each app's `AppConfig` chooses which it uses.
+use std::{ffi::CStr, sync::Arc};
+
+#[cfg(any(feature = "c-sdk", feature = "pure-rust", feature = "otlp"))]
+use crate::app::BackendKind;
+use crate::{
+    app::AppConfig,
+    error::{Error, Result},
+    metrics::MetricUnit,
+    segment::{DatastoreParams, ExternalParams},
+    transaction::TransactionType,
+};
+
+#[cfg(feature = "pure-rust")]
+mod daemon;
+#[cfg(feature = "c-sdk")]
+mod ffi;
+#[cfg(feature = "otlp")]
+mod otlp;
+
+#[cfg(feature = "pure-rust")]
+pub(crate) use self::daemon::configure as configure_daemon;
+#[cfg(all(test, feature = "pure-rust", feature = "async"))]
+pub(crate) use self::daemon::TEST_SETTINGS_LOCK;
+#[cfg(feature = "otlp")]
+pub(crate) use self::otlp::configure as configure_otlp;
+
+/// Connect an app using the backend chosen by its config, waiting up to
+/// `timeout` milliseconds for the daemon to connect it.
+///
+/// Returns `Error::InvalidConfig` if the chosen backend's feature isn't
+/// enabled. Otherwise, errors are the backend's own: the C SDK doesn't say
+/// why it couldn't create an app, so its backend returns
+/// `Error::ConfigError`, while the `pure-rust` backend returns
+/// `Error::ConnectTimeout` if the daemon didn't connect the app in time.
+pub(crate) fn connect(config: &AppConfig, timeout: u16) -> Result<Arc<dyn Backend>> {
+    let _ = timeout;
+    match config.backend {
+        #[cfg(feature = "c-sdk")]
+        BackendKind::CSdk => match ffi::FfiBackend::connect(config, timeout) {
+            Some(backend) => Ok(Arc::new(backend)),
+            None => Err(Error::ConfigError),
+        },
+        #[cfg(feature = "pure-rust")]
+        BackendKind::PureRust => Ok(Arc::new(daemon::DaemonBackend::connect(config, timeout)?)),
+        #[cfg(feature = "otlp")]
+        BackendKind::Otlp => Ok(Arc::new(otlp::OtlpBackend::new(config))),
+        #[allow(unreachable_patterns)]
+        kind => Err(Error::InvalidConfig(format!(
+            "the {:?} backend requires the {} feature",
+            kind,
+            kind.feature()
+        ))),
+    }
+}
+
+/// A random number, for IDs and sampling.
+#[cfg(any(feature = "pure-rust", feature = "otlp"))]
+fn random_u64() -> u64 {
+    use std::{
+        collections::hash_map::RandomState,
+        hash::{BuildHasher, Hasher},
+    };
+
+    RandomState::new().build_hasher().finish()
+}
+
+/// The host of a URI, if it has one.
+#[cfg(any(feature = "pure-rust", feature = "otlp"))]
+fn uri_host(uri: &str) -> Option<&str> {
+    let rest = &uri[uri.find("://")? + 3..];
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    let host = authority.rsplit('@').next().unwrap_or_default();
+    if host.is_empty() {
+        None
+    } else {
+        Some(host)
+    }
+}
+
+/// An opaque handle to a transaction created by a `Backend`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct TransactionHandle(pub(crate) usize);
+
+/// An opaque handle to a segment created by a `Backend`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct SegmentHandle(pub(crate) usize);
+
+/// An opaque handle to a custom event created by a `Backend`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct CustomEventHandle(pub(crate) usize);
+
+/// An attribute value in the form supported by New Relic.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum AttributeValue<'a> {
+    Int(i32),
+    Long(i64),
+    Float(f64),
+    String(&'a CStr),
+    Bool(bool),
+}
+
+/// A destination for the data recorded by an `App`.
+///
+/// Each `App` owns one backend, which is shared by every transaction the
+/// app starts; it must stay usable until the last of them is dropped.
+///
+/// Methods returning `bool` return `false` if the operation failed, in
+/// which case the caller reports the appropriate `Error`. Handles are only
+/// ever passed back to the backend which created them, and are never used
+/// after being ended, recorded or discarded.
+pub(crate) trait Backend: Send + Sync {
+    fn start_transaction(&self, name: &CStr, kind: &TransactionType) -> Option<TransactionHandle>;
+
+    fn set_transaction_name(&self, transaction: TransactionHandle, name: &CStr) -> bool;
+
+    fn add_attribute(
+        &self,
+        transaction: TransactionHandle,
+        name: &CStr,
+        value: AttributeValue<'_>,
+    ) -> bool;
+
+    fn notice_error(
+        &self,
+        transaction: TransactionHandle,
+        priority: i32,
+        message: &CStr,
+        class: &CStr,
+        backtrace: &[String],
+    );
+
+    /// Whether noticed errors keep the Rust backtrace they are given. If
+    /// not, the backtrace is added to the transaction as an attribute.
+    fn records_backtraces(&self) -> bool {
+        true
+    }
+
+    /// Override the start time (in microseconds since the Unix epoch) and
+    /// duration (in microseconds) of a transaction.
+    fn set_transaction_timing(
+        &self,
+        transaction: TransactionHandle,
+        start_micros: u64,
+        duration_micros: u64,
+    ) -> bool;
+
+    fn ignore_transaction(&self, transaction: TransactionHandle) -> bool;
+
+    fn record_custom_metric(
+        &self,
+        transaction: TransactionHandle,
+        name: &CStr,
+        value: f64,
+        unit: MetricUnit,
+    ) -> bool;
+
+    fn end_transaction(&self, transaction: TransactionHandle);
+
+    fn start_custom_segment(
+        &self,
+        transaction: TransactionHandle,
+        name: &CStr,
+        category: &CStr,
+    ) -> Option<SegmentHandle>;
+
+    fn start_datastore_segment(
+        &self,
+        transaction: TransactionHandle,
+        params: &DatastoreParams,
+    ) -> Option<SegmentHandle>;
+
+    fn start_external_segment(
+        &self,
+        transaction: TransactionHandle,
+        params: &ExternalParams,
+    ) -> Option<SegmentHandle>;
+
+    fn set_segment_parent(
+        &self,
+        transaction: TransactionHandle,
+        segment: SegmentHandle,
+        parent: SegmentHandle,
+    ) -> bool;
+
+    /// Override the start time (in microseconds since the start of the
+    /// transaction) and duration (in microseconds) of a segment.
+    fn set_segment_timing(
+        &self,
+        transaction: TransactionHandle,
+        segment: SegmentHandle,
+        start_micros: u64,
+        duration_micros: u64,
+    ) -> bool;
+
+    fn end_segment(&self, transaction: TransactionHandle, segment: SegmentHandle);
+
+    #[cfg(feature = "distributed_tracing")]
+    fn distributed_trace_payload(
+        &self,
+        transaction: TransactionHandle,
+        segment: SegmentHandle,
+    ) -> Option<String>;
+
+    fn create_custom_event(&self, event_type: &CStr) -> Option<CustomEventHandle>;
+
+    fn add_custom_event_attribute(
+        &self,
+        event: CustomEventHandle,
+        name: &CStr,
+        value: AttributeValue<'_>,
+    ) -> bool;
+
+    fn record_custom_event(&self, transaction: TransactionHandle, event: CustomEventHandle);
+
+    fn discard_custom_event(&self, event: CustomEventHandle);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::BackendKind;
+
+    #[test]
+    fn unavailable_backends_are_invalid() {
+        let kinds = [BackendKind::CSdk, BackendKind::PureRust, BackendKind::Otlp];
+        for kind in kinds.iter().filter(|kind| !kind.is_available()) {
+            let mut config =
+                AppConfig::new("test app", "0123456789012345678901234567890123456789").unwrap();
+            config.backend = *kind;
+            match connect(&config, 0) {
+                Err(Error::InvalidConfig(message)) => assert!(message.contains(kind.feature())),
+                _ => panic!("{:?} should be unavailable", kind),
+            }
+        }
+    }
+}
@@ -0,0 +1,1096 @@
+//! A backend which exports to an OpenTelemetry collector over OTLP/HTTP.
+//!
+//! Each transaction becomes a trace whose root span is the transaction and
+//! whose child spans are its segments. Attributes become span attributes,
+//! the noticed error sets the root span's status and custom events become
+//! span events. Custom metrics are exported as gauges. Everything is sent
+//! as JSON by a background thread once the transaction ends; if the
+//! collector falls behind, exports beyond a fixed queue size are dropped.
+use std::{
+    collections::HashMap,
+    env,
+    ffi::{CStr, CString},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError},
+        Mutex, RwLock,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, error, warn};
+use serde_json::{json, Map, Value};
+
+use super::{
+    random_u64, uri_host, AttributeValue, Backend, CustomEventHandle, SegmentHandle,
+    TransactionHandle,
+};
+use crate::{
+    app::AppConfig,
+    metrics::MetricUnit,
+    segment::{Datastore, DatastoreParams, ExternalParams},
+    transaction::TransactionType,
+};
+
+/// The collector's OTLP/HTTP endpoint if one isn't configured.
+const DEFAULT_ENDPOINT: &str = "http://localhost:4318";
+
+/// The standard OpenTelemetry variable used for the endpoint if one isn't
+/// configured.
+const ENDPOINT_VARIABLE: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+
+/// How long to wait for the collector if a timeout isn't configured.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many exports may wait for the exporter before new ones are dropped.
+const EXPORT_QUEUE_SIZE: usize = 128;
+
+/// Span kinds, as defined by OTLP.
+const SPAN_KIND_INTERNAL: u8 = 1;
+const SPAN_KIND_SERVER: u8 = 2;
+const SPAN_KIND_CLIENT: u8 = 3;
+
+/// The status code of a span which failed.
+const STATUS_CODE_ERROR: u8 = 2;
+
+struct OtlpSettings {
+    endpoint: Option<String>,
+    timeout: Option<Duration>,
+}
+
+static SETTINGS: RwLock<OtlpSettings> = RwLock::new(OtlpSettings {
+    endpoint: None,
+    timeout: None,
+});
+
+/// Set the collector endpoint and timeout used by apps created from now on.
+pub(crate) fn configure(endpoint: Option<String>, timeout: Option<Duration>) {
+    let mut settings = SETTINGS.write().unwrap();
+    settings.endpoint = endpoint;
+    settings.timeout = timeout.filter(|timeout| !timeout.is_zero());
+}
+
+fn to_string(value: &CStr) -> String {
+    value.to_string_lossy().into_owned()
+}
+
+fn optional_string(value: &Option<CString>) -> Option<String> {
+    value.as_deref().map(to_string)
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// An attribute value, encoded as an OTLP `AnyValue`.
+fn any_value(value: AttributeValue<'_>) -> Value {
+    match value {
+        AttributeValue::Int(i) => json!({ "intValue": i.to_string() }),
+        AttributeValue::Long(l) => json!({ "intValue": l.to_string() }),
+        AttributeValue::Float(f) => json!({ "doubleValue": f }),
+        AttributeValue::String(s) => json!({ "stringValue": to_string(s) }),
+        AttributeValue::Bool(b) => json!({ "boolValue": b }),
+    }
+}
+
+fn string_value(value: impl Into<String>) -> Value {
+    json!({ "stringValue": value.into() })
+}
+
+/// Attributes, encoded as a list of OTLP `KeyValue`s.
+fn key_values(attributes: &Map<String, Value>) -> Value {
+    attributes
+        .iter()
+        .map(|(key, value)| json!({ "key": key, "value": value }))
+        .collect()
+}
+
+/// The OpenTelemetry name of a datastore product.
+fn db_system(product: &Datastore) -> String {
+    match product {
+        Datastore::MSSQL => "mssql".to_string(),
+        Datastore::Postgres => "postgresql".to_string(),
+        Datastore::ODBC | Datastore::Other => "other_sql".to_string(),
+        product => product.name().to_lowercase(),
+    }
+}
+
+struct NoticedError {
+    priority: i32,
+    message: String,
+    class: String,
+    backtrace: Vec<String>,
+    when: SystemTime,
+}
+
+struct SpanState {
+    span_id: u64,
+    name: String,
+    kind: u8,
+    /// The index of the parent segment, or `None` if the parent is the
+    /// transaction.
+    parent: Option<usize>,
+    /// When the segment started, relative to the start of the transaction.
+    offset: Duration,
+    started: Instant,
+    duration: Option<Duration>,
+    attributes: Map<String, Value>,
+}
+
+struct TransactionState {
+    trace_id: u128,
+    span_id: u64,
+    name: String,
+    kind: u8,
+    start: SystemTime,
+    started: Instant,
+    /// The duration set by `set_transaction_timing`, if any.
+    duration: Option<Duration>,
+    attributes: Map<String, Value>,
+    error: Option<NoticedError>,
+    /// Span events, one for each recorded custom event.
+    events: Vec<Value>,
+    custom_metrics: Vec<(String, f64, MetricUnit, SystemTime)>,
+    segments: Vec<SpanState>,
+    /// The segments which haven't ended, most recently started last.
+    active_segments: Vec<usize>,
+    ignored: bool,
+}
+
+impl TransactionState {
+    /// The spans of this transaction, with the transaction's span first.
+    fn spans(&self, ended: Instant) -> Vec<Value> {
+        let trace_id = format!("{:032x}", self.trace_id);
+        let mut status = json!({});
+        let mut events = self.events.clone();
+        if let Some(error) = &self.error {
+            status = json!({ "code": STATUS_CODE_ERROR, "message": error.message });
+            let mut attributes = vec![
+                json!({ "key": "exception.message", "value": string_value(&error.message) }),
+                json!({ "key": "exception.type", "value": string_value(&error.class) }),
+            ];
+            if !error.backtrace.is_empty() {
+                let stacktrace = error.backtrace.join("\n");
+                attributes.push(
+                    json!({ "key": "exception.stacktrace", "value": string_value(stacktrace) }),
+                );
+            }
+            events.push(json!({
+                "timeUnixNano": unix_nanos(error.when),
+                "name": "exception",
+                "attributes": attributes,
+            }));
+        }
+        let duration = self
+            .duration
+            .unwrap_or_else(|| ended.duration_since(self.started));
+        let mut spans = vec![json!({
+            "traceId": trace_id,
+            "spanId": format!("{:016x}", self.span_id),
+            "name": self.name,
+            "kind": self.kind,
+            "startTimeUnixNano": unix_nanos(self.start),
+            "endTimeUnixNano": unix_nanos(self.start + duration),
+            "attributes": key_values(&self.attributes),
+            "events": events,
+            "status": status,
+        })];
+        spans.extend(self.segments.iter().map(|segment| {
+            let parent = match segment.parent {
+                Some(parent) => self.segments[parent].span_id,
+                None => self.span_id,
+            };
+            let duration = segment
+                .duration
+                .unwrap_or_else(|| ended.saturating_duration_since(segment.started));
+            let start = self.start + segment.offset;
+            json!({
+                "traceId": trace_id,
+                "spanId": format!("{:016x}", segment.span_id),
+                "parentSpanId": format!("{:016x}", parent),
+                "name": segment.name,
+                "kind": segment.kind,
+                "startTimeUnixNano": unix_nanos(start),
+                "endTimeUnixNano": unix_nanos(start + duration),
+                "attributes": key_values(&segment.attributes),
+            })
+        }));
+        spans
+    }
+
+    /// The custom metrics of this transaction, as gauges.
+    fn metrics(&self) -> Vec<Value> {
+        self.custom_metrics
+            .iter()
+            .map(|(name, value, unit, when)| {
+                json!({
+                    "name": name,
+                    "unit": unit.ucum(),
+                    "gauge": {
+                        "dataPoints": [{
+                            "timeUnixNano": unix_nanos(*when),
+                            "asDouble": value,
+                            "attributes": [
+                                { "key": "transaction.name", "value": string_value(&self.name) },
+                            ],
+                        }],
+                    },
+                })
+            })
+            .collect()
+    }
+}
+
+struct CustomEventState {
+    event_type: String,
+    timestamp: SystemTime,
+    attributes: Map<String, Value>,
+}
+
+/// A request for the exporter thread to send.
+struct Export {
+    path: &'static str,
+    body: String,
+}
+
+/// Send exports until the backend is dropped.
+///
+/// `_finished` is dropped when this returns, which tells the backend that
+/// everything has been sent.
+fn export(
+    agent: ureq::Agent,
+    endpoint: String,
+    api_key: String,
+    exports: Receiver<Export>,
+    _finished: Sender<()>,
+) {
+    for export in exports {
+        let url = format!("{}{}", endpoint, export.path);
+        let result = agent
+            .post(&url)
+            .set("Content-Type", "application/json")
+            .set("api-key", &api_key)
+            .send_string(&export.body);
+        match result {
+            Ok(_) => debug!("Exported to {}", url),
+            Err(e) => error!("Could not export to {}: {}", url, e),
+        }
+    }
+}
+
+/// A backend which sends data to an OpenTelemetry collector.
+pub(crate) struct OtlpBackend {
+    resource: Value,
+    transactions: Mutex<HashMap<usize, TransactionState>>,
+    custom_events: Mutex<HashMap<usize, CustomEventState>>,
+    next_handle: AtomicUsize,
+    exports: Mutex<Option<SyncSender<Export>>>,
+    exporter: Option<JoinHandle<()>>,
+    /// Disconnected once the exporter has sent everything.
+    finished: Mutex<Receiver<()>>,
+    /// How long to wait for the exporter when the backend is dropped.
+    timeout: Duration,
+    #[cfg(feature = "distributed_tracing")]
+    distributed_tracing: bool,
+}
+
+impl OtlpBackend {
+    /// Create a backend which exports to the configured collector.
+    ///
+    /// The license key is sent in the `api-key` header, as required by New
+    /// Relic's own OTLP endpoint. At most `EXPORT_QUEUE_SIZE` exports are
+    /// queued while the collector is slow or unreachable; any more are
+    /// dropped.
+    pub(crate) fn new(config: &AppConfig) -> Self {
+        let (endpoint, timeout) = {
+            let settings = SETTINGS.read().unwrap();
+            (
+                settings
+                    .endpoint
+                    .clone()
+                    .or_else(|| env::var(ENDPOINT_VARIABLE).ok())
+                    .unwrap_or_else(|| DEFAULT_ENDPOINT.to_string()),
+                settings.timeout.unwrap_or(DEFAULT_TIMEOUT),
+            )
+        };
+        let endpoint = endpoint.trim_end_matches('/').to_string();
+        debug!("Exporting to the OpenTelemetry collector at {}", endpoint);
+        let agent = ureq::AgentBuilder::new().timeout(timeout).build();
+        let api_key = config.license_key.clone();
+        let (sender, receiver) = mpsc::sync_channel(EXPORT_QUEUE_SIZE);
+        let (finished_sender, finished) = mpsc::channel();
+        let exporter =
+            thread::spawn(move || export(agent, endpoint, api_key, receiver, finished_sender));
+        let mut resource = vec![
+            json!({ "key": "service.name", "value": string_value(&config.app_name) }),
+            json!({ "key": "telemetry.sdk.name", "value": string_value("newrelic") }),
+            json!({ "key": "telemetry.sdk.language", "value": string_value("rust") }),
+            json!({
+                "key": "telemetry.sdk.version",
+                "value": string_value(env!("CARGO_PKG_VERSION")),
+            }),
+        ];
+        if let Some(name) = &config.host_display_name {
+            resource.push(json!({ "key": "host.display_name", "value": string_value(name) }));
+        }
+        for (name, value) in &config.labels {
+            resource.push(json!({ "key": name, "value": string_value(value) }));
+        }
+        OtlpBackend {
+            resource: json!({ "attributes": resource }),
+            transactions: Mutex::default(),
+            custom_events: Mutex::default(),
+            next_handle: AtomicUsize::new(0),
+            exports: Mutex::new(Some(sender)),
+            exporter: Some(exporter),
+            finished: Mutex::new(finished),
+            timeout,
+            #[cfg(feature = "distributed_tracing")]
+            distributed_tracing: config.distributed_tracing,
+        }
+    }
+
+    fn scope() -> Value {
+        json!({ "name": "newrelic", "version": env!("CARGO_PKG_VERSION") })
+    }
+
+    fn export(&self, path: &'static str, body: Value) {
+        if let Some(exports) = &*self.exports.lock().unwrap() {
+            let body = body.to_string();
+            match exports.try_send(Export { path, body }) {
+                Ok(()) => {}
+                Err(TrySendError::Full(export)) => warn!(
+                    "Dropping export to {}, the collector is {} exports behind",
+                    export.path, EXPORT_QUEUE_SIZE
+                ),
+                Err(TrySendError::Disconnected(_)) => {
+                    error!("Could not export, the exporter has stopped")
+                }
+            }
+        }
+    }
+
+    fn next_handle(&self) -> usize {
+        self.next_handle.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn with_transaction<T>(
+        &self,
+        transaction: TransactionHandle,
+        f: impl FnOnce(&mut TransactionState) -> T,
+    ) -> Option<T> {
+        self.transactions
+            .lock()
+            .unwrap()
+            .get_mut(&transaction.0)
+            .map(f)
+    }
+
+    fn start_segment(
+        &self,
+        transaction: TransactionHandle,
+        name: String,
+        kind: u8,
+        attributes: Map<String, Value>,
+    ) -> Option<SegmentHandle> {
+        self.with_transaction(transaction, |state| {
+            let index = state.segments.len();
+            state.segments.push(SpanState {
+                span_id: random_u64(),
+                name,
+                kind,
+                parent: state.active_segments.last().copied(),
+                offset: state.started.elapsed(),
+                started: Instant::now(),
+                duration: None,
+                attributes,
+            });
+            state.active_segments.push(index);
+            SegmentHandle(index)
+        })
+    }
+}
+
+impl Backend for OtlpBackend {
+    fn start_transaction(&self, name: &CStr, kind: &TransactionType) -> Option<TransactionHandle> {
+        let handle = self.next_handle();
+        let kind = match kind {
+            TransactionType::Web => SPAN_KIND_SERVER,
+            TransactionType::NonWeb => SPAN_KIND_INTERNAL,
+        };
+        self.transactions.lock().unwrap().insert(
+            handle,
+            TransactionState {
+                trace_id: (u128::from(random_u64()) << 64) | u128::from(random_u64()),
+                span_id: random_u64(),
+                name: to_string(name),
+                kind,
+                start: SystemTime::now(),
+                started: Instant::now(),
+                duration: None,
+                attributes: Map::new(),
+                error: None,
+                events: Vec::new(),
+                custom_metrics: Vec::new(),
+                segments: Vec::new(),
+                active_segments: Vec::new(),
+                ignored: false,
+            },
+        );
+        Some(TransactionHandle(handle))
+    }
+
+    fn set_transaction_name(&self, transaction: TransactionHandle, name: &CStr) -> bool {
+        self.with_transaction(transaction, |state| state.name = to_string(name))
+            .is_some()
+    }
+
+    fn add_attribute(
+        &self,
+        transaction: TransactionHandle,
+        name: &CStr,
+        value: AttributeValue<'_>,
+    ) -> bool {
+        self.with_transaction(transaction, |state| {
+            state.attributes.insert(to_string(name), any_value(value));
+        })
+        .is_some()
+    }
+
+    fn notice_error(
+        &self,
+        transaction: TransactionHandle,
+        priority: i32,
+        message: &CStr,
+        class: &CStr,
+        backtrace: &[String],
+    ) {
+        self.with_transaction(transaction, |state| {
+            // As with the C SDK, only the highest priority error is kept.
+            if state
+                .error
+                .as_ref()
+                .is_none_or(|error| priority >= error.priority)
+            {
+                state.error = Some(NoticedError {
+                    priority,
+                    message: to_string(message),
+                    class: to_string(class),
+                    backtrace: backtrace.to_vec(),
+                    when: SystemTime::now(),
+                });
+            }
+        });
+    }
+
+    fn set_transaction_timing(
+        &self,
+        transaction: TransactionHandle,
+        start_micros: u64,
+        duration_micros: u64,
+    ) -> bool {
+        self.with_transaction(transaction, |state| {
+            state.start = UNIX_EPOCH + Duration::from_micros(start_micros);
+            state.duration = Some(Duration::from_micros(duration_micros));
+        })
+        .is_some()
+    }
+
+    fn ignore_transaction(&self, transaction: TransactionHandle) -> bool {
+        self.with_transaction(transaction, |state| state.ignored = true)
+            .is_some()
+    }
+
+    fn record_custom_metric(
+        &self,
+        transaction: TransactionHandle,
+        name: &CStr,
+        value: f64,
+        unit: MetricUnit,
+    ) -> bool {
+        self.with_transaction(transaction, |state| {
+            let metric = (to_string(name), value, unit, SystemTime::now());
+            state.custom_metrics.push(metric);
+        })
+        .is_some()
+    }
+
+    fn end_transaction(&self, transaction: TransactionHandle) {
+        let state = match self.transactions.lock().unwrap().remove(&transaction.0) {
+            Some(state) => state,
+            None => return,
+        };
+        if state.ignored {
+            debug!("Not exporting ignored transaction");
+            return;
+        }
+        let spans = state.spans(Instant::now());
+        self.export(
+            "/v1/traces",
+            json!({
+                "resourceSpans": [{
+                    "resource": self.resource,
+                    "scopeSpans": [{ "scope": Self::scope(), "spans": spans }],
+                }],
+            }),
+        );
+        let metrics = state.metrics();
+        if !metrics.is_empty() {
+            self.export(
+                "/v1/metrics",
+                json!({
+                    "resourceMetrics": [{
+                        "resource": self.resource,
+                        "scopeMetrics": [{ "scope": Self::scope(), "metrics": metrics }],
+                    }],
+                }),
+            );
+        }
+    }
+
+    fn start_custom_segment(
+        &self,
+        transaction: TransactionHandle,
+        name: &CStr,
+        category: &CStr,
+    ) -> Option<SegmentHandle> {
+        let mut attributes = Map::new();
+        attributes.insert(
+            "segment.category".to_string(),
+            string_value(to_string(category)),
+        );
+        self.start_segment(transaction, to_string(name), SPAN_KIND_INTERNAL, attributes)
+    }
+
+    fn start_datastore_segment(
+        &self,
+        transaction: TransactionHandle,
+        params: &DatastoreParams,
+    ) -> Option<SegmentHandle> {
+        let system = db_system(&params.product);
+        let collection = optional_string(&params.collection);
+        let operation = optional_string(&params.operation);
+        let name = match (&operation, &collection) {
+            (Some(operation), Some(collection)) => format!("{} {}", operation, collection),
+            (Some(name), None) | (None, Some(name)) => name.clone(),
+            (None, None) => system.clone(),
+        };
+
+        let mut attributes = Map::new();
+        attributes.insert("db.system".to_string(), string_value(system));
+        let strings = [
+            ("db.collection.name", collection),
+            ("db.operation.name", operation),
+            ("server.address", optional_string(&params.host)),
+            ("db.namespace", optional_string(&params.database_name)),
+            ("db.query.text", optional_string(&params.query)),
+        ];
+        for (key, value) in strings.iter() {
+            if let Some(value) = value {
+                attributes.insert(key.to_string(), string_value(value.as_str()));
+            }
+        }
+        if let Some(port_path_or_id) = optional_string(&params.port_path_or_id) {
+            let (key, value) = match port_path_or_id.parse::<u16>() {
+                Ok(port) => ("server.port", json!({ "intValue": port.to_string() })),
+                Err(_) => ("db.instance.id", string_value(port_path_or_id)),
+            };
+            attributes.insert(key.to_string(), value);
+        }
+        self.start_segment(transaction, name, SPAN_KIND_CLIENT, attributes)
+    }
+
+    fn start_external_segment(
+        &self,
+        transaction: TransactionHandle,
+        params: &ExternalParams,
+    ) -> Option<SegmentHandle> {
+        let uri = to_string(&params.uri);
+        let host = uri_host(&uri).map(str::to_string);
+        let procedure = optional_string(&params.procedure);
+        let name = procedure
+            .clone()
+            .or_else(|| host.clone())
+            .unwrap_or_else(|| uri.clone());
+
+        let mut attributes = Map::new();
+        attributes.insert("url.full".to_string(), string_value(uri));
+        let strings = [
+            ("server.address", host),
+            ("http.request.method", procedure),
+            ("external.library", optional_string(&params.library)),
+        ];
+        for (key, value) in strings.iter() {
+            if let Some(value) = value {
+                attributes.insert(key.to_string(), string_value(value.as_str()));
+            }
+        }
+        self.start_segment(transaction, name, SPAN_KIND_CLIENT, attributes)
+    }
+
+    fn set_segment_parent(
+        &self,
+        transaction: TransactionHandle,
+        segment: SegmentHandle,
+        parent: SegmentHandle,
+    ) -> bool {
+        self.with_transaction(transaction, |state| {
+            if segment.0 >= state.segments.len() || parent.0 >= state.segments.len() {
+                return false;
+            }
+            // Refuse to create a cycle.
+            let mut ancestor = Some(parent.0);
+            while let Some(index) = ancestor {
+                if index == segment.0 {
+                    return false;
+                }
+                ancestor = state.segments[index].parent;
+            }
+            state.segments[segment.0].parent = Some(parent.0);
+            true
+        })
+        .unwrap_or(false)
+    }
+
+    fn set_segment_timing(
+        &self,
+        transaction: TransactionHandle,
+        segment: SegmentHandle,
+        start_micros: u64,
+        duration_micros: u64,
+    ) -> bool {
+        self.with_transaction(transaction, |state| {
+            state
+                .segments
+                .get_mut(segment.0)
+                .map(|segment_state| {
+                    segment_state.offset = Duration::from_micros(start_micros);
+                    segment_state.duration = Some(Duration::from_micros(duration_micros));
+                })
+                .is_some()
+        })
+        .unwrap_or(false)
+    }
+
+    fn end_segment(&self, transaction: TransactionHandle, segment: SegmentHandle) {
+        self.with_transaction(transaction, |state| {
+            if let Some(segment_state) = state.segments.get_mut(segment.0) {
+                // Keep any duration set by `set_segment_timing`.
+                if segment_state.duration.is_none() {
+                    segment_state.duration = Some(segment_state.started.elapsed());
+                }
+            }
+            state.active_segments.retain(|index| *index != segment.0);
+        });
+    }
+
+    /// The W3C `traceparent` header identifying the segment's span, if
+    /// distributed tracing is enabled.
+    #[cfg(feature = "distributed_tracing")]
+    fn distributed_trace_payload(
+        &self,
+        transaction: TransactionHandle,
+        segment: SegmentHandle,
+    ) -> Option<String> {
+        if !self.distributed_tracing {
+            return None;
+        }
+        self.with_transaction(transaction, |state| {
+            let span_id = state.segments.get(segment.0)?.span_id;
+            Some(format!("00-{:032x}-{:016x}-01", state.trace_id, span_id))
+        })
+        .flatten()
+    }
+
+    fn create_custom_event(&self, event_type: &CStr) -> Option<CustomEventHandle> {
+        let handle = self.next_handle();
+        self.custom_events.lock().unwrap().insert(
+            handle,
+            CustomEventState {
+                event_type: to_string(event_type),
+                timestamp: SystemTime::now(),
+                attributes: Map::new(),
+            },
+        );
+        Some(CustomEventHandle(handle))
+    }
+
+    fn add_custom_event_attribute(
+        &self,
+        event: CustomEventHandle,
+        name: &CStr,
+        value: AttributeValue<'_>,
+    ) -> bool {
+        match self.custom_events.lock().unwrap().get_mut(&event.0) {
+            Some(state) => {
+                state.attributes.insert(to_string(name), any_value(value));
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn record_custom_event(&self, transaction: TransactionHandle, event: CustomEventHandle) {
+        let state = match self.custom_events.lock().unwrap().remove(&event.0) {
+            Some(state) => state,
+            None => return,
+        };
+        let event = json!({
+            "timeUnixNano": unix_nanos(state.timestamp),
+            "name": state.event_type,
+            "attributes": key_values(&state.attributes),
+        });
+        self.with_transaction(transaction, |transaction| transaction.events.push(event));
+    }
+
+    fn discard_custom_event(&self, event: CustomEventHandle) {
+        self.custom_events.lock().unwrap().remove(&event.0);
+    }
+}
+
+impl Drop for OtlpBackend {
+    /// Wait for the exporter to send everything it has been given, for at
+    /// most the collector timeout.
+    fn drop(&mut self) {
+        self.exports.lock().unwrap().take();
+        match self.finished.get_mut().unwrap().recv_timeout(self.timeout) {
+            Err(RecvTimeoutError::Disconnected) => {
+                if let Some(exporter) = self.exporter.take() {
+                    let _ = exporter.join();
+                }
+            }
+            _ => warn!(
+                "The collector didn't accept everything within {:?}, exporting in the background",
+                self.timeout
+            ),
+        }
+        debug!("Destroyed app");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::{TcpListener, TcpStream},
+        sync::Arc,
+    };
+
+    use super::*;
+
+    /// A request received by the `Collector`.
+    struct Request {
+        path: String,
+        api_key: Option<String>,
+        body: Value,
+    }
+
+    /// A stand-in for an OpenTelemetry collector, accepting OTLP/HTTP JSON
+    /// requests on a local port and recording them.
+    struct Collector {
+        endpoint: String,
+        requests: Arc<Mutex<Vec<Request>>>,
+    }
+
+    impl Collector {
+        fn start() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let endpoint = format!("http://{}", listener.local_addr().unwrap());
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let received = Arc::clone(&requests);
+            thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let received = Arc::clone(&received);
+                    thread::spawn(move || Self::serve(stream, &received));
+                }
+            });
+            Collector { endpoint, requests }
+        }
+
+        /// Answer every request on a connection, which the exporter may
+        /// keep alive between exports.
+        fn serve(stream: TcpStream, received: &Mutex<Vec<Request>>) {
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                    return;
+                }
+                let path = line.split(' ').nth(1).unwrap_or_default().to_string();
+                let (mut length, mut api_key) = (0, None);
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    let header = header.trim_end();
+                    if header.is_empty() {
+                        break;
+                    }
+                    let (name, value) = header.split_once(':').unwrap();
+                    match name.to_ascii_lowercase().as_str() {
+                        "content-length" => length = value.trim().parse().unwrap(),
+                        "api-key" => api_key = Some(value.trim().to_string()),
+                        _ => {}
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                received.lock().unwrap().push(Request {
+                    path,
+                    api_key,
+                    body: serde_json::from_slice(&body).unwrap(),
+                });
+                writer
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                    .unwrap();
+            }
+        }
+
+        /// The bodies of the requests received at `path`.
+        fn bodies(&self, path: &str) -> Vec<Value> {
+            self.requests
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|request| request.path == path)
+                .map(|request| request.body.clone())
+                .collect()
+        }
+    }
+
+    /// Serialises tests which configure the global collector settings.
+    static SETTINGS_LOCK: Mutex<()> = Mutex::new(());
+
+    /// A backend exporting to `endpoint`.
+    fn backend(endpoint: &str, timeout: Option<Duration>) -> OtlpBackend {
+        let _lock = SETTINGS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        configure(Some(endpoint.to_string()), timeout);
+        let config = AppConfig::new("test app", &"0".repeat(40)).unwrap();
+        OtlpBackend::new(&config)
+    }
+
+    fn name(value: &str) -> CString {
+        CString::new(value).unwrap()
+    }
+
+    /// The spans of the only trace exported to `collector`.
+    fn spans(collector: &Collector) -> Vec<Value> {
+        let traces = collector.bodies("/v1/traces");
+        assert_eq!(traces.len(), 1);
+        traces[0]["resourceSpans"][0]["scopeSpans"][0]["spans"]
+            .as_array()
+            .unwrap()
+            .clone()
+    }
+
+    /// A span's start and end times, in nanoseconds since the Unix epoch.
+    fn times(span: &Value) -> (u128, u128) {
+        let nanos = |key: &str| span[key].as_str().unwrap().parse().unwrap();
+        (nanos("startTimeUnixNano"), nanos("endTimeUnixNano"))
+    }
+
+    /// The value of the attribute called `key` in a list of OTLP
+    /// `KeyValue`s.
+    fn attribute<'a>(attributes: &'a Value, key: &str) -> &'a Value {
+        attributes
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|attribute| attribute["key"] == key)
+            .map(|attribute| &attribute["value"])
+            .unwrap_or_else(|| panic!("No attribute {} in {}", key, attributes))
+    }
+
+    #[test]
+    fn exports_to_collector() {
+        let collector = Collector::start();
+        let license_key = "0123456789012345678901234567890123456789";
+        let mut config = AppConfig::new("test app", license_key).unwrap();
+        config.labels = vec![("env".to_string(), "test".to_string())];
+        let backend = {
+            let _lock = SETTINGS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            configure(Some(collector.endpoint.clone()), None);
+            OtlpBackend::new(&config)
+        };
+
+        let transaction = backend
+            .start_transaction(&name("index"), &TransactionType::Web)
+            .unwrap();
+        backend.add_attribute(transaction, &name("user_id"), AttributeValue::Int(42));
+        let segment = backend
+            .start_custom_segment(transaction, &name("render"), &name("Custom"))
+            .unwrap();
+        assert!(backend.set_segment_timing(transaction, segment, 10_000, 20_000));
+        backend.end_segment(transaction, segment);
+        backend.notice_error(
+            transaction,
+            5,
+            &name("message"),
+            &name("class"),
+            &["frame".to_string()],
+        );
+        backend.record_custom_metric(transaction, &name("Custom/size"), 3.0, MetricUnit::Bytes);
+        let event = backend.create_custom_event(&name("Event")).unwrap();
+        backend.add_custom_event_attribute(event, &name("size"), AttributeValue::Long(7));
+        backend.record_custom_event(transaction, event);
+        backend.end_transaction(transaction);
+
+        let ignored = backend
+            .start_transaction(&name("health"), &TransactionType::Web)
+            .unwrap();
+        assert!(backend.ignore_transaction(ignored));
+        backend.end_transaction(ignored);
+
+        // Wait for the exporter to send everything.
+        drop(backend);
+
+        for request in collector.requests.lock().unwrap().iter() {
+            assert_eq!(request.api_key.as_deref(), Some(license_key));
+        }
+        let traces = collector.bodies("/v1/traces");
+        assert_eq!(traces.len(), 1);
+        let resource = &traces[0]["resourceSpans"][0]["resource"]["attributes"];
+        assert_eq!(
+            attribute(resource, "service.name")["stringValue"],
+            "test app"
+        );
+        assert_eq!(attribute(resource, "env")["stringValue"], "test");
+
+        let spans = traces[0]["resourceSpans"][0]["scopeSpans"][0]["spans"]
+            .as_array()
+            .unwrap();
+        assert_eq!(spans.len(), 2);
+        let (root, child) = (&spans[0], &spans[1]);
+        assert_eq!(root["name"], "index");
+        assert_eq!(root["kind"], SPAN_KIND_SERVER);
+        assert_eq!(attribute(&root["attributes"], "user_id")["intValue"], "42");
+        assert_eq!(root["status"]["code"], STATUS_CODE_ERROR);
+        assert_eq!(root["status"]["message"], "message");
+        let events = root["events"].as_array().unwrap();
+        assert_eq!(events[0]["name"], "Event");
+        assert_eq!(attribute(&events[0]["attributes"], "size")["intValue"], "7");
+        assert_eq!(events[1]["name"], "exception");
+        let exception = &events[1]["attributes"];
+        assert_eq!(
+            attribute(exception, "exception.type")["stringValue"],
+            "class"
+        );
+        assert_eq!(
+            attribute(exception, "exception.stacktrace")["stringValue"],
+            "frame"
+        );
+
+        assert_eq!(child["name"], "render");
+        assert_eq!(child["traceId"], root["traceId"]);
+        assert_eq!(child["parentSpanId"], root["spanId"]);
+        let start: u128 = child["startTimeUnixNano"]
+            .as_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let end: u128 = child["endTimeUnixNano"].as_str().unwrap().parse().unwrap();
+        let root_start: u128 = root["startTimeUnixNano"].as_str().unwrap().parse().unwrap();
+        assert_eq!(start - root_start, 10_000_000);
+        assert_eq!(end - start, 20_000_000);
+
+        let metrics = collector.bodies("/v1/metrics");
+        assert_eq!(metrics.len(), 1);
+        let metric = &metrics[0]["resourceMetrics"][0]["scopeMetrics"][0]["metrics"][0];
+        assert_eq!(metric["name"], "Custom/size");
+        assert_eq!(metric["unit"], "By");
+        assert_eq!(metric["gauge"]["dataPoints"][0]["asDouble"], 3.0);
+    }
+
+    #[test]
+    fn ignored_transactions_are_not_exported() {
+        let collector = Collector::start();
+        let backend = backend(&collector.endpoint, None);
+        let transaction = backend
+            .start_transaction(&name("health"), &TransactionType::Web)
+            .unwrap();
+        backend.record_custom_metric(transaction, &name("Custom/size"), 3.0, MetricUnit::Bytes);
+        assert!(backend.ignore_transaction(transaction));
+        backend.end_transaction(transaction);
+        drop(backend);
+
+        assert!(collector.requests.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn timing_sets_span_times() {
+        let collector = Collector::start();
+        let backend = backend(&collector.endpoint, None);
+        let transaction = backend
+            .start_transaction(&name("index"), &TransactionType::NonWeb)
+            .unwrap();
+        let segment = backend
+            .start_custom_segment(transaction, &name("render"), &name("Custom"))
+            .unwrap();
+        assert!(backend.set_transaction_timing(transaction, 1_000_000, 500_000));
+        assert!(backend.set_segment_timing(transaction, segment, 100_000, 200_000));
+        backend.end_segment(transaction, segment);
+        backend.end_transaction(transaction);
+        drop(backend);
+
+        let spans = spans(&collector);
+        assert_eq!(times(&spans[0]), (1_000_000_000, 1_500_000_000));
+        assert_eq!(times(&spans[1]), (1_100_000_000, 1_300_000_000));
+    }
+
+    #[test]
+    fn errors_set_status_and_exception() {
+        let collector = Collector::start();
+        let backend = backend(&collector.endpoint, None);
+        let transaction = backend
+            .start_transaction(&name("index"), &TransactionType::Web)
+            .unwrap();
+        backend.notice_error(transaction, 5, &name("failed"), &name("Timeout"), &[]);
+        backend.end_transaction(transaction);
+        drop(backend);
+
+        let spans = spans(&collector);
+        assert_eq!(spans[0]["status"]["code"], STATUS_CODE_ERROR);
+        assert_eq!(spans[0]["status"]["message"], "failed");
+        let events = spans[0]["events"].as_array().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["name"], "exception");
+        let exception = &events[0]["attributes"];
+        assert_eq!(
+            attribute(exception, "exception.message")["stringValue"],
+            "failed"
+        );
+        assert_eq!(
+            attribute(exception, "exception.type")["stringValue"],
+            "Timeout"
+        );
+        // There's no stacktrace without a backtrace.
+        assert_eq!(exception.as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn drop_waits_for_at_most_the_timeout() {
+        // A collector which accepts connections but never answers.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let streams: Vec<_> = listener.incoming().collect();
+            drop(streams);
+        });
+        let timeout = Duration::from_millis(200);
+        let backend = backend(&endpoint, Some(timeout));
+        for _ in 0..EXPORT_QUEUE_SIZE * 2 {
+            let transaction = backend
+                .start_transaction(&name("index"), &TransactionType::Web)
+                .unwrap();
+            backend.end_transaction(transaction);
+        }
+
+        let started = Instant::now();
+        drop(backend);
+        assert!(started.elapsed() < timeout * 5);
+    }
+}
@@ -0,0 +1,232 @@
+//! Rust backtraces for noticed errors.
+//!
+//! `std::backtrace::Backtrace` only exposes its frames through its `Display`
+//! output on stable Rust, so they are parsed from that. The short format
+//! it uses already hides the frames of the backtrace machinery itself; the
+//! frames of this crate and of the runtime are removed here.
+use std::backtrace::{Backtrace, BacktraceStatus};
+
+/// The function std uses to mark the start of the program's own frames;
+/// it and everything below it belong to the runtime. Only its name is
+/// matched, as its module has moved between Rust versions.
+const SHORT_BACKTRACE_START: &str = "__rust_begin_short_backtrace";
+
+/// Capture a backtrace of the caller to send with a noticed error.
+///
+/// As with `Backtrace::capture`, this returns an empty list unless
+/// backtraces are enabled using `RUST_LIB_BACKTRACE` or `RUST_BACKTRACE`.
+/// Each frame is formatted as `symbol at file:line:column`, without the
+/// frames of this crate at the top of the backtrace or those of the Rust
+/// runtime at the bottom.
+pub(crate) fn capture() -> Vec<String> {
+    let backtrace = Backtrace::capture();
+    if backtrace.status() != BacktraceStatus::Captured {
+        return Vec::new();
+    }
+    trim(frames(&backtrace.to_string()))
+}
+
+/// As many of the top frames of a backtrace as fit in `max_bytes` when
+/// joined by newlines. If the first frame alone is too long it is
+/// truncated, without splitting a UTF-8 character.
+pub(crate) fn top_frames(frames: &[String], max_bytes: usize) -> String {
+    let mut joined = String::new();
+    for frame in frames {
+        if joined.is_empty() {
+            let mut end = frame.len().min(max_bytes);
+            while !frame.is_char_boundary(end) {
+                end -= 1;
+            }
+            joined.push_str(&frame[..end]);
+        } else if joined.len() + 1 + frame.len() <= max_bytes {
+            joined.push('\n');
+            joined.push_str(frame);
+        } else {
+            break;
+        }
+    }
+    joined
+}
+
+/// Parse the frames of a formatted backtrace, in which each frame is a
+/// numbered symbol optionally followed by an indented location.
+fn frames(formatted: &str) -> Vec<String> {
+    let mut frames: Vec<String> = Vec::new();
+    for line in formatted.lines() {
+        let line = line.trim();
+        if let Some(location) = line.strip_prefix("at ") {
+            if let Some(frame) = frames.last_mut() {
+                frame.push_str(" at ");
+                frame.push_str(location);
+            }
+        } else if let Some((index, symbol)) = line.split_once(": ") {
+            if index.chars().all(|c| c.is_ascii_digit()) {
+                frames.push(symbol.to_string());
+            }
+        }
+    }
+    frames
+}
+
+/// Remove this crate's frames from the top of a backtrace and the
+/// runtime's from the bottom.
+///
+/// The runtime's frames start at `SHORT_BACKTRACE_START` if it is found;
+/// otherwise, as with a stripped binary, only the trailing frames which
+/// look like they belong to the runtime are removed.
+fn trim(mut frames: Vec<String>) -> Vec<String> {
+    if let Some(end) = frames.iter().position(|frame| {
+        symbol(frame)
+            .rsplit("::")
+            .next()
+            .is_some_and(|name| name == SHORT_BACKTRACE_START)
+    }) {
+        frames.truncate(end);
+    }
+    while frames.last().is_some_and(|frame| is_runtime(frame)) {
+        frames.pop();
+    }
+    let start = frames
+        .iter()
+        .position(|frame| !is_internal(frame))
+        .unwrap_or(frames.len());
+    frames.split_off(start)
+}
+
+/// The symbol of a frame, without its location.
+fn symbol(frame: &str) -> &str {
+    match frame.find(" at ") {
+        Some(end) => &frame[..end],
+        None => frame,
+    }
+}
+
+/// Whether a frame is in this crate, including its trait implementations.
+fn is_internal(frame: &str) -> bool {
+    let symbol = frame.trim_start_matches('<');
+    symbol.starts_with("newrelic::") || symbol.starts_with("std::backtrace")
+}
+
+/// Whether a frame is part of the runtime which calls `main` or a thread's
+/// closure, rather than of the program.
+fn is_runtime(frame: &str) -> bool {
+    let symbol = symbol(frame);
+    let path = symbol.trim_start_matches('<');
+    [
+        "std::rt::",
+        "std::panic",
+        "std::sys",
+        "core::ops::function::",
+    ]
+    .iter()
+    .any(|prefix| path.starts_with(prefix))
+        || [
+            "main",
+            "<unknown>",
+            "_start",
+            "start_thread",
+            "clone",
+            "clone3",
+        ]
+        .contains(&symbol)
+        || symbol.starts_with("__libc_start")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMATTED: &str = "   0: app::handler
+             at ./src/main.rs:10:5
+   1: newrelic::transaction::Transaction::notice_error
+             at ./src/transaction.rs:680:25
+   2: app::main
+             at ./src/main.rs:2:13
+   3: core::ops::function::FnOnce::call_once
+             at /rustc/library/core/src/ops/function.rs:250:5
+   4: std::sys::backtrace::__rust_begin_short_backtrace
+             at /rustc/library/std/src/sys/backtrace.rs:166:18
+   5: std::rt::lang_start::{{closure}}
+             at /rustc/library/std/src/rt.rs:206:18
+   6: main
+   7: __libc_start_main
+   8: _start
+";
+
+    fn strings(frames: &[&str]) -> Vec<String> {
+        frames.iter().map(|frame| frame.to_string()).collect()
+    }
+
+    #[test]
+    fn frames_include_locations() {
+        let frames = frames(FORMATTED);
+        assert_eq!(frames.len(), 9);
+        assert_eq!(frames[0], "app::handler at ./src/main.rs:10:5");
+        assert_eq!(
+            frames[4],
+            "std::sys::backtrace::__rust_begin_short_backtrace at /rustc/library/std/src/sys/backtrace.rs:166:18"
+        );
+        assert_eq!(frames[6], "main");
+    }
+
+    #[test]
+    fn frames_ignore_other_lines() {
+        let formatted = "stack backtrace:\n   0: app::main\nnote: Some details are omitted\n";
+        assert_eq!(frames(formatted), strings(&["app::main"]));
+    }
+
+    #[test]
+    fn trim_removes_runtime_frames() {
+        let trimmed = trim(frames(FORMATTED));
+        assert_eq!(
+            trimmed,
+            strings(&[
+                "app::handler at ./src/main.rs:10:5",
+                "newrelic::transaction::Transaction::notice_error at ./src/transaction.rs:680:25",
+                "app::main at ./src/main.rs:2:13",
+            ])
+        );
+    }
+
+    #[test]
+    fn trim_removes_crate_frames_from_top() {
+        let trimmed = trim(strings(&[
+            "newrelic::backtrace::capture",
+            "<newrelic::transaction::Transaction>::notice_error",
+            "app::handler",
+            "app::main",
+        ]));
+        assert_eq!(trimmed, strings(&["app::handler", "app::main"]));
+    }
+
+    #[test]
+    fn trim_without_short_backtrace_marker() {
+        // Older Rust versions kept the marker in another module, and
+        // stripped binaries have no symbols for the runtime at all.
+        let trimmed = trim(strings(&[
+            "app::main",
+            "std::sys_common::backtrace::__rust_begin_short_backtrace",
+            "std::rt::lang_start",
+        ]));
+        assert_eq!(trimmed, strings(&["app::main"]));
+        let trimmed = trim(strings(&["app::main", "<unknown>", "<unknown>"]));
+        assert_eq!(trimmed, strings(&["app::main"]));
+    }
+
+    #[test]
+    fn trim_only_internal_frames() {
+        assert!(trim(strings(&["newrelic::backtrace::capture"])).is_empty());
+        assert!(trim(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn top_frames_fit() {
+        let frames = strings(&["first", "second", "third"]);
+        assert_eq!(top_frames(&frames, 100), "first\nsecond\nthird");
+        assert_eq!(top_frames(&frames, 12), "first\nsecond");
+        assert_eq!(top_frames(&frames, 11), "first");
+        assert_eq!(top_frames(&frames, 3), "fir");
+        assert_eq!(top_frames(&strings(&["ééé"]), 3), "é");
+        assert_eq!(top_frames(&[], 10), "");
+    }
+}
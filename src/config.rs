@@ -0,0 +1,682 @@
+use std::{collections::BTreeMap, path::PathBuf, time::Duration};
+
+#[cfg(any(feature = "toml", feature = "yaml"))]
+use serde::{de, Deserialize, Deserializer};
+
+#[cfg(any(feature = "toml", feature = "yaml"))]
+use std::{fs, path::Path};
+
+use crate::{
+    app::{BackendKind, RecordSQL, TracingThreshold},
+    env,
+    error::Result,
+};
+
+#[cfg(any(feature = "toml", feature = "yaml"))]
+use crate::error::Error;
+
+/// Agent configuration, covering every setting of `AppBuilder` and
+/// `NewRelicConfig`.
+///
+/// A `Config` can be loaded from the standard `NEW_RELIC_*` environment
+/// variables and, with the `toml` or `yaml` features, from a configuration
+/// file. Every setting is optional; settings which are not present leave
+/// the SDK defaults untouched.
+///
+/// Environment variables take precedence over settings in a configuration
+/// file, matching the other New Relic agents. `Config::load` applies these
+/// precedence rules; `Config::merge` can be used to combine configs from
+/// other sources.
+///
+/// Durations are given in (possibly fractional) seconds. The transaction
+/// threshold may also be `"apdex_f"` to use `TracingThreshold::ApdexFailing`.
+///
+/// A complete TOML configuration file looks like this:
+///
+/// ```toml
+/// enabled = true
+/// backend = "c-sdk"
+/// app_name = "my app"
+/// license_key = "my license key"
+/// redirect_collector = "collector.newrelic.com"
+/// high_security = false
+///
+/// [labels]
+/// env = "prod"
+/// team = "billing"
+///
+/// [process_host]
+/// display_name = "web-1"
+///
+/// [daemon]
+/// socket = "/tmp/.newrelic.sock"
+/// timeout = 10.0
+///
+/// [log]
+/// level = "info"
+/// output = "stderr"
+///
+/// [transaction_tracer]
+/// enabled = true
+/// transaction_threshold = "apdex_f"
+/// stack_trace_threshold = 0.5
+///
+/// [transaction_tracer.datastore_reporting]
+/// enabled = true
+/// threshold = 0.5
+/// record_sql = "obfuscated"
+///
+/// [datastore_tracer]
+/// database_name_reporting = true
+/// instance_reporting = true
+///
+/// [span_events]
+/// enabled = true
+///
+/// [distributed_tracing]
+/// enabled = false
+///
+/// [attributes]
+/// include = ["request.headers.user-agent"]
+/// exclude = ["request.headers.*", "*.password"]
+/// ```
+///
+/// The equivalent YAML file uses the same keys and nesting.
+///
+/// Example:
+///
+/// ```rust
+/// use newrelic::{AppBuilder, Config, NewRelicConfig};
+///
+/// # #[cfg(any(feature = "toml", feature = "yaml"))]
+/// # fn load() {
+/// let config = Config::load("newrelic.toml").expect("Invalid New Relic configuration");
+/// NewRelicConfig::from_config(&config)
+///     .init()
+///     .expect("Could not connect to daemon!");
+/// let app = AppBuilder::from_config(&config)
+///     .expect("Invalid New Relic configuration")
+///     .build()
+///     .expect("Unable to create app");
+/// # }
+/// ```
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(any(feature = "toml", feature = "yaml"), derive(Deserialize))]
+#[cfg_attr(
+    any(feature = "toml", feature = "yaml"),
+    serde(default, deny_unknown_fields)
+)]
+pub struct Config {
+    pub(crate) enabled: Option<bool>,
+    #[cfg_attr(
+        any(feature = "toml", feature = "yaml"),
+        serde(deserialize_with = "backend")
+    )]
+    pub(crate) backend: Option<BackendKind>,
+    pub(crate) app_name: Option<String>,
+    pub(crate) license_key: Option<String>,
+    pub(crate) redirect_collector: Option<String>,
+    pub(crate) high_security: Option<bool>,
+    pub(crate) labels: Option<BTreeMap<String, String>>,
+    pub(crate) process_host: ProcessHostConfig,
+    pub(crate) daemon: DaemonConfig,
+    pub(crate) log: LogConfig,
+    pub(crate) transaction_tracer: TransactionTracerConfig,
+    pub(crate) datastore_tracer: DatastoreTracerConfig,
+    pub(crate) span_events: SpanEventsConfig,
+    pub(crate) distributed_tracing: DistributedTracingConfig,
+    pub(crate) attributes: AttributesConfig,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(any(feature = "toml", feature = "yaml"), derive(Deserialize))]
+#[cfg_attr(
+    any(feature = "toml", feature = "yaml"),
+    serde(default, deny_unknown_fields)
+)]
+pub(crate) struct ProcessHostConfig {
+    pub(crate) display_name: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(any(feature = "toml", feature = "yaml"), derive(Deserialize))]
+#[cfg_attr(
+    any(feature = "toml", feature = "yaml"),
+    serde(default, deny_unknown_fields)
+)]
+pub(crate) struct DaemonConfig {
+    pub(crate) socket: Option<String>,
+    #[cfg_attr(
+        any(feature = "toml", feature = "yaml"),
+        serde(deserialize_with = "seconds")
+    )]
+    pub(crate) timeout: Option<Duration>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(any(feature = "toml", feature = "yaml"), derive(Deserialize))]
+#[cfg_attr(
+    any(feature = "toml", feature = "yaml"),
+    serde(default, deny_unknown_fields)
+)]
+pub(crate) struct LogConfig {
+    #[cfg_attr(
+        any(feature = "toml", feature = "yaml"),
+        serde(deserialize_with = "log_level")
+    )]
+    pub(crate) level: Option<log::Level>,
+    pub(crate) output: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(any(feature = "toml", feature = "yaml"), derive(Deserialize))]
+#[cfg_attr(
+    any(feature = "toml", feature = "yaml"),
+    serde(default, deny_unknown_fields)
+)]
+pub(crate) struct TransactionTracerConfig {
+    pub(crate) enabled: Option<bool>,
+    #[cfg_attr(
+        any(feature = "toml", feature = "yaml"),
+        serde(deserialize_with = "threshold")
+    )]
+    pub(crate) transaction_threshold: Option<TracingThreshold>,
+    #[cfg_attr(
+        any(feature = "toml", feature = "yaml"),
+        serde(deserialize_with = "seconds")
+    )]
+    pub(crate) stack_trace_threshold: Option<Duration>,
+    pub(crate) datastore_reporting: DatastoreReportingConfig,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(any(feature = "toml", feature = "yaml"), derive(Deserialize))]
+#[cfg_attr(
+    any(feature = "toml", feature = "yaml"),
+    serde(default, deny_unknown_fields)
+)]
+pub(crate) struct DatastoreReportingConfig {
+    pub(crate) enabled: Option<bool>,
+    #[cfg_attr(
+        any(feature = "toml", feature = "yaml"),
+        serde(deserialize_with = "seconds")
+    )]
+    pub(crate) threshold: Option<Duration>,
+    #[cfg_attr(
+        any(feature = "toml", feature = "yaml"),
+        serde(deserialize_with = "record_sql")
+    )]
+    pub(crate) record_sql: Option<RecordSQL>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(any(feature = "toml", feature = "yaml"), derive(Deserialize))]
+#[cfg_attr(
+    any(feature = "toml", feature = "yaml"),
+    serde(default, deny_unknown_fields)
+)]
+pub(crate) struct DatastoreTracerConfig {
+    pub(crate) database_name_reporting: Option<bool>,
+    pub(crate) instance_reporting: Option<bool>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(any(feature = "toml", feature = "yaml"), derive(Deserialize))]
+#[cfg_attr(
+    any(feature = "toml", feature = "yaml"),
+    serde(default, deny_unknown_fields)
+)]
+pub(crate) struct SpanEventsConfig {
+    pub(crate) enabled: Option<bool>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(any(feature = "toml", feature = "yaml"), derive(Deserialize))]
+#[cfg_attr(
+    any(feature = "toml", feature = "yaml"),
+    serde(default, deny_unknown_fields)
+)]
+pub(crate) struct DistributedTracingConfig {
+    pub(crate) enabled: Option<bool>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(any(feature = "toml", feature = "yaml"), derive(Deserialize))]
+#[cfg_attr(
+    any(feature = "toml", feature = "yaml"),
+    serde(default, deny_unknown_fields)
+)]
+pub(crate) struct AttributesConfig {
+    pub(crate) include: Option<Vec<String>>,
+    pub(crate) exclude: Option<Vec<String>>,
+}
+
+impl Config {
+    /// Read configuration from the standard `NEW_RELIC_*` environment
+    /// variables.
+    ///
+    /// See `AppBuilder::from_env` and `NewRelicConfig::from_env` for the
+    /// variables used. Unlike those methods, this does not require
+    /// `NEW_RELIC_APP_NAME` or `NEW_RELIC_LICENSE_KEY` to be set, so that
+    /// they can be provided by another source.
+    ///
+    /// Returns `Error::EnvVarError`, naming the offending variable, if any
+    /// variable has an invalid value.
+    pub fn from_env() -> Result<Self> {
+        Self::from_vars(&env::Vars::process())
+    }
+
+    /// Read configuration from the given environment variables.
+    pub(crate) fn from_vars<F>(vars: &env::Vars<F>) -> Result<Self>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        Ok(Config {
+            enabled: vars.bool(env::ENABLED)?,
+            backend: vars.parse(
+                env::BACKEND,
+                "one of 'c-sdk', 'pure-rust' or 'otlp'",
+                env::parse_backend,
+            )?,
+            app_name: vars.var(env::APP_NAME),
+            license_key: vars.var(env::LICENSE_KEY),
+            redirect_collector: vars.var(env::HOST),
+            high_security: vars.bool(env::HIGH_SECURITY)?,
+            labels: vars.parse(
+                env::LABELS,
+                "semicolon-separated 'name:value' pairs",
+                env::parse_labels,
+            )?,
+            process_host: ProcessHostConfig {
+                display_name: vars.var(env::PROCESS_HOST_DISPLAY_NAME),
+            },
+            daemon: DaemonConfig {
+                socket: vars.var(env::DAEMON_SOCKET),
+                timeout: vars.duration(env::DAEMON_TIMEOUT)?,
+            },
+            log: LogConfig {
+                level: vars.parse(
+                    env::LOG_LEVEL,
+                    "one of 'error', 'warning', 'info' or 'debug'",
+                    env::parse_log_level,
+                )?,
+                output: vars.var(env::LOG).map(PathBuf::from),
+            },
+            transaction_tracer: TransactionTracerConfig {
+                enabled: vars.bool(env::TRANSACTION_TRACER_ENABLED)?,
+                transaction_threshold: vars.parse(
+                    env::TRANSACTION_TRACER_THRESHOLD,
+                    "'apdex_f' or a non-negative number of seconds",
+                    env::parse_threshold,
+                )?,
+                stack_trace_threshold: vars
+                    .duration(env::TRANSACTION_TRACER_STACK_TRACE_THRESHOLD)?,
+                datastore_reporting: DatastoreReportingConfig {
+                    enabled: vars.bool(env::DATASTORE_REPORTING_ENABLED)?,
+                    threshold: vars.duration(env::DATASTORE_REPORTING_THRESHOLD)?,
+                    record_sql: vars.parse(
+                        env::RECORD_SQL,
+                        "one of 'off', 'raw' or 'obfuscated'",
+                        env::parse_record_sql,
+                    )?,
+                },
+            },
+            datastore_tracer: DatastoreTracerConfig {
+                database_name_reporting: vars.bool(env::DATABASE_NAME_REPORTING_ENABLED)?,
+                instance_reporting: vars.bool(env::INSTANCE_REPORTING_ENABLED)?,
+            },
+            span_events: SpanEventsConfig {
+                enabled: vars.bool(env::SPAN_EVENTS_ENABLED)?,
+            },
+            distributed_tracing: DistributedTracingConfig {
+                enabled: vars.bool(env::DISTRIBUTED_TRACING_ENABLED)?,
+            },
+            attributes: AttributesConfig {
+                include: vars.list(env::ATTRIBUTES_INCLUDE),
+                exclude: vars.list(env::ATTRIBUTES_EXCLUDE),
+            },
+        })
+    }
+
+    /// Whether the agent is enabled.
+    ///
+    /// The agent is enabled unless explicitly disabled, using `enabled =
+    /// false` in a configuration file or `NEW_RELIC_ENABLED=false`. Apps
+    /// built from a disabled config never call into the New Relic SDK; see
+    /// `AppBuilder::disabled`.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.unwrap_or(true)
+    }
+
+    /// Parse configuration from a TOML string.
+    #[cfg(feature = "toml")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "toml")))]
+    pub fn from_toml_str(s: &str) -> Result<Self> {
+        toml::from_str(s).map_err(|e| Error::InvalidConfig(e.to_string()))
+    }
+
+    /// Parse configuration from a YAML string.
+    #[cfg(feature = "yaml")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "yaml")))]
+    pub fn from_yaml_str(s: &str) -> Result<Self> {
+        serde_yaml::from_str(s).map_err(|e| Error::InvalidConfig(e.to_string()))
+    }
+
+    /// Read configuration from a file.
+    ///
+    /// The format is chosen using the file extension: `.toml` for TOML,
+    /// or `.yml` / `.yaml` for YAML. Each format requires the feature of
+    /// the same name.
+    ///
+    /// This does not read any environment variables; use `Config::load`
+    /// to allow environment variables to override the file.
+    #[cfg(any(feature = "toml", feature = "yaml"))]
+    #[cfg_attr(docsrs, doc(cfg(any(feature = "toml", feature = "yaml"))))]
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let invalid =
+            |reason: String| Error::InvalidConfig(format!("{}: {}", path.display(), reason));
+        let contents = fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        let config = match extension {
+            #[cfg(feature = "toml")]
+            "toml" => Self::from_toml_str(&contents),
+            #[cfg(feature = "yaml")]
+            "yml" | "yaml" => Self::from_yaml_str(&contents),
+            _ => {
+                return Err(invalid(format!(
+                    "unsupported configuration file extension '{}'",
+                    extension
+                )))
+            }
+        };
+        config.map_err(|e| match e {
+            Error::InvalidConfig(reason) => invalid(reason),
+            e => e,
+        })
+    }
+
+    /// Read configuration from a file, then apply any overrides from the
+    /// standard `NEW_RELIC_*` environment variables.
+    ///
+    /// This is equivalent to
+    /// `Config::from_file(path)?.merge(Config::from_env()?)`.
+    #[cfg(any(feature = "toml", feature = "yaml"))]
+    #[cfg_attr(docsrs, doc(cfg(any(feature = "toml", feature = "yaml"))))]
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Self::load_with(path, &env::Vars::process())
+    }
+
+    /// Read configuration from a file, then apply any overrides from the
+    /// given environment variables.
+    #[cfg(any(feature = "toml", feature = "yaml"))]
+    pub(crate) fn load_with<F>(path: impl AsRef<Path>, vars: &env::Vars<F>) -> Result<Self>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        Ok(Self::from_file(path)?.merge(Self::from_vars(vars)?))
+    }
+
+    /// Combine two configs, with any settings present in `overrides`
+    /// taking precedence over those in `self`.
+    pub fn merge(self, overrides: Config) -> Self {
+        let tt = overrides.transaction_tracer;
+        let dr = tt.datastore_reporting;
+        Config {
+            enabled: overrides.enabled.or(self.enabled),
+            backend: overrides.backend.or(self.backend),
+            app_name: overrides.app_name.or(self.app_name),
+            license_key: overrides.license_key.or(self.license_key),
+            redirect_collector: overrides.redirect_collector.or(self.redirect_collector),
+            high_security: overrides.high_security.or(self.high_security),
+            labels: overrides.labels.or(self.labels),
+            process_host: ProcessHostConfig {
+                display_name: overrides
+                    .process_host
+                    .display_name
+                    .or(self.process_host.display_name),
+            },
+            daemon: DaemonConfig {
+                socket: overrides.daemon.socket.or(self.daemon.socket),
+                timeout: overrides.daemon.timeout.or(self.daemon.timeout),
+            },
+            log: LogConfig {
+                level: overrides.log.level.or(self.log.level),
+                output: overrides.log.output.or(self.log.output),
+            },
+            transaction_tracer: TransactionTracerConfig {
+                enabled: tt.enabled.or(self.transaction_tracer.enabled),
+                transaction_threshold: tt
+                    .transaction_threshold
+                    .or(self.transaction_tracer.transaction_threshold),
+                stack_trace_threshold: tt
+                    .stack_trace_threshold
+                    .or(self.transaction_tracer.stack_trace_threshold),
+                datastore_reporting: DatastoreReportingConfig {
+                    enabled: dr
+                        .enabled
+                        .or(self.transaction_tracer.datastore_reporting.enabled),
+                    threshold: dr
+                        .threshold
+                        .or(self.transaction_tracer.datastore_reporting.threshold),
+                    record_sql: dr
+                        .record_sql
+                        .or(self.transaction_tracer.datastore_reporting.record_sql),
+                },
+            },
+            datastore_tracer: DatastoreTracerConfig {
+                database_name_reporting: overrides
+                    .datastore_tracer
+                    .database_name_reporting
+                    .or(self.datastore_tracer.database_name_reporting),
+                instance_reporting: overrides
+                    .datastore_tracer
+                    .instance_reporting
+                    .or(self.datastore_tracer.instance_reporting),
+            },
+            span_events: SpanEventsConfig {
+                enabled: overrides.span_events.enabled.or(self.span_events.enabled),
+            },
+            distributed_tracing: DistributedTracingConfig {
+                enabled: overrides
+                    .distributed_tracing
+                    .enabled
+                    .or(self.distributed_tracing.enabled),
+            },
+            attributes: AttributesConfig {
+                include: overrides.attributes.include.or(self.attributes.include),
+                exclude: overrides.attributes.exclude.or(self.attributes.exclude),
+            },
+        }
+    }
+}
+
+/// Deserialize an optional value using one of the parsers shared with the
+/// environment variable configuration.
+#[cfg(any(feature = "toml", feature = "yaml"))]
+fn parsed<'de, D, T>(
+    deserializer: D,
+    expected: &str,
+    parser: impl FnOnce(&str) -> Option<T>,
+) -> std::result::Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Value {
+        Number(f64),
+        String(String),
+    }
+
+    let value = match Value::deserialize(deserializer)? {
+        Value::Number(n) => n.to_string(),
+        Value::String(s) => s,
+    };
+    parser(value.trim())
+        .map(Some)
+        .ok_or_else(|| de::Error::custom(format!("expected {}, got '{}'", expected, value)))
+}
+
+#[cfg(any(feature = "toml", feature = "yaml"))]
+fn seconds<'de, D>(deserializer: D) -> std::result::Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    parsed(
+        deserializer,
+        "a non-negative number of seconds",
+        env::parse_seconds,
+    )
+}
+
+#[cfg(any(feature = "toml", feature = "yaml"))]
+fn threshold<'de, D>(deserializer: D) -> std::result::Result<Option<TracingThreshold>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    parsed(
+        deserializer,
+        "'apdex_f' or a non-negative number of seconds",
+        env::parse_threshold,
+    )
+}
+
+#[cfg(any(feature = "toml", feature = "yaml"))]
+fn record_sql<'de, D>(deserializer: D) -> std::result::Result<Option<RecordSQL>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    parsed(
+        deserializer,
+        "one of 'off', 'raw' or 'obfuscated'",
+        env::parse_record_sql,
+    )
+}
+
+#[cfg(any(feature = "toml", feature = "yaml"))]
+fn backend<'de, D>(deserializer: D) -> std::result::Result<Option<BackendKind>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    parsed(
+        deserializer,
+        "one of 'c-sdk', 'pure-rust' or 'otlp'",
+        env::parse_backend,
+    )
+}
+
+#[cfg(any(feature = "toml", feature = "yaml"))]
+fn log_level<'de, D>(deserializer: D) -> std::result::Result<Option<log::Level>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    parsed(
+        deserializer,
+        "one of 'error', 'warning', 'info' or 'debug'",
+        env::parse_log_level,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(app_name: Option<&str>, timeout: Option<u64>) -> Config {
+        Config {
+            app_name: app_name.map(String::from),
+            daemon: DaemonConfig {
+                timeout: timeout.map(Duration::from_secs),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn merge_prefers_overrides() {
+        let merged = config(Some("file"), Some(1)).merge(config(Some("env"), Some(2)));
+        assert_eq!(merged, config(Some("env"), Some(2)));
+    }
+
+    #[test]
+    fn merge_keeps_settings_missing_from_overrides() {
+        let merged = config(Some("file"), Some(1)).merge(config(None, Some(2)));
+        assert_eq!(merged, config(Some("file"), Some(2)));
+        let merged = config(Some("file"), Some(1)).merge(Config::default());
+        assert_eq!(merged, config(Some("file"), Some(1)));
+    }
+
+    #[test]
+    fn enabled_by_default() {
+        assert!(Config::default().is_enabled());
+        let disabled = Config {
+            enabled: Some(false),
+            ..Default::default()
+        };
+        assert!(!disabled.is_enabled());
+        assert!(disabled.clone().merge(Config::default()).enabled == Some(false));
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn toml_values() {
+        let config = Config::from_toml_str(
+            r#"
+            app_name = "my app"
+            backend = "pure-rust"
+
+            [daemon]
+            timeout = 0.25
+
+            [transaction_tracer]
+            transaction_threshold = "apdex_f"
+            stack_trace_threshold = 2
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.app_name.as_deref(), Some("my app"));
+        assert_eq!(config.backend, Some(BackendKind::PureRust));
+        assert_eq!(config.daemon.timeout, Some(Duration::from_millis(250)));
+        assert_eq!(
+            config.transaction_tracer.transaction_threshold,
+            Some(TracingThreshold::ApdexFailing)
+        );
+        assert_eq!(
+            config.transaction_tracer.stack_trace_threshold,
+            Some(Duration::from_secs(2))
+        );
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn toml_rejects_invalid_values() {
+        assert!(Config::from_toml_str("[daemon]\ntimeout = -1").is_err());
+        assert!(Config::from_toml_str("[daemon]\ntimeout = 1e300").is_err());
+        assert!(Config::from_toml_str("unknown = true").is_err());
+        assert!(Config::from_toml_str("backend = \"daemon\"").is_err());
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn load_prefers_env_over_file() {
+        let path = std::env::temp_dir().join(format!("newrelic-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            "app_name = \"file\"\nlicense_key = \"file key\"\n\n[daemon]\ntimeout = 1",
+        )
+        .unwrap();
+        let vars = env::Vars::new(|name| match name {
+            env::APP_NAME => Some("env".to_string()),
+            env::DAEMON_TIMEOUT => Some("2.5".to_string()),
+            _ => None,
+        });
+        let loaded = Config::load_with(&path, &vars);
+        fs::remove_file(&path).unwrap();
+
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.app_name.as_deref(), Some("env"));
+        assert_eq!(loaded.license_key.as_deref(), Some("file key"));
+        assert_eq!(loaded.daemon.timeout, Some(Duration::from_millis(2500)));
+    }
+}
@@ -0,0 +1,424 @@
+/*!
+The current transaction and segment.
+
+Entering a transaction makes it the current transaction of the thread, or,
+using [`with_transaction`], of an async task. Code deep within an
+application, such as libraries, can then create segments nested within the
+current segment using [`custom_segment`], [`datastore_segment`] and
+[`external_segment`], without a `&Transaction` being passed through every
+call.
+
+If there is no current transaction, these functions return `None`, so
+instrumented libraries work unchanged when called outside a transaction.
+
+Contexts nest: entering a transaction or segment replaces the current one
+until the returned [`ContextGuard`] is dropped, or the future passed to
+[`with_transaction`] or [`with_segment`] returns `Poll::Ready`.
+
+Example:
+
+```rust
+use newrelic::{context, AppBuilder, SharedTransaction};
+
+fn load_user() {
+    // No transaction parameter needed.
+    let _segment = context::custom_segment("load_user", "Database");
+}
+
+let app = AppBuilder::disabled().build().expect("Unable to create app");
+let transaction: SharedTransaction = app
+    .web_transaction("GET /users")
+    .expect("Could not start transaction")
+    .into();
+
+let guard = context::enter(transaction);
+assert!(context::current_transaction().is_some());
+load_user();
+
+drop(guard);
+assert!(context::current_transaction().is_none());
+assert!(context::custom_segment("load_user", "Database").is_none());
+```
+
+With the `async` feature, [`with_transaction`] and [`with_segment`] set the
+context of an async task instead.
+*/
+use std::{cell::RefCell, marker::PhantomData, sync::Arc};
+#[cfg(feature = "async")]
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+#[cfg(feature = "async")]
+use pin_project::pin_project;
+
+use crate::{
+    error::Result,
+    segment::{DatastoreParams, ExternalParams},
+    shared::{SharedSegment, SharedTransaction},
+};
+
+/// The current transaction, and the innermost segment entered within it.
+#[derive(Clone)]
+struct Current {
+    transaction: SharedTransaction,
+    segment: Option<Arc<SharedSegment>>,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Current>> = const { RefCell::new(None) };
+}
+
+/// Replace the current context of this thread, returning the previous one.
+fn replace(current: Option<Current>) -> Option<Current> {
+    CURRENT.with(|cell| cell.replace(current))
+}
+
+/// Read the current context of this thread.
+fn with_current<V>(func: impl FnOnce(Option<&Current>) -> V) -> V {
+    CURRENT.with(|cell| func(cell.borrow().as_ref()))
+}
+
+/// A guard which restores the previous context of the thread when dropped.
+///
+/// Returned by [`enter`] and [`enter_segment`]. Guards must be dropped in
+/// the reverse order to that in which they were created, which happens
+/// naturally when they are held in local variables. A guard can't be sent
+/// to another thread.
+#[must_use = "the context is left as soon as the guard is dropped"]
+pub struct ContextGuard {
+    previous: Option<Current>,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl ContextGuard {
+    fn new(current: Option<Current>) -> Self {
+        ContextGuard {
+            previous: replace(current),
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        replace(self.previous.take());
+    }
+}
+
+/// Make `transaction` the current transaction of this thread, with no
+/// current segment, until the returned guard is dropped.
+pub fn enter(transaction: SharedTransaction) -> ContextGuard {
+    ContextGuard::new(Some(Current {
+        transaction,
+        segment: None,
+    }))
+}
+
+/// Make `segment` the current segment of this thread, and its transaction
+/// the current transaction, until the returned guard is dropped.
+///
+/// The segment ends once the guard has been dropped and no other handles
+/// to it, returned by [`current_segment`], remain.
+pub fn enter_segment(segment: SharedSegment) -> ContextGuard {
+    ContextGuard::new(Some(Current {
+        transaction: segment.transaction().clone(),
+        segment: Some(Arc::new(segment)),
+    }))
+}
+
+/// The current transaction, if any.
+pub fn current_transaction() -> Option<SharedTransaction> {
+    with_current(|current| current.map(|current| current.transaction.clone()))
+}
+
+/// The current segment, if any.
+///
+/// This is the innermost segment entered using [`enter_segment`] or
+/// [`with_segment`], and is `None` if the current transaction was entered
+/// without a segment. Holding the returned handle keeps the segment from
+/// ending.
+pub fn current_segment() -> Option<Arc<SharedSegment>> {
+    with_current(|current| current.and_then(|current| current.segment.clone()))
+}
+
+/// Create a segment nested within the current segment, or at the top level
+/// of the current transaction if there is no current segment.
+fn create_segment(
+    nested: impl FnOnce(&SharedSegment) -> Result<SharedSegment>,
+    top_level: impl FnOnce(&SharedTransaction) -> SharedSegment,
+) -> Option<SharedSegment> {
+    let current = with_current(|current| current.cloned())?;
+    Some(match &current.segment {
+        Some(segment) => {
+            nested(segment).unwrap_or_else(|_| SharedSegment::noop(current.transaction))
+        }
+        None => top_level(&current.transaction),
+    })
+}
+
+/// Create a custom segment within the current segment or transaction.
+///
+/// Returns `None` if there is no current transaction. As with
+/// `SharedTransaction::create_custom_segment`, the segment silently does
+/// nothing if it couldn't be started.
+///
+/// The new segment doesn't become the current segment; use
+/// [`enter_segment`] or [`with_segment`] to nest further segments within
+/// it.
+pub fn custom_segment(name: &str, category: &str) -> Option<SharedSegment> {
+    create_segment(
+        |segment| segment.create_custom_nested(name, category),
+        |transaction| transaction.create_custom_segment(name, category),
+    )
+}
+
+/// Create a datastore segment within the current segment or transaction.
+///
+/// See [`custom_segment`] for details.
+pub fn datastore_segment(params: &DatastoreParams) -> Option<SharedSegment> {
+    create_segment(
+        |segment| segment.create_datastore_nested(params),
+        |transaction| transaction.create_datastore_segment(params),
+    )
+}
+
+/// Create an external segment within the current segment or transaction.
+///
+/// See [`custom_segment`] for details.
+pub fn external_segment(params: &ExternalParams) -> Option<SharedSegment> {
+    create_segment(
+        |segment| segment.create_external_nested(params),
+        |transaction| transaction.create_external_segment(params),
+    )
+}
+
+/// Make `transaction` the current transaction whenever `future` is polled.
+///
+/// This behaves like a task-local variable on any async runtime, including
+/// tokio, as the context moves with the future between threads.
+///
+/// Example:
+///
+/// ```rust
+/// # #[tokio::main]
+/// # async fn main() {
+/// use newrelic::{context, AppBuilder, SharedTransaction};
+///
+/// async fn load_user() {
+///     let segment = context::custom_segment("load_user", "Database");
+///     assert!(segment.is_some());
+/// }
+///
+/// let app = AppBuilder::disabled().build().expect("Unable to create app");
+/// let transaction: SharedTransaction = app
+///     .web_transaction("GET /users")
+///     .expect("Could not start transaction")
+///     .into();
+///
+/// tokio::spawn(context::with_transaction(transaction, load_user()))
+///     .await
+///     .unwrap();
+/// assert!(context::current_transaction().is_none());
+/// # }
+/// ```
+#[cfg(feature = "async")]
+#[cfg_attr(docsrs, doc(cfg(feature = "async")))]
+pub fn with_transaction<F: Future>(transaction: SharedTransaction, future: F) -> WithContext<F> {
+    WithContext {
+        inner: future,
+        context: Some(Current {
+            transaction,
+            segment: None,
+        }),
+    }
+}
+
+/// Make `segment` the current segment, and its transaction the current
+/// transaction, whenever `future` is polled.
+///
+/// The segment ends when the future completes, unless handles returned by
+/// [`current_segment`] remain.
+#[cfg(feature = "async")]
+#[cfg_attr(docsrs, doc(cfg(feature = "async")))]
+pub fn with_segment<F: Future>(segment: SharedSegment, future: F) -> WithContext<F> {
+    WithContext {
+        inner: future,
+        context: Some(Current {
+            transaction: segment.transaction().clone(),
+            segment: Some(Arc::new(segment)),
+        }),
+    }
+}
+
+/// A future which is polled within a transaction or segment's context.
+///
+/// Create this using [`with_transaction`] or [`with_segment`]. It is
+/// `'static`, and `Send` if the inner future is.
+#[pin_project]
+#[cfg(feature = "async")]
+#[cfg_attr(docsrs, doc(cfg(feature = "async")))]
+pub struct WithContext<F> {
+    #[pin]
+    inner: F,
+
+    context: Option<Current>,
+}
+
+#[cfg(feature = "async")]
+impl<F: Future> Future for WithContext<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let result = {
+            let _guard = ContextGuard::new(this.context.clone());
+            this.inner.poll(cx)
+        };
+
+        if result.is_ready() {
+            // Drop the context, ending the segment
+            *this.context = None;
+        }
+
+        result
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use super::*;
+    use crate::testing::RecordingApp;
+
+    fn start(app: &RecordingApp, name: &str) -> SharedTransaction {
+        app.web_transaction(name).unwrap().into()
+    }
+
+    /// Whether `transaction` is the current transaction.
+    fn is_current(transaction: &SharedTransaction) -> bool {
+        current_transaction()
+            .is_some_and(|current| std::ptr::eq(current.as_ref(), transaction.as_ref()))
+    }
+
+    #[test]
+    fn no_context_by_default() {
+        assert!(current_transaction().is_none());
+        assert!(current_segment().is_none());
+        assert!(custom_segment("segment", "test").is_none());
+    }
+
+    #[test]
+    fn segments_nest_under_the_current_segment() {
+        let app = RecordingApp::new();
+        {
+            let transaction = start(&app, "nested");
+            let _transaction_guard = enter(transaction.clone());
+            drop(custom_segment("top", "test").unwrap());
+            let outer = transaction.create_custom_segment("outer", "test");
+            let _segment_guard = enter_segment(outer);
+            assert!(current_segment().is_some());
+            drop(custom_segment("inner", "test").unwrap());
+        }
+
+        let recorded = app.transaction("nested").unwrap();
+        assert!(recorded.ended);
+        let names: Vec<_> = recorded.segments.iter().map(|s| s.name()).collect();
+        assert_eq!(names, vec![Some("top"), Some("outer")]);
+        let inner: Vec<_> = recorded.segments[1]
+            .children
+            .iter()
+            .map(|s| s.name())
+            .collect();
+        assert_eq!(inner, vec![Some("inner")]);
+    }
+
+    #[test]
+    fn guards_restore_the_previous_context() {
+        let app = RecordingApp::new();
+        let first = start(&app, "first");
+        let second = start(&app, "second");
+        {
+            let _first = enter(first.clone());
+            assert!(is_current(&first));
+            {
+                let _second = enter(second.clone());
+                assert!(is_current(&second));
+                {
+                    let _segment = enter_segment(first.create_custom_segment("segment", "test"));
+                    assert!(is_current(&first));
+                    assert!(current_segment().is_some());
+                }
+                assert!(is_current(&second));
+                assert!(current_segment().is_none());
+            }
+            assert!(is_current(&first));
+        }
+        assert!(current_transaction().is_none());
+
+        // The segment ended when its guard was dropped.
+        drop(first);
+        let recorded = app.transaction("first").unwrap();
+        assert!(recorded.ended);
+        assert!(recorded.segments[0].ended);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn futures_are_polled_in_context() {
+        use std::task::{RawWaker, RawWakerVTable, Waker};
+
+        fn noop_raw_waker() -> RawWaker {
+            fn clone(_: *const ()) -> RawWaker {
+                noop_raw_waker()
+            }
+            fn noop(_: *const ()) {}
+            static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+            RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+
+        /// A future which records whether there is a current segment each
+        /// time it's polled, and is ready on its third poll.
+        struct Observe(Vec<bool>);
+
+        impl Future for Observe {
+            type Output = Vec<bool>;
+
+            fn poll(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Vec<bool>> {
+                let observed = current_segment().is_some();
+                self.0.push(observed);
+                if self.0.len() == 3 {
+                    Poll::Ready(self.0.clone())
+                } else {
+                    Poll::Pending
+                }
+            }
+        }
+
+        let app = RecordingApp::new();
+        let transaction = start(&app, "async");
+        let segment = transaction.create_custom_segment("segment", "test");
+        let mut future = Box::pin(with_segment(segment, Observe(Vec::new())));
+        let waker = unsafe { Waker::from_raw(noop_raw_waker()) };
+        let mut cx = Context::from_waker(&waker);
+
+        assert!(future.as_mut().poll(&mut cx).is_pending());
+        assert!(current_transaction().is_none());
+        assert!(future.as_mut().poll(&mut cx).is_pending());
+        assert!(current_transaction().is_none());
+        let observed = match future.as_mut().poll(&mut cx) {
+            Poll::Ready(observed) => observed,
+            Poll::Pending => panic!("future should be ready"),
+        };
+        assert_eq!(observed, vec![true, true, true]);
+        assert!(current_transaction().is_none());
+
+        // The context, and with it the segment, was dropped once the future
+        // was ready.
+        drop(transaction);
+        let recorded = app.transaction("async").unwrap();
+        assert!(recorded.ended);
+        assert!(recorded.segments[0].ended);
+    }
+}
@@ -0,0 +1,351 @@
+use std::{collections::BTreeMap, convert::TryFrom, env, str::FromStr, time::Duration};
+
+use crate::{
+    app::{BackendKind, RecordSQL, TracingThreshold},
+    error::{Error, Result},
+};
+
+pub(crate) const ENABLED: &str = "NEW_RELIC_ENABLED";
+pub(crate) const BACKEND: &str = "NEW_RELIC_BACKEND";
+pub(crate) const APP_NAME: &str = "NEW_RELIC_APP_NAME";
+pub(crate) const LICENSE_KEY: &str = "NEW_RELIC_LICENSE_KEY";
+pub(crate) const HOST: &str = "NEW_RELIC_HOST";
+pub(crate) const LABELS: &str = "NEW_RELIC_LABELS";
+pub(crate) const PROCESS_HOST_DISPLAY_NAME: &str = "NEW_RELIC_PROCESS_HOST_DISPLAY_NAME";
+pub(crate) const HIGH_SECURITY: &str = "NEW_RELIC_HIGH_SECURITY";
+pub(crate) const DAEMON_SOCKET: &str = "NEW_RELIC_DAEMON_SOCKET";
+pub(crate) const DAEMON_TIMEOUT: &str = "NEW_RELIC_DAEMON_TIMEOUT";
+pub(crate) const LOG: &str = "NEW_RELIC_LOG";
+pub(crate) const LOG_LEVEL: &str = "NEW_RELIC_LOG_LEVEL";
+pub(crate) const TRANSACTION_TRACER_ENABLED: &str = "NEW_RELIC_TRANSACTION_TRACER_ENABLED";
+pub(crate) const TRANSACTION_TRACER_THRESHOLD: &str =
+    "NEW_RELIC_TRANSACTION_TRACER_TRANSACTION_THRESHOLD";
+pub(crate) const TRANSACTION_TRACER_STACK_TRACE_THRESHOLD: &str =
+    "NEW_RELIC_TRANSACTION_TRACER_STACK_TRACE_THRESHOLD";
+pub(crate) const DATASTORE_REPORTING_ENABLED: &str =
+    "NEW_RELIC_TRANSACTION_TRACER_DATASTORE_REPORTING_ENABLED";
+pub(crate) const DATASTORE_REPORTING_THRESHOLD: &str =
+    "NEW_RELIC_TRANSACTION_TRACER_DATASTORE_REPORTING_THRESHOLD";
+pub(crate) const RECORD_SQL: &str = "NEW_RELIC_TRANSACTION_TRACER_RECORD_SQL";
+pub(crate) const DATABASE_NAME_REPORTING_ENABLED: &str =
+    "NEW_RELIC_DATASTORE_TRACER_DATABASE_NAME_REPORTING_ENABLED";
+pub(crate) const INSTANCE_REPORTING_ENABLED: &str =
+    "NEW_RELIC_DATASTORE_TRACER_INSTANCE_REPORTING_ENABLED";
+pub(crate) const SPAN_EVENTS_ENABLED: &str = "NEW_RELIC_SPAN_EVENTS_ENABLED";
+pub(crate) const DISTRIBUTED_TRACING_ENABLED: &str = "NEW_RELIC_DISTRIBUTED_TRACING_ENABLED";
+pub(crate) const ATTRIBUTES_INCLUDE: &str = "NEW_RELIC_ATTRIBUTES_INCLUDE";
+pub(crate) const ATTRIBUTES_EXCLUDE: &str = "NEW_RELIC_ATTRIBUTES_EXCLUDE";
+
+fn invalid(name: &'static str, reason: impl Into<String>) -> Error {
+    Error::EnvVarError {
+        name,
+        reason: reason.into(),
+    }
+}
+
+/// The environment variables configuration is read from.
+///
+/// `lookup` returns the value of a variable, or `None` if it isn't set.
+/// Apps read the process environment using `Vars::process`; tests use a
+/// fixed set of variables instead, so that they neither depend on nor
+/// change the environment of other tests.
+pub(crate) struct Vars<F> {
+    lookup: F,
+}
+
+impl Vars<fn(&str) -> Option<String>> {
+    /// The variables of this process. Values which aren't valid Unicode
+    /// are read lossily.
+    pub(crate) fn process() -> Self {
+        Vars::new(|name| env::var_os(name).map(|value| value.to_string_lossy().into_owned()))
+    }
+}
+
+impl<F> Vars<F>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    pub(crate) fn new(lookup: F) -> Self {
+        Vars { lookup }
+    }
+
+    /// Read a variable, treating an empty value as unset.
+    pub(crate) fn var(&self, name: &'static str) -> Option<String> {
+        (self.lookup)(name).filter(|value| !value.trim().is_empty())
+    }
+
+    /// Read a variable which must be set.
+    pub(crate) fn required(&self, name: &'static str) -> Result<String> {
+        self.var(name)
+            .ok_or_else(|| invalid(name, "required but not set"))
+    }
+
+    /// Read and parse a variable using the given parser.
+    ///
+    /// The parser returns `None` if the value is invalid; `expected` is
+    /// used to describe valid values in the resulting error.
+    pub(crate) fn parse<T>(
+        &self,
+        name: &'static str,
+        expected: &str,
+        parser: impl FnOnce(&str) -> Option<T>,
+    ) -> Result<Option<T>> {
+        match self.var(name) {
+            Some(value) => parser(value.trim())
+                .map(Some)
+                .ok_or_else(|| invalid(name, format!("expected {}, got '{}'", expected, value))),
+            None => Ok(None),
+        }
+    }
+
+    /// Read a boolean variable.
+    pub(crate) fn bool(&self, name: &'static str) -> Result<Option<bool>> {
+        self.parse(name, "a boolean", parse_bool)
+    }
+
+    /// Read a variable containing a duration in (possibly fractional)
+    /// seconds, as used by the other New Relic agents.
+    pub(crate) fn duration(&self, name: &'static str) -> Result<Option<Duration>> {
+        self.parse(name, "a non-negative number of seconds", parse_seconds)
+    }
+
+    /// Read a variable containing a comma-separated list, as used by the
+    /// other New Relic agents. Empty items are ignored.
+    pub(crate) fn list(&self, name: &'static str) -> Option<Vec<String>> {
+        self.var(name).as_deref().map(parse_list)
+    }
+}
+
+pub(crate) fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Parse labels given as semicolon-separated `name:value` pairs, as used by
+/// the other New Relic agents. Empty pairs are ignored.
+pub(crate) fn parse_labels(value: &str) -> Option<BTreeMap<String, String>> {
+    value
+        .split(';')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once(':')?;
+            Some((name.trim().to_string(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+pub(crate) fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "true" | "1" | "yes" | "on" => Some(true),
+        "false" | "0" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parse a duration in seconds, rejecting durations which can't be
+/// represented by the SDK in microseconds.
+pub(crate) fn parse_seconds(value: &str) -> Option<Duration> {
+    f64::from_str(value)
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .filter(|duration| u64::try_from(duration.as_micros()).is_ok())
+}
+
+pub(crate) fn parse_threshold(value: &str) -> Option<TracingThreshold> {
+    if value.eq_ignore_ascii_case("apdex_f") {
+        Some(TracingThreshold::ApdexFailing)
+    } else {
+        parse_seconds(value).map(TracingThreshold::OverDuration)
+    }
+}
+
+pub(crate) fn parse_record_sql(value: &str) -> Option<RecordSQL> {
+    match value.to_ascii_lowercase().as_str() {
+        "off" => Some(RecordSQL::Off),
+        "raw" => Some(RecordSQL::Raw),
+        "obfuscated" => Some(RecordSQL::Obfuscated),
+        _ => None,
+    }
+}
+
+pub(crate) fn parse_backend(value: &str) -> Option<BackendKind> {
+    match value.to_ascii_lowercase().as_str() {
+        "c-sdk" => Some(BackendKind::CSdk),
+        "pure-rust" => Some(BackendKind::PureRust),
+        "otlp" => Some(BackendKind::Otlp),
+        _ => None,
+    }
+}
+
+pub(crate) fn parse_log_level(value: &str) -> Option<log::Level> {
+    if value.eq_ignore_ascii_case("warning") {
+        Some(log::Level::Warn)
+    } else {
+        log::Level::from_str(value).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{app::AppBuilder, config::Config};
+
+    /// A fixed set of variables.
+    fn vars<'a>(set: &'a [(&str, &str)]) -> Vars<impl Fn(&str) -> Option<String> + 'a> {
+        Vars::new(move |name| {
+            set.iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.to_string())
+        })
+    }
+
+    #[test]
+    fn bools() {
+        for value in &["true", "TRUE", "1", "yes", "On"] {
+            assert_eq!(parse_bool(value), Some(true), "{}", value);
+        }
+        for value in &["false", "False", "0", "no", "OFF"] {
+            assert_eq!(parse_bool(value), Some(false), "{}", value);
+        }
+        assert_eq!(parse_bool("maybe"), None);
+        assert_eq!(parse_bool(""), None);
+    }
+
+    #[test]
+    fn seconds() {
+        assert_eq!(parse_seconds("0"), Some(Duration::from_secs(0)));
+        assert_eq!(parse_seconds("10"), Some(Duration::from_secs(10)));
+        assert_eq!(parse_seconds("0.5"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_seconds("-1"), None);
+        assert_eq!(parse_seconds("NaN"), None);
+        assert_eq!(parse_seconds("inf"), None);
+        assert_eq!(parse_seconds("ten"), None);
+    }
+
+    #[test]
+    fn seconds_overflowing_microseconds() {
+        // The largest number of seconds whose microseconds fit in a u64.
+        let max = u64::MAX / 1_000_000;
+        assert_eq!(
+            parse_seconds(&max.to_string()),
+            Some(Duration::from_secs(max))
+        );
+        assert_eq!(parse_seconds(&(max + 1).to_string()), None);
+        assert_eq!(parse_seconds("1e300"), None);
+    }
+
+    #[test]
+    fn thresholds() {
+        assert_eq!(
+            parse_threshold("apdex_f"),
+            Some(TracingThreshold::ApdexFailing)
+        );
+        assert_eq!(
+            parse_threshold("APDEX_F"),
+            Some(TracingThreshold::ApdexFailing)
+        );
+        assert_eq!(
+            parse_threshold("1.5"),
+            Some(TracingThreshold::OverDuration(Duration::from_millis(1500)))
+        );
+        assert_eq!(parse_threshold("apdex"), None);
+        assert_eq!(parse_threshold("-1"), None);
+    }
+
+    #[test]
+    fn record_sql() {
+        assert_eq!(parse_record_sql("Off"), Some(RecordSQL::Off));
+        assert_eq!(parse_record_sql("raw"), Some(RecordSQL::Raw));
+        assert_eq!(parse_record_sql("obfuscated"), Some(RecordSQL::Obfuscated));
+        assert_eq!(parse_record_sql("hidden"), None);
+    }
+
+    #[test]
+    fn backends() {
+        assert_eq!(parse_backend("c-sdk"), Some(BackendKind::CSdk));
+        assert_eq!(parse_backend("Pure-Rust"), Some(BackendKind::PureRust));
+        assert_eq!(parse_backend("otlp"), Some(BackendKind::Otlp));
+        assert_eq!(parse_backend("daemon"), None);
+    }
+
+    #[test]
+    fn log_levels() {
+        assert_eq!(parse_log_level("warning"), Some(log::Level::Warn));
+        assert_eq!(parse_log_level("WARN"), Some(log::Level::Warn));
+        assert_eq!(parse_log_level("debug"), Some(log::Level::Debug));
+        assert_eq!(parse_log_level("verbose"), None);
+    }
+
+    #[test]
+    fn labels() {
+        let labels = parse_labels("env:prod; team : billing;").unwrap();
+        assert_eq!(labels.len(), 2);
+        assert_eq!(labels["env"], "prod");
+        assert_eq!(labels["team"], "billing");
+        assert_eq!(parse_labels("env"), None);
+        assert_eq!(parse_labels(""), Some(BTreeMap::new()));
+    }
+
+    #[test]
+    fn lists() {
+        assert_eq!(parse_list("a, b.*,,c "), vec!["a", "b.*", "c"]);
+        assert!(parse_list(" , ").is_empty());
+    }
+
+    #[test]
+    fn empty_values_are_unset() {
+        let vars = vars(&[(APP_NAME, " "), (HOST, "collector.example.com")]);
+        assert_eq!(vars.var(APP_NAME), None);
+        assert_eq!(vars.var(LICENSE_KEY), None);
+        assert_eq!(vars.var(HOST).as_deref(), Some("collector.example.com"));
+    }
+
+    #[test]
+    fn invalid_values_name_the_variable() {
+        let invalid = [
+            (ENABLED, "maybe"),
+            (BACKEND, "daemon"),
+            (LABELS, "env"),
+            (DAEMON_TIMEOUT, "-1"),
+            (LOG_LEVEL, "verbose"),
+            (TRANSACTION_TRACER_THRESHOLD, "apdex"),
+            (RECORD_SQL, "hidden"),
+            (SPAN_EVENTS_ENABLED, "2"),
+        ];
+        for (variable, value) in invalid.iter() {
+            match Config::from_vars(&vars(&[(variable, value)])) {
+                Err(Error::EnvVarError { name, reason }) => {
+                    assert_eq!(name, *variable);
+                    assert!(reason.contains(value), "{}", reason);
+                }
+                result => panic!("{}={} gave {:?}", variable, value, result.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn app_builder_requires_name_and_license_key() {
+        let key = "0".repeat(40);
+        let missing = [
+            (APP_NAME, (LICENSE_KEY, key.as_str())),
+            (LICENSE_KEY, (APP_NAME, "app")),
+        ];
+        for (variable, set) in missing.iter() {
+            match AppBuilder::from_vars(&vars(&[*set])) {
+                Err(Error::EnvVarError { name, reason }) => {
+                    assert_eq!(name, *variable);
+                    assert_eq!(reason, "required but not set");
+                }
+                result => panic!("Missing {} gave {:?}", variable, result.map(|_| ())),
+            }
+        }
+        assert!(AppBuilder::from_vars(&vars(&[(APP_NAME, "app"), (LICENSE_KEY, &key)])).is_ok());
+        // A disabled agent needs neither.
+        assert!(AppBuilder::from_vars(&vars(&[(ENABLED, "false")])).is_ok());
+    }
+}
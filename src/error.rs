@@ -1,5 +1,6 @@
 use std::ffi::NulError;
 use std::fmt;
+use std::time::Duration;
 
 #[derive(Debug)]
 /// An error caused by the New Relic SDK.
@@ -11,11 +12,28 @@ use std::fmt;
 pub enum Error {
     /// There was an error setting a transaction attribute.
     AttributeError,
+    /// An attribute name was longer than New Relic allows.
+    AttributeNameTooLong {
+        /// The attribute name.
+        name: String,
+        /// The maximum length of a name, in bytes.
+        limit: usize,
+    },
     /// There was an error configuring the New Relic app.
     ///
     /// This is likely due to an invalid license key; check the New Relic SDK
     /// logs for more details.
     ConfigError,
+    /// The New Relic daemon didn't connect the application within the
+    /// given timeout.
+    ///
+    /// This is only returned by the `pure-rust` backend; the C SDK doesn't
+    /// say why it couldn't create an app, so returns `ConfigError` instead.
+    ///
+    /// Check that the daemon is running and that the app name and license
+    /// key are valid; the New Relic SDK and daemon logs may have more
+    /// details.
+    ConnectTimeout(Duration),
     /// The custom metric could not be created.
     CustomMetricError,
     /// There was an error connecting to the New Relic daemon.
@@ -28,13 +46,38 @@ pub enum Error {
     DaemonError,
     /// The specified duration is too long
     DurationOverFlow,
+    /// An environment variable used to configure New Relic was missing
+    /// or contained an invalid value.
+    EnvVarError {
+        /// The name of the offending environment variable.
+        name: &'static str,
+        /// A description of what was wrong with the variable.
+        reason: String,
+    },
     /// The transaction could not be ignored.
     IgnoreError,
+    /// The agent configuration was invalid or incomplete, or a
+    /// configuration file could not be read.
+    InvalidConfig(String),
+    /// The timing given to `Transaction::set_timing`, or to a segment's
+    /// `set_timing`, was invalid.
+    InvalidTiming(String),
+    /// A custom metric couldn't be created, because another metric with
+    /// the same name but a different kind or unit exists.
+    InvalidMetric(String),
     /// The provided log file contained non-unicode characters.
     LogFileError,
     /// The New Relic SDK returned an error when attempting to configure
     /// logging. Check the SDK logs for more details.
     LoggingError,
+    /// An attribute couldn't be added because its transaction or custom
+    /// event already has as many attributes as New Relic keeps.
+    TooManyAttributes {
+        /// The name of the attribute which couldn't be added.
+        name: String,
+        /// The maximum number of attributes.
+        limit: usize,
+    },
     /// The transaction could not be started.
     /// Check the New Relic SDK logs for more details.
     TransactionStartError,
@@ -43,6 +86,11 @@ pub enum Error {
     /// Check the New Relic SDK logs for more details.
     /// The segment could not be started.
     SegmentStartError,
+    /// A value passed to `add_attributes` couldn't be serialized into
+    /// attributes.
+    SerializeError(String),
+    /// The timing of a transaction or segment could not be overridden.
+    TimingError,
     /// A string parameter contained a null byte and could not be converted
     /// to a CString.
     NulError(NulError),
@@ -60,24 +108,49 @@ impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Error::AttributeError => write!(f, "Error setting attribute; {}", CHECK_NEW_RELIC_LOGS),
+            Error::AttributeNameTooLong { name, limit } => write!(
+                f,
+                "Attribute name {} is {} bytes long, but New Relic's limit is {} bytes",
+                name,
+                name.len(),
+                limit
+            ),
             Error::ConfigError => write!(
                 f,
                 "Error configuring New Relic app; {}",
                 CHECK_NEW_RELIC_LOGS
             ),
+            Error::ConnectTimeout(timeout) => write!(
+                f,
+                "Timed out after {:?} waiting for the New Relic daemon to connect the app; {}",
+                timeout, CHECK_NEW_RELIC_LOGS
+            ),
             Error::DaemonError => write!(
                 f,
                 "Error connecting to New Relic daemon; {}",
                 CHECK_NEW_RELIC_LOGS
             ),
             Error::DurationOverFlow => write!(f, "The specified duration is too long"),
+            Error::EnvVarError { name, reason } => {
+                write!(f, "Invalid environment variable {}: {}", name, reason)
+            }
             Error::CustomMetricError => {
                 write!(f, "Error recording custom metric; {}", CHECK_NEW_RELIC_LOGS)
             }
             Error::IgnoreError => write!(f, "Error ignoring transaction; {}", CHECK_NEW_RELIC_LOGS),
+            Error::InvalidConfig(reason) => {
+                write!(f, "Invalid New Relic configuration: {}", reason)
+            }
+            Error::InvalidTiming(reason) => write!(f, "Invalid timing: {}", reason),
+            Error::InvalidMetric(reason) => write!(f, "Invalid metric: {}", reason),
             Error::NulError(inner) => write!(f, "{}", inner),
             Error::LogFileError => write!(f, "Invalid log file (must be valid Unicode)"),
             Error::LoggingError => write!(f, "Error configuring logging; {}", CHECK_NEW_RELIC_LOGS),
+            Error::TooManyAttributes { name, limit } => write!(
+                f,
+                "Could not add attribute {}: New Relic keeps at most {} attributes",
+                name, limit
+            ),
             Error::TransactionNameError => write!(
                 f,
                 "Error changing the transaction name; {}",
@@ -89,6 +162,10 @@ impl fmt::Display for Error {
             Error::SegmentStartError => {
                 write!(f, "Error starting segment; {}", CHECK_NEW_RELIC_LOGS)
             }
+            Error::SerializeError(reason) => {
+                write!(f, "Could not serialize attributes: {}", reason)
+            }
+            Error::TimingError => write!(f, "Error overriding timing; {}", CHECK_NEW_RELIC_LOGS),
         }
     }
 }
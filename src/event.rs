@@ -1,9 +1,12 @@
 use std::ffi::CString;
 
 use log::{debug, error, warn};
-use newrelic_sys as ffi;
 
+#[cfg(feature = "serde")]
+use crate::attributes;
 use crate::{
+    attributes::{AttributeNames, MAX_CUSTOM_EVENT_ATTRIBUTES},
+    backend::CustomEventHandle,
     error::{Error, Result},
     transaction::{Attribute, Transaction},
 };
@@ -12,59 +15,59 @@ use crate::{
 #[must_use]
 pub struct CustomEvent<'a> {
     transaction: &'a Transaction,
-    inner: *mut ffi::newrelic_custom_event_t,
+    /// The event's handle within the transaction's backend, or `None` if
+    /// the transaction is a no-op.
+    inner: Option<CustomEventHandle>,
     recorded: bool,
+    attribute_names: AttributeNames,
 }
 
 impl<'a> CustomEvent<'a> {
     pub(crate) fn new(transaction: &'a Transaction, event_type: &str) -> Result<Self> {
         let event_type = CString::new(event_type)?;
-        let inner = unsafe { ffi::newrelic_create_custom_event(event_type.as_ptr()) };
-        debug!("Created custom event");
+        let inner = transaction
+            .inner()
+            .and_then(|(backend, _)| backend.create_custom_event(&event_type));
+        if inner.is_some() {
+            debug!("Created custom event");
+        } else if !transaction.is_noop() {
+            error!("Could not create custom event");
+        }
         Ok(CustomEvent {
             inner,
             transaction,
             recorded: false,
+            attribute_names: AttributeNames::new(MAX_CUSTOM_EVENT_ATTRIBUTES),
         })
     }
 
     /// Add an attribute to this custom event.
+    ///
+    /// New Relic keeps at most 255 attributes per custom event, so adding
+    /// more returns `Error::TooManyAttributes`. Names and string values are
+    /// limited, and attributes filtered, as for `Transaction::add_attribute`.
     pub fn add_attribute<'b, T>(&self, name: &str, attribute: T) -> Result<&Self>
     where
         T: Into<Attribute<'b>>,
     {
-        let name = CString::new(name)?;
-        let ok = match attribute.into() {
-            Attribute::Int(i) => unsafe {
-                ffi::newrelic_custom_event_add_attribute_int(self.inner, name.as_ptr(), i)
-            },
-            Attribute::Float(f) => unsafe {
-                ffi::newrelic_custom_event_add_attribute_double(self.inner, name.as_ptr(), f)
-            },
-            Attribute::Long(l) => unsafe {
-                ffi::newrelic_custom_event_add_attribute_long(self.inner, name.as_ptr(), l)
-            },
-            Attribute::String(s) => {
-                let s = CString::new(s)?;
-                unsafe {
-                    ffi::newrelic_custom_event_add_attribute_string(
-                        self.inner,
-                        name.as_ptr(),
-                        s.as_ptr(),
-                    )
-                }
-            }
-            Attribute::OwnedString(s) => {
-                let s = CString::new(s.as_str())?;
-                unsafe {
-                    ffi::newrelic_custom_event_add_attribute_string(
-                        self.inner,
-                        name.as_ptr(),
-                        s.as_ptr(),
-                    )
-                }
-            }
+        let (backend, event) = match (self.transaction.inner(), self.inner) {
+            (Some((backend, _)), Some(event)) => (backend, event),
+            _ => return Ok(self),
         };
+        let attribute = attribute.into();
+        if let Attribute::None = attribute {
+            return Ok(self);
+        }
+        if !self.transaction.attribute_filter().check(name) {
+            return Ok(self);
+        }
+        let c_name = CString::new(name)?;
+        self.attribute_names.add(name)?;
+        let ok = attribute
+            .with_value(name, |value| {
+                backend.add_custom_event_attribute(event, &c_name, value)
+            })?
+            .unwrap_or(true);
         if ok {
             debug!("Added attribute to custom event");
             Ok(self)
@@ -74,11 +77,32 @@ impl<'a> CustomEvent<'a> {
         }
     }
 
+    /// Add every field of a serializable value, such as a struct deriving
+    /// `Serialize`, as an attribute of this custom event.
+    ///
+    /// Values are flattened as for `Transaction::add_attributes`.
+    #[cfg(feature = "serde")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "serde")))]
+    pub fn add_attributes<T>(&self, value: &T) -> Result<&Self>
+    where
+        T: serde::Serialize + ?Sized,
+    {
+        if self.inner.is_none() {
+            return Ok(self);
+        }
+        for (name, attribute) in attributes::flatten(value)? {
+            self.add_attribute(&name, attribute)?;
+        }
+        Ok(self)
+    }
+
     /// Record this custom event, consuming it.
     pub fn record(mut self) {
-        unsafe { ffi::newrelic_record_custom_event(self.transaction.inner, &mut self.inner) };
-        debug!("Recorded custom event");
         self.recorded = true;
+        if let (Some((backend, handle)), Some(event)) = (self.transaction.inner(), self.inner) {
+            backend.record_custom_event(handle, event);
+            debug!("Recorded custom event");
+        }
     }
 }
 
@@ -86,9 +110,12 @@ impl<'a> Drop for CustomEvent<'a> {
     /// If the custom event wasn't ever recorded, we should discard it
     /// to free the memory.
     fn drop(&mut self) {
-        if !self.recorded {
+        if self.recorded {
+            return;
+        }
+        if let (Some((backend, _)), Some(event)) = (self.transaction.inner(), self.inner) {
             warn!("Dropping unrecorded custom event");
-            unsafe { ffi::newrelic_discard_custom_event(&mut self.inner) };
+            backend.discard_custom_event(event);
         }
     }
 }
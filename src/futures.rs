@@ -1,8 +1,23 @@
-use std::{future::Future, pin::Pin, task::Context, task::Poll};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::Context,
+    task::Poll,
+    task::Waker,
+    thread,
+};
 
+use log::{debug, error};
 use pin_project::pin_project;
 
-use crate::{segment, transaction::Transaction};
+use crate::{
+    app::{App, AppConfig},
+    error::{Error, Result},
+    segment,
+    shared::{SharedSegment, SharedTransaction},
+    transaction::Transaction,
+};
 
 /// A trait to make a lifetime scoped reference to a `Transaction` optional
 ///
@@ -60,6 +75,13 @@ impl<'a> OptionalTransaction<'a> for Option<&'a Transaction> {
     }
 }
 
+#[cfg_attr(docsrs, doc(cfg(feature = "async")))]
+impl<'a> OptionalTransaction<'a> for SharedTransaction {
+    fn get_transaction(&'a self) -> Option<&'a Transaction> {
+        Some(self.as_ref())
+    }
+}
+
 /// Extension trait allowing a `Future` to be instrumented inside a `Segment`
 #[cfg_attr(docsrs, doc(cfg(feature = "async")))]
 pub trait Segmented: Sized {
@@ -198,6 +220,82 @@ pub trait Segmented: Sized {
                 .map(|transaction| segment::Segment::external(transaction, params)),
         }
     }
+
+    /// Instruments this future inside a custom segment of a
+    /// `SharedTransaction`.
+    ///
+    /// Unlike `custom_segment`, the returned future doesn't borrow the
+    /// transaction, so it can be spawned onto another task.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// # use newrelic::Error;
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), Error> {
+    /// use newrelic::{App, Segmented, SharedTransaction};
+    ///
+    /// let license_key = std::env::var("NEW_RELIC_LICENSE_KEY").unwrap();
+    ///
+    /// let app = App::new("my app", &license_key).expect("Could not create app");
+    ///
+    /// let transaction: SharedTransaction = app
+    ///     .web_transaction("Transaction name")
+    ///     .expect("Could not start transaction")
+    ///     .into();
+    ///
+    /// tokio::spawn(
+    ///     async { }.shared_custom_segment(&transaction, "Segment name", "Segment category"),
+    /// )
+    /// .await
+    /// .unwrap();
+    ///
+    /// #   Ok(())
+    /// # }
+    /// ```
+    fn shared_custom_segment(
+        self,
+        transaction: &SharedTransaction,
+        name: &str,
+        category: &str,
+    ) -> SharedSegmentedFuture<Self> {
+        SharedSegmentedFuture {
+            inner: self,
+            segment: Some(transaction.create_custom_segment(name, category)),
+        }
+    }
+
+    /// Instruments this future inside a datastore segment of a
+    /// `SharedTransaction`.
+    ///
+    /// Unlike `datastore_segment`, the returned future doesn't borrow the
+    /// transaction, so it can be spawned onto another task.
+    fn shared_datastore_segment(
+        self,
+        transaction: &SharedTransaction,
+        params: &segment::DatastoreParams,
+    ) -> SharedSegmentedFuture<Self> {
+        SharedSegmentedFuture {
+            inner: self,
+            segment: Some(transaction.create_datastore_segment(params)),
+        }
+    }
+
+    /// Instruments this future inside an external segment of a
+    /// `SharedTransaction`.
+    ///
+    /// Unlike `external_segment`, the returned future doesn't borrow the
+    /// transaction, so it can be spawned onto another task.
+    fn shared_external_segment(
+        self,
+        transaction: &SharedTransaction,
+        params: &segment::ExternalParams,
+    ) -> SharedSegmentedFuture<Self> {
+        SharedSegmentedFuture {
+            inner: self,
+            segment: Some(transaction.create_external_segment(params)),
+        }
+    }
 }
 
 impl<T: Sized> Segmented for T {}
@@ -227,3 +325,232 @@ impl<'a, T: Future> Future for SegmentedFuture<'a, T> {
         result
     }
 }
+
+/// A future that has been instrumented inside a segment of a
+/// `SharedTransaction`.
+///
+/// This is `'static`, and `Send` if the inner future is.
+#[pin_project]
+#[cfg_attr(docsrs, doc(cfg(feature = "async")))]
+pub struct SharedSegmentedFuture<T> {
+    #[pin]
+    inner: T,
+
+    segment: Option<SharedSegment>,
+}
+
+impl<T: Future> Future for SharedSegmentedFuture<T> {
+    type Output = T::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let result = this.inner.poll(cx);
+
+        if result.is_ready() {
+            // Drop the segment
+            *this.segment = None;
+        }
+
+        result
+    }
+}
+
+#[derive(Default)]
+struct ConnectState {
+    result: Option<Result<App>>,
+    waker: Option<Waker>,
+    cancelled: bool,
+}
+
+/// A future which resolves to an `App` once it has connected to the daemon.
+///
+/// The connection is made on a dedicated background thread, since the New
+/// Relic SDK blocks the calling thread while it waits for the daemon.
+/// Dropping this future cancels the connection: the SDK call itself can't
+/// be interrupted, but the background thread destroys the `App` as soon as
+/// it has been created.
+///
+/// Create this using `App::connect_async` or `AppBuilder::build_async`.
+#[must_use = "futures do nothing unless polled"]
+#[cfg_attr(docsrs, doc(cfg(feature = "async")))]
+pub struct AppFuture {
+    state: Arc<Mutex<ConnectState>>,
+}
+
+impl AppFuture {
+    pub(crate) fn spawn(config: AppConfig, timeout: u16) -> Self {
+        let state = Arc::new(Mutex::new(ConnectState::default()));
+        let thread_state = Arc::clone(&state);
+        let spawned = thread::Builder::new()
+            .name("newrelic-connect".to_string())
+            .spawn(move || {
+                let result = App::with_timeout(config, timeout);
+                let (unclaimed, waker) = {
+                    let mut state = thread_state.lock().unwrap();
+                    if state.cancelled {
+                        (Some(result), None)
+                    } else {
+                        state.result = Some(result);
+                        (None, state.waker.take())
+                    }
+                };
+                // Destroy any app nobody is waiting for, and wake the task
+                // waiting for one, without holding the lock.
+                if unclaimed.is_some() {
+                    debug!("App connection cancelled; destroying app");
+                }
+                drop(unclaimed);
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
+            });
+        match spawned {
+            Ok(_) => AppFuture { state },
+            Err(e) => {
+                error!("Could not spawn thread to connect app: {}", e);
+                Self::ready(Err(Error::DaemonError))
+            }
+        }
+    }
+
+    pub(crate) fn ready(result: Result<App>) -> Self {
+        AppFuture {
+            state: Arc::new(Mutex::new(ConnectState {
+                result: Some(result),
+                ..Default::default()
+            })),
+        }
+    }
+}
+
+impl Future for AppFuture {
+    type Output = Result<App>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.state.lock().unwrap();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for AppFuture {
+    fn drop(&mut self) {
+        let unclaimed = {
+            let mut state = self.state.lock().unwrap();
+            state.cancelled = true;
+            state.result.take()
+        };
+        // Destroy any app which was connected but never received, without
+        // holding the lock.
+        drop(unclaimed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, task::Wake};
+
+    use super::*;
+    use crate::app::AppBuilder;
+
+    struct NoopWaker;
+
+    impl Wake for NoopWaker {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    /// Poll `future` once.
+    fn poll_once<F: Future + Unpin>(future: &mut F) -> Poll<F::Output> {
+        let waker = Waker::from(Arc::new(NoopWaker));
+        Pin::new(future).poll(&mut Context::from_waker(&waker))
+    }
+
+    #[test]
+    fn disabled_apps_are_ready_immediately() {
+        let mut future = AppBuilder::disabled().build_async();
+        match poll_once(&mut future) {
+            Poll::Ready(Ok(app)) => assert!(app.is_disabled()),
+            Poll::Ready(Err(e)) => panic!("Could not create app: {}", e),
+            Poll::Pending => panic!("Disabled app wasn't ready"),
+        }
+    }
+
+    #[cfg(all(feature = "pure-rust", feature = "testing"))]
+    #[test]
+    fn connects_to_daemon() {
+        use crate::{
+            app::BackendKind,
+            backend::{configure_daemon, TEST_SETTINGS_LOCK},
+            testing::FakeDaemon,
+        };
+
+        let socket = format!("@newrelic-app-future-{}", std::process::id());
+        let daemon = FakeDaemon::start(&socket).unwrap();
+        let _lock = TEST_SETTINGS_LOCK.lock().unwrap();
+        configure_daemon(Some(daemon.socket().to_string()), None);
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let app = runtime
+            .block_on(
+                AppBuilder::new("async app", &"0".repeat(40))
+                    .unwrap()
+                    .backend(BackendKind::PureRust)
+                    .build_async(),
+            )
+            .unwrap();
+        assert!(!app.is_disabled());
+        assert_eq!(daemon.apps()[0].app_name, "async app");
+    }
+
+    #[cfg(feature = "pure-rust")]
+    #[test]
+    fn missing_daemon_times_out() {
+        use crate::{
+            app::BackendKind,
+            backend::{configure_daemon, TEST_SETTINGS_LOCK},
+        };
+
+        let _lock = TEST_SETTINGS_LOCK.lock().unwrap();
+        let socket = format!("/tmp/.newrelic-missing-{}.sock", std::process::id());
+        configure_daemon(Some(socket), None);
+        let mut config = AppConfig::new("async app", &"0".repeat(40)).unwrap();
+        config.backend = BackendKind::PureRust;
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        match runtime.block_on(App::connect_async(config, 200)) {
+            Err(Error::ConnectTimeout(timeout)) => {
+                assert_eq!(timeout, std::time::Duration::from_millis(200))
+            }
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("App connected without a daemon"),
+        }
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn shared_segments_span_spawned_tasks() {
+        use crate::testing::RecordingApp;
+
+        let app = RecordingApp::new();
+        let transaction: SharedTransaction = app.web_transaction("tasks").unwrap().into();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let task = async {
+                tokio::task::yield_now().await;
+                42
+            }
+            .shared_custom_segment(&transaction, "task", "test");
+            assert_eq!(tokio::spawn(task).await.unwrap(), 42);
+        });
+
+        let recorded = app.transaction("tasks").unwrap();
+        assert_eq!(recorded.segments[0].name(), Some("task"));
+        assert!(recorded.segments[0].ended);
+        assert!(!recorded.ended);
+        drop(transaction);
+        assert!(app.transaction("tasks").unwrap().ended);
+    }
+}
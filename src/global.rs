@@ -0,0 +1,175 @@
+/*!
+A process-wide `App`.
+
+Installing an app globally allows code deep within an application, such as
+libraries, to start transactions without an `&App` being passed through
+every constructor.
+
+If no app has been installed, [`app`] returns a no-op handle whose
+transactions silently ignore every method call, in the same way that
+segments degrade silently when they can't be created. This means that
+instrumented libraries work unchanged in programs which never set up New
+Relic.
+
+Example:
+
+```rust
+use newrelic::{global, App};
+
+# if false {
+let license_key = std::env::var("NEW_RELIC_LICENSE_KEY").unwrap();
+let app = App::new("my app", &license_key).expect("Could not create app");
+global::set_app(app);
+# }
+
+// Elsewhere...
+let transaction = global::app()
+    .web_transaction("Transaction name")
+    .expect("Could not start transaction");
+```
+*/
+use std::{
+    ffi::CString,
+    sync::{Arc, RwLock},
+};
+
+use log::debug;
+
+use crate::{
+    app::App,
+    error::Result,
+    transaction::{Transaction, TransactionType},
+};
+
+static GLOBAL_APP: RwLock<Option<Arc<App>>> = RwLock::new(None);
+
+/// Install `app` as the process-wide app, returning the previously
+/// installed app, if any.
+///
+/// Transactions started from the previous app keep it alive until they
+/// end, so it is safe to replace an app while transactions are running.
+pub fn set_app(app: App) -> Option<Arc<App>> {
+    debug!("Installing global app");
+    GLOBAL_APP.write().unwrap().replace(Arc::new(app))
+}
+
+/// Remove the process-wide app, returning it if one was installed.
+///
+/// Handles returned by [`app`] after this is called are no-ops.
+pub fn take_app() -> Option<Arc<App>> {
+    debug!("Removing global app");
+    GLOBAL_APP.write().unwrap().take()
+}
+
+/// Get a handle to the process-wide app.
+///
+/// If no app has been installed using [`set_app`], the returned handle is
+/// a no-op.
+pub fn app() -> GlobalApp {
+    GlobalApp {
+        app: GLOBAL_APP.read().unwrap().clone(),
+    }
+}
+
+/// A handle to the process-wide app, obtained using [`app`].
+#[derive(Clone)]
+pub struct GlobalApp {
+    app: Option<Arc<App>>,
+}
+
+impl GlobalApp {
+    /// Whether this handle is a no-op, because no app was installed when
+    /// it was obtained or the installed app is disabled.
+    pub fn is_noop(&self) -> bool {
+        self.app.as_ref().is_none_or(|app| app.is_disabled())
+    }
+
+    /// Begin a new web transaction in New Relic with the given name.
+    ///
+    /// If this handle is a no-op, the transaction is a no-op too.
+    ///
+    /// This function will return an `Err` if the name contains a NUL byte.
+    pub fn web_transaction(&self, name: &str) -> Result<Transaction> {
+        match &self.app {
+            Some(app) => app.web_transaction(name),
+            None => {
+                CString::new(name)?;
+                Ok(Transaction::noop(TransactionType::Web))
+            }
+        }
+    }
+
+    /// Begin a new non-web transaction in New Relic with the given name.
+    ///
+    /// If this handle is a no-op, the transaction is a no-op too.
+    ///
+    /// This function will return an `Err` if the name contains a NUL byte.
+    pub fn non_web_transaction(&self, name: &str) -> Result<Transaction> {
+        match &self.app {
+            Some(app) => app.non_web_transaction(name),
+            None => {
+                CString::new(name)?;
+                Ok(Transaction::noop(TransactionType::NonWeb))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Serialises tests, which share the global app.
+    static LOCK: Mutex<()> = Mutex::new(());
+
+    fn installed() -> Option<Arc<App>> {
+        app().app
+    }
+
+    #[test]
+    fn falls_back_to_noop() {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        take_app();
+        let app = app();
+        assert!(app.is_noop());
+        assert!(app.web_transaction("index").unwrap().is_noop());
+        assert!(app.non_web_transaction("job").unwrap().is_noop());
+        assert!(app.web_transaction("in\0dex").is_err());
+    }
+
+    #[test]
+    fn set_app_replaces_the_app() {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        take_app();
+        assert!(set_app(App::disabled()).is_none());
+        let first = installed().unwrap();
+        let previous = set_app(App::disabled()).unwrap();
+        assert!(Arc::ptr_eq(&previous, &first));
+        assert!(!Arc::ptr_eq(&installed().unwrap(), &first));
+        take_app();
+    }
+
+    #[test]
+    fn take_app_empties_the_slot() {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        set_app(App::disabled());
+        let installed_app = installed().unwrap();
+        let taken = take_app().unwrap();
+        assert!(Arc::ptr_eq(&taken, &installed_app));
+        assert!(installed().is_none());
+        assert!(take_app().is_none());
+    }
+
+    #[test]
+    fn disabled_apps_are_noops() {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        set_app(App::disabled());
+        let app = app();
+        assert!(app.app.is_some());
+        assert!(app.is_noop());
+        assert!(app.web_transaction("index").unwrap().is_noop());
+        take_app();
+    }
+}
@@ -0,0 +1,231 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use log::debug;
+
+use crate::{
+    attributes::matches,
+    error::{Error, Result},
+};
+
+/// Rules deciding which transactions are ignored, so that their data isn't
+/// sent to New Relic.
+///
+/// Transactions can be ignored by name, such as health checks, or sampled
+/// at a fixed rate, to reduce the volume of data reported for high-volume
+/// transactions. Rules match transaction names using glob patterns, in
+/// which `*` matches any sequence of characters, including none, as for
+/// `AttributeFilter`.
+///
+/// The rules are configured using `AppBuilder::ignore_rules`. They are
+/// checked when a transaction is started and whenever it is renamed, using
+/// the name after any `NamingRules` are applied but before their limit on
+/// unique names, so ignored transactions are never renamed to `Other` and
+/// don't count towards the limit. Matching transactions are ignored using
+/// `Transaction::ignore`. An ignored transaction can't be un-ignored by
+/// renaming it.
+///
+/// Example:
+///
+/// ```rust
+/// use newrelic::IgnoreRules;
+///
+/// # fn main() -> Result<(), newrelic::Error> {
+/// let rules = IgnoreRules::new()
+///     .ignore("GET /health*")
+///     .ignore("*/metrics")
+///     .sample("GET /items*", 0.1)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct IgnoreRules {
+    ignore: Vec<String>,
+    sample: Vec<SampleRule>,
+}
+
+/// Keeps a fraction of the transactions whose names match a pattern.
+#[derive(Debug)]
+struct SampleRule {
+    pattern: String,
+    rate: f64,
+    /// The number of matching transactions seen so far.
+    seen: AtomicU64,
+}
+
+impl Clone for SampleRule {
+    fn clone(&self) -> Self {
+        SampleRule {
+            pattern: self.pattern.clone(),
+            rate: self.rate,
+            seen: AtomicU64::new(0),
+        }
+    }
+}
+
+impl SampleRule {
+    /// Whether to keep the next matching transaction.
+    ///
+    /// Sampling is deterministic rather than random: the rule keeps a
+    /// transaction whenever doing so brings the fraction kept so far back
+    /// up to the rate, spreading the kept transactions evenly.
+    fn keep(&self) -> bool {
+        let seen = self.seen.fetch_add(1, Ordering::Relaxed) as f64;
+        ((seen + 1.0) * self.rate).floor() > (seen * self.rate).floor()
+    }
+}
+
+impl IgnoreRules {
+    /// Create a set of rules which ignores no transactions.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ignore every transaction whose name matches `pattern`.
+    pub fn ignore(mut self, pattern: impl Into<String>) -> Self {
+        self.ignore.push(pattern.into());
+        self
+    }
+
+    /// Keep only the fraction `rate` of the transactions whose names match
+    /// `pattern`, ignoring the rest.
+    ///
+    /// A rate of `0.1` keeps one in every ten matching transactions. If
+    /// several sampling rules match a name, the first one added is used. A
+    /// transaction is sampled once, when it first matches the rule; renaming
+    /// it only samples it again if the new name matches a different rule.
+    ///
+    /// Returns `Error::InvalidConfig` if `rate` isn't between 0 and 1.
+    pub fn sample(mut self, pattern: impl Into<String>, rate: f64) -> Result<Self> {
+        if !(0.0..=1.0).contains(&rate) {
+            return Err(Error::InvalidConfig(format!(
+                "transaction sample rate must be between 0 and 1, got {}",
+                rate
+            )));
+        }
+        self.sample.push(SampleRule {
+            pattern: pattern.into(),
+            rate,
+            seen: AtomicU64::new(0),
+        });
+        Ok(self)
+    }
+
+    /// Whether the transaction named `name` should be ignored.
+    ///
+    /// `sampled_by` is the index of the sampling rule the transaction was
+    /// last sampled by, if any. A transaction is only sampled again if it's
+    /// renamed to match a different rule, so renaming a kept transaction
+    /// doesn't skew the rule's rate.
+    pub(crate) fn should_ignore(&self, name: &str, sampled_by: &mut Option<usize>) -> bool {
+        if self.ignore.iter().any(|pattern| matches(pattern, name)) {
+            debug!("Ignoring transaction {}", name);
+            return true;
+        }
+        let index = match self
+            .sample
+            .iter()
+            .position(|rule| matches(&rule.pattern, name))
+        {
+            Some(index) if *sampled_by != Some(index) => index,
+            _ => return false,
+        };
+        *sampled_by = Some(index);
+        if self.sample[index].keep() {
+            false
+        } else {
+            debug!("Ignoring transaction {} which wasn't sampled", name);
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ignored(rules: &IgnoreRules, name: &str) -> bool {
+        rules.should_ignore(name, &mut None)
+    }
+
+    #[test]
+    fn ignores_matching_names() {
+        let rules = IgnoreRules::new()
+            .ignore("GET /health*")
+            .ignore("*/metrics");
+        assert!(ignored(&rules, "GET /health"));
+        assert!(ignored(&rules, "GET /healthz"));
+        assert!(ignored(&rules, "GET /admin/metrics"));
+        assert!(!ignored(&rules, "POST /health"));
+        assert!(!ignored(&rules, "GET /metrics/cpu"));
+        assert!(!ignored(&IgnoreRules::new(), "GET /health"));
+    }
+
+    #[test]
+    fn samples_at_the_rate() {
+        let rules = IgnoreRules::new().sample("GET /items*", 0.1).unwrap();
+        let kept: Vec<usize> = (0..100)
+            .filter(|_| !ignored(&rules, "GET /items"))
+            .collect();
+        assert_eq!(kept, vec![9, 19, 29, 39, 49, 59, 69, 79, 89, 99]);
+        assert!(!ignored(&rules, "GET /users"));
+
+        let none = IgnoreRules::new().sample("*", 0.0).unwrap();
+        assert!((0..10).all(|_| ignored(&none, "GET /items")));
+        let all = IgnoreRules::new().sample("*", 1.0).unwrap();
+        assert!((0..10).all(|_| !ignored(&all, "GET /items")));
+    }
+
+    #[test]
+    fn rejects_invalid_rates() {
+        for rate in &[-0.1, 1.1, f64::NAN, f64::INFINITY] {
+            assert!(
+                matches!(
+                    IgnoreRules::new().sample("*", *rate),
+                    Err(Error::InvalidConfig(_))
+                ),
+                "{}",
+                rate
+            );
+        }
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rules = IgnoreRules::new()
+            .sample("GET /items/*", 1.0)
+            .unwrap()
+            .sample("GET *", 0.0)
+            .unwrap();
+        assert!(!ignored(&rules, "GET /items/1"));
+        assert!(ignored(&rules, "GET /users"));
+
+        // Ignore rules apply before any sampling rule.
+        let rules = IgnoreRules::new()
+            .sample("GET *", 1.0)
+            .unwrap()
+            .ignore("GET /health");
+        assert!(ignored(&rules, "GET /health"));
+        assert!(!ignored(&rules, "GET /users"));
+    }
+
+    #[test]
+    fn renames_are_sampled_once_per_rule() {
+        let rules = IgnoreRules::new()
+            .sample("GET /items*", 0.5)
+            .unwrap()
+            .sample("GET /users*", 0.5)
+            .unwrap();
+        let mut sampled_by = None;
+        // The first draw at a rate of 0.5 ignores the transaction, the second
+        // keeps it.
+        assert!(ignored(&rules, "GET /items"));
+        assert!(!rules.should_ignore("GET /items", &mut sampled_by));
+        assert_eq!(sampled_by, Some(0));
+        // Renaming within the same rule doesn't draw again.
+        assert!(!rules.should_ignore("GET /items/{id}", &mut sampled_by));
+        assert!(ignored(&rules, "GET /items"));
+        // Renaming into another rule does.
+        assert!(rules.should_ignore("GET /users", &mut sampled_by));
+        assert_eq!(sampled_by, Some(1));
+    }
+}
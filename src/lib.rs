@@ -13,6 +13,7 @@ https://docs.newrelic.com/docs/agents/c-sdk/get-started/introduction-c-sdk
 for details.
 
 In particular, the New Relic SDK will not link against musl - see the [newrelic-sys] crate for more details.
+The `pure-rust` feature avoids the C SDK entirely; see [Pure Rust](#pure-rust) below.
 
 See https://github.com/hjr3/newrelic-rs for the <0.1.0 repository.
 
@@ -59,10 +60,71 @@ and custom events.
 This crate still requires the New Relic daemon to be running as per the
 [documentation for the New Relic C SDK][c-sdk]; be sure to read this first.
 
+## Configuration
+
+An app can be configured entirely from the standard `NEW_RELIC_*` environment
+variables using [`AppBuilder::from_env`] and [`NewRelicConfig::from_env`].
+
+With the `toml` or `yaml` features, the same settings can be loaded from a
+configuration file using [`Config::load`], with environment variables taking
+precedence over the file.
+
+Setting `NEW_RELIC_ENABLED=false` (or using [`AppBuilder::disabled`]) creates an
+app which never calls into the C SDK, so no daemon is needed; its transactions
+silently do nothing. This is useful for local development and CI.
+
+## Testing
+
+The [`testing`] module, enabled with the feature `testing`, provides a
+`RecordingApp` which records transactions, segments, custom events and more
+in memory so that instrumentation can be asserted on in ordinary tests.
+Its `FakeDaemon` stands in for the New Relic daemon, so the full path
+through the SDK can be tested without network access.
+
 ## Async
 
 The [`Segmented`] extension trait adds the ability to run a future inside of a segment.  The feature `async` is required.
 
+Segments borrow their transaction, so instrumented work can't be spawned onto
+another thread or task. Convert the transaction into a [`SharedTransaction`]
+instead: it is cheaply cloneable, and its segments and futures are `'static`
+and `Send`.
+
+## Pure Rust
+
+With the `pure-rust` feature, the crate talks to the daemon over its socket
+itself instead of going through the C SDK, so it builds without a C toolchain
+and links against musl. Disable the default `c-sdk` feature to drop the
+dependency on [newrelic-sys] altogether:
+
+```toml
+[dependencies]
+newrelic = { version = "0.2", default-features = false, features = ["pure-rust"] }
+```
+
+The API is the same. Transactions are sent to the daemon with the metrics,
+events and errors the C SDK would record for them, but transaction traces,
+slow queries, span events and distributed trace payloads aren't supported yet.
+If both features are enabled, each app chooses its backend with
+`AppBuilder::backend(BackendKind::PureRust)`; the C SDK is the default.
+
+## OpenTelemetry
+
+With the `otlp` feature, transactions are exported to an OpenTelemetry
+collector over OTLP/HTTP instead, so existing instrumentation can feed a
+collector-based pipeline without a daemon. Each transaction becomes a trace:
+segments become child spans, attributes become span attributes, the noticed
+error sets the span's status and custom events become span events. Custom
+metrics are exported as gauges.
+
+The collector's endpoint is set using `NewRelicConfig::otlp_endpoint`,
+falling back to `OTEL_EXPORTER_OTLP_ENDPOINT` and then
+`http://localhost:4318`. With the `distributed_tracing` feature, the
+distributed trace payload of a segment is its W3C `traceparent` header.
+Apps use the OTLP exporter if it's the only backend enabled, or if they
+choose it with `AppBuilder::backend(BackendKind::Otlp)`. The backend can
+also be chosen with `NEW_RELIC_BACKEND` or the `backend` config file key.
+
 ## Distributed Tracing
 
 [Distributed tracing][nr-distributed-tracing] is available wiith the feature `distributed_tracing`.  Notably, this feature requires the [libc] crate.
@@ -73,26 +135,58 @@ The [`Segmented`] extension trait adds the ability to run a future inside of a s
 [libc]: https://crates.io/crates/libc
 [nr-distributed-tracing]: https://docs.newrelic.com/docs/understand-dependencies/distributed-tracing/get-started/introduction-distributed-tracing
 [`Segmented`]: ./trait.Segmented.html
+[`SharedTransaction`]: ./struct.SharedTransaction.html
+[`AppBuilder::from_env`]: ./struct.AppBuilder.html#method.from_env
+[`NewRelicConfig::from_env`]: ./struct.NewRelicConfig.html#method.from_env
+[`Config::load`]: ./struct.Config.html#method.load
+[`AppBuilder::disabled`]: ./struct.AppBuilder.html#method.disabled
+[`testing`]: ./testing/index.html
 [rocket_newrelic]: https://crates.io/crates/rocket_newrelic
 */
 #![deny(missing_docs)]
 
 mod app;
+mod attributes;
+mod backend;
+mod backtrace;
+mod config;
+pub mod context;
+mod env;
 mod error;
 mod event;
+pub mod global;
+mod ignore;
+mod metrics;
+mod naming;
+#[cfg(any(feature = "pure-rust", feature = "testing"))]
+mod protocol;
 mod segment;
+mod shared;
+#[cfg(feature = "testing")]
+#[cfg_attr(docsrs, doc(cfg(feature = "testing")))]
+pub mod testing;
 mod transaction;
+mod web;
 
 pub use log::Level as LogLevel;
 
-pub use app::{App, AppBuilder, AppConfig, LogOutput, NewRelicConfig, RecordSQL, TracingThreshold};
+pub use app::{
+    App, AppBuilder, AppConfig, BackendKind, LogOutput, NewRelicConfig, RecordSQL, TracingThreshold,
+};
+pub use attributes::AttributeFilter;
+pub use config::Config;
 pub use error::{Error, Result};
 pub use event::CustomEvent;
+pub use ignore::IgnoreRules;
+pub use metrics::{Counter, Gauge, MetricUnit, Summary};
+pub use naming::NamingRules;
 pub use segment::{
     Datastore, DatastoreParams, DatastoreParamsBuilder, ExternalParams, ExternalParamsBuilder,
     ReferencingSegment, Segment,
 };
-pub use transaction::{Attribute, Transaction};
+pub use shared::{SharedSegment, SharedTransaction};
+pub use transaction::{Attribute, Transaction, TransactionType};
+pub use web::{WebRequest, WebResponse};
 
 #[cfg(feature = "async")]
 #[cfg_attr(docsrs, doc(cfg(feature = "async")))]
@@ -100,4 +194,6 @@ mod futures;
 
 #[cfg(feature = "async")]
 #[cfg_attr(docsrs, doc(cfg(feature = "async")))]
-pub use futures::{OptionalTransaction, Segmented, SegmentedFuture};
+pub use futures::{
+    AppFuture, OptionalTransaction, Segmented, SegmentedFuture, SharedSegmentedFuture,
+};
@@ -0,0 +1,430 @@
+use std::{
+    collections::BTreeMap,
+    ffi::CString,
+    sync::{Arc, Mutex},
+};
+
+use log::{debug, warn};
+
+use crate::error::{Error, Result};
+
+/// The unit of a custom metric's values.
+///
+/// New Relic stores the values of custom metrics recorded by the SDK as
+/// times in seconds. Values in other units are sent unscaled, so the
+/// reported value is the value recorded, and the unit is appended to the
+/// metric's name in square brackets, as with the other New Relic agents:
+/// a counter `Custom/Queue/Processed` with `MetricUnit::Count` is reported
+/// as `Custom/Queue/Processed[count]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MetricUnit {
+    /// A number of things, such as requests or messages.
+    Count,
+    /// A number of bytes.
+    Bytes,
+    /// A percentage.
+    Percent,
+    /// A time in milliseconds.
+    Milliseconds,
+    /// A time in seconds.
+    Seconds,
+}
+
+impl MetricUnit {
+    /// The value in milliseconds to send to the SDK, which reports it in
+    /// seconds, for a value in this unit.
+    #[cfg_attr(
+        not(any(feature = "c-sdk", feature = "pure-rust", feature = "testing")),
+        allow(dead_code)
+    )]
+    pub(crate) fn milliseconds(self, value: f64) -> f64 {
+        match self {
+            MetricUnit::Milliseconds => value,
+            _ => value * 1000.0,
+        }
+    }
+
+    /// The suffix added to the names of metrics in this unit.
+    fn suffix(self) -> &'static str {
+        match self {
+            MetricUnit::Count => "[count]",
+            MetricUnit::Bytes => "[bytes]",
+            MetricUnit::Percent => "[percent]",
+            MetricUnit::Milliseconds | MetricUnit::Seconds => "",
+        }
+    }
+
+    /// The unit's code in the Unified Code for Units of Measure, as used
+    /// by OpenTelemetry.
+    #[cfg_attr(not(feature = "otlp"), allow(dead_code))]
+    pub(crate) fn ucum(self) -> &'static str {
+        match self {
+            MetricUnit::Count => "1",
+            MetricUnit::Bytes => "By",
+            MetricUnit::Percent => "%",
+            MetricUnit::Milliseconds => "ms",
+            MetricUnit::Seconds => "s",
+        }
+    }
+}
+
+/// The value of a metric aggregated since it was last reported, or `None`
+/// if nothing has been recorded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Aggregate {
+    Counter(Option<f64>),
+    Gauge(Option<f64>),
+    Summary(Option<Stats>),
+}
+
+/// The statistics of a summary's values.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Stats {
+    count: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+}
+
+impl Aggregate {
+    fn kind(&self) -> &'static str {
+        match self {
+            Aggregate::Counter(_) => "counter",
+            Aggregate::Gauge(_) => "gauge",
+            Aggregate::Summary(_) => "summary",
+        }
+    }
+
+    /// Take the aggregated value, leaving nothing recorded.
+    fn take(&mut self) -> Aggregate {
+        match self {
+            Aggregate::Counter(value) => Aggregate::Counter(value.take()),
+            Aggregate::Gauge(value) => Aggregate::Gauge(value.take()),
+            Aggregate::Summary(summary) => Aggregate::Summary(summary.take()),
+        }
+    }
+}
+
+struct Metric {
+    name: String,
+    unit: MetricUnit,
+    aggregate: Mutex<Aggregate>,
+}
+
+impl Metric {
+    /// Update the aggregated value with `value`, ignoring values which
+    /// aren't finite.
+    fn record(&self, value: f64, update: impl FnOnce(&mut Aggregate, f64)) {
+        if !value.is_finite() {
+            warn!(
+                "Ignoring non-finite value {} of metric {}",
+                value, self.name
+            );
+            return;
+        }
+        update(&mut self.aggregate.lock().unwrap(), value);
+    }
+}
+
+/// A value reported by `MetricRegistry::drain`.
+pub(crate) struct MetricValue {
+    pub(crate) name: CString,
+    pub(crate) value: f64,
+    pub(crate) unit: MetricUnit,
+}
+
+/// The custom metrics of an app, aggregated until they are reported.
+#[derive(Default)]
+pub(crate) struct MetricRegistry {
+    metrics: Mutex<BTreeMap<String, Arc<Metric>>>,
+}
+
+impl MetricRegistry {
+    /// Get or create the metric called `name`, checking that an existing
+    /// metric has the same kind and unit.
+    fn metric(&self, name: &str, unit: MetricUnit, empty: Aggregate) -> Result<Arc<Metric>> {
+        CString::new(name)?;
+        let mut metrics = self.metrics.lock().unwrap();
+        if let Some(metric) = metrics.get(name) {
+            let kind = metric.aggregate.lock().unwrap().kind();
+            if kind != empty.kind() || metric.unit != unit {
+                return Err(Error::InvalidMetric(format!(
+                    "metric {} already exists as a {} in {:?}",
+                    name, kind, metric.unit
+                )));
+            }
+            return Ok(Arc::clone(metric));
+        }
+        debug!("Created {} metric {}", empty.kind(), name);
+        let metric = Arc::new(Metric {
+            name: name.to_string(),
+            unit,
+            aggregate: Mutex::new(empty),
+        });
+        metrics.insert(name.to_string(), Arc::clone(&metric));
+        Ok(metric)
+    }
+
+    pub(crate) fn counter(&self, name: &str, unit: MetricUnit) -> Result<Counter> {
+        self.metric(name, unit, Aggregate::Counter(None))
+            .map(Counter)
+    }
+
+    pub(crate) fn gauge(&self, name: &str, unit: MetricUnit) -> Result<Gauge> {
+        self.metric(name, unit, Aggregate::Gauge(None)).map(Gauge)
+    }
+
+    pub(crate) fn summary(&self, name: &str, unit: MetricUnit) -> Result<Summary> {
+        self.metric(name, unit, Aggregate::Summary(None))
+            .map(Summary)
+    }
+
+    /// Take the values recorded since the metrics were last reported.
+    pub(crate) fn drain(&self) -> Vec<MetricValue> {
+        let metrics = self.metrics.lock().unwrap();
+        let mut values = Vec::new();
+        for metric in metrics.values() {
+            let aggregate = metric.aggregate.lock().unwrap().take();
+            let mut push = |suffix: &str, value: f64, unit: MetricUnit| {
+                let name = format!("{}{}{}", metric.name, suffix, unit.suffix());
+                // Names were checked for NUL bytes when the metric was created.
+                if let Ok(name) = CString::new(name) {
+                    values.push(MetricValue { name, value, unit });
+                }
+            };
+            match aggregate {
+                Aggregate::Counter(Some(value)) | Aggregate::Gauge(Some(value)) => {
+                    push("", value, metric.unit)
+                }
+                Aggregate::Summary(Some(stats)) => {
+                    push("/count", stats.count as f64, MetricUnit::Count);
+                    push("/sum", stats.sum, metric.unit);
+                    push("/min", stats.min, metric.unit);
+                    push("/max", stats.max, metric.unit);
+                }
+                _ => {}
+            }
+        }
+        values
+    }
+}
+
+/// A custom metric counting things, such as processed messages or bytes
+/// sent, created using `App::counter`.
+///
+/// The counter reports the total added since it was last reported.
+#[derive(Clone)]
+pub struct Counter(Arc<Metric>);
+
+impl Counter {
+    /// Add one to the counter.
+    pub fn increment(&self) {
+        self.add(1.0);
+    }
+
+    /// Add `value` to the counter.
+    pub fn add(&self, value: f64) {
+        self.0.record(value, |aggregate, value| {
+            if let Aggregate::Counter(total) = aggregate {
+                *total = Some(total.unwrap_or(0.0) + value);
+            }
+        });
+    }
+}
+
+/// A custom metric measuring a current value, such as a queue's depth,
+/// created using `App::gauge`.
+///
+/// The gauge reports the last value set since it was last reported.
+#[derive(Clone)]
+pub struct Gauge(Arc<Metric>);
+
+impl Gauge {
+    /// Set the gauge's value.
+    pub fn set(&self, value: f64) {
+        self.0.record(value, |aggregate, value| {
+            if let Aggregate::Gauge(current) = aggregate {
+                *current = Some(value);
+            }
+        });
+    }
+}
+
+/// A custom metric summarising a distribution of values, such as response
+/// sizes, created using `App::summary`.
+///
+/// The summary reports the count, sum, minimum and maximum of the values
+/// recorded since it was last reported, as the metrics `<name>/count`,
+/// `<name>/sum`, `<name>/min` and `<name>/max`.
+#[derive(Clone)]
+pub struct Summary(Arc<Metric>);
+
+impl Summary {
+    /// Record a value.
+    pub fn record(&self, value: f64) {
+        self.0.record(value, |aggregate, value| {
+            if let Aggregate::Summary(summary) = aggregate {
+                *summary = Some(match summary.take() {
+                    Some(stats) => Stats {
+                        count: stats.count + 1,
+                        sum: stats.sum + value,
+                        min: stats.min.min(value),
+                        max: stats.max.max(value),
+                    },
+                    None => Stats {
+                        count: 1,
+                        sum: value,
+                        min: value,
+                        max: value,
+                    },
+                });
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drained(registry: &MetricRegistry) -> Vec<(String, f64, MetricUnit)> {
+        registry
+            .drain()
+            .into_iter()
+            .map(|metric| {
+                let name = metric.name.into_string().unwrap();
+                (name, metric.value, metric.unit)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn counters_sum_until_drained() {
+        let registry = MetricRegistry::default();
+        let counter = registry.counter("Custom/Sent", MetricUnit::Bytes).unwrap();
+        assert!(drained(&registry).is_empty());
+        counter.add(1024.0);
+        counter.increment();
+        registry
+            .counter("Custom/Sent", MetricUnit::Bytes)
+            .unwrap()
+            .add(2.0);
+        assert_eq!(
+            drained(&registry),
+            vec![("Custom/Sent[bytes]".to_string(), 1027.0, MetricUnit::Bytes)]
+        );
+        assert!(drained(&registry).is_empty());
+        counter.increment();
+        assert_eq!(
+            drained(&registry),
+            vec![("Custom/Sent[bytes]".to_string(), 1.0, MetricUnit::Bytes)]
+        );
+    }
+
+    #[test]
+    fn gauges_keep_the_last_value() {
+        let registry = MetricRegistry::default();
+        let gauge = registry.gauge("Custom/Depth", MetricUnit::Count).unwrap();
+        gauge.set(3.0);
+        gauge.set(5.0);
+        gauge.set(4.0);
+        assert_eq!(
+            drained(&registry),
+            vec![("Custom/Depth[count]".to_string(), 4.0, MetricUnit::Count)]
+        );
+        assert!(drained(&registry).is_empty());
+    }
+
+    #[test]
+    fn summaries_report_statistics() {
+        let registry = MetricRegistry::default();
+        let summary = registry
+            .summary("Custom/Latency", MetricUnit::Milliseconds)
+            .unwrap();
+        for value in &[20.0, 5.0, 35.0] {
+            summary.record(*value);
+        }
+        assert_eq!(
+            drained(&registry),
+            vec![
+                (
+                    "Custom/Latency/count[count]".to_string(),
+                    3.0,
+                    MetricUnit::Count
+                ),
+                (
+                    "Custom/Latency/sum".to_string(),
+                    60.0,
+                    MetricUnit::Milliseconds
+                ),
+                (
+                    "Custom/Latency/min".to_string(),
+                    5.0,
+                    MetricUnit::Milliseconds
+                ),
+                (
+                    "Custom/Latency/max".to_string(),
+                    35.0,
+                    MetricUnit::Milliseconds
+                ),
+            ]
+        );
+        assert!(drained(&registry).is_empty());
+    }
+
+    #[test]
+    fn names_are_reused_with_the_same_kind_and_unit() {
+        let registry = MetricRegistry::default();
+        registry.counter("Custom/Jobs", MetricUnit::Count).unwrap();
+        assert!(registry.counter("Custom/Jobs", MetricUnit::Count).is_ok());
+        assert!(matches!(
+            registry.gauge("Custom/Jobs", MetricUnit::Count),
+            Err(Error::InvalidMetric(_))
+        ));
+        assert!(matches!(
+            registry.summary("Custom/Jobs", MetricUnit::Count),
+            Err(Error::InvalidMetric(_))
+        ));
+        assert!(matches!(
+            registry.counter("Custom/Jobs", MetricUnit::Bytes),
+            Err(Error::InvalidMetric(_))
+        ));
+        assert!(matches!(
+            registry.counter("Custom/\0Jobs", MetricUnit::Count),
+            Err(Error::NulError(_))
+        ));
+    }
+
+    #[test]
+    fn non_finite_values_are_dropped() {
+        let registry = MetricRegistry::default();
+        let counter = registry.counter("Custom/Count", MetricUnit::Count).unwrap();
+        let gauge = registry.gauge("Custom/Gauge", MetricUnit::Percent).unwrap();
+        let summary = registry
+            .summary("Custom/Summary", MetricUnit::Seconds)
+            .unwrap();
+        for value in &[f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            counter.add(*value);
+            gauge.set(*value);
+            summary.record(*value);
+        }
+        assert!(drained(&registry).is_empty());
+
+        counter.add(1.0);
+        counter.add(f64::NAN);
+        gauge.set(50.0);
+        gauge.set(f64::INFINITY);
+        assert_eq!(
+            drained(&registry),
+            vec![
+                ("Custom/Count[count]".to_string(), 1.0, MetricUnit::Count),
+                (
+                    "Custom/Gauge[percent]".to_string(),
+                    50.0,
+                    MetricUnit::Percent
+                ),
+            ]
+        );
+    }
+}
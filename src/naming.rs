@@ -0,0 +1,364 @@
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use log::{debug, warn};
+#[cfg(feature = "regex")]
+use regex::Regex;
+
+use crate::attributes::matches;
+#[cfg(feature = "regex")]
+use crate::error::{Error, Result};
+
+/// The name given to transactions once an app has reached its limit of
+/// unique transaction names.
+const OTHER_TRANSACTION_NAME: &str = "Other";
+
+/// The placeholder which replaces IDs collapsed by
+/// `NamingRules::collapse_ids`.
+const ID_PLACEHOLDER: &str = "*";
+
+/// Rules applied to every transaction name before it is sent to New
+/// Relic, to prevent metric grouping issues.
+///
+/// New Relic aggregates metrics by transaction name, so names containing
+/// IDs, such as the raw path `/users/12345`, create a new metric for every
+/// ID and quickly exhaust New Relic's limits. These rules rewrite such
+/// names, and can cap the number of unique names an app reports.
+///
+/// Rules are applied in the order they are added, each to the result of
+/// the previous rule. Names are split into segments on `/`.
+///
+/// The rules are configured using `AppBuilder::naming_rules`, and apply to
+/// the names given to `App::web_transaction`, `App::non_web_transaction`
+/// and `Transaction::name`.
+///
+/// Example:
+///
+/// ```rust
+/// use newrelic::NamingRules;
+///
+/// let rules = NamingRules::new()
+///     .collapse_ids()
+///     .replace_segment("*@*", "{email}")
+///     .max_unique_names(500);
+///
+/// assert_eq!(rules.apply("/users/12345/orders"), "/users/*/orders");
+/// assert_eq!(
+///     rules.apply("/orders/0b7c2a9e-3f4d-4c8e-9a6b-1d2e3f4a5b6c"),
+///     "/orders/*"
+/// );
+/// assert_eq!(rules.apply("/invite/someone@example.com"), "/invite/{email}");
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct NamingRules {
+    rules: Vec<Rule>,
+    max_unique_names: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+enum Rule {
+    /// Replace numeric and UUID segments with a placeholder.
+    CollapseIds,
+    /// Replace segments matching a glob pattern.
+    Segment {
+        pattern: String,
+        replacement: String,
+    },
+    /// Replace every match of a regular expression.
+    #[cfg(feature = "regex")]
+    Regex { regex: Regex, replacement: String },
+}
+
+impl NamingRules {
+    /// Create a set of rules which leaves names unchanged.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace every segment consisting only of digits, or which is a
+    /// UUID, with `*`.
+    pub fn collapse_ids(mut self) -> Self {
+        self.rules.push(Rule::CollapseIds);
+        self
+    }
+
+    /// Replace every segment matching the glob `pattern` with
+    /// `replacement`.
+    ///
+    /// In the pattern, `*` matches any sequence of characters, including
+    /// none, as for `AttributeFilter`.
+    pub fn replace_segment(
+        mut self,
+        pattern: impl Into<String>,
+        replacement: impl Into<String>,
+    ) -> Self {
+        self.rules.push(Rule::Segment {
+            pattern: pattern.into(),
+            replacement: replacement.into(),
+        });
+        self
+    }
+
+    /// Replace every match of the regular expression `pattern` with
+    /// `replacement`, which may refer to capture groups as described by
+    /// `regex::Regex::replace_all`.
+    ///
+    /// Returns `Error::InvalidConfig` if the pattern isn't a valid regular
+    /// expression.
+    #[cfg(feature = "regex")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "regex")))]
+    pub fn replace(mut self, pattern: &str, replacement: impl Into<String>) -> Result<Self> {
+        let regex = Regex::new(pattern)
+            .map_err(|e| Error::InvalidConfig(format!("invalid transaction naming rule: {}", e)))?;
+        self.rules.push(Rule::Regex {
+            regex,
+            replacement: replacement.into(),
+        });
+        Ok(self)
+    }
+
+    /// Report at most `limit` unique transaction names.
+    ///
+    /// Once an app has reported `limit` different names, transactions with
+    /// any other name are named `Other` instead, and a warning is logged.
+    /// Names are counted after the other rules are applied, and the names
+    /// of transactions ignored by `IgnoreRules` aren't counted.
+    pub fn max_unique_names(mut self, limit: usize) -> Self {
+        self.max_unique_names = Some(limit);
+        self
+    }
+
+    /// Apply the rewriting rules to `name`.
+    ///
+    /// This doesn't apply the limit on unique names, which depends on the
+    /// names an app has already reported.
+    pub fn apply<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        let mut name = Cow::Borrowed(name);
+        for rule in &self.rules {
+            let renamed = match rule {
+                Rule::CollapseIds => replace_segments(&name, |segment| {
+                    if is_id(segment) {
+                        Some(ID_PLACEHOLDER)
+                    } else {
+                        None
+                    }
+                }),
+                Rule::Segment {
+                    pattern,
+                    replacement,
+                } => replace_segments(&name, |segment| {
+                    if matches(pattern, segment) {
+                        Some(replacement.as_str())
+                    } else {
+                        None
+                    }
+                }),
+                #[cfg(feature = "regex")]
+                Rule::Regex { regex, replacement } => {
+                    match regex.replace_all(&name, replacement.as_str()) {
+                        Cow::Owned(renamed) => Some(renamed),
+                        Cow::Borrowed(_) => None,
+                    }
+                }
+            };
+            if let Some(renamed) = renamed {
+                name = Cow::Owned(renamed);
+            }
+        }
+        name
+    }
+}
+
+/// Replace the segments of `name` for which `replacement` returns a value,
+/// or return `None` if none were replaced.
+fn replace_segments<'r>(
+    name: &str,
+    replacement: impl Fn(&str) -> Option<&'r str>,
+) -> Option<String> {
+    let mut replaced = false;
+    let segments: Vec<&str> = name
+        .split('/')
+        .map(|segment| match replacement(segment) {
+            Some(new) if !segment.is_empty() => {
+                replaced = true;
+                new
+            }
+            _ => segment,
+        })
+        .collect();
+    if replaced {
+        Some(segments.join("/"))
+    } else {
+        None
+    }
+}
+
+/// Whether a segment is a numeric ID or a UUID.
+fn is_id(segment: &str) -> bool {
+    let bytes = segment.as_bytes();
+    let numeric = !bytes.is_empty() && bytes.iter().all(u8::is_ascii_digit);
+    let uuid = bytes.len() == 36
+        && bytes.iter().enumerate().all(|(i, b)| match i {
+            8 | 13 | 18 | 23 => *b == b'-',
+            _ => b.is_ascii_hexdigit(),
+        });
+    numeric || uuid
+}
+
+/// Applies an app's naming rules, tracking the unique names it has
+/// reported.
+#[derive(Debug, Default)]
+pub(crate) struct TransactionNamer {
+    rules: NamingRules,
+    names: Mutex<HashSet<String>>,
+    limit_reached: AtomicBool,
+}
+
+impl TransactionNamer {
+    pub(crate) fn new(rules: NamingRules) -> Self {
+        TransactionNamer {
+            rules,
+            names: Mutex::new(HashSet::new()),
+            limit_reached: AtomicBool::new(false),
+        }
+    }
+
+    /// Apply the rewriting rules to the transaction name `name`.
+    pub(crate) fn rename<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        let renamed = self.rules.apply(name);
+        if renamed != name {
+            debug!("Renamed transaction {} to {}", name, renamed);
+        }
+        renamed
+    }
+
+    /// Apply the limit on unique names to `renamed`, the result of
+    /// `rename`, counting it towards the limit.
+    ///
+    /// Only names which are reported should be passed here, so that the
+    /// names of ignored transactions don't use up the limit.
+    pub(crate) fn limit<'a>(&self, renamed: Cow<'a, str>) -> Cow<'a, str> {
+        let limit = match self.rules.max_unique_names {
+            Some(limit) => limit,
+            None => return renamed,
+        };
+        let mut names = self.names.lock().unwrap();
+        if names.contains(renamed.as_ref()) {
+            return renamed;
+        }
+        if names.len() >= limit {
+            if !self.limit_reached.swap(true, Ordering::Relaxed) {
+                warn!(
+                    "Reached the limit of {} unique transaction names, reporting new names as {}",
+                    limit, OTHER_TRANSACTION_NAME
+                );
+            }
+            debug!(
+                "Renamed transaction {} to {}",
+                renamed, OTHER_TRANSACTION_NAME
+            );
+            return Cow::Borrowed(OTHER_TRANSACTION_NAME);
+        }
+        names.insert(renamed.to_string());
+        renamed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids() {
+        assert!(is_id("0"));
+        assert!(is_id("12345"));
+        assert!(is_id("0b7c2a9e-3f4d-4c8e-9a6b-1d2e3f4a5b6c"));
+        assert!(is_id("0B7C2A9E-3F4D-4C8E-9A6B-1D2E3F4A5B6C"));
+        assert!(!is_id(""));
+        assert!(!is_id("-1"));
+        assert!(!is_id("12a"));
+        assert!(!is_id("0b7c2a9e3f4d4c8e9a6b1d2e3f4a5b6c"));
+        assert!(!is_id("0b7c2a9e-3f4d-4c8e-9a6b-1d2e3f4a5b6g"));
+        assert!(!is_id("0b7c2a9e-3f4d-4c8e-9a6b1-d2e3f4a5b6c"));
+    }
+
+    #[test]
+    fn replaces_segments() {
+        let everything = |_: &str| Some("x");
+        assert_eq!(
+            replace_segments("/a/b", everything).as_deref(),
+            Some("/x/x")
+        );
+        assert_eq!(
+            replace_segments("a//b/", everything).as_deref(),
+            Some("x//x/")
+        );
+        assert_eq!(replace_segments("/", everything), None);
+        assert_eq!(replace_segments("", everything), None);
+        assert_eq!(replace_segments("/a/b", |_| None), None);
+    }
+
+    #[test]
+    fn rules_apply_in_order() {
+        let name = "/users/12345";
+        let rules = NamingRules::new()
+            .collapse_ids()
+            .replace_segment("users", "people")
+            .replace_segment("*", "{id}");
+        assert_eq!(rules.apply(name), "/{id}/{id}");
+
+        let rules = NamingRules::new()
+            .replace_segment("1*", "{id}")
+            .collapse_ids();
+        assert_eq!(rules.apply(name), "/users/{id}");
+
+        let rules = NamingRules::new()
+            .collapse_ids()
+            .replace_segment("1*", "{id}");
+        assert_eq!(rules.apply(name), "/users/*");
+
+        assert!(matches!(NamingRules::new().apply(name), Cow::Borrowed(_)));
+        assert!(matches!(
+            NamingRules::new().collapse_ids().apply("/users"),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn regex_rules() {
+        let rules = NamingRules::new()
+            .replace(r"^/v\d+/", "/")
+            .unwrap()
+            .collapse_ids();
+        assert_eq!(rules.apply("/v2/users/12"), "/users/*");
+        assert!(matches!(
+            NamingRules::new().replace("(", ""),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn limits_unique_names() {
+        let namer = TransactionNamer::new(NamingRules::new().collapse_ids().max_unique_names(2));
+        let name = |name| namer.limit(namer.rename(name)).into_owned();
+        assert_eq!(name("/users/1"), "/users/*");
+        assert_eq!(name("/orders"), "/orders");
+        assert_eq!(name("/items"), OTHER_TRANSACTION_NAME);
+        assert_eq!(name("/users/2"), "/users/*");
+        assert_eq!(name("/orders"), "/orders");
+        assert_eq!(name("/items"), OTHER_TRANSACTION_NAME);
+
+        let namer = TransactionNamer::new(NamingRules::new());
+        for i in 0..100 {
+            let name = format!("/users/{}", i);
+            assert_eq!(namer.limit(namer.rename(&name)), name);
+        }
+    }
+}
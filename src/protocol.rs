@@ -0,0 +1,993 @@
+//! The protocol spoken between New Relic agents and the daemon.
+//!
+//! Each message is a flatbuffer following the daemon's `protocol.fbs`
+//! schema, preceded by an 8-byte preamble holding its length and format.
+//! The tables below are written by hand in the shape of `flatc`-generated
+//! code, and only cover the parts of the schema this crate uses.
+//!
+//! The agent's side of the protocol is used by the `pure-rust` backend, and
+//! the daemon's side by `testing::FakeDaemon`.
+#![cfg_attr(not(all(feature = "pure-rust", feature = "testing")), allow(dead_code))]
+use std::{
+    fs,
+    io::{self, Read, Write},
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use flatbuffers::{
+    FlatBufferBuilder, Follow, ForwardsUOffset, InvalidFlatbuffer, Push, Table, VOffsetT, Vector,
+    Verifiable, Verifier, WIPOffset,
+};
+
+/// The format given in the preamble of flatbuffers messages.
+const PREAMBLE_FORMAT: u32 = 2;
+
+/// The largest message the daemon accepts.
+const MAX_MESSAGE_LENGTH: usize = 32 * 1024 * 1024;
+
+/// The types of the `data` union of a `Message`.
+const MESSAGE_BODY_APP: u8 = 1;
+const MESSAGE_BODY_APP_REPLY: u8 = 2;
+const MESSAGE_BODY_TRANSACTION: u8 = 3;
+
+/// The vtable offset of the field at `index` within a table.
+const fn field(index: VOffsetT) -> VOffsetT {
+    4 + 2 * index
+}
+
+/// Define a table which wraps a verified `Table`.
+macro_rules! table {
+    ($(#[$meta:meta])* $name:ident) => {
+        $(#[$meta])*
+        struct $name<'a> {
+            table: Table<'a>,
+        }
+
+        impl<'a> Follow<'a> for $name<'a> {
+            type Inner = Self;
+
+            unsafe fn follow(buf: &'a [u8], loc: usize) -> Self {
+                $name {
+                    table: Table::new(buf, loc),
+                }
+            }
+        }
+
+        // Not every table uses every helper.
+        #[allow(dead_code)]
+        impl<'a> $name<'a> {
+            fn string(&self, index: VOffsetT) -> Option<&'a str> {
+                // Safety: the table was verified as part of its message.
+                unsafe { self.table.get::<ForwardsUOffset<&str>>(field(index), None) }
+            }
+
+            fn scalar<T: Follow<'a, Inner = T> + Copy + 'a>(&self, index: VOffsetT, default: T) -> T {
+                // Safety: the table was verified as part of its message.
+                unsafe { self.table.get::<T>(field(index), Some(default)) }.unwrap_or(default)
+            }
+
+            fn tables<T: Follow<'a, Inner = T> + 'a>(&self, index: VOffsetT) -> Vec<T> {
+                // Safety: the table was verified as part of its message.
+                unsafe {
+                    self.table
+                        .get::<ForwardsUOffset<Vector<'a, ForwardsUOffset<T>>>>(field(index), None)
+                }
+                .map(|tables| tables.iter().collect())
+                .unwrap_or_default()
+            }
+        }
+    };
+}
+
+/// Connect to the daemon's socket. On Linux, a socket starting with '@' is
+/// the name of an abstract socket.
+pub(crate) fn connect(socket: &str) -> io::Result<UnixStream> {
+    match socket.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+            UnixStream::connect_addr(&SocketAddr::from_abstract_name(name)?)
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => Err(abstract_unsupported()),
+        None => UnixStream::connect(socket),
+    }
+}
+
+/// Listen on a daemon socket, replacing any stale socket file left at the
+/// same path.
+pub(crate) fn bind(socket: &str) -> io::Result<UnixListener> {
+    match socket.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+            UnixListener::bind_addr(&SocketAddr::from_abstract_name(name)?)
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => Err(abstract_unsupported()),
+        None => {
+            let stale = fs::symlink_metadata(socket)
+                .map(|metadata| metadata.file_type().is_socket())
+                .unwrap_or(false);
+            if stale {
+                fs::remove_file(socket)?;
+            }
+            UnixListener::bind(socket)
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn abstract_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "abstract sockets are only supported on Linux",
+    )
+}
+
+/// Write `message` preceded by its preamble.
+pub(crate) fn write_message(writer: &mut impl Write, message: &[u8]) -> io::Result<()> {
+    if message.len() > MAX_MESSAGE_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("message is too large ({} bytes)", message.len()),
+        ));
+    }
+    let mut buffer = Vec::with_capacity(message.len() + 8);
+    buffer.extend_from_slice(&(message.len() as u32).to_le_bytes());
+    buffer.extend_from_slice(&PREAMBLE_FORMAT.to_le_bytes());
+    buffer.extend_from_slice(message);
+    writer.write_all(&buffer)?;
+    writer.flush()
+}
+
+/// Read a message, checking its preamble.
+pub(crate) fn read_message(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut preamble = [0; 8];
+    reader.read_exact(&mut preamble)?;
+    let length = u32::from_le_bytes([preamble[0], preamble[1], preamble[2], preamble[3]]) as usize;
+    let format = u32::from_le_bytes([preamble[4], preamble[5], preamble[6], preamble[7]]);
+    if format != PREAMBLE_FORMAT {
+        return Err(invalid_data(format!(
+            "unexpected message format {}",
+            format
+        )));
+    }
+    if length > MAX_MESSAGE_LENGTH {
+        return Err(invalid_data(format!(
+            "message is too large ({} bytes)",
+            length
+        )));
+    }
+    let mut message = vec![0; length];
+    reader.read_exact(&mut message)?;
+    Ok(message)
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// The information identifying an app to the daemon.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct AppInfo {
+    pub(crate) license: String,
+    pub(crate) app_name: String,
+    pub(crate) agent_language: String,
+    pub(crate) agent_version: String,
+    pub(crate) high_security: bool,
+    pub(crate) redirect_collector: String,
+    /// The environment, as a JSON array of `[name, value]` pairs.
+    pub(crate) environment: String,
+    /// The agent's settings, as a JSON object.
+    pub(crate) settings: String,
+    /// The app's labels, as a JSON array of objects with `label_type` and
+    /// `label_value` keys.
+    pub(crate) labels: String,
+    pub(crate) display_host: String,
+    pub(crate) supported_security_policies: String,
+    pub(crate) host: String,
+}
+
+/// Encode a query asking the daemon for the state of an app.
+///
+/// `agent_run_id` is the run ID the app was previously given, if any.
+pub(crate) fn encode_app(agent_run_id: Option<&str>, app: &AppInfo) -> Vec<u8> {
+    let mut fbb = FlatBufferBuilder::new();
+    let host = fbb.create_string(&app.host);
+    let supported_security_policies = fbb.create_string(&app.supported_security_policies);
+    let display_host = fbb.create_string(&app.display_host);
+    let labels = fbb.create_string(&app.labels);
+    let settings = fbb.create_string(&app.settings);
+    let environment = fbb.create_string(&app.environment);
+    let redirect_collector = fbb.create_string(&app.redirect_collector);
+    let agent_version = fbb.create_string(&app.agent_version);
+    let agent_language = fbb.create_string(&app.agent_language);
+    let app_name = fbb.create_string(&app.app_name);
+    let license = fbb.create_string(&app.license);
+
+    let start = fbb.start_table();
+    fbb.push_slot_always(field(12), host);
+    fbb.push_slot_always(field(11), supported_security_policies);
+    fbb.push_slot_always(field(9), display_host);
+    fbb.push_slot_always(field(8), labels);
+    fbb.push_slot_always(field(7), settings);
+    fbb.push_slot_always(field(6), environment);
+    fbb.push_slot_always(field(5), redirect_collector);
+    fbb.push_slot(field(4), app.high_security, false);
+    fbb.push_slot_always(field(3), agent_version);
+    fbb.push_slot_always(field(2), agent_language);
+    fbb.push_slot_always(field(1), app_name);
+    fbb.push_slot_always(field(0), license);
+    let body = fbb.end_table(start);
+
+    finish_message(fbb, agent_run_id, MESSAGE_BODY_APP, body)
+}
+
+/// The state of an app, as reported by the daemon.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AppStatus {
+    Unknown,
+    Disconnected,
+    InvalidLicense,
+    Connected,
+    StillValid,
+}
+
+impl AppStatus {
+    fn to_i8(self) -> i8 {
+        match self {
+            AppStatus::Unknown => 0,
+            AppStatus::Disconnected => 1,
+            AppStatus::InvalidLicense => 2,
+            AppStatus::Connected => 3,
+            AppStatus::StillValid => 4,
+        }
+    }
+
+    fn from_i8(status: i8) -> Option<Self> {
+        match status {
+            0 => Some(AppStatus::Unknown),
+            1 => Some(AppStatus::Disconnected),
+            2 => Some(AppStatus::InvalidLicense),
+            3 => Some(AppStatus::Connected),
+            4 => Some(AppStatus::StillValid),
+            _ => None,
+        }
+    }
+}
+
+/// The daemon's reply to an app query.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct AppReply {
+    pub(crate) status: AppStatus,
+    /// The collector's raw JSON reply, if the app is connected.
+    pub(crate) connect_reply: Option<String>,
+}
+
+/// Decode the daemon's reply to an app query.
+pub(crate) fn decode_app_reply(message: &[u8]) -> io::Result<AppReply> {
+    let message = flatbuffers::root::<MessageTable>(message)
+        .map_err(|error| invalid_data(error.to_string()))?;
+    if message.data_type() != MESSAGE_BODY_APP_REPLY {
+        return Err(invalid_data(format!(
+            "unexpected message type {}",
+            message.data_type()
+        )));
+    }
+    let reply = message
+        .data::<AppReplyTable>()
+        .ok_or_else(|| invalid_data("app reply is missing a body"))?;
+    let status = AppStatus::from_i8(reply.status())
+        .ok_or_else(|| invalid_data(format!("unknown app status {}", reply.status())))?;
+    Ok(AppReply {
+        status,
+        connect_reply: reply.connect_reply().map(str::to_string),
+    })
+}
+
+/// Encode the daemon's reply to an app query.
+pub(crate) fn encode_app_reply(reply: &AppReply) -> Vec<u8> {
+    let mut fbb = FlatBufferBuilder::new();
+    let connect_reply = reply
+        .connect_reply
+        .as_deref()
+        .map(|connect_reply| fbb.create_string(connect_reply));
+    let security_policies = fbb.create_string("{}");
+    let connect_timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+
+    let start = fbb.start_table();
+    fbb.push_slot(field(3), connect_timestamp, 0);
+    fbb.push_slot_always(field(2), security_policies);
+    if let Some(connect_reply) = connect_reply {
+        fbb.push_slot_always(field(1), connect_reply);
+    }
+    fbb.push_slot(field(5), DEFAULT_SAMPLING_TARGET, 0);
+    fbb.push_slot(field(4), DEFAULT_HARVEST_FREQUENCY, 0);
+    fbb.push_slot(field(0), reply.status.to_i8(), 0);
+    let body = fbb.end_table(start);
+
+    finish_message(fbb, None, MESSAGE_BODY_APP_REPLY, body)
+}
+
+/// The harvest frequency and sampling target given in app replies, matching
+/// the collector's defaults.
+const DEFAULT_HARVEST_FREQUENCY: u16 = 60;
+const DEFAULT_SAMPLING_TARGET: u16 = 10;
+
+/// A message sent by an agent to the daemon.
+#[derive(Debug, Clone)]
+pub(crate) enum Message {
+    App(AppInfo),
+    Transaction(TransactionData),
+    /// A message of a type the daemon side doesn't handle, such as span
+    /// batches.
+    Other(u8),
+}
+
+/// Decode a message sent by an agent, along with its agent run ID.
+pub(crate) fn decode_message(message: &[u8]) -> io::Result<(Option<String>, Message)> {
+    let message = flatbuffers::root::<MessageTable>(message)
+        .map_err(|error| invalid_data(error.to_string()))?;
+    let agent_run_id = message.agent_run_id().map(str::to_string);
+    let body = match message.data_type() {
+        MESSAGE_BODY_APP => {
+            let app = message
+                .data::<AppTable>()
+                .ok_or_else(|| invalid_data("app query is missing a body"))?;
+            Message::App(app.decode())
+        }
+        MESSAGE_BODY_TRANSACTION => {
+            let txn = message
+                .data::<TransactionTable>()
+                .ok_or_else(|| invalid_data("transaction is missing a body"))?;
+            Message::Transaction(txn.decode())
+        }
+        other => Message::Other(other),
+    };
+    Ok((agent_run_id, body))
+}
+
+/// The data of a metric, in seconds.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct MetricData {
+    pub(crate) count: f64,
+    pub(crate) total: f64,
+    pub(crate) exclusive: f64,
+    pub(crate) min: f64,
+    pub(crate) max: f64,
+    pub(crate) sum_squares: f64,
+    pub(crate) scoped: bool,
+    pub(crate) forced: bool,
+}
+
+impl Push for MetricData {
+    type Output = MetricData;
+
+    unsafe fn push(&self, dst: &mut [u8], _written_len: usize) {
+        let values = [
+            self.count,
+            self.total,
+            self.exclusive,
+            self.min,
+            self.max,
+            self.sum_squares,
+        ];
+        for (dst, value) in dst.chunks_exact_mut(8).zip(values.iter()) {
+            dst.copy_from_slice(&value.to_le_bytes());
+        }
+        dst[48] = self.scoped as u8;
+        dst[49] = self.forced as u8;
+        for byte in &mut dst[50..56] {
+            *byte = 0;
+        }
+    }
+}
+
+impl<'a> Follow<'a> for MetricData {
+    type Inner = MetricData;
+
+    unsafe fn follow(buf: &'a [u8], loc: usize) -> Self {
+        let bytes = &buf[loc..loc + METRIC_DATA_SIZE];
+        let value = |index: usize| {
+            let mut value = [0; 8];
+            value.copy_from_slice(&bytes[index * 8..index * 8 + 8]);
+            f64::from_le_bytes(value)
+        };
+        MetricData {
+            count: value(0),
+            total: value(1),
+            exclusive: value(2),
+            min: value(3),
+            max: value(4),
+            sum_squares: value(5),
+            scoped: bytes[48] != 0,
+            forced: bytes[49] != 0,
+        }
+    }
+}
+
+impl Verifiable for MetricData {
+    fn run_verifier(v: &mut Verifier, pos: usize) -> Result<(), InvalidFlatbuffer> {
+        v.in_buffer::<[u8; METRIC_DATA_SIZE]>(pos)
+    }
+}
+
+/// The size of the `MetricData` struct within a flatbuffer.
+const METRIC_DATA_SIZE: usize = 56;
+
+/// A metric recorded by a transaction.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Metric {
+    pub(crate) name: String,
+    pub(crate) data: MetricData,
+}
+
+/// A noticed error, with the JSON expected by the collector.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ErrorData {
+    pub(crate) priority: i32,
+    pub(crate) data: String,
+}
+
+/// The data sent to the daemon at the end of a transaction.
+///
+/// Events are given as the JSON expected by the collector.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct TransactionData {
+    pub(crate) name: String,
+    pub(crate) uri: String,
+    pub(crate) pid: i32,
+    pub(crate) txn_event: Option<String>,
+    pub(crate) metrics: Vec<Metric>,
+    pub(crate) errors: Vec<ErrorData>,
+    pub(crate) custom_events: Vec<String>,
+    pub(crate) error_events: Vec<String>,
+    pub(crate) sampling_priority: f64,
+}
+
+/// Encode the data of a finished transaction.
+pub(crate) fn encode_transaction(agent_run_id: &str, txn: &TransactionData) -> Vec<u8> {
+    let mut fbb = FlatBufferBuilder::new();
+
+    let error_events = event_vector(&mut fbb, &txn.error_events);
+    let custom_events = event_vector(&mut fbb, &txn.custom_events);
+    let errors = if txn.errors.is_empty() {
+        None
+    } else {
+        let errors: Vec<_> = txn
+            .errors
+            .iter()
+            .map(|error| {
+                let data = fbb.create_string(&error.data);
+                let start = fbb.start_table();
+                fbb.push_slot_always(field(1), data);
+                fbb.push_slot(field(0), error.priority, 0);
+                fbb.end_table(start)
+            })
+            .collect();
+        Some(fbb.create_vector(&errors))
+    };
+    let metrics = if txn.metrics.is_empty() {
+        None
+    } else {
+        let metrics: Vec<_> = txn
+            .metrics
+            .iter()
+            .map(|metric| {
+                let name = fbb.create_string(&metric.name);
+                let start = fbb.start_table();
+                fbb.push_slot_always(field(1), metric.data);
+                fbb.push_slot_always(field(0), name);
+                fbb.end_table(start)
+            })
+            .collect();
+        Some(fbb.create_vector(&metrics))
+    };
+    let txn_event = txn.txn_event.as_deref().map(|data| event(&mut fbb, data));
+    let uri = fbb.create_string(&txn.uri);
+    let name = fbb.create_string(&txn.name);
+
+    let start = fbb.start_table();
+    fbb.push_slot(field(11), txn.sampling_priority, 0.0);
+    if let Some(error_events) = error_events {
+        fbb.push_slot_always(field(10), error_events);
+    }
+    if let Some(custom_events) = custom_events {
+        fbb.push_slot_always(field(8), custom_events);
+    }
+    if let Some(errors) = errors {
+        fbb.push_slot_always(field(6), errors);
+    }
+    if let Some(metrics) = metrics {
+        fbb.push_slot_always(field(5), metrics);
+    }
+    if let Some(txn_event) = txn_event {
+        fbb.push_slot_always(field(4), txn_event);
+    }
+    fbb.push_slot(field(3), txn.pid, 0);
+    fbb.push_slot_always(field(1), uri);
+    fbb.push_slot_always(field(0), name);
+    let body = fbb.end_table(start);
+
+    finish_message(fbb, Some(agent_run_id), MESSAGE_BODY_TRANSACTION, body)
+}
+
+type TableOffset = WIPOffset<flatbuffers::TableFinishedWIPOffset>;
+
+fn event(fbb: &mut FlatBufferBuilder, data: &str) -> TableOffset {
+    let data = fbb.create_string(data);
+    let start = fbb.start_table();
+    fbb.push_slot_always(field(0), data);
+    fbb.end_table(start)
+}
+
+fn event_vector<'a>(
+    fbb: &mut FlatBufferBuilder<'a>,
+    events: &[String],
+) -> Option<WIPOffset<Vector<'a, ForwardsUOffset<flatbuffers::TableFinishedWIPOffset>>>> {
+    if events.is_empty() {
+        return None;
+    }
+    let events: Vec<_> = events.iter().map(|data| event(fbb, data)).collect();
+    Some(fbb.create_vector(&events))
+}
+
+fn finish_message(
+    mut fbb: FlatBufferBuilder,
+    agent_run_id: Option<&str>,
+    data_type: u8,
+    body: TableOffset,
+) -> Vec<u8> {
+    let agent_run_id = agent_run_id
+        .filter(|id| !id.is_empty())
+        .map(|id| fbb.create_string(id));
+    let start = fbb.start_table();
+    fbb.push_slot_always(field(2), body);
+    fbb.push_slot(field(1), data_type, 0);
+    if let Some(agent_run_id) = agent_run_id {
+        fbb.push_slot_always(field(0), agent_run_id);
+    }
+    let message = fbb.end_table(start);
+    fbb.finish(message, None);
+    fbb.finished_data().to_vec()
+}
+
+table!(
+    /// The root table of every message.
+    MessageTable
+);
+
+impl Verifiable for MessageTable<'_> {
+    fn run_verifier(v: &mut Verifier, pos: usize) -> Result<(), InvalidFlatbuffer> {
+        v.visit_table(pos)?
+            .visit_field::<ForwardsUOffset<&str>>("agent_run_id", field(0), false)?
+            .visit_union::<u8, _>(
+                "data_type",
+                field(1),
+                "data",
+                field(2),
+                false,
+                |data_type, v, pos| match data_type {
+                    MESSAGE_BODY_APP => {
+                        v.verify_union_variant::<ForwardsUOffset<AppTable>>("MessageBody::App", pos)
+                    }
+                    MESSAGE_BODY_TRANSACTION => v
+                        .verify_union_variant::<ForwardsUOffset<TransactionTable>>(
+                            "MessageBody::Transaction",
+                            pos,
+                        ),
+                    MESSAGE_BODY_APP_REPLY => v
+                        .verify_union_variant::<ForwardsUOffset<AppReplyTable>>(
+                            "MessageBody::AppReply",
+                            pos,
+                        ),
+                    _ => Ok(()),
+                },
+            )?
+            .finish();
+        Ok(())
+    }
+}
+
+impl<'a> MessageTable<'a> {
+    fn agent_run_id(&self) -> Option<&'a str> {
+        self.string(0)
+    }
+
+    fn data_type(&self) -> u8 {
+        self.scalar(1, 0)
+    }
+
+    /// The body of the message, which must have been checked to be a `T`
+    /// using `data_type`.
+    fn data<T: Follow<'a, Inner = T> + 'a>(&self) -> Option<T> {
+        // Safety: the message was verified by `flatbuffers::root`, including
+        // the body for every type this is called with.
+        unsafe { self.table.get::<ForwardsUOffset<T>>(field(2), None) }
+    }
+}
+
+table!(
+    /// The body of the daemon's reply to an app query.
+    AppReplyTable
+);
+
+impl Verifiable for AppReplyTable<'_> {
+    fn run_verifier(v: &mut Verifier, pos: usize) -> Result<(), InvalidFlatbuffer> {
+        v.visit_table(pos)?
+            .visit_field::<i8>("status", field(0), false)?
+            .visit_field::<ForwardsUOffset<&str>>("connect_reply", field(1), false)?
+            .visit_field::<ForwardsUOffset<&str>>("security_policies", field(2), false)?
+            .visit_field::<u64>("connect_timestamp", field(3), false)?
+            .visit_field::<u16>("harvest_frequency", field(4), false)?
+            .visit_field::<u16>("sampling_target", field(5), false)?
+            .finish();
+        Ok(())
+    }
+}
+
+impl<'a> AppReplyTable<'a> {
+    fn status(&self) -> i8 {
+        self.scalar(0, 0)
+    }
+
+    fn connect_reply(&self) -> Option<&'a str> {
+        self.string(1)
+    }
+}
+
+table!(
+    /// The body of an agent's app query.
+    AppTable
+);
+
+impl Verifiable for AppTable<'_> {
+    fn run_verifier(v: &mut Verifier, pos: usize) -> Result<(), InvalidFlatbuffer> {
+        v.visit_table(pos)?
+            .visit_field::<ForwardsUOffset<&str>>("license", field(0), false)?
+            .visit_field::<ForwardsUOffset<&str>>("app_name", field(1), false)?
+            .visit_field::<ForwardsUOffset<&str>>("agent_language", field(2), false)?
+            .visit_field::<ForwardsUOffset<&str>>("agent_version", field(3), false)?
+            .visit_field::<bool>("high_security", field(4), false)?
+            .visit_field::<ForwardsUOffset<&str>>("redirect_collector", field(5), false)?
+            .visit_field::<ForwardsUOffset<&str>>("environment", field(6), false)?
+            .visit_field::<ForwardsUOffset<&str>>("settings", field(7), false)?
+            .visit_field::<ForwardsUOffset<&str>>("labels", field(8), false)?
+            .visit_field::<ForwardsUOffset<&str>>("display_host", field(9), false)?
+            .visit_field::<ForwardsUOffset<&str>>("security_policy_token", field(10), false)?
+            .visit_field::<ForwardsUOffset<&str>>("supported_security_policies", field(11), false)?
+            .visit_field::<ForwardsUOffset<&str>>("host", field(12), false)?
+            .finish();
+        Ok(())
+    }
+}
+
+impl AppTable<'_> {
+    fn decode(&self) -> AppInfo {
+        let string = |index| self.string(index).unwrap_or_default().to_string();
+        AppInfo {
+            license: string(0),
+            app_name: string(1),
+            agent_language: string(2),
+            agent_version: string(3),
+            high_security: self.scalar(4, false),
+            redirect_collector: string(5),
+            environment: string(6),
+            settings: string(7),
+            labels: string(8),
+            display_host: string(9),
+            supported_security_policies: string(11),
+            host: string(12),
+        }
+    }
+}
+
+table!(
+    /// The body of a transaction sent by an agent.
+    TransactionTable
+);
+
+impl Verifiable for TransactionTable<'_> {
+    fn run_verifier(v: &mut Verifier, pos: usize) -> Result<(), InvalidFlatbuffer> {
+        v.visit_table(pos)?
+            .visit_field::<ForwardsUOffset<&str>>("name", field(0), false)?
+            .visit_field::<ForwardsUOffset<&str>>("uri", field(1), false)?
+            .visit_field::<ForwardsUOffset<&str>>("synthetics_resource_id", field(2), false)?
+            .visit_field::<i32>("pid", field(3), false)?
+            .visit_field::<ForwardsUOffset<EventTable>>("txn_event", field(4), false)?
+            .visit_field::<ForwardsUOffset<Vector<ForwardsUOffset<MetricTable>>>>(
+                "metrics",
+                field(5),
+                false,
+            )?
+            .visit_field::<ForwardsUOffset<Vector<ForwardsUOffset<ErrorTable>>>>(
+                "errors",
+                field(6),
+                false,
+            )?
+            .visit_field::<ForwardsUOffset<Vector<ForwardsUOffset<EventTable>>>>(
+                "custom_events",
+                field(8),
+                false,
+            )?
+            .visit_field::<ForwardsUOffset<Vector<ForwardsUOffset<EventTable>>>>(
+                "error_events",
+                field(10),
+                false,
+            )?
+            .visit_field::<f64>("sampling_priority", field(11), false)?
+            .finish();
+        Ok(())
+    }
+}
+
+impl TransactionTable<'_> {
+    fn decode(&self) -> TransactionData {
+        let events = |index| {
+            self.tables::<EventTable>(index)
+                .iter()
+                .map(EventTable::data)
+                .collect()
+        };
+        TransactionData {
+            name: self.string(0).unwrap_or_default().to_string(),
+            uri: self.string(1).unwrap_or_default().to_string(),
+            pid: self.scalar(3, 0),
+            txn_event: self.event(4),
+            metrics: self
+                .tables::<MetricTable>(5)
+                .iter()
+                .map(|metric| Metric {
+                    name: metric.string(0).unwrap_or_default().to_string(),
+                    data: metric.data(),
+                })
+                .collect(),
+            errors: self
+                .tables::<ErrorTable>(6)
+                .iter()
+                .map(|error| ErrorData {
+                    priority: error.scalar(0, 0),
+                    data: error.string(1).unwrap_or_default().to_string(),
+                })
+                .collect(),
+            custom_events: events(8),
+            error_events: events(10),
+            sampling_priority: self.scalar(11, 0.0),
+        }
+    }
+
+    fn event(&self, index: VOffsetT) -> Option<String> {
+        // Safety: the transaction was verified as part of its message.
+        unsafe {
+            self.table
+                .get::<ForwardsUOffset<EventTable>>(field(index), None)
+        }
+        .map(|event| event.data())
+    }
+}
+
+table!(
+    /// An event, holding the JSON expected by the collector.
+    EventTable
+);
+
+impl Verifiable for EventTable<'_> {
+    fn run_verifier(v: &mut Verifier, pos: usize) -> Result<(), InvalidFlatbuffer> {
+        v.visit_table(pos)?
+            .visit_field::<ForwardsUOffset<&str>>("data", field(0), false)?
+            .finish();
+        Ok(())
+    }
+}
+
+impl EventTable<'_> {
+    fn data(&self) -> String {
+        self.string(0).unwrap_or_default().to_string()
+    }
+}
+
+table!(
+    /// A metric recorded by a transaction.
+    MetricTable
+);
+
+impl Verifiable for MetricTable<'_> {
+    fn run_verifier(v: &mut Verifier, pos: usize) -> Result<(), InvalidFlatbuffer> {
+        v.visit_table(pos)?
+            .visit_field::<ForwardsUOffset<&str>>("name", field(0), false)?
+            .visit_field::<MetricData>("data", field(1), false)?
+            .finish();
+        Ok(())
+    }
+}
+
+impl MetricTable<'_> {
+    fn data(&self) -> MetricData {
+        self.scalar(1, MetricData::default())
+    }
+}
+
+table!(
+    /// A noticed error, holding the JSON expected by the collector.
+    ErrorTable
+);
+
+impl Verifiable for ErrorTable<'_> {
+    fn run_verifier(v: &mut Verifier, pos: usize) -> Result<(), InvalidFlatbuffer> {
+        v.visit_table(pos)?
+            .visit_field::<i32>("priority", field(0), false)?
+            .visit_field::<ForwardsUOffset<&str>>("data", field(1), false)?
+            .finish();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn app() -> AppInfo {
+        AppInfo {
+            license: "0123456789012345678901234567890123456789".to_string(),
+            app_name: "my app".to_string(),
+            agent_language: "c".to_string(),
+            agent_version: "1.3.0".to_string(),
+            high_security: true,
+            redirect_collector: "collector.newrelic.com".to_string(),
+            environment: "[]".to_string(),
+            settings: r#"{"span_events.enabled":true}"#.to_string(),
+            labels: r#"[{"label_type":"env","label_value":"prod"}]"#.to_string(),
+            display_host: "web-1".to_string(),
+            supported_security_policies: "{}".to_string(),
+            host: "host-1".to_string(),
+        }
+    }
+
+    fn transaction() -> TransactionData {
+        TransactionData {
+            name: "WebTransaction/Action/index".to_string(),
+            uri: "<unknown>".to_string(),
+            pid: 1234,
+            txn_event: Some(r#"[{"type":"Transaction"},{},{}]"#.to_string()),
+            metrics: vec![
+                Metric {
+                    name: "WebTransaction".to_string(),
+                    data: MetricData {
+                        count: 1.0,
+                        total: 0.5,
+                        exclusive: 0.25,
+                        min: 0.5,
+                        max: 0.5,
+                        sum_squares: 0.25,
+                        scoped: false,
+                        forced: true,
+                    },
+                },
+                Metric {
+                    name: "Custom/work".to_string(),
+                    data: MetricData {
+                        count: 2.0,
+                        scoped: true,
+                        ..MetricData::default()
+                    },
+                },
+            ],
+            errors: vec![ErrorData {
+                priority: 5,
+                data: r#"[0,"name","message","class",{}]"#.to_string(),
+            }],
+            custom_events: vec![
+                r#"[{"type":"First"},{},{}]"#.to_string(),
+                r#"[{"type":"Second"},{},{}]"#.to_string(),
+            ],
+            error_events: vec![r#"[{"type":"TransactionError"},{},{}]"#.to_string()],
+            sampling_priority: 0.75,
+        }
+    }
+
+    #[test]
+    fn app_round_trip() {
+        let message = encode_app(Some("run-1"), &app());
+        match decode_message(&message).unwrap() {
+            (Some(agent_run_id), Message::App(decoded)) => {
+                assert_eq!(agent_run_id, "run-1");
+                assert_eq!(decoded, app());
+            }
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn app_without_run_id() {
+        let message = encode_app(None, &app());
+        assert!(matches!(
+            decode_message(&message).unwrap(),
+            (None, Message::App(_))
+        ));
+        // An empty run ID is the same as none.
+        let message = encode_app(Some(""), &app());
+        assert!(decode_message(&message).unwrap().0.is_none());
+    }
+
+    #[test]
+    fn app_reply_round_trip() {
+        for status in &[
+            AppStatus::Unknown,
+            AppStatus::Disconnected,
+            AppStatus::InvalidLicense,
+            AppStatus::Connected,
+            AppStatus::StillValid,
+        ] {
+            let reply = AppReply {
+                status: *status,
+                connect_reply: Some(r#"{"agent_run_id":"run-1"}"#.to_string()),
+            };
+            assert_eq!(decode_app_reply(&encode_app_reply(&reply)).unwrap(), reply);
+        }
+        let reply = AppReply {
+            status: AppStatus::Unknown,
+            connect_reply: None,
+        };
+        assert_eq!(decode_app_reply(&encode_app_reply(&reply)).unwrap(), reply);
+    }
+
+    #[test]
+    fn transaction_round_trip() {
+        let message = encode_transaction("run-1", &transaction());
+        match decode_message(&message).unwrap() {
+            (Some(agent_run_id), Message::Transaction(decoded)) => {
+                assert_eq!(agent_run_id, "run-1");
+                assert_eq!(decoded, transaction());
+            }
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn empty_transaction_round_trip() {
+        let data = TransactionData {
+            name: "OtherTransaction/Action/job".to_string(),
+            ..TransactionData::default()
+        };
+        match decode_message(&encode_transaction("run-1", &data)).unwrap() {
+            (_, Message::Transaction(decoded)) => assert_eq!(decoded, data),
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn framing_round_trip() {
+        let first = encode_app(None, &app());
+        let second = encode_transaction("run-1", &transaction());
+        let mut buffer = Vec::new();
+        write_message(&mut buffer, &first).unwrap();
+        write_message(&mut buffer, &second).unwrap();
+
+        let mut reader = Cursor::new(buffer);
+        assert_eq!(read_message(&mut reader).unwrap(), first);
+        assert_eq!(read_message(&mut reader).unwrap(), second);
+        assert!(read_message(&mut reader).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_messages() {
+        assert!(decode_message(b"not a flatbuffer").is_err());
+        assert!(decode_app_reply(&encode_app(None, &app())).is_err());
+        let mut truncated = encode_transaction("run-1", &transaction());
+        truncated.truncate(truncated.len() / 2);
+        assert!(decode_message(&truncated).is_err());
+    }
+}
@@ -1,9 +1,10 @@
-use std::{ffi::CString, os::raw::c_char};
+use std::{ffi::CString, time::Duration};
 
 use log::{debug, error};
-use newrelic_sys as ffi;
 
 use crate::{
+    app::micros,
+    backend::SegmentHandle,
     error::{Error, Result},
     transaction::Transaction,
 };
@@ -24,8 +25,11 @@ use crate::{
 ///
 #[derive(Default)]
 struct SegmentPointer {
-    /// This holds an unsafe reference to a raw Segment.
-    inner: Option<*mut ffi::newrelic_segment_t>,
+    /// The segment's handle within the transaction's backend, or `None` if
+    /// the segment was never started or has ended.
+    inner: Option<SegmentHandle>,
+    /// Whether the segment has ended.
+    ended: bool,
 }
 
 impl SegmentPointer {
@@ -34,7 +38,10 @@ impl SegmentPointer {
         name: impl AsRef<str>,
         category: impl AsRef<str>,
     ) -> Result<Self> {
-        let transaction = transaction.as_ref();
+        let (backend, handle) = match transaction.as_ref().inner() {
+            Some(inner) => inner,
+            None => return Ok(Self::default()),
+        };
         let name = name.as_ref();
         let category = category.as_ref();
         let c_name = CString::new(name);
@@ -42,23 +49,18 @@ impl SegmentPointer {
 
         let pointer = match (c_name, c_category) {
             (Ok(c_name), Ok(c_category)) => {
-                let pointer = unsafe {
-                    ffi::newrelic_start_segment(
-                        transaction.inner,
-                        c_name.as_ptr(),
-                        c_category.as_ptr(),
-                    )
-                };
-                if pointer.is_null() {
-                    error!(
-                        "Could not create segment with name {} due to invalid transaction",
-                        name
-                    );
-                    Err(Error::SegmentStartError)
-                } else {
-                    Ok(Self {
-                        inner: Some(pointer),
-                    })
+                match backend.start_custom_segment(handle, &c_name, &c_category) {
+                    Some(segment) => Ok(Self {
+                        inner: Some(segment),
+                        ended: false,
+                    }),
+                    None => {
+                        error!(
+                            "Could not create segment with name {} due to invalid transaction",
+                            name
+                        );
+                        Err(Error::SegmentStartError)
+                    }
                 }
             }
             _ => {
@@ -78,17 +80,20 @@ impl SegmentPointer {
         transaction: impl AsRef<Transaction>,
         params: impl AsRef<DatastoreParams>,
     ) -> Result<Self> {
-        let transaction = transaction.as_ref();
+        let (backend, handle) = match transaction.as_ref().inner() {
+            Some(inner) => inner,
+            None => return Ok(Self::default()),
+        };
         let params = params.as_ref();
-        let pointer =
-            unsafe { ffi::newrelic_start_datastore_segment(transaction.inner, &params.as_ptr()) };
-        let pointer = if pointer.is_null() {
-            error!("Could not create datastore segment due to invalid transaction");
-            Err(Error::SegmentStartError)
-        } else {
-            Ok(Self {
-                inner: Some(pointer),
-            })
+        let pointer = match backend.start_datastore_segment(handle, params) {
+            Some(segment) => Ok(Self {
+                inner: Some(segment),
+                ended: false,
+            }),
+            None => {
+                error!("Could not create datastore segment due to invalid transaction");
+                Err(Error::SegmentStartError)
+            }
         };
         debug!("Created segment");
         pointer
@@ -98,42 +103,49 @@ impl SegmentPointer {
         transaction: impl AsRef<Transaction>,
         params: impl AsRef<ExternalParams>,
     ) -> Result<Self> {
-        let transaction = transaction.as_ref();
+        let (backend, handle) = match transaction.as_ref().inner() {
+            Some(inner) => inner,
+            None => return Ok(Self::default()),
+        };
         let params = params.as_ref();
         debug!("Trying to start external segment");
-        let pointer =
-            unsafe { ffi::newrelic_start_external_segment(transaction.inner, &params.as_ptr()) };
-        let pointer = if pointer.is_null() {
-            error!("Could not create external segment due to invalid transaction");
-            Err(Error::SegmentStartError)
-        } else {
-            Ok(Self {
-                inner: Some(pointer),
-            })
+        let pointer = match backend.start_external_segment(handle, params) {
+            Some(segment) => Ok(Self {
+                inner: Some(segment),
+                ended: false,
+            }),
+            None => {
+                error!("Could not create external segment due to invalid transaction");
+                Err(Error::SegmentStartError)
+            }
         };
         debug!("Created segment");
         pointer
     }
 
+    /// Make this segment a child of `parent`.
+    fn set_parent(&self, transaction: &Transaction, parent: SegmentHandle) {
+        if let (Some((backend, handle)), Some(segment)) = (transaction.inner(), self.inner) {
+            backend.set_segment_parent(handle, segment, parent);
+        }
+    }
+
     pub fn custom_nested(
         &self,
         transaction: impl AsRef<Transaction>,
         name: impl AsRef<str>,
         category: impl AsRef<str>,
     ) -> Result<Self> {
-        let inner = self.inner.ok_or_else(|| {
-            error!("Could not create external segment due to invalid parent segment");
+        let transaction = transaction.as_ref();
+        if transaction.is_noop() {
+            return Ok(Self::default());
+        }
+        let parent = self.inner.ok_or_else(|| {
+            error!("Could not create custom segment due to invalid parent segment");
             Error::SegmentStartError
         })?;
-        let transaction = transaction.as_ref();
-        let name = name.as_ref();
-        let category = category.as_ref();
         let nested_pointer = Self::custom(transaction, name, category)?;
-        // If result is ok, then guaranteed there is some pointer
-        let nested_inner = nested_pointer.inner.unwrap();
-        unsafe {
-            ffi::newrelic_set_segment_parent(nested_inner, inner);
-        }
+        nested_pointer.set_parent(transaction, parent);
         Ok(nested_pointer)
     }
 
@@ -142,18 +154,16 @@ impl SegmentPointer {
         transaction: impl AsRef<Transaction>,
         params: impl AsRef<DatastoreParams>,
     ) -> Result<Self> {
-        let inner = self.inner.ok_or_else(|| {
-            error!("Could not create external segment due to invalid parent segment");
+        let transaction = transaction.as_ref();
+        if transaction.is_noop() {
+            return Ok(Self::default());
+        }
+        let parent = self.inner.ok_or_else(|| {
+            error!("Could not create datastore segment due to invalid parent segment");
             Error::SegmentStartError
         })?;
-        let transaction = transaction.as_ref();
-        let params = params.as_ref();
         let nested_pointer = Self::datastore(transaction, params)?;
-        // If result is ok, then guaranteed there is some pointer
-        let nested_inner = nested_pointer.inner.unwrap();
-        unsafe {
-            ffi::newrelic_set_segment_parent(nested_inner, inner);
-        }
+        nested_pointer.set_parent(transaction, parent);
         Ok(nested_pointer)
     }
 
@@ -162,48 +172,61 @@ impl SegmentPointer {
         transaction: impl AsRef<Transaction>,
         params: impl AsRef<ExternalParams>,
     ) -> Result<Self> {
-        let inner = self.inner.ok_or_else(|| {
+        let transaction = transaction.as_ref();
+        if transaction.is_noop() {
+            return Ok(Self::default());
+        }
+        let parent = self.inner.ok_or_else(|| {
             error!("Could not create external segment due to invalid parent segment");
             Error::SegmentStartError
         })?;
-        let transaction = transaction.as_ref();
-        let params = params.as_ref();
         let nested_pointer = Self::external(transaction, params)?;
-        // If result is ok, then guaranteed there is some pointer
-        let nested_inner = nested_pointer.inner.unwrap();
-        unsafe {
-            ffi::newrelic_set_segment_parent(nested_inner, inner);
-        }
+        nested_pointer.set_parent(transaction, parent);
         Ok(nested_pointer)
     }
 
     #[cfg(feature = "distributed_tracing")]
     pub fn distributed_trace(&self, transaction: impl AsRef<Transaction>) -> Option<String> {
-        let transaction = transaction.as_ref();
-        self.inner.map(|pointer| {
-            let payload = FreeableString::new(unsafe {
-                ffi::newrelic_create_distributed_trace_payload_httpsafe(transaction.inner, pointer)
-            });
-            payload.convert()
-        })
+        let (backend, handle) = transaction.as_ref().inner()?;
+        backend.distributed_trace_payload(handle, self.inner?)
+    }
+
+    pub fn set_timing(
+        &self,
+        transaction: impl AsRef<Transaction>,
+        start: Duration,
+        duration: Duration,
+    ) -> Result<()> {
+        let (start, duration) = (micros(start)?, micros(duration)?);
+        if self.ended {
+            return Err(Error::InvalidTiming(
+                "the segment has already ended".to_string(),
+            ));
+        }
+        let (backend, handle, segment) = match (transaction.as_ref().inner(), self.inner) {
+            (Some((backend, handle)), Some(segment)) => (backend, handle, segment),
+            _ => return Ok(()),
+        };
+        let ok = backend.set_segment_timing(handle, segment, start, duration);
+        if ok {
+            Ok(())
+        } else {
+            Err(Error::TimingError)
+        }
     }
 
     pub fn end(&mut self, transaction: impl AsRef<Transaction>) {
-        if let Some(mut inner) = self.inner {
-            let transaction = transaction.as_ref();
-            unsafe {
-                ffi::newrelic_end_segment(transaction.inner, &mut inner);
+        if let Some(segment) = self.inner {
+            if let Some((backend, handle)) = transaction.as_ref().inner() {
+                backend.end_segment(handle, segment);
             }
             debug!("Ended segment");
             self.inner = None;
+            self.ended = true;
         }
     }
 }
 
-unsafe impl Send for SegmentPointer {}
-
-unsafe impl Sync for SegmentPointer {}
-
 /// A segment with a reference to a transaction via the `AsRef` trait.
 ///
 /// Use segments to instrument transactions with greater granularity.
@@ -218,6 +241,20 @@ pub struct ReferencingSegment<T: AsRef<Transaction> + Clone> {
 }
 
 impl<T: AsRef<Transaction> + Clone> ReferencingSegment<T> {
+    /// Create a segment which ignores every method call, for when a real
+    /// segment couldn't be started.
+    pub(crate) fn noop(transaction: T) -> Self {
+        Self {
+            transaction,
+            segment_pointer: SegmentPointer::default(),
+        }
+    }
+
+    /// The transaction this segment belongs to.
+    pub(crate) fn transaction(&self) -> &T {
+        &self.transaction
+    }
+
     /// Create a custom segment within this transaction.
     ///
     /// Example:
@@ -625,6 +662,49 @@ impl<T: AsRef<Transaction> + Clone> ReferencingSegment<T> {
             .distributed_trace(self.transaction.as_ref())
     }
 
+    /// Override the start time and duration of this segment.
+    ///
+    /// `start` is relative to the start of the transaction. This is useful
+    /// for reporting work timed by another system, such as the execution
+    /// time returned with a database query's statistics. The timing is kept
+    /// when the segment ends, so it must be set before then.
+    ///
+    /// This does nothing if the segment's transaction is a no-op. Returns
+    /// `Error::InvalidTiming` if the segment has already ended, and
+    /// `Error::DurationOverFlow` if `start` or `duration` is too long to be
+    /// represented in microseconds.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use std::time::Duration;
+    ///
+    /// use newrelic::{App, Datastore, DatastoreParamsBuilder, ReferencingSegment};
+    ///
+    /// let license_key = std::env::var("NEW_RELIC_LICENSE_KEY").unwrap();
+    ///
+    /// let app = App::new("my app", &license_key)
+    ///     .expect("Could not create app");
+    /// let transaction = app
+    ///     .web_transaction("Test transaction")
+    ///     .expect("Could not start transaction");
+    /// let segment_params = DatastoreParamsBuilder::new(Datastore::Postgres)
+    ///     .collection("people")
+    ///     .operation("select")
+    ///     .build()
+    ///     .expect("Invalid datastore segment parameters");
+    /// let segment = ReferencingSegment::datastore(&transaction, &segment_params)
+    ///     .expect("Could not start segment");
+    /// // The execution time reported by the database.
+    /// segment
+    ///     .set_timing(Duration::from_millis(5), Duration::from_millis(20))
+    ///     .expect("Could not set timing");
+    /// ```
+    pub fn set_timing(&self, start: Duration, duration: Duration) -> Result<()> {
+        self.segment_pointer
+            .set_timing(self.transaction.as_ref(), start, duration)
+    }
+
     /// Explicitly end this segment.
     ///
     /// If this is not called, the segment is automatically ended
@@ -656,24 +736,29 @@ pub struct Segment<'a> {
     /// just want to continue even if New Relic monitoring isn't working...
     /// right?
     inner: Option<ReferencingSegment<&'a Transaction>>,
+    /// Whether the segment has ended.
+    ended: bool,
 }
 
 impl<'a> Segment<'a> {
     pub(crate) fn custom(transaction: &'a Transaction, name: &str, category: &str) -> Self {
         Self {
             inner: ReferencingSegment::custom(transaction, name, category).ok(),
+            ended: false,
         }
     }
 
     pub(crate) fn datastore(transaction: &'a Transaction, params: &DatastoreParams) -> Self {
         Self {
             inner: ReferencingSegment::datastore(transaction, params).ok(),
+            ended: false,
         }
     }
 
     pub(crate) fn external(transaction: &'a Transaction, params: &ExternalParams) -> Self {
         Self {
             inner: ReferencingSegment::external(transaction, params).ok(),
+            ended: false,
         }
     }
 
@@ -828,7 +913,10 @@ impl<'a> Segment<'a> {
             .inner
             .as_ref()
             .and_then(|inner| inner.create_custom_nested(name, category).ok());
-        Self { inner: nested }
+        Self {
+            inner: nested,
+            ended: false,
+        }
     }
 
     /// Create a new datastore segment nested within this one.
@@ -864,7 +952,10 @@ impl<'a> Segment<'a> {
             .inner
             .as_ref()
             .and_then(|inner| inner.create_datastore_nested(params).ok());
-        Self { inner: nested }
+        Self {
+            inner: nested,
+            ended: false,
+        }
     }
 
     /// Create a new external segment nested within this one.
@@ -900,7 +991,10 @@ impl<'a> Segment<'a> {
             .inner
             .as_ref()
             .and_then(|inner| inner.create_external_nested(params).ok());
-        Self { inner: nested }
+        Self {
+            inner: nested,
+            ended: false,
+        }
     }
 
     /// Create a distributed trace payload, a base64-encoded string, to add to a service's outbound
@@ -956,13 +1050,61 @@ impl<'a> Segment<'a> {
             .unwrap_or("".to_string())
     }
 
+    /// Override the start time and duration of this segment.
+    ///
+    /// `start` is relative to the start of the transaction. This is useful
+    /// for reporting work timed by another system, such as the duration of
+    /// a request measured by the remote service. The timing is kept when
+    /// the segment ends, so it must be set before then.
+    ///
+    /// As with other segment methods, this does nothing if the segment
+    /// could not be created. Returns `Error::InvalidTiming` if the segment
+    /// has already ended, and `Error::DurationOverFlow` if `start` or
+    /// `duration` is too long to be represented in microseconds.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use std::time::Duration;
+    ///
+    /// use newrelic::App;
+    ///
+    /// let license_key = std::env::var("NEW_RELIC_LICENSE_KEY").unwrap();
+    ///
+    /// let app = App::new("my app", &license_key)
+    ///     .expect("Could not create app");
+    /// let transaction = app
+    ///     .non_web_transaction("Import batch")
+    ///     .expect("Could not start transaction");
+    /// let segment = transaction.create_custom_segment("Parse", "Import");
+    /// segment
+    ///     .set_timing(Duration::ZERO, Duration::from_millis(120))
+    ///     .expect("Could not set timing");
+    /// ```
+    pub fn set_timing(&self, start: Duration, duration: Duration) -> Result<()> {
+        match &self.inner {
+            Some(inner) => inner.set_timing(start, duration),
+            None => {
+                micros(start)?;
+                micros(duration)?;
+                if self.ended {
+                    return Err(Error::InvalidTiming(
+                        "the segment has already ended".to_string(),
+                    ));
+                }
+                Ok(())
+            }
+        }
+    }
+
     /// Explicitly end this segment.
     ///
     /// If this is not called, the segment is automatically ended
     /// when dropped.
     pub fn end(&mut self) {
         if let Some(ref mut inner) = self.inner {
-            inner.end()
+            inner.end();
+            self.ended = inner.segment_pointer.ended;
         }
         self.inner = None;
     }
@@ -974,49 +1116,13 @@ impl<'a> Drop for Segment<'a> {
     }
 }
 
-#[cfg(feature = "distributed_tracing")]
-#[cfg_attr(docsrs, doc(cfg(feature = "distributed_tracing")))]
-struct FreeableString(*mut std::os::raw::c_char);
-
-#[cfg(feature = "distributed_tracing")]
-#[cfg_attr(docsrs, doc(cfg(feature = "distributed_tracing")))]
-impl FreeableString {
-    fn new(inner: *mut std::os::raw::c_char) -> Self {
-        Self(inner)
-    }
-
-    fn convert(&self) -> String {
-        let c_str = unsafe { std::ffi::CStr::from_ptr(self.0) };
-
-        c_str.to_str().unwrap().to_string()
-    }
-}
-
-#[cfg(feature = "distributed_tracing")]
-#[cfg_attr(docsrs, doc(cfg(feature = "distributed_tracing")))]
-impl Drop for FreeableString {
-    fn drop(&mut self) {
-        unsafe {
-            libc::free(self.0 as *mut std::ffi::c_void);
-        }
-    }
-}
-
-macro_rules! cstring_or_null_ptr {
-    ($param:expr) => {
-        match $param {
-            Some(p) => CString::new(p)?.into_raw(),
-            None => std::ptr::null_mut(),
-        }
-    };
-}
-
-macro_rules! drop_if_non_null {
-    ($field:expr) => {
-        if !$field.is_null() {
-            let _ = CString::from_raw($field);
-        }
-    };
+/// Convert an optional parameter to a `CString`, failing if it contains a
+/// NUL byte.
+fn optional_cstring(param: Option<&str>) -> Result<Option<CString>> {
+    Ok(match param {
+        Some(p) => Some(CString::new(p)?),
+        None => None,
+    })
 }
 
 /// Builder for parameters used to instrument external calls.
@@ -1059,11 +1165,10 @@ impl<'a> ExternalParamsBuilder<'a> {
     /// This will fail if any of the the parameters contain null bytes.
     pub fn build(self) -> Result<ExternalParams> {
         debug!("Creating ExternalParams");
-        let uri = CString::new(self.uri)?.into_raw();
         Ok(ExternalParams {
-            uri,
-            procedure: cstring_or_null_ptr!(self.procedure),
-            library: cstring_or_null_ptr!(self.library),
+            uri: CString::new(self.uri)?,
+            procedure: optional_cstring(self.procedure)?,
+            library: optional_cstring(self.library)?,
         })
     }
 }
@@ -1071,31 +1176,16 @@ impl<'a> ExternalParamsBuilder<'a> {
 /// Parameters used to instrument external segments.
 ///
 /// Create this using `ExternalParamsBuilder`.
+// Only sent by the C SDK, in transaction traces and span events, and by
+// the OTLP exporter.
+#[cfg_attr(
+    not(any(feature = "c-sdk", feature = "otlp", feature = "testing")),
+    allow(dead_code)
+)]
 pub struct ExternalParams {
-    uri: *mut c_char,
-    procedure: *mut c_char,
-    library: *mut c_char,
-}
-
-impl ExternalParams {
-    fn as_ptr(&self) -> ffi::newrelic_external_segment_params_t {
-        ffi::newrelic_external_segment_params_t {
-            uri: self.uri,
-            procedure: self.procedure,
-            library: self.library,
-        }
-    }
-}
-
-impl Drop for ExternalParams {
-    fn drop(&mut self) {
-        debug!("Reclaiming ExternalParams");
-        unsafe {
-            let _ = CString::from_raw(self.uri);
-            drop_if_non_null!(self.procedure);
-            drop_if_non_null!(self.library);
-        }
-    }
+    pub(crate) uri: CString,
+    pub(crate) procedure: Option<CString>,
+    pub(crate) library: Option<CString>,
 }
 
 impl AsRef<Self> for ExternalParams {
@@ -1104,10 +1194,6 @@ impl AsRef<Self> for ExternalParams {
     }
 }
 
-unsafe impl Send for ExternalParams {}
-
-unsafe impl Sync for ExternalParams {}
-
 /// The datastore type, used when instrumenting a datastore segment.
 pub enum Datastore {
     /// Firebird. Uses query instrumentation.
@@ -1139,23 +1225,27 @@ pub enum Datastore {
 }
 
 impl Datastore {
-    fn inner(&self) -> *mut c_char {
-        let datastore = match self {
-            Datastore::Firebird => ffi::NEWRELIC_DATASTORE_FIREBIRD.as_ptr(),
-            Datastore::Informix => ffi::NEWRELIC_DATASTORE_INFORMIX.as_ptr(),
-            Datastore::MSSQL => ffi::NEWRELIC_DATASTORE_MSSQL.as_ptr(),
-            Datastore::MySQL => ffi::NEWRELIC_DATASTORE_MYSQL.as_ptr(),
-            Datastore::Oracle => ffi::NEWRELIC_DATASTORE_ORACLE.as_ptr(),
-            Datastore::Postgres => ffi::NEWRELIC_DATASTORE_POSTGRES.as_ptr(),
-            Datastore::SQLite => ffi::NEWRELIC_DATASTORE_SQLITE.as_ptr(),
-            Datastore::Sybase => ffi::NEWRELIC_DATASTORE_SYBASE.as_ptr(),
-            Datastore::Memcached => ffi::NEWRELIC_DATASTORE_MEMCACHE.as_ptr(),
-            Datastore::MongoDB => ffi::NEWRELIC_DATASTORE_MONGODB.as_ptr(),
-            Datastore::ODBC => ffi::NEWRELIC_DATASTORE_ODBC.as_ptr(),
-            Datastore::Redis => ffi::NEWRELIC_DATASTORE_REDIS.as_ptr(),
-            Datastore::Other => ffi::NEWRELIC_DATASTORE_OTHER.as_ptr(),
-        };
-        datastore as *mut c_char
+    /// The name of the datastore product, as used by the New Relic SDK.
+    #[cfg_attr(
+        not(any(feature = "pure-rust", feature = "otlp", feature = "testing")),
+        allow(dead_code)
+    )]
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Datastore::Firebird => "Firebird",
+            Datastore::Informix => "Informix",
+            Datastore::MSSQL => "MSSQL",
+            Datastore::MySQL => "MySQL",
+            Datastore::Oracle => "Oracle",
+            Datastore::Postgres => "Postgres",
+            Datastore::SQLite => "SQLite",
+            Datastore::Sybase => "Sybase",
+            Datastore::Memcached => "Memcached",
+            Datastore::MongoDB => "MongoDB",
+            Datastore::ODBC => "ODBC",
+            Datastore::Redis => "Redis",
+            Datastore::Other => "Other",
+        }
     }
 }
 
@@ -1241,13 +1331,13 @@ impl<'a> DatastoreParamsBuilder<'a> {
     /// This will fail if any of the parameters contain null bytes.
     pub fn build(self) -> Result<DatastoreParams> {
         Ok(DatastoreParams {
-            product: self.product.inner(),
-            collection: cstring_or_null_ptr!(self.collection),
-            operation: cstring_or_null_ptr!(self.operation),
-            host: cstring_or_null_ptr!(self.host),
-            port_path_or_id: cstring_or_null_ptr!(self.port_path_or_id),
-            database_name: cstring_or_null_ptr!(self.database_name),
-            query: cstring_or_null_ptr!(self.query),
+            product: self.product,
+            collection: optional_cstring(self.collection)?,
+            operation: optional_cstring(self.operation)?,
+            host: optional_cstring(self.host)?,
+            port_path_or_id: optional_cstring(self.port_path_or_id)?,
+            database_name: optional_cstring(self.database_name)?,
+            query: optional_cstring(self.query)?,
         })
     }
 }
@@ -1255,50 +1345,122 @@ impl<'a> DatastoreParamsBuilder<'a> {
 /// Parameters used to instrument datastore segments.
 ///
 /// Create this using `DatastoreParamsBuilder`.
+// Only sent by the C SDK, in transaction traces and span events, and by
+// the OTLP exporter.
+#[cfg_attr(
+    not(any(feature = "c-sdk", feature = "otlp", feature = "testing")),
+    allow(dead_code)
+)]
 pub struct DatastoreParams {
-    product: *mut c_char,
-    collection: *mut c_char,
-    operation: *mut c_char,
-    host: *mut c_char,
-    port_path_or_id: *mut c_char,
-    database_name: *mut c_char,
-    query: *mut c_char,
+    pub(crate) product: Datastore,
+    pub(crate) collection: Option<CString>,
+    pub(crate) operation: Option<CString>,
+    pub(crate) host: Option<CString>,
+    pub(crate) port_path_or_id: Option<CString>,
+    pub(crate) database_name: Option<CString>,
+    pub(crate) query: Option<CString>,
 }
 
-impl DatastoreParams {
-    fn as_ptr(&self) -> ffi::newrelic_datastore_segment_params_t {
-        ffi::newrelic_datastore_segment_params_t {
-            product: self.product,
-            collection: self.collection,
-            operation: self.operation,
-            host: self.host,
-            port_path_or_id: self.port_path_or_id,
-            database_name: self.database_name,
-            query: self.query,
-        }
+impl AsRef<Self> for DatastoreParams {
+    fn as_ref(&self) -> &Self {
+        self
     }
 }
 
-impl Drop for DatastoreParams {
-    fn drop(&mut self) {
-        debug!("Reclaiming DatastoreParams");
-        unsafe {
-            drop_if_non_null!(self.collection);
-            drop_if_non_null!(self.operation);
-            drop_if_non_null!(self.host);
-            drop_if_non_null!(self.port_path_or_id);
-            drop_if_non_null!(self.database_name);
-            drop_if_non_null!(self.query);
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::App;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
     }
-}
 
-impl AsRef<Self> for DatastoreParams {
-    fn as_ref(&self) -> &Self {
-        self
+    #[cfg(feature = "testing")]
+    #[test]
+    fn segment_timing_is_recorded() {
+        use crate::testing::RecordingApp;
+
+        let app = RecordingApp::new();
+        let transaction = app.web_transaction("timed").unwrap();
+        let datastore = DatastoreParamsBuilder::new(Datastore::Postgres)
+            .collection("users")
+            .operation("select")
+            .build()
+            .unwrap();
+        let external = ExternalParamsBuilder::new("https://example.com/")
+            .build()
+            .unwrap();
+        {
+            let custom = transaction.create_custom_segment("custom", "test");
+            custom.set_timing(ms(1), ms(10)).unwrap();
+            let datastore = transaction.create_datastore_segment(&datastore);
+            datastore.set_timing(ms(2), ms(20)).unwrap();
+            let external = ReferencingSegment::external(&transaction, &external).unwrap();
+            external.set_timing(ms(3), ms(30)).unwrap();
+        }
+        drop(transaction);
+
+        let recorded = app.transaction("timed").unwrap();
+        let timings: Vec<_> = recorded.segments.iter().map(|s| s.timing).collect();
+        assert_eq!(
+            timings,
+            vec![
+                Some((ms(1), ms(10))),
+                Some((ms(2), ms(20))),
+                Some((ms(3), ms(30))),
+            ]
+        );
+        assert!(recorded.segments.iter().all(|s| s.ended));
     }
-}
 
-unsafe impl Send for DatastoreParams {}
+    #[cfg(feature = "testing")]
+    #[test]
+    fn ended_segments_reject_timing() {
+        use crate::testing::RecordingApp;
+
+        let app = RecordingApp::new();
+        {
+            let transaction = app.web_transaction("ended").unwrap();
+
+            let mut segment = transaction.create_custom_segment("segment", "test");
+            segment.end();
+            let result = segment.set_timing(ms(1), ms(10));
+            assert!(
+                matches!(&result, Err(Error::InvalidTiming(m)) if m == "the segment has already ended")
+            );
+
+            let mut segment = ReferencingSegment::custom(&transaction, "segment", "test").unwrap();
+            segment.end();
+            let result = segment.set_timing(ms(1), ms(10));
+            assert!(
+                matches!(&result, Err(Error::InvalidTiming(m)) if m == "the segment has already ended")
+            );
+        }
 
-unsafe impl Sync for DatastoreParams {}
+        let recorded = app.transaction("ended").unwrap();
+        assert!(recorded.segments.iter().all(|s| s.timing.is_none()));
+    }
+
+    #[test]
+    fn segments_which_never_started_ignore_timing() {
+        let app = App::disabled();
+        let transaction = app.web_transaction("noop").unwrap();
+
+        let mut segment = transaction.create_custom_segment("segment", "test");
+        assert!(segment.set_timing(ms(1), ms(10)).is_ok());
+        segment.end();
+        assert!(segment.set_timing(ms(1), ms(10)).is_ok());
+
+        let mut segment = ReferencingSegment::custom(&transaction, "segment", "test").unwrap();
+        assert!(segment.set_timing(ms(1), ms(10)).is_ok());
+        segment.end();
+        assert!(segment.set_timing(ms(1), ms(10)).is_ok());
+
+        let noop = ReferencingSegment::noop(&transaction);
+        assert!(matches!(
+            noop.set_timing(Duration::MAX, ms(10)),
+            Err(Error::DurationOverFlow)
+        ));
+    }
+}
@@ -0,0 +1,318 @@
+use std::{
+    error::Error as StdError,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    error::Result,
+    event::CustomEvent,
+    segment::{DatastoreParams, ExternalParams, ReferencingSegment},
+    transaction::{Attribute, Transaction, TransactionType},
+    web::{WebRequest, WebResponse},
+};
+
+/// A segment of a `SharedTransaction`.
+///
+/// Unlike a `Segment`, this owns a handle to its transaction, so it is
+/// `'static` and `Send` and can be moved into spawned threads and tasks.
+pub type SharedSegment = ReferencingSegment<SharedTransaction>;
+
+/// A transaction which can be shared between threads and tasks.
+///
+/// `Transaction`s and their segments are tied to the lifetime of the
+/// transaction, so instrumented work can't be moved into a spawned thread
+/// or task. A `SharedTransaction` is cheap to clone, and its segments own a
+/// clone of it, so they can be.
+///
+/// The transaction ends when the last clone of it, including those held by
+/// its segments, is dropped.
+///
+/// Example:
+///
+/// ```rust
+/// use std::thread;
+///
+/// use newrelic::{App, SharedTransaction};
+///
+/// let license_key = std::env::var("NEW_RELIC_LICENSE_KEY").unwrap();
+///
+/// let app = App::new("my app", &license_key)
+///     .expect("Could not create app");
+/// let transaction: SharedTransaction = app
+///     .web_transaction("Test transaction")
+///     .expect("Could not start transaction")
+///     .into();
+/// let segment = transaction.create_custom_segment("Test segment", "Test category");
+/// thread::spawn(move || {
+///     // Do some work, then end the segment.
+///     drop(segment);
+/// })
+/// .join()
+/// .unwrap();
+/// ```
+#[derive(Clone)]
+pub struct SharedTransaction(Arc<Transaction>);
+
+impl SharedTransaction {
+    /// Share a transaction.
+    pub fn new(transaction: Transaction) -> Self {
+        SharedTransaction(Arc::new(transaction))
+    }
+
+    /// Whether this is a no-op transaction, which doesn't report anything
+    /// to New Relic.
+    pub fn is_noop(&self) -> bool {
+        self.0.is_noop()
+    }
+
+    /// Get the type of the transaction.
+    pub fn r#type(&self) -> &TransactionType {
+        self.0.r#type()
+    }
+
+    /// Add an attribute to the transaction.
+    ///
+    /// Returns an error if the New Relic SDK returns an error.
+    pub fn add_attribute<'a, T>(&self, name: &str, attribute: T) -> Result<()>
+    where
+        T: Into<Attribute<'a>>,
+    {
+        self.0.add_attribute(name, attribute)
+    }
+
+    /// Add every field of a serializable value as an attribute of the
+    /// transaction.
+    ///
+    /// See `Transaction::add_attributes` for details.
+    #[cfg(feature = "serde")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "serde")))]
+    pub fn add_attributes<T>(&self, value: &T) -> Result<()>
+    where
+        T: serde::Serialize + ?Sized,
+    {
+        self.0.add_attributes(value)
+    }
+
+    /// Record the request handled by this web transaction.
+    ///
+    /// See `Transaction::set_web_request` for details.
+    pub fn set_web_request<'a, R>(&self, request: R) -> Result<()>
+    where
+        R: Into<WebRequest<'a>>,
+    {
+        self.0.set_web_request(request)
+    }
+
+    /// Record the response sent by this web transaction.
+    ///
+    /// See `Transaction::set_web_response` for details.
+    pub fn set_web_response<'a, R>(&self, response: R) -> Result<()>
+    where
+        R: Into<WebResponse<'a>>,
+    {
+        self.0.set_web_response(response)
+    }
+
+    /// Create a custom segment within this transaction, and pass it to
+    /// `func`.
+    pub fn custom_segment<F, V>(&self, name: &str, category: &str, func: F) -> V
+    where
+        F: FnOnce(SharedSegment) -> V,
+    {
+        func(self.create_custom_segment(name, category))
+    }
+
+    /// Create a datastore segment within this transaction, and pass it to
+    /// `func`.
+    pub fn datastore_segment<F, V>(&self, params: &DatastoreParams, func: F) -> V
+    where
+        F: FnOnce(SharedSegment) -> V,
+    {
+        func(self.create_datastore_segment(params))
+    }
+
+    /// Create an external segment within this transaction, and pass it to
+    /// `func`.
+    pub fn external_segment<F, V>(&self, params: &ExternalParams, func: F) -> V
+    where
+        F: FnOnce(SharedSegment) -> V,
+    {
+        func(self.create_external_segment(params))
+    }
+
+    /// Create a custom segment within this transaction.
+    ///
+    /// As with `Transaction::create_custom_segment`, the segment silently
+    /// does nothing if it couldn't be started.
+    pub fn create_custom_segment(&self, name: &str, category: &str) -> SharedSegment {
+        ReferencingSegment::custom(self.clone(), name, category)
+            .unwrap_or_else(|_| ReferencingSegment::noop(self.clone()))
+    }
+
+    /// Create a datastore segment within this transaction.
+    ///
+    /// As with `Transaction::create_datastore_segment`, the segment
+    /// silently does nothing if it couldn't be started.
+    pub fn create_datastore_segment(&self, params: &DatastoreParams) -> SharedSegment {
+        ReferencingSegment::datastore(self.clone(), params)
+            .unwrap_or_else(|_| ReferencingSegment::noop(self.clone()))
+    }
+
+    /// Create an external segment within this transaction.
+    ///
+    /// As with `Transaction::create_external_segment`, the segment
+    /// silently does nothing if it couldn't be started.
+    pub fn create_external_segment(&self, params: &ExternalParams) -> SharedSegment {
+        ReferencingSegment::external(self.clone(), params)
+            .unwrap_or_else(|_| ReferencingSegment::noop(self.clone()))
+    }
+
+    /// Record an error in this transaction.
+    ///
+    /// `priority` is an arbitrary integer indicating the error priority.
+    /// `message` is the error message; `class` is the error class or type.
+    pub fn notice_error(&self, priority: i32, message: &str, class: &str) -> Result<()> {
+        self.0.notice_error(priority, message, class)
+    }
+
+    /// Record an error value in this transaction.
+    ///
+    /// See `Transaction::notice_error_from` for details.
+    pub fn notice_error_from<E>(&self, error: &E) -> Result<()>
+    where
+        E: StdError + ?Sized,
+    {
+        self.0.notice_error_from(error)
+    }
+
+    /// Record an error value in this transaction, with a priority and
+    /// extra attributes.
+    ///
+    /// See `Transaction::notice_error_with` for details.
+    pub fn notice_error_with<E>(
+        &self,
+        priority: i32,
+        error: &E,
+        attributes: &[(&str, Attribute<'_>)],
+    ) -> Result<()>
+    where
+        E: StdError + ?Sized,
+    {
+        self.0.notice_error_with(priority, error, attributes)
+    }
+
+    /// Record an error value in this transaction, with a priority, class and
+    /// extra attributes.
+    ///
+    /// See `Transaction::notice_error_with_class` for details.
+    pub fn notice_error_with_class<E>(
+        &self,
+        priority: i32,
+        error: &E,
+        class: &str,
+        attributes: &[(&str, Attribute<'_>)],
+    ) -> Result<()>
+    where
+        E: StdError + ?Sized,
+    {
+        self.0
+            .notice_error_with_class(priority, error, class, attributes)
+    }
+
+    /// Ignore this transaction.
+    ///
+    /// Data for this transaction will not be sent to New Relic.
+    pub fn ignore(&self) -> Result<()> {
+        self.0.ignore()
+    }
+
+    /// Override the start time and duration of this transaction.
+    ///
+    /// See `Transaction::set_timing` for details.
+    pub fn set_timing(&self, start: SystemTime, duration: Duration) -> Result<()> {
+        self.0.set_timing(start, duration)
+    }
+
+    /// Record a custom metric for this transaction.
+    ///
+    /// The metric will be named according to `metric_name` and will
+    /// record for `duration`.
+    pub fn record_custom_metric(&self, metric_name: &str, duration: Duration) -> Result<()> {
+        self.0.record_custom_metric(metric_name, duration)
+    }
+
+    /// Create a custom event attached to this transaction.
+    pub fn custom_event(&self, event_type: &str) -> Result<CustomEvent<'_>> {
+        self.0.custom_event(event_type)
+    }
+
+    /// Change the name of the transaction
+    pub fn name(&self, new_name: &str) -> Result<()> {
+        self.0.name(new_name)
+    }
+}
+
+impl From<Transaction> for SharedTransaction {
+    fn from(transaction: Transaction) -> Self {
+        SharedTransaction::new(transaction)
+    }
+}
+
+impl AsRef<Transaction> for SharedTransaction {
+    fn as_ref(&self) -> &Transaction {
+        &self.0
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::testing::RecordingApp;
+
+    #[test]
+    fn segments_can_move_to_other_threads() {
+        let app = RecordingApp::new();
+        let other: SharedTransaction = app.web_transaction("other").unwrap().into();
+        let transaction: SharedTransaction = app.web_transaction("shared").unwrap().into();
+        let segment = transaction.create_custom_segment("worker", "test");
+        let clone = transaction.clone();
+        thread::spawn(move || {
+            segment.custom_nested("nested", "test", |_| {}).unwrap();
+            drop(segment);
+            clone.add_attribute("thread", "worker").unwrap();
+            // `clone` is dropped here, but the original remains.
+        })
+        .join()
+        .unwrap();
+
+        let recorded = app.transaction("shared").unwrap();
+        assert!(!recorded.ended);
+        assert_eq!(recorded.segments.len(), 1);
+        assert_eq!(recorded.segments[0].name(), Some("worker"));
+        assert!(recorded.segments[0].ended);
+        assert_eq!(recorded.segments[0].children[0].name(), Some("nested"));
+        assert!(app.transaction("other").unwrap().segments.is_empty());
+
+        drop(transaction);
+        assert!(app.transaction("shared").unwrap().ended);
+        assert!(!app.transaction("other").unwrap().ended);
+        drop(other);
+    }
+
+    #[test]
+    fn segments_keep_the_transaction_running() {
+        let app = RecordingApp::new();
+        let transaction: SharedTransaction = app.web_transaction("shared").unwrap().into();
+        let segment = transaction.create_custom_segment("worker", "test");
+        drop(transaction);
+        assert!(!app.transaction("shared").unwrap().ended);
+
+        thread::spawn(move || drop(segment)).join().unwrap();
+        let recorded = app.transaction("shared").unwrap();
+        assert!(recorded.ended);
+        assert!(recorded.segments[0].ended);
+    }
+}
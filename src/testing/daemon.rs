@@ -0,0 +1,611 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    os::unix::net::UnixStream,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+use serde_json::json;
+
+use super::{RecordedCustomEvent, RecordedError, Value};
+use crate::protocol::{self, AppInfo, AppReply, AppStatus, Message, TransactionData};
+
+/// A stand-in for the New Relic daemon, for use in end-to-end tests.
+///
+/// The daemon listens on a Unix socket, connects every app which asks
+/// without contacting New Relic, and decodes the transactions it is sent.
+/// Point the SDK at it using `NewRelicConfig::socket` before creating the
+/// first `App`; no network access is needed.
+///
+/// Example:
+///
+/// ```rust
+/// use std::time::Duration;
+///
+/// use newrelic::{testing::{FakeDaemon, Value}, App, NewRelicConfig};
+///
+/// let daemon = FakeDaemon::start("/tmp/.newrelic-fake.sock")
+///     .expect("Could not start daemon");
+/// NewRelicConfig::default()
+///     .socket(daemon.socket())
+///     .init()
+///     .expect("Could not initialise New Relic");
+///
+/// let app = App::new("my app", "0123456789012345678901234567890123456789")
+///     .expect("Could not create app");
+/// {
+///     let transaction = app
+///         .web_transaction("index")
+///         .expect("Could not start transaction");
+///     transaction
+///         .add_attribute("user_id", 42)
+///         .expect("Could not add attribute");
+/// }
+///
+/// let transactions = daemon.wait_for_transactions(1, Duration::from_secs(5));
+/// let transaction = &transactions[0];
+/// assert_eq!(transaction.name, "WebTransaction/Action/index");
+/// assert!(transaction.metric("WebTransaction").is_some());
+/// let event = transaction.event.as_ref().expect("No transaction event");
+/// assert_eq!(event.user_attributes["user_id"], Value::Long(42));
+/// ```
+pub struct FakeDaemon {
+    socket: String,
+    shared: Arc<Shared>,
+    listener: Option<JoinHandle<()>>,
+}
+
+impl FakeDaemon {
+    /// Start a daemon listening on `socket`.
+    ///
+    /// As with `NewRelicConfig::socket`, on Linux a socket starting with
+    /// '@' is the name of an abstract socket rather than a path. A socket
+    /// file left at the path by an earlier run is replaced.
+    pub fn start(socket: &str) -> io::Result<Self> {
+        let listener = protocol::bind(socket)?;
+        let shared = Arc::new(Shared::default());
+        let handle = {
+            let shared = Arc::clone(&shared);
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if shared.shutdown.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    match stream {
+                        Ok(stream) => {
+                            let shared = Arc::clone(&shared);
+                            thread::spawn(move || serve(stream, &shared));
+                        }
+                        Err(e) => warn!("FakeDaemon could not accept a connection: {}", e),
+                    }
+                }
+            })
+        };
+        debug!("FakeDaemon listening on {}", socket);
+        Ok(FakeDaemon {
+            socket: socket.to_string(),
+            shared,
+            listener: Some(handle),
+        })
+    }
+
+    /// The socket the daemon is listening on.
+    pub fn socket(&self) -> &str {
+        &self.socket
+    }
+
+    /// Every app the daemon has connected, in order.
+    pub fn apps(&self) -> Vec<ReceivedApp> {
+        self.shared.received().apps.clone()
+    }
+
+    /// A snapshot of every transaction received so far, in order.
+    pub fn transactions(&self) -> Vec<ReceivedTransaction> {
+        self.shared.received().transactions.clone()
+    }
+
+    /// Wait until at least `count` transactions have been received, or
+    /// until `timeout` has passed, returning those received so far.
+    pub fn wait_for_transactions(
+        &self,
+        count: usize,
+        timeout: Duration,
+    ) -> Vec<ReceivedTransaction> {
+        let deadline = Instant::now() + timeout;
+        let mut received = self.shared.received();
+        while received.transactions.len() < count {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            received = self
+                .shared
+                .changed
+                .wait_timeout(received, remaining)
+                .unwrap()
+                .0;
+        }
+        received.transactions.clone()
+    }
+
+    /// Forget the transactions received so far.
+    ///
+    /// Connected apps stay connected.
+    pub fn clear(&self) {
+        self.shared.received().transactions.clear();
+    }
+}
+
+impl Drop for FakeDaemon {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::SeqCst);
+        // Wake the listener, which is waiting for a connection.
+        let _ = protocol::connect(&self.socket);
+        if let Some(listener) = self.listener.take() {
+            let _ = listener.join();
+        }
+        if !self.socket.starts_with('@') {
+            let _ = fs::remove_file(&self.socket);
+        }
+    }
+}
+
+/// An app connected by a [`FakeDaemon`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ReceivedApp {
+    /// The run ID given to the app.
+    pub agent_run_id: String,
+    /// The name of the app.
+    pub app_name: String,
+    /// The license key given by the app.
+    pub license_key: String,
+    /// The language of the agent, which is `c` for the C SDK.
+    pub agent_language: String,
+    /// The version of the agent.
+    pub agent_version: String,
+    /// The collector host the daemon was asked to use.
+    pub redirect_collector: String,
+    /// Whether the app asked for high security mode.
+    pub high_security: bool,
+    /// The app's labels, as name and value pairs.
+    pub labels: Vec<(String, String)>,
+    /// The display name given for the host, or an empty string.
+    pub display_host: String,
+    /// The hostname of the machine running the app.
+    pub host: String,
+    /// The app's settings, as a JSON object.
+    pub settings: String,
+}
+
+/// A transaction received by a [`FakeDaemon`].
+///
+/// Durations are given in seconds, and integer attributes are decoded as
+/// `Value::Long` since events don't distinguish between integer sizes.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct ReceivedTransaction {
+    /// The run ID of the transaction's app.
+    pub agent_run_id: String,
+    /// The full name of the transaction, such as
+    /// `WebTransaction/Action/index`.
+    pub name: String,
+    /// The URI of the transaction, or `<unknown>`.
+    pub uri: String,
+    /// The ID of the process which sent the transaction.
+    pub pid: i32,
+    /// The transaction event, if one was sent.
+    pub event: Option<ReceivedEvent>,
+    /// The metrics recorded by the transaction.
+    pub metrics: Vec<ReceivedMetric>,
+    /// The noticed error sent with the transaction, if any.
+    pub errors: Vec<RecordedError>,
+    /// The custom events recorded in the transaction.
+    pub custom_events: Vec<RecordedCustomEvent>,
+    /// The error events recorded in the transaction.
+    pub error_events: Vec<ReceivedEvent>,
+}
+
+impl ReceivedTransaction {
+    /// The unscoped metric called `name`.
+    pub fn metric(&self, name: &str) -> Option<&ReceivedMetric> {
+        self.metrics
+            .iter()
+            .find(|metric| !metric.scoped && metric.name == name)
+    }
+
+    /// The metric called `name` scoped to this transaction.
+    pub fn scoped_metric(&self, name: &str) -> Option<&ReceivedMetric> {
+        self.metrics
+            .iter()
+            .find(|metric| metric.scoped && metric.name == name)
+    }
+}
+
+/// An event received by a [`FakeDaemon`].
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct ReceivedEvent {
+    /// The attributes set by the agent, such as `type` and `duration`.
+    pub intrinsics: BTreeMap<String, Value>,
+    /// The attributes added by the user.
+    pub user_attributes: BTreeMap<String, Value>,
+    /// The attributes added by the agent on the user's behalf.
+    pub agent_attributes: BTreeMap<String, Value>,
+}
+
+/// A metric received by a [`FakeDaemon`].
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct ReceivedMetric {
+    /// The name of the metric.
+    pub name: String,
+    /// Whether the metric is scoped to its transaction.
+    pub scoped: bool,
+    /// Whether the metric is always kept, even if the agent is over its
+    /// metric limit.
+    pub forced: bool,
+    /// The number of times the metric was recorded.
+    pub count: f64,
+    /// The total time, in seconds.
+    pub total: f64,
+    /// The exclusive time, in seconds.
+    pub exclusive: f64,
+    /// The shortest time recorded, in seconds.
+    pub min: f64,
+    /// The longest time recorded, in seconds.
+    pub max: f64,
+    /// The sum of the squares of the times recorded.
+    pub sum_squares: f64,
+}
+
+#[derive(Default)]
+struct Received {
+    apps: Vec<ReceivedApp>,
+    transactions: Vec<ReceivedTransaction>,
+}
+
+#[derive(Default)]
+struct Shared {
+    received: Mutex<Received>,
+    changed: Condvar,
+    shutdown: AtomicBool,
+}
+
+impl Shared {
+    fn received(&self) -> MutexGuard<'_, Received> {
+        self.received.lock().unwrap()
+    }
+
+    /// Reply to an app query, connecting the app if it isn't already.
+    fn connect(&self, agent_run_id: Option<String>, app: AppInfo) -> AppReply {
+        let mut received = self.received();
+        let connected =
+            agent_run_id.filter(|id| received.apps.iter().any(|app| &app.agent_run_id == id));
+        if connected.is_some() {
+            return AppReply {
+                status: AppStatus::StillValid,
+                connect_reply: None,
+            };
+        }
+        let agent_run_id = format!("fake-run-{}", received.apps.len() + 1);
+        let connect_reply = json!({
+            "agent_run_id": agent_run_id,
+            "apdex_t": 0.5,
+            "collect_analytics_events": true,
+            "collect_custom_events": true,
+            "collect_error_events": true,
+            "collect_errors": true,
+            "collect_traces": true,
+            "data_report_period": 60,
+        });
+        debug!("FakeDaemon connected app {}", app.app_name);
+        received.apps.push(ReceivedApp {
+            agent_run_id,
+            app_name: app.app_name,
+            license_key: app.license,
+            agent_language: app.agent_language,
+            agent_version: app.agent_version,
+            redirect_collector: app.redirect_collector,
+            high_security: app.high_security,
+            labels: decode_labels(&app.labels),
+            display_host: app.display_host,
+            host: app.host,
+            settings: app.settings,
+        });
+        self.changed.notify_all();
+        AppReply {
+            status: AppStatus::Connected,
+            connect_reply: Some(connect_reply.to_string()),
+        }
+    }
+
+    fn receive(&self, agent_run_id: Option<String>, txn: TransactionData) {
+        let transaction = ReceivedTransaction {
+            agent_run_id: agent_run_id.unwrap_or_default(),
+            name: txn.name,
+            uri: txn.uri,
+            pid: txn.pid,
+            event: txn.txn_event.as_deref().and_then(event),
+            metrics: txn
+                .metrics
+                .into_iter()
+                .map(|metric| ReceivedMetric {
+                    name: metric.name,
+                    scoped: metric.data.scoped,
+                    forced: metric.data.forced,
+                    count: metric.data.count,
+                    total: metric.data.total,
+                    exclusive: metric.data.exclusive,
+                    min: metric.data.min,
+                    max: metric.data.max,
+                    sum_squares: metric.data.sum_squares,
+                })
+                .collect(),
+            errors: txn
+                .errors
+                .iter()
+                .filter_map(|error| noticed_error(error.priority, &error.data))
+                .collect(),
+            custom_events: txn
+                .custom_events
+                .iter()
+                .filter_map(|data| custom_event(data))
+                .collect(),
+            error_events: txn
+                .error_events
+                .iter()
+                .filter_map(|data| event(data))
+                .collect(),
+        };
+        debug!("FakeDaemon received transaction {}", transaction.name);
+        self.received().transactions.push(transaction);
+        self.changed.notify_all();
+    }
+}
+
+/// Handle the messages sent over a single connection.
+fn serve(mut stream: UnixStream, shared: &Shared) {
+    // The agent closing the connection ends the loop.
+    while let Ok(message) = protocol::read_message(&mut stream) {
+        let (agent_run_id, message) = match protocol::decode_message(&message) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("FakeDaemon received an invalid message: {}", e);
+                return;
+            }
+        };
+        match message {
+            Message::App(app) => {
+                let reply = protocol::encode_app_reply(&shared.connect(agent_run_id, app));
+                if let Err(e) = protocol::write_message(&mut stream, &reply) {
+                    warn!("FakeDaemon could not reply to app query: {}", e);
+                    return;
+                }
+            }
+            Message::Transaction(txn) => shared.receive(agent_run_id, txn),
+            Message::Other(data_type) => {
+                debug!("FakeDaemon ignoring message of type {}", data_type)
+            }
+        }
+    }
+}
+
+fn value(value: &serde_json::Value) -> Option<Value> {
+    match value {
+        serde_json::Value::Bool(b) => Some(Value::Bool(*b)),
+        serde_json::Value::Number(n) => n
+            .as_i64()
+            .map(Value::Long)
+            .or_else(|| n.as_f64().map(Value::Float)),
+        serde_json::Value::String(s) => Some(Value::String(s.clone())),
+        _ => None,
+    }
+}
+
+fn attributes(object: Option<&serde_json::Value>) -> BTreeMap<String, Value> {
+    object
+        .and_then(serde_json::Value::as_object)
+        .map(|object| {
+            object
+                .iter()
+                .filter_map(|(name, attribute)| Some((name.clone(), value(attribute)?)))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Decode an app's labels, which are an array of objects with
+/// `label_type` and `label_value` keys.
+fn decode_labels(data: &str) -> Vec<(String, String)> {
+    let labels: Vec<serde_json::Value> = serde_json::from_str(data).unwrap_or_default();
+    labels
+        .iter()
+        .filter_map(|label| {
+            let name = label.get("label_type")?.as_str()?;
+            let value = label.get("label_value")?.as_str()?;
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+/// Decode an event, which is an array of intrinsics, user attributes and
+/// agent attributes.
+fn event(data: &str) -> Option<ReceivedEvent> {
+    let event: Vec<serde_json::Value> = match serde_json::from_str(data) {
+        Ok(event) => event,
+        Err(e) => {
+            warn!("FakeDaemon received an invalid event: {}", e);
+            return None;
+        }
+    };
+    Some(ReceivedEvent {
+        intrinsics: attributes(event.first()),
+        user_attributes: attributes(event.get(1)),
+        agent_attributes: attributes(event.get(2)),
+    })
+}
+
+fn custom_event(data: &str) -> Option<RecordedCustomEvent> {
+    let event = event(data)?;
+    let event_type = match event.intrinsics.get("type") {
+        Some(Value::String(event_type)) => event_type.clone(),
+        _ => String::new(),
+    };
+    Some(RecordedCustomEvent {
+        event_type,
+        attributes: event.user_attributes,
+    })
+}
+
+/// Decode a noticed error, which is an array of the time it was noticed,
+/// the transaction name, the message, the class and further parameters
+/// including the stack trace.
+fn noticed_error(priority: i32, data: &str) -> Option<RecordedError> {
+    let error: Vec<serde_json::Value> = match serde_json::from_str(data) {
+        Ok(error) => error,
+        Err(e) => {
+            warn!("FakeDaemon received an invalid error: {}", e);
+            return None;
+        }
+    };
+    let string = |index: usize| {
+        error
+            .get(index)
+            .and_then(serde_json::Value::as_str)
+            .unwrap_or_default()
+            .to_string()
+    };
+    let backtrace = error
+        .get(4)
+        .and_then(|params| params.get("stack_trace"))
+        .and_then(serde_json::Value::as_array)
+        .map(|frames| {
+            frames
+                .iter()
+                .filter_map(|frame| frame.as_str().map(str::to_string))
+                .collect()
+        })
+        .unwrap_or_default();
+    Some(RecordedError {
+        priority,
+        message: string(2),
+        class: string(3),
+        backtrace,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::protocol::{Metric, MetricData};
+
+    /// A socket path unique to this process and test.
+    fn socket(test: &str) -> String {
+        let name = format!("newrelic-fake-{}-{}.sock", std::process::id(), test);
+        std::env::temp_dir().join(name).display().to_string()
+    }
+
+    fn app() -> AppInfo {
+        AppInfo {
+            license: "0123456789012345678901234567890123456789".to_string(),
+            app_name: "my app".to_string(),
+            agent_language: "rust".to_string(),
+            labels: r#"[{"label_type":"env","label_value":"prod"}]"#.to_string(),
+            ..AppInfo::default()
+        }
+    }
+
+    /// Send an app query over `stream` and decode the reply.
+    fn query(stream: &mut UnixStream, agent_run_id: Option<&str>) -> AppReply {
+        protocol::write_message(stream, &protocol::encode_app(agent_run_id, &app())).unwrap();
+        protocol::decode_app_reply(&protocol::read_message(stream).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn connects_apps() {
+        let daemon = FakeDaemon::start(&socket("connect")).unwrap();
+        let mut stream = protocol::connect(daemon.socket()).unwrap();
+
+        let reply = query(&mut stream, None);
+        assert_eq!(reply.status, AppStatus::Connected);
+        let connect_reply: serde_json::Value =
+            serde_json::from_str(&reply.connect_reply.unwrap()).unwrap();
+        assert_eq!(connect_reply["agent_run_id"], "fake-run-1");
+
+        let apps = daemon.apps();
+        assert_eq!(apps.len(), 1);
+        assert_eq!(apps[0].agent_run_id, "fake-run-1");
+        assert_eq!(apps[0].app_name, "my app");
+        assert_eq!(apps[0].agent_language, "rust");
+        assert_eq!(
+            apps[0].labels,
+            vec![("env".to_string(), "prod".to_string())]
+        );
+
+        // Apps which are already connected stay connected.
+        let reply = query(&mut stream, Some("fake-run-1"));
+        assert_eq!(reply.status, AppStatus::StillValid);
+        assert_eq!(reply.connect_reply, None);
+        assert_eq!(daemon.apps().len(), 1);
+    }
+
+    #[test]
+    fn records_transactions() {
+        let daemon = FakeDaemon::start(&socket("record")).unwrap();
+        let mut stream = protocol::connect(daemon.socket()).unwrap();
+        query(&mut stream, None);
+
+        let transaction = TransactionData {
+            name: "WebTransaction/Action/index".to_string(),
+            uri: "<unknown>".to_string(),
+            pid: 1234,
+            txn_event: Some(r#"[{"type":"Transaction"},{"user_id":42},{}]"#.to_string()),
+            metrics: vec![Metric {
+                name: "WebTransaction".to_string(),
+                data: MetricData {
+                    count: 1.0,
+                    total: 0.5,
+                    ..MetricData::default()
+                },
+            }],
+            custom_events: vec![r#"[{"type":"Event"},{"size":3},{}]"#.to_string()],
+            ..TransactionData::default()
+        };
+        let message = protocol::encode_transaction("fake-run-1", &transaction);
+        protocol::write_message(&mut stream, &message).unwrap();
+
+        let transactions = daemon.wait_for_transactions(1, Duration::from_secs(5));
+        assert_eq!(transactions.len(), 1);
+        let received = &transactions[0];
+        assert_eq!(received.agent_run_id, "fake-run-1");
+        assert_eq!(received.name, "WebTransaction/Action/index");
+        assert_eq!(received.pid, 1234);
+        let event = received.event.as_ref().unwrap();
+        assert_eq!(event.user_attributes["user_id"], Value::Long(42));
+        let metric = received.metric("WebTransaction").unwrap();
+        assert_eq!((metric.count, metric.total), (1.0, 0.5));
+        assert_eq!(received.custom_events[0].event_type, "Event");
+        assert_eq!(received.custom_events[0].attributes["size"], Value::Long(3));
+
+        daemon.clear();
+        assert!(daemon.transactions().is_empty());
+    }
+
+    #[test]
+    fn removes_socket_on_drop() {
+        let socket = socket("drop");
+        let daemon = FakeDaemon::start(&socket).unwrap();
+        assert!(Path::new(&socket).exists());
+        drop(daemon);
+        assert!(!Path::new(&socket).exists());
+    }
+}
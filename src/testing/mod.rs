@@ -0,0 +1,957 @@
+/*!
+Helpers for testing code instrumented with this crate.
+
+A [`RecordingApp`] is an `App` which records everything in memory instead
+of sending it to New Relic, so no daemon is needed. After exercising the
+code under test, the recorded transactions can be inspected, including
+their name changes, attributes, noticed errors, custom events, custom
+metrics and the full tree of segments.
+
+Segments are nested exactly as they were created: segments created
+directly from a transaction are roots of the tree, and segments created
+using the `_nested` methods are children of the segment they were created
+from.
+
+Example:
+
+```rust
+use newrelic::{
+    testing::{RecordingApp, SegmentKind, Value},
+    Datastore, DatastoreParamsBuilder, TransactionType,
+};
+
+let app = RecordingApp::new();
+
+// Code under test, which would usually take `&App`.
+{
+    let transaction = app
+        .web_transaction("GET /users/{id}")
+        .expect("Could not start transaction");
+    transaction
+        .add_attribute("user_id", 42)
+        .expect("Could not add attribute");
+    let params = DatastoreParamsBuilder::new(Datastore::Postgres)
+        .collection("users")
+        .operation("select")
+        .build()
+        .expect("Invalid datastore segment parameters");
+    transaction.custom_segment("handler", "web", |segment| {
+        segment.datastore_nested(&params, |_| {});
+    });
+}
+
+let transaction = app
+    .transaction("GET /users/{id}")
+    .expect("No transaction recorded");
+assert!(transaction.ended);
+assert_eq!(transaction.transaction_type, TransactionType::Web);
+assert_eq!(transaction.attributes["user_id"], Value::Int(42));
+
+let handler = &transaction.segments[0];
+assert_eq!(handler.name(), Some("handler"));
+match &handler.children[0].kind {
+    SegmentKind::Datastore(params) => {
+        assert_eq!(params.product, "Postgres");
+        assert_eq!(params.collection.as_deref(), Some("users"));
+    }
+    other => panic!("Unexpected segment: {:?}", other),
+}
+```
+
+To test the real path to New Relic instead, a [`FakeDaemon`] stands in for
+the daemon. It connects apps without contacting New Relic and decodes the
+transactions sent to it, including the metrics and events the SDK creates
+for them.
+*/
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::{CStr, CString},
+    ops::Deref,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+mod daemon;
+
+pub use self::daemon::{
+    FakeDaemon, ReceivedApp, ReceivedEvent, ReceivedMetric, ReceivedTransaction,
+};
+
+use crate::{
+    app::{App, AppBuilder, AppConfig},
+    backend::{AttributeValue, Backend, CustomEventHandle, SegmentHandle, TransactionHandle},
+    metrics::MetricUnit,
+    segment::{DatastoreParams, ExternalParams},
+    transaction::TransactionType,
+};
+
+/// An `App` which records transactions in memory, for use in tests.
+///
+/// `RecordingApp` dereferences to `App`, so can be passed to code expecting
+/// an `&App`. See the [module documentation](index.html) for an example.
+pub struct RecordingApp {
+    app: App,
+    backend: Arc<RecordingBackend>,
+}
+
+impl RecordingApp {
+    /// Create a new app with nothing recorded.
+    pub fn new() -> Self {
+        Self::with_config(None)
+    }
+
+    /// Create a new app with nothing recorded, which filters attributes,
+    /// renames transactions and ignores transactions as an app built by
+    /// `builder` would.
+    ///
+    /// The other settings of the builder don't affect what's recorded. A
+    /// disabled builder has no settings, so the defaults are used.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use newrelic::{testing::RecordingApp, AppBuilder, IgnoreRules, NamingRules};
+    ///
+    /// # fn main() -> Result<(), newrelic::Error> {
+    /// let app = RecordingApp::from_builder(
+    ///     AppBuilder::new("my app", &"0".repeat(40))?
+    ///         .naming_rules(NamingRules::new().collapse_ids())
+    ///         .ignore_rules(IgnoreRules::new().ignore("GET /health")),
+    /// );
+    /// drop(app.web_transaction("GET /users/42")?);
+    /// drop(app.web_transaction("GET /health")?);
+    ///
+    /// assert!(!app.transaction("GET /users/*").unwrap().ignored);
+    /// assert!(app.transaction("GET /health").unwrap().ignored);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn from_builder(builder: &AppBuilder) -> Self {
+        Self::with_config(builder.config())
+    }
+
+    fn with_config(config: Option<&AppConfig>) -> Self {
+        let backend = Arc::new(RecordingBackend::default());
+        RecordingApp {
+            app: App::with_backend(Arc::clone(&backend) as Arc<dyn Backend>, config),
+            backend,
+        }
+    }
+
+    /// The app, which can be passed to the code under test.
+    pub fn app(&self) -> &App {
+        &self.app
+    }
+
+    /// A snapshot of every transaction started so far, in the order they
+    /// were started.
+    ///
+    /// Transactions which are still running are included, with `ended` set
+    /// to `false`.
+    pub fn transactions(&self) -> Vec<RecordedTransaction> {
+        let state = self.backend.state();
+        state
+            .transactions
+            .keys()
+            .map(|&id| state.snapshot(id))
+            .collect()
+    }
+
+    /// A snapshot of the first transaction whose current name is `name`.
+    pub fn transaction(&self, name: &str) -> Option<RecordedTransaction> {
+        self.transactions()
+            .into_iter()
+            .find(|transaction| transaction.name() == name)
+    }
+
+    /// Forget everything recorded so far, freeing the memory used by ended
+    /// transactions and their segments.
+    ///
+    /// Transactions which are still running are unaffected, and will be
+    /// included in future snapshots.
+    pub fn clear(&self) {
+        self.backend.state().clear();
+    }
+}
+
+impl Default for RecordingApp {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Deref for RecordingApp {
+    type Target = App;
+
+    fn deref(&self) -> &App {
+        &self.app
+    }
+}
+
+/// A transaction recorded by a [`RecordingApp`].
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct RecordedTransaction {
+    /// The type of the transaction.
+    pub transaction_type: TransactionType,
+    /// Every name the transaction has had, starting with the name it was
+    /// started with and followed by any changes made using
+    /// `Transaction::name`.
+    pub names: Vec<String>,
+    /// The attributes added to the transaction.
+    pub attributes: BTreeMap<String, Value>,
+    /// The errors noticed in the transaction, in order.
+    pub errors: Vec<RecordedError>,
+    /// The custom events recorded in the transaction, in order.
+    ///
+    /// Events which were dropped without being recorded aren't included.
+    pub custom_events: Vec<RecordedCustomEvent>,
+    /// The custom metrics recorded in the transaction, in order.
+    pub custom_metrics: Vec<RecordedMetric>,
+    /// The root segments of the transaction, in the order they were
+    /// started.
+    pub segments: Vec<RecordedSegment>,
+    /// The start time and duration set using `Transaction::set_timing`,
+    /// if any.
+    pub timing: Option<(SystemTime, Duration)>,
+    /// Whether the transaction was ignored using `Transaction::ignore`.
+    pub ignored: bool,
+    /// Whether the transaction has ended.
+    pub ended: bool,
+}
+
+impl RecordedTransaction {
+    /// The current name of the transaction.
+    pub fn name(&self) -> &str {
+        self.names.last().map(String::as_str).unwrap_or("")
+    }
+
+    /// Iterate over every segment of the transaction, depth first.
+    pub fn all_segments(&self) -> impl Iterator<Item = &RecordedSegment> {
+        let mut stack: Vec<&RecordedSegment> = self.segments.iter().rev().collect();
+        std::iter::from_fn(move || {
+            let segment = stack.pop()?;
+            stack.extend(segment.children.iter().rev());
+            Some(segment)
+        })
+    }
+
+    /// Find the first segment, depth first, matching `predicate`.
+    pub fn find_segment<P>(&self, mut predicate: P) -> Option<&RecordedSegment>
+    where
+        P: FnMut(&RecordedSegment) -> bool,
+    {
+        self.all_segments().find(|segment| predicate(segment))
+    }
+}
+
+/// The value of a recorded attribute.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    /// A short (i32) integer value.
+    Int(i32),
+    /// A long (i64) integer value.
+    Long(i64),
+    /// A float (f64) value.
+    Float(f64),
+    /// A string value.
+    String(String),
+    /// A boolean value.
+    Bool(bool),
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(value: i32) -> Self {
+        Value::Int(value)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Value::Long(value)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Float(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::String(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::String(value)
+    }
+}
+
+impl Value {
+    fn recorded(value: AttributeValue<'_>) -> Self {
+        match value {
+            AttributeValue::Int(i) => Value::Int(i),
+            AttributeValue::Long(l) => Value::Long(l),
+            AttributeValue::Float(f) => Value::Float(f),
+            AttributeValue::String(s) => Value::String(string(s)),
+            AttributeValue::Bool(b) => Value::Bool(b),
+        }
+    }
+}
+
+/// An error noticed using `Transaction::notice_error`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct RecordedError {
+    /// The priority of the error.
+    pub priority: i32,
+    /// The error message.
+    pub message: String,
+    /// The error class.
+    pub class: String,
+    /// The frames of the Rust backtrace captured with the error, if
+    /// backtraces are enabled.
+    pub backtrace: Vec<String>,
+}
+
+/// A custom event recorded using `CustomEvent::record`.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct RecordedCustomEvent {
+    /// The type of the event.
+    pub event_type: String,
+    /// The attributes added to the event.
+    pub attributes: BTreeMap<String, Value>,
+}
+
+/// A custom metric recorded using `Transaction::record_custom_metric`, or
+/// reported by one of the app's counters, gauges and summaries.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct RecordedMetric {
+    /// The name of the metric.
+    pub name: String,
+    /// The value sent to the New Relic SDK, which treats it as a time in
+    /// milliseconds.
+    pub milliseconds: f64,
+    /// The value of the metric, in `unit`.
+    pub value: f64,
+    /// The unit of the metric's value.
+    pub unit: MetricUnit,
+}
+
+/// A recorded segment, along with the segments nested within it.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct RecordedSegment {
+    /// The type of segment, and the parameters it was created with.
+    pub kind: SegmentKind,
+    /// The segments nested within this one, in the order they were started.
+    pub children: Vec<RecordedSegment>,
+    /// The start time, relative to the start of the transaction, and
+    /// duration set using `set_timing`, if any.
+    pub timing: Option<(Duration, Duration)>,
+    /// Whether the segment has ended.
+    pub ended: bool,
+}
+
+impl RecordedSegment {
+    /// The name of the segment, if it is a custom segment.
+    pub fn name(&self) -> Option<&str> {
+        match &self.kind {
+            SegmentKind::Custom { name, .. } => Some(name),
+            _ => None,
+        }
+    }
+}
+
+/// The type of a recorded segment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SegmentKind {
+    /// A custom segment.
+    Custom {
+        /// The name of the segment.
+        name: String,
+        /// The category of the segment.
+        category: String,
+    },
+    /// A datastore segment.
+    Datastore(RecordedDatastoreParams),
+    /// An external segment.
+    External(RecordedExternalParams),
+}
+
+/// The `DatastoreParams` a datastore segment was created with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct RecordedDatastoreParams {
+    /// The datastore product, as named by New Relic, such as `"Postgres"`.
+    pub product: String,
+    /// The collection, such as a table name.
+    pub collection: Option<String>,
+    /// The operation, such as `"select"`.
+    pub operation: Option<String>,
+    /// The host of the datastore.
+    pub host: Option<String>,
+    /// The port, path or ID of the datastore.
+    pub port_path_or_id: Option<String>,
+    /// The name of the database.
+    pub database_name: Option<String>,
+    /// The query.
+    pub query: Option<String>,
+}
+
+/// The `ExternalParams` an external segment was created with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct RecordedExternalParams {
+    /// The URI of the external call.
+    pub uri: String,
+    /// The procedure, such as an HTTP method.
+    pub procedure: Option<String>,
+    /// The library used to make the call.
+    pub library: Option<String>,
+}
+
+fn string(value: &CStr) -> String {
+    value.to_string_lossy().into_owned()
+}
+
+fn optional_string(value: &Option<CString>) -> Option<String> {
+    value.as_deref().map(string)
+}
+
+impl From<&DatastoreParams> for RecordedDatastoreParams {
+    fn from(params: &DatastoreParams) -> Self {
+        RecordedDatastoreParams {
+            product: params.product.name().to_string(),
+            collection: optional_string(&params.collection),
+            operation: optional_string(&params.operation),
+            host: optional_string(&params.host),
+            port_path_or_id: optional_string(&params.port_path_or_id),
+            database_name: optional_string(&params.database_name),
+            query: optional_string(&params.query),
+        }
+    }
+}
+
+impl From<&ExternalParams> for RecordedExternalParams {
+    fn from(params: &ExternalParams) -> Self {
+        RecordedExternalParams {
+            uri: string(&params.uri),
+            procedure: optional_string(&params.procedure),
+            library: optional_string(&params.library),
+        }
+    }
+}
+
+struct TransactionState {
+    transaction_type: TransactionType,
+    names: Vec<String>,
+    attributes: BTreeMap<String, Value>,
+    errors: Vec<RecordedError>,
+    custom_events: Vec<RecordedCustomEvent>,
+    custom_metrics: Vec<RecordedMetric>,
+    timing: Option<(SystemTime, Duration)>,
+    ignored: bool,
+    ended: bool,
+}
+
+struct SegmentState {
+    transaction: usize,
+    parent: Option<usize>,
+    kind: SegmentKind,
+    timing: Option<(Duration, Duration)>,
+    ended: bool,
+}
+
+/// Everything recorded, keyed by the IDs used as handles. IDs are never
+/// reused, so handles to data removed by `clear` are simply ignored.
+#[derive(Default)]
+struct State {
+    next_id: usize,
+    transactions: BTreeMap<usize, TransactionState>,
+    segments: BTreeMap<usize, SegmentState>,
+    /// Custom events which have been created but not yet recorded.
+    custom_events: HashMap<usize, RecordedCustomEvent>,
+}
+
+impl State {
+    fn next_id(&mut self) -> usize {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn transaction(&mut self, handle: TransactionHandle) -> Option<&mut TransactionState> {
+        self.transactions.get_mut(&handle.0)
+    }
+
+    fn segment(&mut self, handle: SegmentHandle) -> Option<&mut SegmentState> {
+        self.segments.get_mut(&handle.0)
+    }
+
+    fn segment_tree(&self, transaction: usize, parent: Option<usize>) -> Vec<RecordedSegment> {
+        self.segments
+            .iter()
+            .filter(|(_, segment)| segment.transaction == transaction && segment.parent == parent)
+            .map(|(&id, segment)| RecordedSegment {
+                kind: segment.kind.clone(),
+                children: self.segment_tree(transaction, Some(id)),
+                timing: segment.timing,
+                ended: segment.ended,
+            })
+            .collect()
+    }
+
+    fn snapshot(&self, id: usize) -> RecordedTransaction {
+        let transaction = &self.transactions[&id];
+        RecordedTransaction {
+            transaction_type: transaction.transaction_type,
+            names: transaction.names.clone(),
+            attributes: transaction.attributes.clone(),
+            errors: transaction.errors.clone(),
+            custom_events: transaction.custom_events.clone(),
+            custom_metrics: transaction.custom_metrics.clone(),
+            segments: self.segment_tree(id, None),
+            timing: transaction.timing,
+            ignored: transaction.ignored,
+            ended: transaction.ended,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.transactions
+            .retain(|_, transaction| !transaction.ended);
+        let transactions = &self.transactions;
+        self.segments
+            .retain(|_, segment| transactions.contains_key(&segment.transaction));
+    }
+}
+
+/// A backend which records everything in memory.
+#[derive(Default)]
+struct RecordingBackend {
+    state: Mutex<State>,
+}
+
+impl RecordingBackend {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn start_segment(&self, transaction: TransactionHandle, kind: SegmentKind) -> SegmentHandle {
+        let mut state = self.state();
+        let id = state.next_id();
+        state.segments.insert(
+            id,
+            SegmentState {
+                transaction: transaction.0,
+                parent: None,
+                kind,
+                timing: None,
+                ended: false,
+            },
+        );
+        SegmentHandle(id)
+    }
+}
+
+impl Backend for RecordingBackend {
+    fn start_transaction(&self, name: &CStr, kind: &TransactionType) -> Option<TransactionHandle> {
+        let mut state = self.state();
+        let id = state.next_id();
+        state.transactions.insert(
+            id,
+            TransactionState {
+                transaction_type: *kind,
+                names: vec![string(name)],
+                attributes: BTreeMap::new(),
+                errors: Vec::new(),
+                custom_events: Vec::new(),
+                custom_metrics: Vec::new(),
+                timing: None,
+                ignored: false,
+                ended: false,
+            },
+        );
+        Some(TransactionHandle(id))
+    }
+
+    fn set_transaction_name(&self, transaction: TransactionHandle, name: &CStr) -> bool {
+        match self.state().transaction(transaction) {
+            Some(transaction) => {
+                transaction.names.push(string(name));
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn add_attribute(
+        &self,
+        transaction: TransactionHandle,
+        name: &CStr,
+        value: AttributeValue<'_>,
+    ) -> bool {
+        match self.state().transaction(transaction) {
+            Some(transaction) => {
+                transaction
+                    .attributes
+                    .insert(string(name), Value::recorded(value));
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn notice_error(
+        &self,
+        transaction: TransactionHandle,
+        priority: i32,
+        message: &CStr,
+        class: &CStr,
+        backtrace: &[String],
+    ) {
+        if let Some(transaction) = self.state().transaction(transaction) {
+            transaction.errors.push(RecordedError {
+                priority,
+                message: string(message),
+                class: string(class),
+                backtrace: backtrace.to_vec(),
+            });
+        }
+    }
+
+    fn set_transaction_timing(
+        &self,
+        transaction: TransactionHandle,
+        start_micros: u64,
+        duration_micros: u64,
+    ) -> bool {
+        match self.state().transaction(transaction) {
+            Some(transaction) => {
+                transaction.timing = Some((
+                    UNIX_EPOCH + Duration::from_micros(start_micros),
+                    Duration::from_micros(duration_micros),
+                ));
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn ignore_transaction(&self, transaction: TransactionHandle) -> bool {
+        match self.state().transaction(transaction) {
+            Some(transaction) => {
+                transaction.ignored = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn record_custom_metric(
+        &self,
+        transaction: TransactionHandle,
+        name: &CStr,
+        value: f64,
+        unit: MetricUnit,
+    ) -> bool {
+        match self.state().transaction(transaction) {
+            Some(transaction) => {
+                transaction.custom_metrics.push(RecordedMetric {
+                    name: string(name),
+                    milliseconds: unit.milliseconds(value),
+                    value,
+                    unit,
+                });
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn end_transaction(&self, transaction: TransactionHandle) {
+        if let Some(transaction) = self.state().transaction(transaction) {
+            transaction.ended = true;
+        }
+    }
+
+    fn start_custom_segment(
+        &self,
+        transaction: TransactionHandle,
+        name: &CStr,
+        category: &CStr,
+    ) -> Option<SegmentHandle> {
+        let kind = SegmentKind::Custom {
+            name: string(name),
+            category: string(category),
+        };
+        Some(self.start_segment(transaction, kind))
+    }
+
+    fn start_datastore_segment(
+        &self,
+        transaction: TransactionHandle,
+        params: &DatastoreParams,
+    ) -> Option<SegmentHandle> {
+        let kind = SegmentKind::Datastore(params.into());
+        Some(self.start_segment(transaction, kind))
+    }
+
+    fn start_external_segment(
+        &self,
+        transaction: TransactionHandle,
+        params: &ExternalParams,
+    ) -> Option<SegmentHandle> {
+        let kind = SegmentKind::External(params.into());
+        Some(self.start_segment(transaction, kind))
+    }
+
+    fn set_segment_parent(
+        &self,
+        _transaction: TransactionHandle,
+        segment: SegmentHandle,
+        parent: SegmentHandle,
+    ) -> bool {
+        match self.state().segment(segment) {
+            Some(segment) => {
+                segment.parent = Some(parent.0);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn set_segment_timing(
+        &self,
+        _transaction: TransactionHandle,
+        segment: SegmentHandle,
+        start_micros: u64,
+        duration_micros: u64,
+    ) -> bool {
+        match self.state().segment(segment) {
+            Some(segment) => {
+                segment.timing = Some((
+                    Duration::from_micros(start_micros),
+                    Duration::from_micros(duration_micros),
+                ));
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn end_segment(&self, _transaction: TransactionHandle, segment: SegmentHandle) {
+        if let Some(segment) = self.state().segment(segment) {
+            segment.ended = true;
+        }
+    }
+
+    #[cfg(feature = "distributed_tracing")]
+    fn distributed_trace_payload(
+        &self,
+        transaction: TransactionHandle,
+        segment: SegmentHandle,
+    ) -> Option<String> {
+        Some(format!("recording-{}-{}", transaction.0, segment.0))
+    }
+
+    fn create_custom_event(&self, event_type: &CStr) -> Option<CustomEventHandle> {
+        let mut state = self.state();
+        let id = state.next_id();
+        state.custom_events.insert(
+            id,
+            RecordedCustomEvent {
+                event_type: string(event_type),
+                attributes: BTreeMap::new(),
+            },
+        );
+        Some(CustomEventHandle(id))
+    }
+
+    fn add_custom_event_attribute(
+        &self,
+        event: CustomEventHandle,
+        name: &CStr,
+        value: AttributeValue<'_>,
+    ) -> bool {
+        match self.state().custom_events.get_mut(&event.0) {
+            Some(event) => {
+                event
+                    .attributes
+                    .insert(string(name), Value::recorded(value));
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn record_custom_event(&self, transaction: TransactionHandle, event: CustomEventHandle) {
+        let mut state = self.state();
+        if let Some(event) = state.custom_events.remove(&event.0) {
+            if let Some(transaction) = state.transaction(transaction) {
+                transaction.custom_events.push(event);
+            }
+        }
+    }
+
+    fn discard_custom_event(&self, event: CustomEventHandle) {
+        self.state().custom_events.remove(&event.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ignore::IgnoreRules, naming::NamingRules};
+
+    #[test]
+    fn clear_removes_ended_transactions() {
+        let app = RecordingApp::new();
+        let running = app.web_transaction("running").unwrap();
+        {
+            let ended = app.non_web_transaction("ended").unwrap();
+            ended.custom_segment("segment", "test", |_| {});
+        }
+        let mut segment = running.create_custom_segment("segment", "test");
+
+        app.clear();
+        assert_eq!(app.transactions().len(), 1);
+        assert!(app.transaction("ended").is_none());
+        {
+            let state = app.backend.state();
+            assert_eq!(state.transactions.len(), 1);
+            assert_eq!(state.segments.len(), 1);
+        }
+
+        segment.end();
+        drop(segment);
+        drop(running);
+        let running = app.transaction("running").unwrap();
+        assert!(running.ended);
+        assert!(running.segments[0].ended);
+
+        app.clear();
+        assert!(app.transactions().is_empty());
+        let state = app.backend.state();
+        assert!(state.transactions.is_empty());
+        assert!(state.segments.is_empty());
+    }
+
+    #[test]
+    fn handles_to_cleared_data_are_ignored() {
+        let app = RecordingApp::new();
+        let transaction = app.web_transaction("transaction").unwrap();
+        let handle = transaction.inner().unwrap().1;
+        drop(transaction);
+        app.clear();
+        let name = CString::new("renamed").unwrap();
+        assert!(!app.backend.set_transaction_name(handle, &name));
+        assert!(app.transactions().is_empty());
+    }
+
+    #[test]
+    fn error_classes() {
+        use std::error::Error as StdError;
+
+        let app = RecordingApp::new();
+        let transaction = app.web_transaction("errors").unwrap();
+        let error = "forty-two".parse::<i32>().unwrap_err();
+        transaction.notice_error_from(&error).unwrap();
+        let boxed: Box<dyn StdError> = Box::new(error.clone());
+        transaction.notice_error_from(boxed.as_ref()).unwrap();
+        transaction
+            .notice_error_with_class(0, boxed.as_ref(), "InvalidAnswer", &[])
+            .unwrap();
+        drop(transaction);
+
+        let errors = &app.transaction("errors").unwrap().errors;
+        assert_eq!(errors[0].class, "core::num::error::ParseIntError");
+        // Trait objects are classed by the trait, whose path depends on the
+        // Rust version.
+        assert!(errors[1].class.starts_with("dyn "), "{}", errors[1].class);
+        assert_eq!(errors[2].class, "InvalidAnswer");
+        assert!(errors
+            .iter()
+            .all(|e| e.message == "invalid digit found in string"));
+    }
+
+    #[test]
+    fn flushes_metrics() {
+        let app = RecordingApp::from_builder(
+            AppBuilder::new("test", &"0".repeat(40))
+                .unwrap()
+                .ignore_rules(IgnoreRules::new().ignore("*")),
+        );
+        let counter = app.counter("Custom/Processed", MetricUnit::Count).unwrap();
+        app.flush_metrics().unwrap();
+        assert!(app.transactions().is_empty());
+
+        counter.add(2.0);
+        drop(app.non_web_transaction("ignored").unwrap());
+        app.flush_metrics().unwrap();
+        counter.increment();
+
+        let backend = Arc::clone(&app.backend);
+        drop(app);
+        let state = backend.state();
+        let flushes: Vec<_> = state
+            .transactions
+            .keys()
+            .map(|&id| state.snapshot(id))
+            .filter(|t| t.names == ["FlushMetrics"])
+            .collect();
+        assert_eq!(flushes.len(), 2);
+        for (flush, value) in flushes.iter().zip(&[2.0, 1.0]) {
+            assert!(flush.ended && !flush.ignored);
+            assert_eq!(flush.transaction_type, TransactionType::NonWeb);
+            assert_eq!(flush.custom_metrics.len(), 1);
+            assert_eq!(flush.custom_metrics[0].name, "Custom/Processed[count]");
+            assert_eq!(flush.custom_metrics[0].value, *value);
+        }
+    }
+
+    #[test]
+    fn ignored_names_are_not_counted() {
+        let app = RecordingApp::from_builder(
+            AppBuilder::new("test", &"0".repeat(40))
+                .unwrap()
+                .naming_rules(NamingRules::new().collapse_ids().max_unique_names(2))
+                .ignore_rules(IgnoreRules::new().ignore("GET /health*")),
+        );
+        drop(app.web_transaction("GET /health").unwrap());
+        drop(app.web_transaction("GET /health/1").unwrap());
+        let renamed = app.web_transaction("index").unwrap();
+        renamed.name("GET /health/2").unwrap();
+        drop(renamed);
+        drop(app.web_transaction("GET /users/1").unwrap());
+        drop(app.web_transaction("GET /orders").unwrap());
+
+        let names: Vec<_> = app
+            .transactions()
+            .into_iter()
+            .map(|t| (t.names, t.ignored))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                (vec!["GET /health".to_string()], true),
+                (vec!["GET /health/*".to_string()], true),
+                (vec!["index".to_string(), "GET /health/*".to_string()], true),
+                (vec!["GET /users/*".to_string()], false),
+                (vec!["Other".to_string()], false),
+            ]
+        );
+    }
+}
@@ -1,16 +1,43 @@
-use std::{ffi::CString, time::Duration};
+use std::{
+    any,
+    borrow::Cow,
+    convert::TryFrom,
+    error::Error as StdError,
+    ffi::CString,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use log::{debug, error};
-use newrelic_sys as ffi;
+use log::{debug, error, warn};
 
+#[cfg(feature = "serde")]
+use crate::attributes;
 use crate::{
-    app::App,
+    app::{micros, App},
+    attributes::{
+        truncate, AttributeFilter, AttributeNames, MAX_ATTRIBUTE_BYTES, MAX_TRANSACTION_ATTRIBUTES,
+    },
+    backend::{AttributeValue, Backend, TransactionHandle},
+    backtrace,
     error::{Error, Result},
     event::CustomEvent,
+    ignore::IgnoreRules,
+    metrics::{MetricRegistry, MetricUnit},
+    naming::TransactionNamer,
     segment::{DatastoreParams, ExternalParams, Segment},
+    web::{WebRequest, WebResponse},
 };
 
+/// The attribute holding the Rust backtrace of a noticed error, for
+/// backends which can't record it with the error.
+const BACKTRACE_ATTRIBUTE: &str = "error.backtrace";
+
 /// A type of transaction monitored by New Relic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TransactionType {
     /// A web transaction.
     Web,
@@ -18,8 +45,17 @@ pub enum TransactionType {
     NonWeb,
 }
 
-/// An attribute to add to a transaction.
+/// An attribute to add to a transaction or custom event.
+///
+/// Attributes are usually created by converting a value using `From`, which
+/// is implemented for integers, floats, booleans, strings, `Duration`s and
+/// `Option`s of those. Use `Attribute::display` for any other `Display`
+/// type.
+///
+/// More kinds of attribute may be added in future, so matches on this enum
+/// need a wildcard arm.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Attribute<'a> {
     /// A short (i32) integer attribute.
     Int(i32),
@@ -31,6 +67,67 @@ pub enum Attribute<'a> {
     String(&'a str),
     /// An owned string attribute.
     OwnedString(&'a String),
+    /// A boolean attribute.
+    ///
+    /// The C SDK has no boolean attributes, so with the C SDK these are
+    /// sent as the strings `"true"` and `"false"`.
+    Bool(bool),
+    /// An unsigned long (u64) integer attribute.
+    ///
+    /// Values up to `i64::MAX` are sent as long attributes; larger values
+    /// are sent as the nearest float.
+    UnsignedLong(u64),
+    /// A borrowed or owned string attribute.
+    Text(Cow<'a, str>),
+    /// No attribute. Adding this does nothing.
+    None,
+}
+
+impl<'a> Attribute<'a> {
+    /// Create a string attribute from the `Display` representation of a
+    /// value.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use std::net::Ipv4Addr;
+    ///
+    /// use newrelic::Attribute;
+    ///
+    /// let attribute = Attribute::display(Ipv4Addr::LOCALHOST);
+    /// ```
+    pub fn display(value: impl fmt::Display) -> Self {
+        Attribute::Text(Cow::Owned(value.to_string()))
+    }
+
+    /// Call `f` with the value of this attribute in the form passed to a
+    /// backend, returning `None` without calling it for `Attribute::None`.
+    ///
+    /// Returns an error if a string attribute contains a NUL byte.
+    /// String values longer than New Relic allows are truncated, with a
+    /// warning naming the attribute.
+    pub(crate) fn with_value<T>(
+        &self,
+        name: &str,
+        f: impl FnOnce(AttributeValue<'_>) -> T,
+    ) -> Result<Option<T>> {
+        Ok(Some(match self {
+            Attribute::Int(i) => f(AttributeValue::Int(*i)),
+            Attribute::Long(l) => f(AttributeValue::Long(*l)),
+            Attribute::Float(x) => f(AttributeValue::Float(*x)),
+            Attribute::String(s) => f(AttributeValue::String(&CString::new(truncate(name, s))?)),
+            Attribute::OwnedString(s) => {
+                f(AttributeValue::String(&CString::new(truncate(name, s))?))
+            }
+            Attribute::Bool(b) => f(AttributeValue::Bool(*b)),
+            Attribute::UnsignedLong(u) => match i64::try_from(*u) {
+                Ok(l) => f(AttributeValue::Long(l)),
+                Err(_) => f(AttributeValue::Float(*u as f64)),
+            },
+            Attribute::Text(s) => f(AttributeValue::String(&CString::new(truncate(name, s))?)),
+            Attribute::None => return Ok(None),
+        }))
+    }
 }
 
 impl<'a> From<i32> for Attribute<'a> {
@@ -73,6 +170,68 @@ impl<'a> From<&'a String> for Attribute<'a> {
     }
 }
 
+impl<'a> From<bool> for Attribute<'a> {
+    #[inline]
+    fn from(original: bool) -> Attribute<'a> {
+        Attribute::Bool(original)
+    }
+}
+
+impl<'a> From<u32> for Attribute<'a> {
+    #[inline]
+    fn from(original: u32) -> Attribute<'a> {
+        Attribute::Long(original.into())
+    }
+}
+
+impl<'a> From<u64> for Attribute<'a> {
+    #[inline]
+    fn from(original: u64) -> Attribute<'a> {
+        Attribute::UnsignedLong(original)
+    }
+}
+
+impl<'a> From<usize> for Attribute<'a> {
+    #[inline]
+    fn from(original: usize) -> Attribute<'a> {
+        Attribute::UnsignedLong(original as u64)
+    }
+}
+
+impl<'a> From<String> for Attribute<'a> {
+    #[inline]
+    fn from(original: String) -> Attribute<'a> {
+        Attribute::Text(Cow::Owned(original))
+    }
+}
+
+impl<'a> From<Cow<'a, str>> for Attribute<'a> {
+    #[inline]
+    fn from(original: Cow<'a, str>) -> Attribute<'a> {
+        Attribute::Text(original)
+    }
+}
+
+/// Durations are sent as float attributes in seconds, as New Relic does
+/// for its own durations.
+impl<'a> From<Duration> for Attribute<'a> {
+    #[inline]
+    fn from(original: Duration) -> Attribute<'a> {
+        Attribute::Float(original.as_secs_f64())
+    }
+}
+
+/// `None` converts to `Attribute::None`, so that adding it does nothing.
+impl<'a, T> From<Option<T>> for Attribute<'a>
+where
+    T: Into<Attribute<'a>>,
+{
+    #[inline]
+    fn from(original: Option<T>) -> Attribute<'a> {
+        original.map_or(Attribute::None, Into::into)
+    }
+}
+
 #[derive(PartialEq, Eq)]
 enum State {
     Running,
@@ -80,45 +239,121 @@ enum State {
 }
 
 /// A transaction monitored by New Relic.
+///
+/// A transaction may be a no-op, for example when it was started using
+/// a `global::GlobalApp` without an installed app. No-op transactions
+/// silently ignore every method call, and their segments and custom events
+/// are no-ops too.
 pub struct Transaction {
-    pub(crate) inner: *mut ffi::newrelic_txn_t,
+    /// The backend recording this transaction and the transaction's handle,
+    /// or `None` for a no-op transaction.
+    inner: Option<(Arc<dyn Backend>, TransactionHandle)>,
     _type: TransactionType,
     state: State,
+    attribute_names: AttributeNames,
+    attribute_filter: Arc<AttributeFilter>,
+    namer: Arc<TransactionNamer>,
+    ignore_rules: Arc<IgnoreRules>,
+    metrics: Arc<MetricRegistry>,
+    ignored: AtomicBool,
+    /// The index of the sampling rule in `ignore_rules` the transaction was
+    /// last sampled by, if any.
+    sampled_by: Mutex<Option<usize>>,
 }
 
 impl Transaction {
     pub(crate) fn web(app: &App, name: &str) -> Result<Self> {
-        let name = CString::new(name)?;
-        let inner = unsafe { ffi::newrelic_start_web_transaction(app.inner, name.as_ptr()) };
-        if inner.is_null() {
-            error!("Could not start web transaction");
-            Err(Error::TransactionStartError)
-        } else {
-            debug!("Started web transaction");
-            Ok(Transaction {
-                inner,
-                _type: TransactionType::Web,
-                state: State::Running,
-            })
-        }
+        Self::start(app, name, TransactionType::Web)
     }
 
     pub(crate) fn non_web(app: &App, name: &str) -> Result<Self> {
-        let name = CString::new(name)?;
-        let inner = unsafe { ffi::newrelic_start_non_web_transaction(app.inner, name.as_ptr()) };
-        if inner.is_null() {
-            error!("Could not start non-web transaction");
-            Err(Error::TransactionStartError)
+        Self::start(app, name, TransactionType::NonWeb)
+    }
+
+    fn start(app: &App, name: &str, _type: TransactionType) -> Result<Self> {
+        CString::new(name)?;
+        let kind = match _type {
+            TransactionType::Web => "web",
+            TransactionType::NonWeb => "non-web",
+        };
+        let backend = match app.backend() {
+            Some(backend) => backend,
+            None => return Ok(Self::noop(_type)),
+        };
+        let name = app.namer().rename(name);
+        let mut sampled_by = None;
+        let ignore = app.ignore_rules().should_ignore(&name, &mut sampled_by);
+        let name = if ignore {
+            name
         } else {
-            debug!("Started non-web transaction");
-            Ok(Transaction {
-                inner,
-                _type: TransactionType::NonWeb,
-                state: State::Running,
-            })
+            app.namer().limit(name)
+        };
+        match backend.start_transaction(&CString::new(name.as_ref())?, &_type) {
+            Some(handle) => {
+                debug!("Started {} transaction", kind);
+                let transaction = Transaction {
+                    inner: Some((Arc::clone(backend), handle)),
+                    _type,
+                    state: State::Running,
+                    attribute_names: AttributeNames::new(MAX_TRANSACTION_ATTRIBUTES),
+                    attribute_filter: Arc::clone(app.attribute_filter()),
+                    namer: Arc::clone(app.namer()),
+                    ignore_rules: Arc::clone(app.ignore_rules()),
+                    metrics: Arc::clone(app.metrics()),
+                    ignored: AtomicBool::new(false),
+                    sampled_by: Mutex::new(sampled_by),
+                };
+                if ignore {
+                    transaction.ignore()?;
+                }
+                Ok(transaction)
+            }
+            None => {
+                error!("Could not start {} transaction", kind);
+                Err(Error::TransactionStartError)
+            }
+        }
+    }
+
+    /// Create a transaction which ignores every method call.
+    pub(crate) fn noop(_type: TransactionType) -> Self {
+        Transaction {
+            inner: None,
+            _type,
+            state: State::Ended,
+            attribute_names: AttributeNames::new(MAX_TRANSACTION_ATTRIBUTES),
+            attribute_filter: Arc::default(),
+            namer: Arc::default(),
+            ignore_rules: Arc::default(),
+            metrics: Arc::default(),
+            ignored: AtomicBool::new(false),
+            sampled_by: Mutex::new(None),
         }
     }
 
+    /// The backend recording this transaction and the transaction's handle,
+    /// or `None` for a no-op transaction.
+    pub(crate) fn inner(&self) -> Option<(&dyn Backend, TransactionHandle)> {
+        self.inner
+            .as_ref()
+            .map(|(backend, handle)| (backend.as_ref(), *handle))
+    }
+
+    /// The filter applied to the attributes of this transaction and its
+    /// custom events.
+    pub(crate) fn attribute_filter(&self) -> &AttributeFilter {
+        &self.attribute_filter
+    }
+
+    /// Whether this is a no-op transaction, which doesn't report anything
+    /// to New Relic.
+    ///
+    /// Transactions started from a disabled app, or from a global handle
+    /// with no app installed, are no-ops.
+    pub fn is_noop(&self) -> bool {
+        self.inner.is_none()
+    }
+
     /// Get the type of the transaction.
     pub fn r#type(&self) -> &TransactionType {
         &self._type
@@ -126,31 +361,40 @@ impl Transaction {
 
     /// Add an attribute to the transaction.
     ///
+    /// New Relic keeps at most 64 attributes per transaction, so adding
+    /// more returns `Error::TooManyAttributes`; replacing an existing
+    /// attribute doesn't count towards the limit. Names longer than 255
+    /// bytes return `Error::AttributeNameTooLong`, and string values longer
+    /// than 255 bytes are truncated on a character boundary, with a
+    /// warning.
+    ///
+    /// Attributes excluded by the app's `AttributeFilter` are dropped
+    /// silently, and don't count towards the limit.
+    ///
     /// Returns an error if the New Relic SDK returns an error.
     pub fn add_attribute<'a, T>(&self, name: &str, attribute: T) -> Result<()>
     where
         T: Into<Attribute<'a>>,
     {
-        let name = CString::new(name)?;
-        let ok = match attribute.into() {
-            Attribute::Int(i) => unsafe {
-                ffi::newrelic_add_attribute_int(self.inner, name.as_ptr(), i)
-            },
-            Attribute::Float(f) => unsafe {
-                ffi::newrelic_add_attribute_double(self.inner, name.as_ptr(), f)
-            },
-            Attribute::Long(l) => unsafe {
-                ffi::newrelic_add_attribute_long(self.inner, name.as_ptr(), l)
-            },
-            Attribute::String(s) => {
-                let s = CString::new(s)?;
-                unsafe { ffi::newrelic_add_attribute_string(self.inner, name.as_ptr(), s.as_ptr()) }
-            }
-            Attribute::OwnedString(s) => {
-                let s = CString::new(s.as_str())?;
-                unsafe { ffi::newrelic_add_attribute_string(self.inner, name.as_ptr(), s.as_ptr()) }
-            }
+        self.set_attribute(name, &attribute.into())
+    }
+
+    fn set_attribute(&self, name: &str, attribute: &Attribute<'_>) -> Result<()> {
+        let (backend, handle) = match self.inner() {
+            Some(inner) => inner,
+            None => return Ok(()),
         };
+        if let Attribute::None = attribute {
+            return Ok(());
+        }
+        if !self.attribute_filter.check(name) {
+            return Ok(());
+        }
+        let c_name = CString::new(name)?;
+        self.attribute_names.add(name)?;
+        let ok = attribute
+            .with_value(name, |value| backend.add_attribute(handle, &c_name, value))?
+            .unwrap_or(true);
         if ok {
             Ok(())
         } else {
@@ -158,6 +402,111 @@ impl Transaction {
         }
     }
 
+    /// Add every field of a serializable value, such as a struct deriving
+    /// `Serialize`, as an attribute of the transaction.
+    ///
+    /// Nested structs and maps are flattened, with their keys joined by
+    /// dots. New Relic attributes can't hold lists, so sequences are added
+    /// as their JSON representation; `None` and unit values are skipped.
+    ///
+    /// Returns `Error::SerializeError` if the value doesn't serialize to a
+    /// struct or map. If adding an attribute fails, the attributes before
+    /// it will already have been added.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use newrelic::App;
+    /// use serde::Serialize;
+    ///
+    /// #[derive(Serialize)]
+    /// struct User {
+    ///     id: u64,
+    ///     plan: &'static str,
+    /// }
+    ///
+    /// #[derive(Serialize)]
+    /// struct RequestContext {
+    ///     request_id: String,
+    ///     user: User,
+    /// }
+    ///
+    /// let license_key = std::env::var("NEW_RELIC_LICENSE_KEY").unwrap();
+    ///
+    /// let app = App::new("my app", &license_key)
+    ///     .expect("Could not create app");
+    /// let transaction = app
+    ///     .web_transaction("Test transaction")
+    ///     .expect("Could not start transaction");
+    /// let context = RequestContext {
+    ///     request_id: "abc123".to_string(),
+    ///     user: User { id: 42, plan: "free" },
+    /// };
+    /// // Adds `request_id`, `user.id` and `user.plan`.
+    /// transaction
+    ///     .add_attributes(&context)
+    ///     .expect("Could not add attributes");
+    /// ```
+    #[cfg(feature = "serde")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "serde")))]
+    pub fn add_attributes<T>(&self, value: &T) -> Result<()>
+    where
+        T: serde::Serialize + ?Sized,
+    {
+        if self.is_noop() {
+            return Ok(());
+        }
+        for (name, attribute) in attributes::flatten(value)? {
+            self.set_attribute(&name, &attribute)?;
+        }
+        Ok(())
+    }
+
+    /// Record the request handled by this web transaction, using the
+    /// standard `request.*` attributes understood by the New Relic UI.
+    ///
+    /// See `WebRequest` for the attributes recorded. With the `http`
+    /// feature, an `&http::Request` or `&http::request::Parts` can be
+    /// passed directly.
+    ///
+    /// Returns an error if any of the attributes can't be added, as for
+    /// `Transaction::add_attribute`.
+    pub fn set_web_request<'a, R>(&self, request: R) -> Result<()>
+    where
+        R: Into<WebRequest<'a>>,
+    {
+        if self.is_noop() {
+            return Ok(());
+        }
+        for (name, attribute) in request.into().attributes() {
+            self.set_attribute(&name, &attribute)?;
+        }
+        Ok(())
+    }
+
+    /// Record the response sent by this web transaction, using the
+    /// standard `http.statusCode` and `response.*` attributes understood by
+    /// the New Relic UI.
+    ///
+    /// See `WebResponse` for the attributes recorded. With the `http`
+    /// feature, an `&http::Response` or `&http::response::Parts` can be
+    /// passed directly.
+    ///
+    /// Returns an error if any of the attributes can't be added, as for
+    /// `Transaction::add_attribute`.
+    pub fn set_web_response<'a, R>(&self, response: R) -> Result<()>
+    where
+        R: Into<WebResponse<'a>>,
+    {
+        if self.is_noop() {
+            return Ok(());
+        }
+        for (name, attribute) in response.into().attributes() {
+            self.set_attribute(&name, &attribute)?;
+        }
+        Ok(())
+    }
+
     /// Create a custom segment within this transaction.
     ///
     /// Example:
@@ -341,40 +690,265 @@ impl Transaction {
     ///
     /// `priority` is an arbitrary integer indicating the error priority.
     /// `message` is the error message; `class` is the error class or type.
+    ///
+    /// If backtraces are enabled using `RUST_LIB_BACKTRACE` or
+    /// `RUST_BACKTRACE`, a backtrace of the caller is sent with the error.
+    /// The `pure-rust` and `otlp` backends send it as the error's stack
+    /// trace. The C SDK can't record Rust stack traces, so it adds as many
+    /// of the top frames as fit in 255 bytes as the `error.backtrace`
+    /// attribute of the transaction instead; like any other attribute, it
+    /// is subject to the app's `AttributeFilter` and the transaction's
+    /// attribute limit.
     pub fn notice_error(&self, priority: i32, message: &str, class: &str) -> Result<()> {
+        self.notice_error_with_attributes(priority, message, class, &[])
+    }
+
+    /// Add `attributes` to the transaction and record an error, after
+    /// checking that the message and class are valid.
+    fn notice_error_with_attributes(
+        &self,
+        priority: i32,
+        message: &str,
+        class: &str,
+        attributes: &[(&str, Attribute<'_>)],
+    ) -> Result<()> {
+        let (backend, handle) = match self.inner() {
+            Some(inner) => inner,
+            None => return Ok(()),
+        };
         let message = CString::new(message)?;
         let class = CString::new(class)?;
-        unsafe {
-            ffi::newrelic_notice_error(self.inner, priority, message.as_ptr(), class.as_ptr());
+        for (name, attribute) in attributes {
+            self.set_attribute(name, attribute)?;
+        }
+        let backtrace = backtrace::capture();
+        if !backtrace.is_empty() && !backend.records_backtraces() {
+            let frames = backtrace::top_frames(&backtrace, MAX_ATTRIBUTE_BYTES);
+            if let Err(e) = self.set_attribute(BACKTRACE_ATTRIBUTE, &Attribute::String(&frames)) {
+                warn!("Could not add the backtrace of a noticed error: {}", e);
+            }
         }
+        backend.notice_error(handle, priority, &message, &class, &backtrace);
         Ok(())
     }
 
+    /// Record an error value in this transaction.
+    ///
+    /// The error class is the full name of the error's type, as given by
+    /// `std::any::type_name`, and the message is the error followed by each
+    /// of its sources, separated by `": "`. The error is noticed with a
+    /// priority of 0; use `notice_error_with` to set the priority or add
+    /// attributes.
+    ///
+    /// **Trait objects aren't classed by their concrete type.** The class
+    /// is the name of the static type of `error`, so every `&dyn Error`,
+    /// such as the contents of a `Box<dyn Error>`, is classed as
+    /// `dyn core::error::Error` and grouped together in New Relic. Downcast
+    /// these to their concrete type first, or use `notice_error_with_class`
+    /// to set the class explicitly.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use newrelic::App;
+    ///
+    /// let license_key = std::env::var("NEW_RELIC_LICENSE_KEY").unwrap();
+    ///
+    /// let app = App::new("my app", &license_key)
+    ///     .expect("Could not create app");
+    /// let transaction = app
+    ///     .web_transaction("Test transaction")
+    ///     .expect("Could not start transaction");
+    /// if let Err(e) = "forty-two".parse::<i32>() {
+    ///     // Noticed with the class "core::num::error::ParseIntError".
+    ///     transaction.notice_error_from(&e).expect("Could not notice error");
+    /// }
+    /// ```
+    pub fn notice_error_from<E>(&self, error: &E) -> Result<()>
+    where
+        E: StdError + ?Sized,
+    {
+        self.notice_error_with(0, error, &[])
+    }
+
+    /// Record an error value in this transaction, with a priority and
+    /// extra attributes.
+    ///
+    /// The class and message are derived from `error` as for
+    /// `notice_error_from`, so trait objects are all given the same class.
+    /// New Relic reports errors with the attributes of their transaction,
+    /// so `attributes` are added to the transaction.
+    ///
+    /// Nothing is added if the message or class contains a NUL byte. If
+    /// adding an attribute fails, the error isn't recorded, but the
+    /// attributes before it will already have been added.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use newrelic::App;
+    ///
+    /// let license_key = std::env::var("NEW_RELIC_LICENSE_KEY").unwrap();
+    ///
+    /// let app = App::new("my app", &license_key)
+    ///     .expect("Could not create app");
+    /// let transaction = app
+    ///     .web_transaction("Test transaction")
+    ///     .expect("Could not start transaction");
+    /// if let Err(e) = std::fs::read("config.toml") {
+    ///     transaction
+    ///         .notice_error_with(10, &e, &[("path", "config.toml".into())])
+    ///         .expect("Could not notice error");
+    /// }
+    /// ```
+    pub fn notice_error_with<E>(
+        &self,
+        priority: i32,
+        error: &E,
+        attributes: &[(&str, Attribute<'_>)],
+    ) -> Result<()>
+    where
+        E: StdError + ?Sized,
+    {
+        self.notice_error_with_class(priority, error, any::type_name::<E>(), attributes)
+    }
+
+    /// Record an error value in this transaction, with a priority, class and
+    /// extra attributes.
+    ///
+    /// The message is derived from `error` as for `notice_error_from`, but
+    /// the class is given by the caller, which is useful for trait objects
+    /// or to group errors differently. Attributes are added as for
+    /// `notice_error_with`.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use std::error::Error;
+    ///
+    /// use newrelic::App;
+    ///
+    /// let license_key = std::env::var("NEW_RELIC_LICENSE_KEY").unwrap();
+    ///
+    /// let app = App::new("my app", &license_key)
+    ///     .expect("Could not create app");
+    /// let transaction = app
+    ///     .web_transaction("Test transaction")
+    ///     .expect("Could not start transaction");
+    /// let result: Result<(), Box<dyn Error>> = Err("no such user".into());
+    /// if let Err(e) = result {
+    ///     transaction
+    ///         .notice_error_with_class(0, e.as_ref(), "UserNotFound", &[])
+    ///         .expect("Could not notice error");
+    /// }
+    /// ```
+    pub fn notice_error_with_class<E>(
+        &self,
+        priority: i32,
+        error: &E,
+        class: &str,
+        attributes: &[(&str, Attribute<'_>)],
+    ) -> Result<()>
+    where
+        E: StdError + ?Sized,
+    {
+        if self.is_noop() {
+            return Ok(());
+        }
+        self.notice_error_with_attributes(priority, &error_message(error), class, attributes)
+    }
+
     /// Ignore this transaction.
     ///
     /// Data for this transaction will not be sent to New Relic.
     pub fn ignore(&self) -> Result<()> {
-        let ok = unsafe { ffi::newrelic_ignore_transaction(self.inner) };
+        let (backend, handle) = match self.inner() {
+            Some(inner) => inner,
+            None => return Ok(()),
+        };
+        let ok = backend.ignore_transaction(handle);
         if ok {
+            self.ignored.store(true, Ordering::Relaxed);
             Ok(())
         } else {
             Err(Error::IgnoreError)
         }
     }
 
+    /// Override the start time and duration of this transaction.
+    ///
+    /// This is useful when the work being reported happened before it was
+    /// instrumented, for example when replaying requests from a log. The
+    /// timing replaces the one measured by New Relic, and is used when the
+    /// transaction ends.
+    ///
+    /// Returns `Error::InvalidTiming` if `start` is before the Unix epoch or
+    /// in the future, and `Error::DurationOverFlow` if `duration` is too
+    /// long to be represented in microseconds.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use std::time::{Duration, SystemTime};
+    ///
+    /// use newrelic::App;
+    ///
+    /// let license_key = std::env::var("NEW_RELIC_LICENSE_KEY").unwrap();
+    ///
+    /// let app = App::new("my app", &license_key)
+    ///     .expect("Could not create app");
+    /// let transaction = app
+    ///     .web_transaction("Replayed request")
+    ///     .expect("Could not start transaction");
+    /// let start = SystemTime::now() - Duration::from_secs(60);
+    /// transaction
+    ///     .set_timing(start, Duration::from_millis(250))
+    ///     .expect("Could not set timing");
+    /// ```
+    pub fn set_timing(&self, start: SystemTime, duration: Duration) -> Result<()> {
+        // Validate the timing first, so it is rejected for no-op
+        // transactions too.
+        if start > SystemTime::now() {
+            return Err(Error::InvalidTiming(
+                "start time is in the future".to_string(),
+            ));
+        }
+        let since_epoch = start
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| Error::InvalidTiming("start time is before the Unix epoch".to_string()))?;
+        let (start, duration) = (micros(since_epoch)?, micros(duration)?);
+        let (backend, handle) = match self.inner() {
+            Some(inner) => inner,
+            None => return Ok(()),
+        };
+        let ok = backend.set_transaction_timing(handle, start, duration);
+        if ok {
+            Ok(())
+        } else {
+            Err(Error::TimingError)
+        }
+    }
+
     /// Record a custom metric for this transaction.
     ///
     /// The metric will be named according to `metric_name` and will
-    /// record for `duration`.
+    /// record for `duration`, with microsecond precision.
+    ///
+    /// To record values other than times, or to aggregate values before
+    /// they are reported, use `App::counter`, `App::gauge` or
+    /// `App::summary` instead.
     pub fn record_custom_metric(&self, metric_name: &str, duration: Duration) -> Result<()> {
-        let metric_name = CString::new(metric_name)?;
-        let ok = unsafe {
-            ffi::newrelic_record_custom_metric(
-                self.inner,
-                metric_name.as_ptr(),
-                duration.as_millis() as f64,
-            )
+        let (backend, handle) = match self.inner() {
+            Some(inner) => inner,
+            None => return Ok(()),
         };
+        let metric_name = CString::new(metric_name)?;
+        let ok = backend.record_custom_metric(
+            handle,
+            &metric_name,
+            duration.as_secs_f64() * 1000.0,
+            MetricUnit::Milliseconds,
+        );
         if ok {
             Ok(())
         } else {
@@ -403,20 +977,40 @@ impl Transaction {
     /// custom_event.add_attribute("number of foos", 1_000);
     /// custom_event.record();
     /// ```
-    pub fn custom_event(&self, event_type: &str) -> Result<CustomEvent> {
+    pub fn custom_event(&self, event_type: &str) -> Result<CustomEvent<'_>> {
         CustomEvent::new(self, event_type)
     }
 
     /// Change the name of the transaction
+    ///
+    /// The app's `NamingRules` are applied to the new name, and the
+    /// transaction is ignored if its `IgnoreRules` match the result.
     pub fn name(&self, new_name: &str) -> Result<()> {
-        let metric_name = CString::new(new_name)?;
-
-        let ok = unsafe { ffi::newrelic_set_transaction_name(self.inner, metric_name.as_ptr()) };
-        if ok {
-            Ok(())
+        let (backend, handle) = match self.inner() {
+            Some(inner) => inner,
+            None => return Ok(()),
+        };
+        CString::new(new_name)?;
+        let new_name = self.namer.rename(new_name);
+        let ignore = self.ignored.load(Ordering::Relaxed)
+            || self
+                .ignore_rules
+                .should_ignore(&new_name, &mut self.sampled_by.lock().unwrap());
+        let new_name = if ignore {
+            new_name
         } else {
-            Err(Error::TransactionNameError)
+            self.namer.limit(new_name)
+        };
+        let metric_name = CString::new(new_name.as_ref())?;
+
+        let ok = backend.set_transaction_name(handle, &metric_name);
+        if !ok {
+            return Err(Error::TransactionNameError);
         }
+        if ignore {
+            self.ignore()?;
+        }
+        Ok(())
     }
 
     /// Explicitly end this transaction.
@@ -425,8 +1019,20 @@ impl Transaction {
     /// when dropped.
     pub fn end(&mut self) {
         if let State::Running = self.state {
-            unsafe {
-                ffi::newrelic_end_transaction(&mut self.inner);
+            if let Some((backend, handle)) = self.inner() {
+                // Ignored transactions are discarded, so leave the app's
+                // metrics to be reported by the next transaction.
+                if !self.ignored.load(Ordering::Relaxed) {
+                    for metric in self.metrics.drain() {
+                        backend.record_custom_metric(
+                            handle,
+                            &metric.name,
+                            metric.value,
+                            metric.unit,
+                        );
+                    }
+                }
+                backend.end_transaction(handle);
             }
             debug!("Ended transaction");
             self.state = State::Ended;
@@ -446,6 +1052,142 @@ impl AsRef<Self> for Transaction {
     }
 }
 
-unsafe impl Send for Transaction {}
+/// The message of a noticed error: the error followed by each of its
+/// sources.
+fn error_message<E: StdError + ?Sized>(error: &E) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn noop_transactions_validate_timing() {
+        let app = App::disabled();
+        let transaction = app.web_transaction("index").unwrap();
+        let future = SystemTime::now() + Duration::from_secs(60);
+        let result = transaction.set_timing(future, Duration::ZERO);
+        assert!(matches!(result, Err(Error::InvalidTiming(_))));
+        let result = transaction.set_timing(UNIX_EPOCH, Duration::MAX);
+        assert!(matches!(result, Err(Error::DurationOverFlow)));
+        assert!(transaction
+            .set_timing(SystemTime::now(), Duration::from_millis(10))
+            .is_ok());
+
+        let segment = transaction.create_custom_segment("render", "Custom");
+        let result = segment.set_timing(Duration::MAX, Duration::ZERO);
+        assert!(matches!(result, Err(Error::DurationOverFlow)));
+    }
+
+    /// The value `attribute` is passed to a backend as, if any.
+    fn backend_value(attribute: Attribute<'_>) -> Option<String> {
+        attribute
+            .with_value("test", |value| format!("{:?}", value))
+            .unwrap()
+    }
+
+    #[test]
+    fn unsigned_longs_beyond_i64_are_floats() {
+        let max = i64::MAX as u64;
+        assert_eq!(
+            backend_value(max.into()),
+            Some(format!("{:?}", AttributeValue::Long(i64::MAX)))
+        );
+        assert_eq!(
+            backend_value((max + 1).into()),
+            Some(format!("{:?}", AttributeValue::Float((max + 1) as f64)))
+        );
+        assert_eq!(
+            backend_value(u64::MAX.into()),
+            Some(format!("{:?}", AttributeValue::Float(u64::MAX as f64)))
+        );
+    }
+
+    #[test]
+    fn durations_are_seconds() {
+        let attribute = Attribute::from(Duration::from_millis(1500));
+        assert!(matches!(attribute, Attribute::Float(seconds) if seconds == 1.5));
+    }
+
+    #[test]
+    fn options_convert_their_values() {
+        assert!(matches!(Attribute::from(Some(3)), Attribute::Int(3)));
+        assert!(matches!(Attribute::from(None::<i32>), Attribute::None));
+        assert_eq!(backend_value(Attribute::None), None);
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn none_attributes_are_not_added() {
+        use crate::testing::{RecordingApp, Value};
+
+        let app = RecordingApp::new();
+        {
+            let transaction = app.web_transaction("index").unwrap();
+            transaction.add_attribute("user", None::<&str>).unwrap();
+            transaction.add_attribute("size", Some(3)).unwrap();
+        }
+        let transactions = app.transactions();
+        let attributes = &transactions[0].attributes;
+        assert!(!attributes.contains_key("user"));
+        assert_eq!(attributes["size"], Value::Int(3));
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn errors_are_classed_by_static_type() {
+        use crate::testing::RecordingApp;
+
+        let app = RecordingApp::new();
+        {
+            let transaction = app.web_transaction("index").unwrap();
+            let error = "forty-two".parse::<i32>().unwrap_err();
+            transaction.notice_error_from(&error).unwrap();
+            let boxed: Box<dyn StdError> = Box::new(error);
+            transaction.notice_error_from(boxed.as_ref()).unwrap();
+            transaction
+                .notice_error_with_class(0, boxed.as_ref(), "ParseIntError", &[])
+                .unwrap();
+        }
+        let transactions = app.transactions();
+        let classes: Vec<&str> = transactions[0]
+            .errors
+            .iter()
+            .map(|error| error.class.as_str())
+            .collect();
+        assert_eq!(
+            classes,
+            vec![
+                "core::num::error::ParseIntError",
+                "dyn core::error::Error",
+                "ParseIntError"
+            ]
+        );
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn invalid_errors_add_no_attributes() {
+        use crate::testing::RecordingApp;
 
-unsafe impl Sync for Transaction {}
+        let app = RecordingApp::new();
+        {
+            let transaction = app.web_transaction("index").unwrap();
+            let error = "forty-two".parse::<i32>().unwrap_err();
+            let attributes = [("input", Attribute::String("forty-two"))];
+            let result = transaction.notice_error_with_class(0, &error, "Parse\0Int", &attributes);
+            assert!(matches!(result, Err(Error::NulError(_))));
+        }
+        let transactions = app.transactions();
+        assert!(transactions[0].attributes.is_empty());
+        assert!(transactions[0].errors.is_empty());
+    }
+}
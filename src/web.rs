@@ -0,0 +1,371 @@
+use log::debug;
+
+use crate::transaction::Attribute;
+
+/// The headers recorded from web requests by the other New Relic agents,
+/// and the attributes they are recorded as.
+const REQUEST_HEADERS: &[(&str, &str)] = &[
+    ("accept", "request.headers.accept"),
+    ("content-length", "request.headers.contentLength"),
+    ("content-type", "request.headers.contentType"),
+    ("host", "request.headers.host"),
+    ("referer", "request.headers.referer"),
+    ("user-agent", "request.headers.userAgent"),
+];
+
+/// The headers recorded from web responses by the other New Relic agents,
+/// and the attributes they are recorded as.
+const RESPONSE_HEADERS: &[(&str, &str)] = &[
+    ("content-length", "response.headers.contentLength"),
+    ("content-type", "response.headers.contentType"),
+];
+
+/// The request handled by a web transaction, recorded using
+/// `Transaction::set_web_request`.
+///
+/// The method and URI are recorded as the standard `request.method` and
+/// `request.uri` attributes. The query string and fragment are removed
+/// from the URI, as they often contain sensitive data.
+///
+/// The standard request headers (`Accept`, `Content-Length`,
+/// `Content-Type`, `Host`, `Referer` and `User-Agent`) are recorded using
+/// the attribute names the New Relic UI understands, such as
+/// `request.headers.userAgent`. Other headers, such as `Authorization` or
+/// `Cookie`, often hold credentials, so `header` ignores them; use
+/// `custom_header` to record one as `request.headers.` followed by its
+/// lowercased name.
+///
+/// With the `http` feature, a `WebRequest` can be created from an
+/// `http::Request` or its `http::request::Parts`, recording only the
+/// standard headers.
+///
+/// Example:
+///
+/// ```rust
+/// use newrelic::{AppBuilder, WebRequest};
+///
+/// let app = AppBuilder::disabled().build().expect("Unable to create app");
+/// let transaction = app
+///     .web_transaction("GET /users")
+///     .expect("Could not start transaction");
+/// transaction
+///     .set_web_request(
+///         WebRequest::new("GET", "/users?page=2").header("User-Agent", "curl/8.0"),
+///     )
+///     .expect("Could not record request");
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebRequest<'a> {
+    method: &'a str,
+    uri: &'a str,
+    headers: Vec<(&'a str, &'a str)>,
+    custom_headers: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> WebRequest<'a> {
+    /// Describe a request with the given method, such as `GET`, and URI.
+    pub fn new(method: &'a str, uri: &'a str) -> Self {
+        WebRequest {
+            method,
+            uri,
+            headers: Vec::new(),
+            custom_headers: Vec::new(),
+        }
+    }
+
+    /// Record a request header, if it's one of the standard headers.
+    pub fn header(mut self, name: &'a str, value: &'a str) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+
+    /// Record any request header, even if it isn't one of the standard
+    /// headers.
+    ///
+    /// Take care not to record headers holding credentials, such as
+    /// `Authorization` or `Cookie`.
+    pub fn custom_header(mut self, name: &'a str, value: &'a str) -> Self {
+        self.custom_headers.push((name, value));
+        self
+    }
+
+    /// The attributes recording this request.
+    pub(crate) fn attributes(&self) -> Vec<(String, Attribute<'a>)> {
+        let mut attributes = vec![
+            ("request.method".to_string(), Attribute::String(self.method)),
+            (
+                "request.uri".to_string(),
+                Attribute::String(strip_query(self.uri)),
+            ),
+        ];
+        for (name, value) in headers(&self.headers, &self.custom_headers, REQUEST_HEADERS) {
+            let name = header_attribute("request", REQUEST_HEADERS, name);
+            let value = if name == "request.headers.referer" {
+                strip_query(value)
+            } else {
+                value
+            };
+            let attribute = header_value(&name, value);
+            attributes.push((name, attribute));
+        }
+        attributes
+    }
+}
+
+/// The response sent by a web transaction, recorded using
+/// `Transaction::set_web_response`.
+///
+/// The status code is recorded as the standard `http.statusCode`
+/// attribute, and also as the older `httpResponseCode` attribute used by
+/// some views of the New Relic UI.
+///
+/// The standard response headers (`Content-Length` and `Content-Type`)
+/// are recorded using the attribute names the New Relic UI understands,
+/// such as `response.headers.contentType`. Other headers, such as
+/// `Set-Cookie`, are ignored by `header`; use `custom_header` to record
+/// one as `response.headers.` followed by its lowercased name.
+///
+/// With the `http` feature, a `WebResponse` can be created from an
+/// `http::Response` or its `http::response::Parts`, recording only the
+/// standard headers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebResponse<'a> {
+    status: u16,
+    headers: Vec<(&'a str, &'a str)>,
+    custom_headers: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> WebResponse<'a> {
+    /// Describe a response with the given status code.
+    pub fn new(status: u16) -> Self {
+        WebResponse {
+            status,
+            headers: Vec::new(),
+            custom_headers: Vec::new(),
+        }
+    }
+
+    /// Record a response header, if it's one of the standard headers.
+    pub fn header(mut self, name: &'a str, value: &'a str) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+
+    /// Record any response header, even if it isn't one of the standard
+    /// headers.
+    ///
+    /// Take care not to record headers holding credentials, such as
+    /// `Set-Cookie`.
+    pub fn custom_header(mut self, name: &'a str, value: &'a str) -> Self {
+        self.custom_headers.push((name, value));
+        self
+    }
+
+    /// The attributes recording this response.
+    pub(crate) fn attributes(&self) -> Vec<(String, Attribute<'a>)> {
+        let mut attributes = vec![
+            (
+                "http.statusCode".to_string(),
+                Attribute::Int(i32::from(self.status)),
+            ),
+            (
+                "httpResponseCode".to_string(),
+                Attribute::display(self.status),
+            ),
+        ];
+        for (name, value) in headers(&self.headers, &self.custom_headers, RESPONSE_HEADERS) {
+            let name = header_attribute("response", RESPONSE_HEADERS, name);
+            let attribute = header_value(&name, value);
+            attributes.push((name, attribute));
+        }
+        attributes
+    }
+}
+
+/// The headers to record: the standard ones out of `headers`, followed by
+/// every custom header.
+fn headers<'a, 'b>(
+    headers: &'b [(&'a str, &'a str)],
+    custom_headers: &'b [(&'a str, &'a str)],
+    standard: &'b [(&str, &str)],
+) -> impl Iterator<Item = (&'a str, &'a str)> + 'b {
+    headers
+        .iter()
+        .filter(move |(name, _)| {
+            let is_standard = standard
+                .iter()
+                .any(|(header, _)| header.eq_ignore_ascii_case(name));
+            if !is_standard {
+                debug!("Not recording non-standard header {}", name);
+            }
+            is_standard
+        })
+        .chain(custom_headers)
+        .copied()
+}
+
+/// The name of the attribute recording the header `name` of a request or
+/// response.
+fn header_attribute(kind: &str, standard: &[(&str, &str)], name: &str) -> String {
+    let name = name.to_ascii_lowercase();
+    match standard.iter().find(|(header, _)| *header == name) {
+        Some((_, attribute)) => attribute.to_string(),
+        None => format!("{}.headers.{}", kind, name),
+    }
+}
+
+/// Remove the query string and fragment from a URI.
+fn strip_query(uri: &str) -> &str {
+    match uri.find(['?', '#']) {
+        Some(end) => &uri[..end],
+        None => uri,
+    }
+}
+
+/// The value of the header recorded as the attribute `name`; content
+/// lengths are recorded as numbers when they're valid.
+fn header_value<'a>(name: &str, value: &'a str) -> Attribute<'a> {
+    if name.ends_with(".headers.contentLength") {
+        if let Ok(length) = value.trim().parse::<i64>() {
+            return Attribute::Long(length);
+        }
+    }
+    Attribute::String(value)
+}
+
+/// The standard headers of an `http` request or response.
+#[cfg(feature = "http")]
+fn standard_headers<'a>(
+    headers: &'a http::HeaderMap,
+    standard: &[(&'static str, &str)],
+) -> Vec<(&'a str, &'a str)> {
+    standard
+        .iter()
+        .filter_map(|&(name, _)| {
+            let value = headers.get(name)?.to_str().ok()?;
+            Some((name, value))
+        })
+        .collect()
+}
+
+#[cfg(feature = "http")]
+#[cfg_attr(docsrs, doc(cfg(feature = "http")))]
+impl<'a> From<&'a http::request::Parts> for WebRequest<'a> {
+    fn from(parts: &'a http::request::Parts) -> Self {
+        WebRequest {
+            method: parts.method.as_str(),
+            uri: parts.uri.path(),
+            headers: standard_headers(&parts.headers, REQUEST_HEADERS),
+            custom_headers: Vec::new(),
+        }
+    }
+}
+
+#[cfg(feature = "http")]
+#[cfg_attr(docsrs, doc(cfg(feature = "http")))]
+impl<'a, B> From<&'a http::Request<B>> for WebRequest<'a> {
+    fn from(request: &'a http::Request<B>) -> Self {
+        WebRequest {
+            method: request.method().as_str(),
+            uri: request.uri().path(),
+            headers: standard_headers(request.headers(), REQUEST_HEADERS),
+            custom_headers: Vec::new(),
+        }
+    }
+}
+
+#[cfg(feature = "http")]
+#[cfg_attr(docsrs, doc(cfg(feature = "http")))]
+impl<'a> From<&'a http::response::Parts> for WebResponse<'a> {
+    fn from(parts: &'a http::response::Parts) -> Self {
+        WebResponse {
+            status: parts.status.as_u16(),
+            headers: standard_headers(&parts.headers, RESPONSE_HEADERS),
+            custom_headers: Vec::new(),
+        }
+    }
+}
+
+#[cfg(feature = "http")]
+#[cfg_attr(docsrs, doc(cfg(feature = "http")))]
+impl<'a, B> From<&'a http::Response<B>> for WebResponse<'a> {
+    fn from(response: &'a http::Response<B>) -> Self {
+        WebResponse {
+            status: response.status().as_u16(),
+            headers: standard_headers(response.headers(), RESPONSE_HEADERS),
+            custom_headers: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(attributes: Vec<(String, Attribute<'_>)>) -> Vec<String> {
+        attributes.into_iter().map(|(name, _)| name).collect()
+    }
+
+    #[test]
+    fn request_records_standard_headers() {
+        let request = WebRequest::new("GET", "/users?page=2")
+            .header("User-Agent", "curl/8.0")
+            .header("Referer", "https://example.com/?token=secret")
+            .header("Authorization", "Bearer secret")
+            .header("Cookie", "session=secret")
+            .header("X-Request-Id", "abc");
+        let attributes = request.attributes();
+        let referer = attributes
+            .iter()
+            .find(|(name, _)| name == "request.headers.referer")
+            .map(|(_, attribute)| attribute);
+        assert!(matches!(
+            referer,
+            Some(Attribute::String("https://example.com/"))
+        ));
+        assert_eq!(
+            names(attributes),
+            vec![
+                "request.method",
+                "request.uri",
+                "request.headers.userAgent",
+                "request.headers.referer",
+            ]
+        );
+    }
+
+    #[test]
+    fn request_records_custom_headers() {
+        let request = WebRequest::new("GET", "/users")
+            .custom_header("X-Request-Id", "abc")
+            .custom_header("Content-Type", "text/plain");
+        assert_eq!(
+            names(request.attributes()),
+            vec![
+                "request.method",
+                "request.uri",
+                "request.headers.x-request-id",
+                "request.headers.contentType",
+            ]
+        );
+    }
+
+    #[test]
+    fn response_records_standard_headers() {
+        let response = WebResponse::new(200)
+            .header("Content-Length", "42")
+            .header("Set-Cookie", "session=secret")
+            .custom_header("X-Cache", "HIT");
+        let attributes = response.attributes();
+        assert!(matches!(attributes[2].1, Attribute::Long(42)));
+        assert_eq!(
+            names(attributes),
+            vec![
+                "http.statusCode",
+                "httpResponseCode",
+                "response.headers.contentLength",
+                "response.headers.x-cache",
+            ]
+        );
+    }
+}